@@ -21,12 +21,21 @@ fn main() {
     loop {
         let input = read_from_stdin(
             "Select operation:
+                -> open
+                -> sub_account
                 -> deposit
                 -> withdraw
                 -> send
+                -> transfer
+                -> repay
                 -> submit_order
                 -> orderbook
                 -> account
+                -> rollup
+                -> positions
+                -> margin
+                -> statement
+                -> ticker
                 -> txlog
                 -> quit",
         );
@@ -39,13 +48,35 @@ fn process_actions(client: &reqwest::blocking::Client, host: &Url, action: &str)
     let alert = yansi::Style::new(Red).italic();
 
     match action {
+        "open" | "OPEN" => match open(client, host) {
+            Ok(account) => {
+                println!("{}", success.paint("Account opened"));
+                print_account_record_table(account);
+            }
+            Err(e) => eprintln!(
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "sub_account" | "SUB_ACCOUNT" => match sub_account(client, host) {
+            Ok(account) => {
+                println!("{}", success.paint("Sub-account opened"));
+                print_account_record_table(account);
+            }
+            Err(e) => eprintln!(
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
         "deposit" | "DEPOSIT" => match deposit(client, host) {
             Ok(tx) => {
                 println!("{}", success.paint("Deposit successful"));
                 print_tx_table(tx);
             }
             Err(e) => eprintln!(
-                "{}: {:?}",
+                "{}: {}",
                 alert.paint("Something went wrong"),
                 alert.paint(e)
             ),
@@ -56,7 +87,7 @@ fn process_actions(client: &reqwest::blocking::Client, host: &Url, action: &str)
                 print_tx_table(tx);
             }
             Err(e) => eprintln!(
-                "{}: {:?}",
+                "{}: {}",
                 alert.paint("Something went wrong"),
                 alert.paint(e)
             ),
@@ -64,10 +95,32 @@ fn process_actions(client: &reqwest::blocking::Client, host: &Url, action: &str)
         "send" | "SEND" => match send(client, host) {
             Ok(tx) => {
                 println!("{}", success.paint("Send successful"));
-                print_send_table(tx);
+                print_tx_table(tx);
             }
             Err(e) => eprintln!(
-                "{}: {:?}",
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "transfer" | "TRANSFER" => match transfer(client, host) {
+            Ok(tx) => {
+                println!("{}", success.paint("Transfer successful"));
+                print_tx_table(tx);
+            }
+            Err(e) => eprintln!(
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "repay" | "REPAY" => match repay(client, host) {
+            Ok(tx) => {
+                println!("{}", success.paint("Repayment successful"));
+                print_tx_table(tx);
+            }
+            Err(e) => eprintln!(
+                "{}: {}",
                 alert.paint("Something went wrong"),
                 alert.paint(e)
             ),
@@ -77,9 +130,10 @@ fn process_actions(client: &reqwest::blocking::Client, host: &Url, action: &str)
                 println!("{}", success.paint("Order submitted successfully!"));
                 println!("{}", Cyan.paint("Matched with the following:"));
                 print_partial_orders_table(receipt.matches);
+                println!("{}", Cyan.paint(format!("Fee paid: {}", receipt.fee)));
             }
             Err(e) => eprintln!(
-                "{}: {:?}",
+                "{}: {}",
                 alert.paint("Something went wrong"),
                 alert.paint(e)
             ),
@@ -87,15 +141,55 @@ fn process_actions(client: &reqwest::blocking::Client, host: &Url, action: &str)
         "orderbook" | "ORDERBOOK" => match orderbook(client, host) {
             Ok(orderbook) => print_partial_orders_table(orderbook),
             Err(e) => eprintln!(
-                "{}: {:?}",
+                "{}: {}",
                 alert.paint("Something went wrong"),
                 alert.paint(e)
             ),
         },
         "account" | "ACCOUNT" => match account(client, host) {
-            Ok(balance) => print_account_table(balance),
+            Ok(holdings) => print_account_table(holdings),
             Err(e) => eprintln!(
-                "{}: {:?}",
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "rollup" | "ROLLUP" => match rollup(client, host) {
+            Ok(holdings) => print_account_table(holdings),
+            Err(e) => eprintln!(
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "positions" | "POSITIONS" => match positions(client, host) {
+            Ok(positions) => print_positions_table(positions),
+            Err(e) => eprintln!(
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "margin" | "MARGIN" => match margin(client, host) {
+            Ok(report) => print_margin_table(report),
+            Err(e) => eprintln!(
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "statement" | "STATEMENT" => match statement(client, host) {
+            Ok(path) => println!("{}", success.paint(format!("Statement written to {path}"))),
+            Err(e) => eprintln!(
+                "{}: {}",
+                alert.paint("Something went wrong"),
+                alert.paint(e)
+            ),
+        },
+        "ticker" | "TICKER" => match ticker(client, host) {
+            Ok(ticker) => print_ticker_table(ticker),
+            Err(e) => eprintln!(
+                "{}: {}",
                 alert.paint("Something went wrong"),
                 alert.paint(e)
             ),
@@ -103,7 +197,7 @@ fn process_actions(client: &reqwest::blocking::Client, host: &Url, action: &str)
         "txlog" | "TXLOG" => match txlog(client, host) {
             Ok(txs) => print_txlog_table(txs),
             Err(e) => eprintln!(
-                "{}: {:?}",
+                "{}: {}",
                 alert.paint("Something went wrong"),
                 alert.paint(e)
             ),
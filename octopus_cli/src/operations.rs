@@ -1,11 +1,19 @@
 use octopus_engine::{
-    errors::{OctopusError},
+    accounting::{Account, Balance},
+    errors::OctopusError,
+    margin::MarginReport,
+    positions::{MarkPrice, PositionReport},
+    statement::{RangeKind, StatementFormat},
+    ticker::Ticker,
     tx::Tx,
-    types::{DepositArgs, OrderArgs, PartialOrder, Receipt, SendArgs, Side, WithdrawArgs},
+    types::{
+        AccountArgs, Asset, DepositArgs, Instrument, OrderArgs, PartialOrder, Receipt, RepayArgs,
+        SendArgs, Side, SubAccountArgs, TransferArgs, WithdrawArgs, DEFAULT_MARKET,
+    },
 };
 use reqwest::Url;
 
-use std::{error::Error, io};
+use std::{collections::BTreeMap, error::Error, fs, io};
 use yansi::Color::{Blue, Cyan};
 
 pub fn read_from_stdin(label: &str) -> String {
@@ -19,19 +27,81 @@ pub fn read_from_stdin(label: &str) -> String {
     user_input.trim().to_owned()
 }
 
+pub fn read_asset() -> Asset {
+    let quote = Instrument::default().quote;
+    match read_from_stdin(&format!("What is the asset? (default is {quote})")).as_str() {
+        "" => quote,
+        asset => Asset::new(&asset.to_uppercase()),
+    }
+}
+
+pub fn open(client: &reqwest::blocking::Client, host: &Url) -> Result<Account, Box<dyn Error>> {
+    let signer = read_from_stdin("What is the new account name?");
+
+    println!("Opening account {}", Cyan.paint(&signer));
+
+    let body = AccountArgs { signer };
+
+    let response = client
+        .post(host.join("/admin/account/open")?)
+        .json(&body)
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<Account>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+pub fn sub_account(
+    client: &reqwest::blocking::Client,
+    host: &Url,
+) -> Result<Account, Box<dyn Error>> {
+    let signer = read_from_stdin("What is the parent account name?");
+    let account = read_from_stdin("What is the new sub-account name?");
+
+    println!(
+        "Opening sub-account {} of {}",
+        Cyan.paint(&account),
+        Cyan.paint(&signer)
+    );
+
+    let body = SubAccountArgs { signer, account };
+
+    let response = client
+        .post(host.join("/account/sub_accounts/open")?)
+        .json(&body)
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<Account>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
 pub fn deposit(client: &reqwest::blocking::Client, host: &Url) -> Result<Tx, Box<dyn Error>> {
     let signer = read_from_stdin("What is the signer account name?");
+    let asset = read_asset();
     let amount = read_from_stdin("What is the amount?")
         .parse()
         .expect("Please input a valid number");
 
     println!(
-        "Depositing {} to {}",
+        "Depositing {} {} to {}",
         Cyan.paint(&amount),
+        Cyan.paint(&asset),
         Cyan.paint(&signer)
     );
 
-    let body = DepositArgs { signer, amount };
+    let body = DepositArgs {
+        signer,
+        asset,
+        amount,
+        idempotency_key: None,
+        actor: None,
+    };
 
     let response = client
         .post(host.join("/account/deposit")?)
@@ -47,17 +117,25 @@ pub fn deposit(client: &reqwest::blocking::Client, host: &Url) -> Result<Tx, Box
 
 pub fn withdraw(client: &reqwest::blocking::Client, host: &Url) -> Result<Tx, Box<dyn Error>> {
     let signer = read_from_stdin("What is the signer account name?");
+    let asset = read_asset();
     let amount = read_from_stdin("What is the amount?")
         .parse()
         .expect("Please input a valid number");
 
     println!(
-        "Withdrawing {} from {}",
+        "Withdrawing {} {} from {}",
         Cyan.paint(&amount),
+        Cyan.paint(&asset),
         Cyan.paint(&signer)
     );
 
-    let body = WithdrawArgs { signer, amount };
+    let body = WithdrawArgs {
+        signer,
+        asset,
+        amount,
+        idempotency_key: None,
+        actor: None,
+    };
 
     let response = client
         .post(host.join("/account/withdraw")?)
@@ -71,24 +149,61 @@ pub fn withdraw(client: &reqwest::blocking::Client, host: &Url) -> Result<Tx, Bo
     }
 }
 
-pub fn send(client: &reqwest::blocking::Client, host: &Url) -> Result<(Tx, Tx), Box<dyn Error>> {
+pub fn repay(client: &reqwest::blocking::Client, host: &Url) -> Result<Tx, Box<dyn Error>> {
+    let signer = read_from_stdin("What is the signer account name?");
+    let asset = read_asset();
+    let amount = read_from_stdin("What is the amount?")
+        .parse()
+        .expect("Please input a valid number");
+
+    println!(
+        "Repaying {} {} borrowed by {}",
+        Cyan.paint(&amount),
+        Cyan.paint(&asset),
+        Cyan.paint(&signer)
+    );
+
+    let body = RepayArgs {
+        signer,
+        asset,
+        amount,
+    };
+
+    let response = client
+        .post(host.join("/account/repay")?)
+        .json(&body)
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<Tx>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+pub fn send(client: &reqwest::blocking::Client, host: &Url) -> Result<Tx, Box<dyn Error>> {
     let signer = read_from_stdin("What is the sender account name?");
     let recipient = read_from_stdin("What is the recipient account name?");
+    let asset = read_asset();
     let amount = read_from_stdin("What is the amount?")
         .parse()
         .expect("Please input a valid number");
 
     println!(
-        "Sending {} from {} to {}",
+        "Sending {} {} from {} to {}",
         Cyan.paint(&amount),
+        Cyan.paint(&asset),
         Cyan.paint(&signer),
         Cyan.paint(&recipient)
     );
 
     let body = SendArgs {
         signer,
+        asset,
         amount,
         recipient,
+        idempotency_key: None,
+        actor: None,
     };
 
     let response = client
@@ -97,7 +212,44 @@ pub fn send(client: &reqwest::blocking::Client, host: &Url) -> Result<(Tx, Tx),
         .send()?;
 
     if response.status() == reqwest::StatusCode::OK {
-        Ok(response.json::<(Tx, Tx)>()?)
+        Ok(response.json::<Tx>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+pub fn transfer(client: &reqwest::blocking::Client, host: &Url) -> Result<Tx, Box<dyn Error>> {
+    let signer = read_from_stdin("What is your account name?");
+    let from = read_from_stdin("Which account is it from?");
+    let to = read_from_stdin("Which account is it to?");
+    let asset = read_asset();
+    let amount = read_from_stdin("What is the amount?")
+        .parse()
+        .expect("Please input a valid number");
+
+    println!(
+        "Transferring {} {} from {} to {}",
+        Cyan.paint(&amount),
+        Cyan.paint(&asset),
+        Cyan.paint(&from),
+        Cyan.paint(&to)
+    );
+
+    let body = TransferArgs {
+        signer,
+        from,
+        to,
+        amount,
+        asset,
+    };
+
+    let response = client
+        .post(host.join("/account/transfer")?)
+        .json(&body)
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<Tx>()?)
     } else {
         Err(Box::new(response.json::<OctopusError>()?))
     }
@@ -128,6 +280,9 @@ pub fn submit_order(
         .parse()
         .expect("Please input a valid number");
 
+    let account = read_from_stdin("Which sub-account is it for? (default is your own)");
+    let account = (!account.is_empty()).then_some(account);
+
     println!("{}", Cyan.paint("Submitting order....."));
 
     let body = OrderArgs {
@@ -135,6 +290,9 @@ pub fn submit_order(
         amount,
         side,
         signer,
+        market: DEFAULT_MARKET.to_string(),
+        account,
+        idempotency_key: None,
     };
 
     let response = client
@@ -164,10 +322,13 @@ pub fn orderbook(
     }
 }
 
-pub fn account(client: &reqwest::blocking::Client, host: &Url) -> Result<u64, Box<dyn Error>> {
+pub fn account(
+    client: &reqwest::blocking::Client,
+    host: &Url,
+) -> Result<BTreeMap<Asset, Balance>, Box<dyn Error>> {
     let signer = read_from_stdin("What is the account name?");
 
-    println!("{}", Cyan.paint("Checking account balance....."));
+    println!("{}", Cyan.paint("Checking account holdings....."));
 
     let response = client
         .get(host.join("/account")?)
@@ -175,7 +336,133 @@ pub fn account(client: &reqwest::blocking::Client, host: &Url) -> Result<u64, Bo
         .send()?;
 
     if response.status() == reqwest::StatusCode::OK {
-        Ok(response.json::<u64>()?)
+        Ok(response.json::<BTreeMap<Asset, Balance>>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+pub fn rollup(
+    client: &reqwest::blocking::Client,
+    host: &Url,
+) -> Result<BTreeMap<Asset, Balance>, Box<dyn Error>> {
+    let signer = read_from_stdin("What is the account name?");
+
+    println!(
+        "{}",
+        Cyan.paint("Adding up the holdings of the account and its sub-accounts.....")
+    );
+
+    let response = client
+        .get(host.join("/account/rollup")?)
+        .query(&[("signer", &signer)])
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<BTreeMap<Asset, Balance>>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+pub fn positions(
+    client: &reqwest::blocking::Client,
+    host: &Url,
+) -> Result<Vec<PositionReport>, Box<dyn Error>> {
+    let signer = read_from_stdin("What is the account name?");
+    let mark = match read_from_stdin("Value at the last or mid price? (default is last)").as_str() {
+        "mid" | "MID" => MarkPrice::Mid,
+        _ => MarkPrice::Last,
+    };
+
+    println!("{}", Cyan.paint("Checking positions....."));
+
+    let response = client
+        .get(host.join("/positions")?)
+        .query(&[("signer", &signer)])
+        .query(&[("mark", &mark)])
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<Vec<PositionReport>>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+pub fn margin(
+    client: &reqwest::blocking::Client,
+    host: &Url,
+) -> Result<MarginReport, Box<dyn Error>> {
+    let signer = read_from_stdin("What is the account name?");
+
+    println!("{}", Cyan.paint("Checking margin....."));
+
+    let response = client
+        .get(host.join("/margin")?)
+        .query(&[("signer", &signer)])
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<MarginReport>()?)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+/// Downloads the statement of an account and writes it to a file, returns the path of the file
+pub fn statement(client: &reqwest::blocking::Client, host: &Url) -> Result<String, Box<dyn Error>> {
+    let signer = read_from_stdin("What is the account name?");
+    let by = match read_from_stdin("Range by sequence or time? (default is sequence)").as_str() {
+        "time" | "TIME" => RangeKind::Time,
+        _ => RangeKind::Sequence,
+    };
+    let bound = |label: &str| match read_from_stdin(label).as_str() {
+        "" => None,
+        bound => Some(bound.parse::<u64>().expect("Please input a valid number")),
+    };
+    let from = bound("From? (leave empty for the start)");
+    let to = bound("To? (leave empty for the end)");
+    let (format, extension) =
+        match read_from_stdin("Format json or csv? (default is json)").as_str() {
+            "csv" | "CSV" => (StatementFormat::Csv, "csv"),
+            _ => (StatementFormat::Json, "json"),
+        };
+    let default_path = format!("statement-{signer}.{extension}");
+    let path = match read_from_stdin(&format!("Write to which file? (default is {default_path})"))
+        .as_str()
+    {
+        "" => default_path,
+        path => path.to_string(),
+    };
+
+    println!("{}", Cyan.paint("Fetching statement....."));
+
+    let mut query = vec![("signer", signer)];
+    query.extend(from.map(|from| ("from", from.to_string())));
+    query.extend(to.map(|to| ("to", to.to_string())));
+    let response = client
+        .get(host.join("/account/statement")?)
+        .query(&query)
+        .query(&[("by", by)])
+        .query(&[("format", format)])
+        .send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        fs::write(&path, response.text()?)?;
+        Ok(path)
+    } else {
+        Err(Box::new(response.json::<OctopusError>()?))
+    }
+}
+
+pub fn ticker(client: &reqwest::blocking::Client, host: &Url) -> Result<Ticker, Box<dyn Error>> {
+    println!("{}", Cyan.paint("Printing 24h ticker....."));
+
+    let response = client.get(host.join("/ticker")?).send()?;
+
+    if response.status() == reqwest::StatusCode::OK {
+        Ok(response.json::<Ticker>()?)
     } else {
         Err(Box::new(response.json::<OctopusError>()?))
     }
@@ -1,9 +1,15 @@
 use cli_table::{format::Justify, Cell, CellStruct, Style, Table};
 use octopus_engine::{
-    tx::Tx,
-    types::{PartialOrder, Side},
+    accounting::{Account, AccountStatus, Balance},
+    liquidation::LiquidationStep,
+    margin::{MarginReport, MarginStatus},
+    positions::PositionReport,
+    ticker::Ticker,
+    tx::{Tx, TxKind},
+    types::{Asset, PartialOrder, Side},
 };
-use yansi::Color::{Cyan, Green, Red, RGB};
+use std::collections::BTreeMap;
+use yansi::Color::{Cyan, Green, Magenta, Red, Yellow, RGB};
 
 pub fn print_welcome() {
     let octopus_text = r#"
@@ -60,29 +66,7 @@ pub fn print_welcome() {
 }
 
 pub fn print_tx_table(tx: Tx) {
-    let table = vec![generate_tx_row(tx)]
-        .table()
-        .title(vec![
-            "Operation".cell().bold(true),
-            "Account".cell().bold(true),
-            "Amount".cell().bold(true),
-        ])
-        .bold(true);
-
-    println!("{}", table.display().unwrap());
-}
-
-pub fn print_send_table(tx: (Tx, Tx)) {
-    let table = vec![generate_tx_row(tx.0), generate_tx_row(tx.1)]
-        .table()
-        .title(vec![
-            "Operation".cell().bold(true),
-            "Account".cell().bold(true),
-            "Amount".cell().bold(true),
-        ])
-        .bold(true);
-
-    println!("{}", table.display().unwrap());
+    print_txlog_table(vec![tx]);
 }
 
 pub fn print_txlog_table(txs: Vec<Tx>) {
@@ -91,9 +75,14 @@ pub fn print_txlog_table(txs: Vec<Tx>) {
     let table = rows
         .table()
         .title(vec![
+            "Id".cell().bold(true),
+            "Sequence".cell().bold(true),
+            "Timestamp".cell().bold(true),
             "Operation".cell().bold(true),
             "Account".cell().bold(true),
+            "Asset".cell().bold(true),
             "Amount".cell().bold(true),
+            "Reference".cell().bold(true),
         ])
         .bold(true);
 
@@ -131,30 +120,349 @@ pub fn print_partial_orders_table(pos: Vec<PartialOrder>) {
     println!("{}", table.display().unwrap());
 }
 
-pub fn print_account_table(balance: u64) {
-    let table = vec![vec![Cyan.paint(balance).cell().justify(Justify::Center)]]
-        .table()
-        .title(vec!["Balance".cell().bold(true)])
-        .bold(true);
+pub fn print_account_record_table(account: Account) {
+    let status = match account.status {
+        AccountStatus::Active => Green.paint("ACTIVE"),
+        AccountStatus::Frozen => Yellow.paint("FROZEN"),
+        AccountStatus::Closed => Red.paint("CLOSED"),
+    };
+    let table = vec![vec![
+        Cyan.paint(&account.signer).cell().justify(Justify::Center),
+        status.cell().justify(Justify::Center),
+        Cyan.paint(account.parent.unwrap_or("-".to_string()))
+            .cell()
+            .justify(Justify::Center),
+        Cyan.paint(account.opened_at)
+            .cell()
+            .justify(Justify::Center),
+    ]]
+    .table()
+    .title(vec![
+        "Account".cell().bold(true),
+        "Status".cell().bold(true),
+        "Parent".cell().bold(true),
+        "Opened".cell().bold(true),
+    ])
+    .bold(true);
 
     println!("{}", table.display().unwrap());
 }
 
-fn generate_tx_row(tx: Tx) -> Vec<CellStruct> {
-    match tx {
-        Tx::Withdraw { account, amount } => {
+pub fn print_account_table(holdings: BTreeMap<Asset, Balance>) {
+    let rows: Vec<Vec<CellStruct>> = holdings
+        .iter()
+        .map(|(asset, balance)| {
             vec![
-                Red.paint("WITHDRAW").cell().justify(Justify::Center),
-                Cyan.paint(account).cell().justify(Justify::Center),
-                Cyan.paint(amount).cell().justify(Justify::Center),
+                Cyan.paint(asset).cell().justify(Justify::Center),
+                Cyan.paint(balance.total).cell().justify(Justify::Center),
+                Green
+                    .paint(balance.available)
+                    .cell()
+                    .justify(Justify::Center),
             ]
+        })
+        .collect();
+
+    let table = rows
+        .table()
+        .title(vec![
+            "Asset".cell().bold(true),
+            "Total".cell().bold(true),
+            "Available".cell().bold(true),
+        ])
+        .bold(true);
+
+    println!("{}", table.display().unwrap());
+}
+
+pub fn print_positions_table(positions: Vec<PositionReport>) {
+    let price = |p: Option<u64>| p.map_or("-".to_string(), |p| p.to_string());
+    let pnl = |pnl: i128| {
+        if pnl < 0 {
+            Red.paint(pnl.to_string())
+        } else {
+            Green.paint(format!("{pnl:+}"))
         }
-        Tx::Deposit { account, amount } => {
+    };
+    let rows: Vec<Vec<CellStruct>> = positions
+        .iter()
+        .map(|position| {
+            let unrealized = match position.unrealized_pnl {
+                Some(unrealized) => pnl(unrealized),
+                None => Cyan.paint("-".to_string()),
+            };
             vec![
-                Green.paint("DEPOSIT").cell().justify(Justify::Center),
-                Cyan.paint(account).cell().justify(Justify::Center),
-                Cyan.paint(amount).cell().justify(Justify::Center),
+                Cyan.paint(position.instrument.symbol())
+                    .cell()
+                    .justify(Justify::Center),
+                Cyan.paint(position.net).cell().justify(Justify::Center),
+                Cyan.paint(price(position.average_price))
+                    .cell()
+                    .justify(Justify::Center),
+                pnl(position.realized_pnl).cell().justify(Justify::Center),
+                Cyan.paint(price(position.mark_price))
+                    .cell()
+                    .justify(Justify::Center),
+                unrealized.cell().justify(Justify::Center),
             ]
+        })
+        .collect();
+
+    let table = rows
+        .table()
+        .title(vec![
+            "Market".cell().bold(true),
+            "Net".cell().bold(true),
+            "Avg price".cell().bold(true),
+            "Realized PnL".cell().bold(true),
+            "Mark".cell().bold(true),
+            "Unrealized PnL".cell().bold(true),
+        ])
+        .bold(true);
+
+    println!("{}", table.display().unwrap());
+}
+
+pub fn print_margin_table(report: MarginReport) {
+    let status = match report.status {
+        MarginStatus::Healthy => Green.paint("HEALTHY"),
+        MarginStatus::MarginCall => Red.paint("MARGIN CALL"),
+    };
+    let table = vec![vec![
+        Cyan.paint(&report.asset).cell().justify(Justify::Center),
+        Cyan.paint(report.credit_limit)
+            .cell()
+            .justify(Justify::Center),
+        Cyan.paint(report.debt).cell().justify(Justify::Center),
+        Cyan.paint(format!("{:.2}%", report.usage_bps as f64 / 100.0))
+            .cell()
+            .justify(Justify::Center),
+        Green
+            .paint(report.buying_power)
+            .cell()
+            .justify(Justify::Center),
+        Cyan.paint(report.equity).cell().justify(Justify::Center),
+        Cyan.paint(report.maintenance_margin)
+            .cell()
+            .justify(Justify::Center),
+        status.cell().justify(Justify::Center),
+    ]]
+    .table()
+    .title(vec![
+        "Asset".cell().bold(true),
+        "Credit limit".cell().bold(true),
+        "Debt".cell().bold(true),
+        "Usage".cell().bold(true),
+        "Buying power".cell().bold(true),
+        "Equity".cell().bold(true),
+        "Maintenance".cell().bold(true),
+        "Status".cell().bold(true),
+    ])
+    .bold(true);
+
+    println!("{}", table.display().unwrap());
+}
+
+pub fn print_ticker_table(ticker: Ticker) {
+    let price = |p: Option<u64>| p.map_or("-".to_string(), |p| p.to_string());
+    let change = if ticker.price_change < 0 {
+        Red.paint(format!(
+            "{} ({:.2}%)",
+            ticker.price_change, ticker.percent_change
+        ))
+    } else {
+        Green.paint(format!(
+            "+{} (+{:.2}%)",
+            ticker.price_change, ticker.percent_change
+        ))
+    };
+
+    let table = vec![vec![
+        Cyan.paint(price(ticker.last_price))
+            .cell()
+            .justify(Justify::Center),
+        change.cell().justify(Justify::Center),
+        Cyan.paint(price(ticker.high))
+            .cell()
+            .justify(Justify::Center),
+        Cyan.paint(price(ticker.low))
+            .cell()
+            .justify(Justify::Center),
+        Cyan.paint(ticker.volume).cell().justify(Justify::Center),
+        Cyan.paint(ticker.quote_volume)
+            .cell()
+            .justify(Justify::Center),
+        Cyan.paint(price(ticker.vwap))
+            .cell()
+            .justify(Justify::Center),
+        Green
+            .paint(price(ticker.best_bid))
+            .cell()
+            .justify(Justify::Center),
+        Red.paint(price(ticker.best_ask))
+            .cell()
+            .justify(Justify::Center),
+    ]]
+    .table()
+    .title(vec![
+        "Last".cell().bold(true),
+        "24h Change".cell().bold(true),
+        "24h High".cell().bold(true),
+        "24h Low".cell().bold(true),
+        "Volume".cell().bold(true),
+        "Quote Volume".cell().bold(true),
+        "VWAP".cell().bold(true),
+        "Best Bid".cell().bold(true),
+        "Best Ask".cell().bold(true),
+    ])
+    .bold(true);
+
+    println!("{}", table.display().unwrap());
+}
+
+fn generate_tx_row(tx: Tx) -> Vec<CellStruct> {
+    let (operation, account, asset, amount) = match tx.kind {
+        TxKind::Withdraw {
+            account,
+            asset,
+            amount,
+        } => (
+            Red.paint("WITHDRAW"),
+            account,
+            asset.to_string(),
+            amount.to_string(),
+        ),
+        TxKind::Deposit {
+            account,
+            asset,
+            amount,
+        } => (
+            Green.paint("DEPOSIT"),
+            account,
+            asset.to_string(),
+            amount.to_string(),
+        ),
+        TxKind::Transfer {
+            sender,
+            recipient,
+            asset,
+            amount,
+        } => (
+            Cyan.paint("TRANSFER"),
+            format!("{sender} -> {recipient}"),
+            asset.to_string(),
+            amount.to_string(),
+        ),
+        TxKind::TradeSettlement {
+            buyer,
+            seller,
+            base,
+            quote,
+            amount,
+            price,
+        } => (
+            Magenta.paint("TRADE"),
+            format!("{seller} -> {buyer}"),
+            format!("{base}/{quote}"),
+            format!("{amount} @ {price}"),
+        ),
+        TxKind::Fee {
+            account,
+            asset,
+            amount,
+        } => (
+            Red.paint("FEE"),
+            account,
+            asset.to_string(),
+            amount.to_string(),
+        ),
+        TxKind::Adjustment {
+            account,
+            asset,
+            amount,
+        } => (
+            Yellow.paint("ADJUSTMENT"),
+            account,
+            asset.to_string(),
+            format!("{amount:+}"),
+        ),
+        TxKind::Borrow {
+            account,
+            asset,
+            amount,
+        } => (
+            Yellow.paint("BORROW"),
+            account,
+            asset.to_string(),
+            amount.to_string(),
+        ),
+        TxKind::Repay {
+            account,
+            asset,
+            amount,
+        } => (
+            Green.paint("REPAY"),
+            account,
+            asset.to_string(),
+            amount.to_string(),
+        ),
+        TxKind::Liquidation { account, step } => {
+            let (market, detail) = match step {
+                LiquidationStep::Started {
+                    equity,
+                    maintenance_margin,
+                } => (
+                    "-".to_string(),
+                    format!("started {equity}/{maintenance_margin}"),
+                ),
+                LiquidationStep::OrdersCancelled { ordinals } => {
+                    ("-".to_string(), format!("cancelled {ordinals:?}"))
+                }
+                LiquidationStep::Sold {
+                    market,
+                    ordinal,
+                    amount,
+                    filled,
+                } => (market, format!("#{ordinal} sold {filled}/{amount}")),
+                LiquidationStep::Finished {
+                    equity,
+                    maintenance_margin,
+                    status,
+                } => (
+                    "-".to_string(),
+                    format!("{status:?} {equity}/{maintenance_margin}"),
+                ),
+            };
+            (Red.paint("LIQUIDATION"), account, market, detail)
         }
-    }
+        TxKind::Rejected {
+            account,
+            order,
+            reason,
+        } => (
+            Red.paint("REJECTED"),
+            account,
+            "-".to_string(),
+            format!(
+                "{:?} {}@{} {}",
+                order.side,
+                order.amount,
+                order.price,
+                reason.code()
+            ),
+        ),
+    };
+
+    vec![
+        Cyan.paint(tx.id).cell().justify(Justify::Center),
+        Cyan.paint(tx.sequence).cell().justify(Justify::Center),
+        Cyan.paint(tx.timestamp).cell().justify(Justify::Center),
+        operation.cell().justify(Justify::Center),
+        Cyan.paint(account).cell().justify(Justify::Center),
+        Cyan.paint(asset).cell().justify(Justify::Center),
+        Cyan.paint(amount).cell().justify(Justify::Center),
+        Cyan.paint(tx.reference.unwrap_or("-".to_string()))
+            .cell()
+            .justify(Justify::Center),
+    ]
 }
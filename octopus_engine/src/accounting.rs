@@ -1,11 +1,123 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-use crate::{errors::AccountError, tx::Tx};
+use serde::{Deserialize, Serialize};
 
-/// A type for managing accounts and their current currency balance
+use crate::{
+    engine::timestamp,
+    errors::AccountError,
+    ledger::{EntryKind, Ledger, LedgerAccount, Movement},
+    liquidation::LiquidationStep,
+    margin::{MarginReport, MarginStatus, MarginTerms},
+    tx::{Tx, TxKind},
+    types::{self, Asset, Instrument, Order, Receipt, Side},
+};
+
+/// A type for managing accounts and the balance of every asset they hold.
+/// Balances are kept in a double-entry [`Ledger`], every change to them is a journal entry.
 #[derive(Debug, Clone)]
 pub struct Accounts {
-    pub accounts: HashMap<String, u64>,
+    pub ledger: Ledger,
+    /// The part of each balance set aside for resting orders
+    pub holds: HashMap<(String, Asset), u64>,
+    /// Every account that was opened, whatever its status
+    pub records: HashMap<String, Account>,
+    /// The terms of the accounts that can trade on margin
+    pub margin_terms: HashMap<String, MarginTerms>,
+    /// What each account borrowed on margin and hasn't paid back yet
+    pub debts: HashMap<(String, Asset), u64>,
+    /// The price each instrument last traded at, what holdings are valued at for margin
+    pub last_prices: HashMap<Instrument, u64>,
+    /// The buyers and sellers of the trades settled since margin calls were last taken
+    traded: BTreeSet<String>,
+    /// The instruments those trades moved the price of
+    repriced: HashSet<Instrument>,
+    /// The id of the last transaction made
+    issued: u64,
+}
+
+/// Where an account is in its lifecycle
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum AccountStatus {
+    /// Can trade and move its funds
+    Active,
+    /// Can be credited, but can't trade, withdraw or send until it's unfrozen
+    Frozen,
+    /// Emptied and closed for good
+    Closed,
+}
+
+/// The record of an account that was opened on the platform
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub signer: String,
+    pub status: AccountStatus,
+    /// Milliseconds since the unix epoch
+    pub opened_at: u64,
+    /// Milliseconds since the unix epoch of the last change of status
+    pub updated_at: u64,
+    /// The account this is a sub-account of, which can act on it
+    #[serde(default)]
+    pub parent: Option<String>,
+}
+
+/// The balance of an asset in an account and how much of it isn't set aside for resting orders
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Balance {
+    pub total: u64,
+    pub available: u64,
+}
+
+/// A balance of the live accounts that differs from the one rebuilt from the transaction log
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Mismatch {
+    pub signer: String,
+    pub asset: Asset,
+    /// The balance the transaction log adds up to
+    pub expected: u64,
+    /// The balance of the live account
+    pub actual: u64,
+}
+
+/// An `amount` of an asset set aside in the signer account for an order
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hold {
+    pub signer: String,
+    pub asset: Asset,
+    pub amount: u64,
+}
+
+impl Hold {
+    /// What `amount` units at `price` can cost the signer in an [`Instrument`]:
+    /// a buy pays their price in the quote asset and a sell gives up the units of the base asset
+    /// # Errors
+    /// The price of a buy doesn't fit in a `u64`
+    pub fn of(
+        instrument: &Instrument,
+        signer: &str,
+        side: &Side,
+        price: u64,
+        amount: u64,
+    ) -> Result<Hold, AccountError> {
+        let (asset, amount) = match side {
+            Side::Buy => (&instrument.quote, types::value(amount, price)?),
+            Side::Sell => (&instrument.base, amount),
+        };
+        Ok(Hold {
+            signer: signer.to_string(),
+            asset: asset.clone(),
+            amount,
+        })
+    }
+
+    /// Splits off up to `amount` of the hold, leaving the rest in place
+    pub fn take(&mut self, amount: u64) -> Hold {
+        let taken = amount.min(self.amount);
+        self.amount -= taken;
+        Hold {
+            amount: taken,
+            ..self.clone()
+        }
+    }
 }
 
 impl Default for Accounts {
@@ -18,88 +130,1063 @@ impl Accounts {
     /// Returns an empty instance of the [`Accounts`] type
     pub fn new() -> Self {
         Accounts {
-            accounts: Default::default(),
+            ledger: Ledger::new(),
+            holds: Default::default(),
+            records: Default::default(),
+            margin_terms: Default::default(),
+            debts: Default::default(),
+            last_prices: Default::default(),
+            traded: Default::default(),
+            repriced: Default::default(),
+            issued: 0,
+        }
+    }
+
+    /// Rebuilds the balances by applying the transactions in order to empty accounts.
+    /// Trade settlements of the same sequence are paid for together, as they were when they were made.
+    /// Holds, account statuses, sub-accounts and margin terms aren't transactions, so the rebuilt accounts are all
+    /// active, stand on their own, have no holds and can't borrow more, although they still owe what they borrowed.
+    /// # Errors
+    /// A transaction can't be applied, the log doesn't add up
+    pub fn from_transactions(txs: &[Tx]) -> Result<Accounts, AccountError> {
+        let mut accounts = Accounts::new();
+        for entry in txs.chunk_by(|a, b| a.sequence == b.sequence) {
+            accounts.replay(entry)?;
         }
+        Ok(accounts)
     }
 
-    pub fn balance_of(&mut self, signer: &str) -> Result<&u64, AccountError> {
-        self.accounts
+    /// Applies the transactions of one journal entry
+    fn replay(&mut self, txs: &[Tx]) -> Result<(), AccountError> {
+        let mut trades = vec![];
+        for tx in txs {
+            for signer in tx.kind.accounts() {
+                if !self.exists(signer) {
+                    self.open(signer)?;
+                }
+            }
+            match &tx.kind {
+                TxKind::Deposit {
+                    account,
+                    asset,
+                    amount,
+                } => {
+                    self.deposit(account, asset, *amount)?;
+                }
+                TxKind::Withdraw {
+                    account,
+                    asset,
+                    amount,
+                } => {
+                    self.withdraw(account, asset, *amount)?;
+                }
+                TxKind::Transfer {
+                    sender,
+                    recipient,
+                    asset,
+                    amount,
+                } => {
+                    self.send(sender, recipient, asset, *amount)?;
+                }
+                TxKind::TradeSettlement {
+                    buyer,
+                    seller,
+                    base,
+                    quote,
+                    amount,
+                    price,
+                } => trades.push(Trade {
+                    id: tx.reference.clone().unwrap_or_default(),
+                    buyer: buyer.clone(),
+                    seller: seller.clone(),
+                    base: base.clone(),
+                    quote: quote.clone(),
+                    amount: *amount,
+                    price: *price,
+                    buyer_fee: 0,
+                    seller_fee: 0,
+                }),
+                // fees are charged on the trade they reference, which settles before them
+                TxKind::Fee { asset, amount, .. } => {
+                    let trade = trades
+                        .iter_mut()
+                        .rev()
+                        .find(|trade| Some(&trade.id) == tx.reference.as_ref())
+                        .ok_or(AccountError::Unsupported("fee without a trade".to_string()))?;
+                    if asset == &trade.base {
+                        trade.buyer_fee = *amount;
+                    } else {
+                        trade.seller_fee = *amount;
+                    }
+                }
+                TxKind::Adjustment {
+                    account,
+                    asset,
+                    amount,
+                } => {
+                    let reason = tx.reference.clone().unwrap_or_default();
+                    self.adjust(account, asset, *amount, &reason)?;
+                }
+                TxKind::Borrow {
+                    account,
+                    asset,
+                    amount,
+                } => {
+                    self.lend(account, asset, *amount)?;
+                }
+                TxKind::Repay {
+                    account,
+                    asset,
+                    amount,
+                } => {
+                    self.repay(account, asset, *amount)?;
+                }
+                TxKind::Liquidation { .. } | TxKind::Rejected { .. } => {}
+            }
+        }
+        self.settle(&[], &trades)?;
+        Ok(())
+    }
+
+    /// Every signer balance that differs from the accounts rebuilt from the transactions, ordered by signer.
+    /// # Errors
+    /// The transactions can't be replayed, see [`Accounts::from_transactions`]
+    pub fn reconcile(&self, txs: &[Tx]) -> Result<Vec<Mismatch>, AccountError> {
+        let expected = Accounts::from_transactions(txs)?;
+        let keys: BTreeSet<(&String, &Asset)> = self
+            .ledger
+            .balances()
+            .chain(expected.ledger.balances())
+            .filter_map(|(account, asset, _)| match account {
+                LedgerAccount::User(signer) => Some((signer, asset)),
+                _ => None,
+            })
+            .collect();
+
+        Ok(keys
+            .into_iter()
+            .filter_map(|(signer, asset)| {
+                let expected = expected.balance_of(signer, asset).unwrap_or(0);
+                let actual = self.balance_of(signer, asset).unwrap_or(0);
+                (expected != actual).then(|| Mismatch {
+                    signer: signer.clone(),
+                    asset: asset.clone(),
+                    expected,
+                    actual,
+                })
+            })
+            .collect())
+    }
+
+    /// Whether an account was opened for the signer, it may have been frozen or closed since
+    pub fn exists(&self, signer: &str) -> bool {
+        self.records.contains_key(signer)
+    }
+
+    /// The record of the `signer` account
+    /// # Errors
+    /// The account doesn't exist
+    pub fn account(&self, signer: &str) -> Result<&Account, AccountError> {
+        self.records
             .get(signer)
             .ok_or(AccountError::NotFound(signer.to_string()))
     }
 
-    /// Either deposits the `amount` provided into the `signer` account or adds the amount to the existing account.
+    /// Opens an active account for the signer with no balances and returns its record.
+    /// Names starting with `@` are kept for the system accounts of the ledger.
     /// # Errors
-    /// Attempted overflow
-    pub fn deposit(&mut self, signer: &str, amount: u64) -> Result<Tx, AccountError> {
-        if let Some(account) = self.accounts.get_mut(signer) {
-            (*account)
-                .checked_add(amount)
-                .map(|r| {
-                    *account = r;
-                    r
-                })
-                .ok_or(AccountError::OverFunded(signer.to_string(), amount))
-                // Using map() here is an easy way to only manipulate the non-error result
-                .map(|_| Tx::Deposit {
-                    account: signer.to_string(),
-                    amount,
-                })
-        } else {
-            self.accounts.insert(signer.to_string(), amount);
-            Ok(Tx::Deposit {
-                account: signer.to_string(),
-                amount,
+    /// The account exists already, even if it was closed, or the name isn't allowed
+    pub fn open(&mut self, signer: &str) -> Result<Account, AccountError> {
+        if !matches!(LedgerAccount::parse(signer), Some(LedgerAccount::User(_))) {
+            return Err(AccountError::Unsupported(format!(
+                "account name '{}'",
+                signer
+            )));
+        }
+        if self.exists(signer) {
+            return Err(AccountError::AlreadyExists(signer.to_string()));
+        }
+        let now = timestamp();
+        let account = Account {
+            signer: signer.to_string(),
+            status: AccountStatus::Active,
+            opened_at: now,
+            updated_at: now,
+            parent: None,
+        };
+        self.records.insert(signer.to_string(), account.clone());
+        Ok(account)
+    }
+
+    /// Opens an active sub-account of the `parent` account for the signer and returns its record.
+    /// The parent and the accounts above it can act on the sub-account, it can't act on them.
+    /// # Errors
+    /// The parent isn't active, the account exists already or the name isn't allowed
+    pub fn open_sub_account(
+        &mut self,
+        parent: &str,
+        signer: &str,
+    ) -> Result<Account, AccountError> {
+        self.ensure_active(parent)?;
+        let mut account = self.open(signer)?;
+        account.parent = Some(parent.to_string());
+        self.records.insert(signer.to_string(), account.clone());
+        Ok(account)
+    }
+
+    /// The sub-accounts opened under the `signer` account, ordered by name
+    /// # Errors
+    /// The account doesn't exist
+    pub fn sub_accounts(&self, signer: &str) -> Result<Vec<Account>, AccountError> {
+        self.account(signer)?;
+        let mut sub_accounts: Vec<Account> = self
+            .records
+            .values()
+            .filter(|account| account.parent.as_deref() == Some(signer))
+            .cloned()
+            .collect();
+        sub_accounts.sort_by(|a, b| a.signer.cmp(&b.signer));
+        Ok(sub_accounts)
+    }
+
+    /// The `signer` account followed by every account below it, parents before their sub-accounts
+    fn family(&self, signer: &str) -> Vec<String> {
+        let mut family = vec![signer.to_string()];
+        let mut next = 0;
+        while let Some(member) = family.get(next).cloned() {
+            let sub_accounts = self.sub_accounts(&member).unwrap_or_default();
+            family.extend(sub_accounts.into_iter().map(|account| account.signer));
+            next += 1;
+        }
+        family
+    }
+
+    /// Whether the `actor` can act on the `signer` account: it's their own or below it
+    pub fn acts_for(&self, actor: &str, signer: &str) -> bool {
+        let mut account = self.records.get(signer);
+        while let Some(a) = account {
+            if a.signer == actor {
+                return true;
+            }
+            account = a
+                .parent
+                .as_ref()
+                .and_then(|parent| self.records.get(parent));
+        }
+        false
+    }
+
+    /// Checks that the `actor` can act on the `signer` account, see [`Accounts::acts_for`]
+    /// # Errors
+    /// Either account doesn't exist or the account isn't the actor's own or below it
+    pub fn ensure_authorized(&self, actor: &str, signer: &str) -> Result<(), AccountError> {
+        self.account(actor)?;
+        self.account(signer)?;
+        match self.acts_for(actor, signer) {
+            true => Ok(()),
+            false => Err(AccountError::Unauthorized(signer.to_string())),
+        }
+    }
+
+    /// Stops the account from trading and moving its funds out. Freezing a frozen account changes nothing.
+    /// Resting orders aren't tracked here, whoever holds them has to cancel them.
+    /// # Errors
+    /// The account doesn't exist or was closed
+    pub fn freeze(&mut self, signer: &str) -> Result<Account, AccountError> {
+        self.change_status(signer, AccountStatus::Frozen)
+    }
+
+    /// Lets a frozen account trade and move its funds again
+    /// # Errors
+    /// The account doesn't exist or was closed
+    pub fn unfreeze(&mut self, signer: &str) -> Result<Account, AccountError> {
+        self.change_status(signer, AccountStatus::Active)
+    }
+
+    /// Closes an active or frozen account for good, once every balance has been withdrawn, every debt repaid
+    /// and every sub-account closed
+    /// # Errors
+    /// The account doesn't exist, was closed already, still holds some asset, owes some or has open sub-accounts
+    pub fn close(&mut self, signer: &str) -> Result<Account, AccountError> {
+        let sub_accounts = self.sub_accounts(signer)?;
+        if self.signer_balances(signer).any(|(_, balance)| balance > 0)
+            || self.debts.keys().any(|(s, _)| s == signer)
+            || sub_accounts
+                .iter()
+                .any(|account| account.status != AccountStatus::Closed)
+        {
+            return Err(AccountError::NotEmpty(signer.to_string()));
+        }
+        self.change_status(signer, AccountStatus::Closed)
+    }
+
+    /// Moves an account that isn't closed to the status
+    fn change_status(
+        &mut self,
+        signer: &str,
+        status: AccountStatus,
+    ) -> Result<Account, AccountError> {
+        let account = self
+            .records
+            .get_mut(signer)
+            .ok_or(AccountError::NotFound(signer.to_string()))?;
+        if account.status == AccountStatus::Closed {
+            return Err(AccountError::Closed(signer.to_string()));
+        }
+        if account.status != status {
+            account.status = status;
+            account.updated_at = timestamp();
+        }
+        Ok(account.clone())
+    }
+
+    /// Checks that the account can trade and move its funds
+    /// # Errors
+    /// The account doesn't exist, is frozen or closed
+    pub fn ensure_active(&self, signer: &str) -> Result<(), AccountError> {
+        match self.account(signer)?.status {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Frozen => Err(AccountError::Frozen(signer.to_string())),
+            AccountStatus::Closed => Err(AccountError::Closed(signer.to_string())),
+        }
+    }
+
+    /// Checks that the account can still be credited
+    fn ensure_open(&self, signer: &str) -> Result<(), AccountError> {
+        match self.account(signer)?.status {
+            AccountStatus::Closed => Err(AccountError::Closed(signer.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// The ledger balance of every asset posted to the `signer` account
+    fn signer_balances<'a>(&'a self, signer: &'a str) -> impl Iterator<Item = (&'a Asset, u64)> {
+        self.ledger
+            .balances()
+            .filter_map(move |(account, asset, balance)| match account {
+                // accounts can't be overdrawn, so their balances always fit
+                LedgerAccount::User(s) if s == signer => Some((asset, balance as u64)),
+                _ => None,
             })
+    }
+
+    /// The balance of an asset in the `signer` account, 0 for assets it never held.
+    /// # Errors
+    /// The account doesn't exist
+    pub fn balance_of(&self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        if !self.exists(signer) {
+            return Err(AccountError::NotFound(signer.to_string()));
         }
+        let account = LedgerAccount::User(signer.to_string());
+        Ok(self.ledger.balance(&account, asset) as u64)
+    }
+
+    /// The amount of an asset in the `signer` account that is set aside for resting orders
+    pub fn locked(&self, signer: &str, asset: &Asset) -> u64 {
+        self.holds
+            .get(&(signer.to_string(), asset.clone()))
+            .copied()
+            .unwrap_or(0)
     }
 
-    /// Withdraws the `amount` from the `signer` account.
+    /// The balance of an asset in the `signer` account that isn't set aside for resting orders.
     /// # Errors
-    /// Attempted overflow
-    pub fn withdraw(&mut self, signer: &str, amount: u64) -> Result<Tx, AccountError> {
-        // check if signer exists inside accounts hashmap
-        match self.accounts.get_mut(signer) {
-            // If account exists, start withdrawal:
-            Some(account_balance) => {
-                // subtract amount from account balance
-                (*account_balance)
-                    .checked_sub(amount)
-                    // if it's successful, update new account_balance to be subtraction result
-                    .map(|r| {
-                        *account_balance = r;
-                        r
-                    })
-                    .ok_or(
-                        // if it fails, then return AccountError::UnderFunded
-                        AccountError::UnderFunded(signer.to_string()),
-                    )
-                    .map(|_| Tx::Withdraw {
-                        account: signer.to_string(),
-                        amount,
-                    })
+    /// The account doesn't exist
+    pub fn available(&self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        let balance = self.balance_of(signer, asset)?;
+        Ok(balance.saturating_sub(self.locked(signer, asset)))
+    }
+
+    /// Every asset held by the `signer` account with its total and available balance
+    /// # Errors
+    /// The account doesn't exist
+    pub fn holdings(&self, signer: &str) -> Result<BTreeMap<Asset, Balance>, AccountError> {
+        if !self.exists(signer) {
+            return Err(AccountError::NotFound(signer.to_string()));
+        }
+        Ok(self
+            .signer_balances(signer)
+            .map(|(asset, balance)| {
+                let available = balance.saturating_sub(self.locked(signer, asset));
+                let balance = Balance {
+                    total: balance,
+                    available,
+                };
+                (asset.clone(), balance)
+            })
+            .collect())
+    }
+
+    /// The holdings of the `signer` account added up with those of every account below it
+    /// # Errors
+    /// The account doesn't exist
+    pub fn rollup(&self, signer: &str) -> Result<BTreeMap<Asset, Balance>, AccountError> {
+        let mut rollup: BTreeMap<Asset, Balance> = BTreeMap::new();
+        for member in self.family(signer) {
+            for (asset, balance) in self.holdings(&member)? {
+                let sum = rollup.entry(asset).or_default();
+                sum.total = sum.total.saturating_add(balance.total);
+                sum.available = sum.available.saturating_add(balance.available);
             }
-            // If account doesn't exist, return AccountError::NotFound
-            None => Err(AccountError::NotFound(signer.to_string())),
         }
+        Ok(rollup)
     }
 
-    /// Withdraws the amount from the sender account and deposits it in the recipient account.
-    ///
+    /// What the `signer` account can pay for orders with in an asset: its available balance and,
+    /// in the asset it borrows on margin, the credit it has left
     /// # Errors
     /// The account doesn't exist
+    pub fn buying_power(&self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        match self.margin_terms.get(signer) {
+            Some(terms) if &terms.asset == asset => Ok(self.margin(signer)?.buying_power),
+            _ => self.available(signer, asset),
+        }
+    }
+
+    /// Sets the hold aside so it can't be withdrawn, sent or reserved again until it's released.
+    /// Whatever the account doesn't have available is borrowed against its credit line first,
+    /// returns the loan if one was made.
+    /// # Errors
+    /// The account doesn't exist, isn't active or its buying power doesn't cover the amount
+    pub fn reserve(&mut self, hold: &Hold) -> Result<Option<Tx>, AccountError> {
+        self.ensure_active(&hold.signer)?;
+        let available = self.available(&hold.signer, &hold.asset)?;
+        let loan = match hold.amount.saturating_sub(available) {
+            0 => None,
+            _ if self.buying_power(&hold.signer, &hold.asset)? < hold.amount => {
+                return Err(AccountError::UnderFunded(hold.signer.to_string()));
+            }
+            shortfall => Some(self.lend(&hold.signer, &hold.asset, shortfall)?),
+        };
+        *self
+            .holds
+            .entry((hold.signer.to_string(), hold.asset.clone()))
+            .or_default() += hold.amount;
+        Ok(loan)
+    }
+
+    /// Makes a hold available again. Releasing more than is held releases everything.
+    pub fn release(&mut self, hold: &Hold) {
+        let key = (hold.signer.to_string(), hold.asset.clone());
+        if let Some(locked) = self.holds.get_mut(&key) {
+            *locked = locked.saturating_sub(hold.amount);
+            if *locked == 0 {
+                self.holds.remove(&key);
+            }
+        }
+    }
+
+    /// Adds the `amount` of the asset to the balance of the `signer` account, frozen accounts can still be credited.
+    /// The deposit is posted against the external cash account.
+    /// # Errors
+    /// The account doesn't exist, was closed or would overflow
+    pub fn deposit(
+        &mut self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.ensure_open(signer)?;
+        let movement = Movement {
+            from: LedgerAccount::External,
+            to: LedgerAccount::User(signer.to_string()),
+            asset: asset.clone(),
+            amount,
+        };
+        let sequence = self.post(EntryKind::Deposit, &[movement])?;
+
+        Ok(self.issue(
+            sequence,
+            None,
+            TxKind::Deposit {
+                account: signer.to_string(),
+                asset: asset.clone(),
+                amount,
+            },
+        ))
+    }
+
+    /// Withdraws the `amount` of the asset from the `signer` account to the external cash account,
+    /// which can't touch what is locked in holds.
+    /// # Errors
+    /// The account doesn't exist, isn't active or doesn't have enough of the asset available
+    pub fn withdraw(
+        &mut self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.ensure_active(signer)?;
+        self.ensure_unencumbered(signer, asset, amount)?;
+        let movement = Movement {
+            from: LedgerAccount::User(signer.to_string()),
+            to: LedgerAccount::External,
+            asset: asset.clone(),
+            amount,
+        };
+        let sequence = self.post(EntryKind::Withdrawal, &[movement])?;
+
+        Ok(self.issue(
+            sequence,
+            None,
+            TxKind::Withdraw {
+                account: signer.to_string(),
+                asset: asset.clone(),
+                amount,
+            },
+        ))
+    }
+
+    /// Withdraws the amount of the asset from the sender account and deposits it in the recipient account.
+    /// Neither account changes unless both sides succeed.
+    ///
+    /// # Errors
+    /// The account doesn't exist, the sender isn't active or is underfunded,
+    /// or the recipient was closed or would overflow
     pub fn send(
         &mut self,
         sender: &str,
         recipient: &str,
+        asset: &Asset,
         amount: u64,
-    ) -> Result<(Tx, Tx), AccountError> {
-        // withdraw amount from sender
-        let w_tx = self.withdraw(sender, amount)?;
-        // deposit amount to recipient
-        let d_tx = self.deposit(recipient, amount)?;
+    ) -> Result<Tx, AccountError> {
+        self.ensure_active(sender)?;
+        self.ensure_unencumbered(sender, asset, amount)?;
+        self.ensure_open(recipient)?;
+        let movement = Movement {
+            from: LedgerAccount::User(sender.to_string()),
+            to: LedgerAccount::User(recipient.to_string()),
+            asset: asset.clone(),
+            amount,
+        };
+        let sequence = self.post(EntryKind::Transfer, &[movement])?;
+
+        Ok(self.issue(
+            sequence,
+            None,
+            TxKind::Transfer {
+                sender: sender.to_string(),
+                recipient: recipient.to_string(),
+                asset: asset.clone(),
+                amount,
+            },
+        ))
+    }
+
+    /// Sends funds between two sub-accounts of the same parent, or between a sub-account and its parent,
+    /// on behalf of the `actor`, who has to be able to act on the sender
+    /// # Errors
+    /// Either account doesn't exist, the actor can't act on the sender, the accounts aren't related
+    /// or the funds can't be sent, see [`Accounts::send`]
+    pub fn transfer(
+        &mut self,
+        actor: &str,
+        sender: &str,
+        recipient: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.ensure_authorized(actor, sender)?;
+        let sender_parent = self.account(sender)?.parent.as_deref();
+        let recipient_parent = self.account(recipient)?.parent.as_deref();
+        let related = match (sender_parent, recipient_parent) {
+            (Some(a), Some(b)) if a == b => true,
+            (a, b) => a == Some(recipient) || b == Some(sender),
+        };
+        if !related {
+            return Err(AccountError::Unsupported(format!(
+                "transfer from '{}' to '{}'",
+                sender, recipient
+            )));
+        }
+        self.send(sender, recipient, asset, amount)
+    }
+
+    /// Corrects the balance of an asset in the `signer` account against the adjustments account,
+    /// crediting positive amounts and debiting negative ones. The `reason` is kept as the reference.
+    /// # Errors
+    /// The account doesn't exist, was closed or would be overdrawn or overflow
+    pub fn adjust(
+        &mut self,
+        signer: &str,
+        asset: &Asset,
+        amount: i64,
+        reason: &str,
+    ) -> Result<Tx, AccountError> {
+        self.ensure_open(signer)?;
+        let user = LedgerAccount::User(signer.to_string());
+        let (from, to) = if amount < 0 {
+            (user, LedgerAccount::Adjustments)
+        } else {
+            (LedgerAccount::Adjustments, user)
+        };
+        let movement = Movement {
+            from,
+            to,
+            asset: asset.clone(),
+            amount: amount.unsigned_abs(),
+        };
+        let sequence = self.post(EntryKind::Adjustment, &[movement])?;
+
+        Ok(self.issue(
+            sequence,
+            Some(reason.to_string()),
+            TxKind::Adjustment {
+                account: signer.to_string(),
+                asset: asset.clone(),
+                amount,
+            },
+        ))
+    }
+
+    /// Sets the terms the `signer` account trades on margin with and returns where it stands.
+    /// Lowering the credit limit below the debt only stops the account from borrowing more.
+    /// # Errors
+    /// The account doesn't exist, was closed or owes another asset than the one of the terms
+    pub fn set_margin(
+        &mut self,
+        signer: &str,
+        terms: MarginTerms,
+    ) -> Result<MarginReport, AccountError> {
+        self.ensure_open(signer)?;
+        if self
+            .debts
+            .keys()
+            .any(|(s, asset)| s == signer && asset != &terms.asset)
+        {
+            return Err(AccountError::Unsupported(format!(
+                "changing the asset '{}' owes",
+                signer
+            )));
+        }
+        self.margin_terms.insert(signer.to_string(), terms);
+        self.margin(signer)
+    }
+
+    /// Where the `signer` account stands on margin, valuing its holdings at the price they last traded at
+    /// # Errors
+    /// The account doesn't exist or has no margin terms
+    pub fn margin(&self, signer: &str) -> Result<MarginReport, AccountError> {
+        self.account(signer)?;
+        let terms = self
+            .margin_terms
+            .get(signer)
+            .ok_or(AccountError::Unsupported(format!(
+                "margin for '{}'",
+                signer
+            )))?;
+
+        let mut cash = 0;
+        let mut exposure: u128 = 0;
+        for (asset, balance) in self.signer_balances(signer) {
+            if asset == &terms.asset {
+                cash = balance;
+                continue;
+            }
+            let instrument = Instrument {
+                base: asset.clone(),
+                quote: terms.asset.clone(),
+            };
+            if let Some(price) = self.last_prices.get(&instrument) {
+                exposure = exposure.saturating_add(balance as u128 * *price as u128);
+            }
+        }
+        let debt = self.owed(signer, &terms.asset);
+        let available = self.available(signer, &terms.asset)?;
+        Ok(MarginReport::new(
+            signer, terms, debt, cash, exposure, available,
+        ))
+    }
 
-        Ok((w_tx, d_tx))
+    /// Where every account in a margin call stands, ordered by signer
+    pub fn margin_calls(&self) -> Vec<MarginReport> {
+        let mut calls: Vec<MarginReport> = self
+            .margin_terms
+            .keys()
+            .filter_map(|signer| self.margin(signer).ok())
+            .filter(|report| report.status == MarginStatus::MarginCall)
+            .collect();
+        calls.sort_by(|a, b| a.signer.cmp(&b.signer));
+        calls
+    }
+
+    /// Where the accounts the trades settled since the last call put in a margin call stand, ordered by signer.
+    /// Only the buyers and sellers of the trades and the accounts holding an asset whose price they moved are checked.
+    pub fn take_margin_calls(&mut self) -> Vec<MarginReport> {
+        let traded = std::mem::take(&mut self.traded);
+        let repriced = std::mem::take(&mut self.repriced);
+        let touched = |signer: &String, terms: &MarginTerms| {
+            traded.contains(signer)
+                || repriced.iter().any(|instrument| {
+                    instrument.quote == terms.asset
+                        && self.balance_of(signer, &instrument.base).unwrap_or(0) > 0
+                })
+        };
+
+        let mut calls: Vec<MarginReport> = self
+            .margin_terms
+            .iter()
+            .filter(|(signer, terms)| touched(signer, terms))
+            .filter_map(|(signer, _)| self.margin(signer).ok())
+            .filter(|report| report.status == MarginStatus::MarginCall)
+            .collect();
+        calls.sort_by(|a, b| a.signer.cmp(&b.signer));
+        calls
+    }
+
+    /// The amount of an asset the `signer` account borrowed and hasn't paid back
+    pub fn owed(&self, signer: &str, asset: &Asset) -> u64 {
+        self.debts
+            .get(&(signer.to_string(), asset.clone()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Pays back up to `amount` of what the `signer` account owes of the asset from its available balance,
+    /// frozen accounts can still repay. Repaying more than is owed repays the whole debt.
+    /// # Errors
+    /// The account doesn't exist, was closed, owes none of the asset or doesn't have the amount available
+    pub fn repay(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<Tx, AccountError> {
+        self.ensure_open(signer)?;
+        let key = (signer.to_string(), asset.clone());
+        let owed = self.owed(signer, asset);
+        if owed == 0 {
+            return Err(AccountError::Unsupported(format!(
+                "repaying '{}' who owes no {}",
+                signer, asset
+            )));
+        }
+        let amount = amount.min(owed);
+        let movement = Movement {
+            from: LedgerAccount::User(signer.to_string()),
+            to: LedgerAccount::Credit,
+            asset: asset.clone(),
+            amount,
+        };
+        let sequence = self.post(EntryKind::Repayment, &[movement])?;
+        if amount == owed {
+            self.debts.remove(&key);
+        } else {
+            self.debts.insert(key, owed - amount);
+        }
+
+        Ok(self.issue(
+            sequence,
+            None,
+            TxKind::Repay {
+                account: signer.to_string(),
+                asset: asset.clone(),
+                amount,
+            },
+        ))
+    }
+
+    /// Repays as much of what the `signer` account owes of the asset as its available balance covers,
+    /// returns the repayment if anything was repaid
+    /// # Errors
+    /// The account doesn't exist or was closed
+    pub fn settle_debt(&mut self, signer: &str, asset: &Asset) -> Result<Option<Tx>, AccountError> {
+        let amount = self.available(signer, asset)?.min(self.owed(signer, asset));
+        if amount == 0 {
+            return Ok(None);
+        }
+        self.repay(signer, asset, amount).map(Some)
+    }
+
+    /// Records a step of the liquidation of the `signer` account under the last journal entry
+    pub fn log_liquidation(&mut self, signer: &str, step: LiquidationStep) -> Tx {
+        let sequence = self.ledger.journal().len() as u64;
+        let kind = TxKind::Liquidation {
+            account: signer.to_string(),
+            step,
+        };
+        self.issue(sequence, None, kind)
+    }
+
+    /// Records that an order the market placed for its signer on its own failed with the `error`,
+    /// under the last journal entry
+    pub fn log_rejection(&mut self, order: &Order, error: &AccountError) -> Tx {
+        let sequence = self.ledger.journal().len() as u64;
+        let kind = TxKind::Rejected {
+            account: order.signer.clone(),
+            order: order.clone(),
+            reason: error.clone(),
+        };
+        self.issue(sequence, None, kind)
+    }
+
+    /// Lends the `amount` of the asset to the `signer` account from the credit account
+    fn lend(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<Tx, AccountError> {
+        let movement = Movement {
+            from: LedgerAccount::Credit,
+            to: LedgerAccount::User(signer.to_string()),
+            asset: asset.clone(),
+            amount,
+        };
+        let sequence = self.post(EntryKind::Borrowing, &[movement])?;
+        *self
+            .debts
+            .entry((signer.to_string(), asset.clone()))
+            .or_default() += amount;
+
+        Ok(self.issue(
+            sequence,
+            None,
+            TxKind::Borrow {
+                account: signer.to_string(),
+                asset: asset.clone(),
+                amount,
+            },
+        ))
+    }
+
+    /// Checks that moving `amount` of the asset out of the account leaves its debts covered:
+    /// an account that owes anything can only move out what it has available of the borrowed asset over its debt
+    fn ensure_unencumbered(
+        &self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<(), AccountError> {
+        for ((s, owed), debt) in &self.debts {
+            if s == signer
+                && (owed != asset || self.available(signer, asset)? < amount.saturating_add(*debt))
+            {
+                return Err(AccountError::UnderFunded(signer.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases the holds and then pays for the trades and their fees as a single journal entry,
+    /// either every balance change is applied or none of them are.
+    /// Returns a transaction for each trade in order followed by the fees charged on it, referencing its id.
+    ///
+    /// # Errors
+    /// A payment fails, see [`Accounts::send`]
+    pub fn settle(&mut self, releases: &[Hold], trades: &[Trade]) -> Result<Vec<Tx>, AccountError> {
+        let movements = trades
+            .iter()
+            .map(Trade::movements)
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        let saved: Vec<_> = releases
+            .iter()
+            .map(|hold| {
+                let key = (hold.signer.clone(), hold.asset.clone());
+                let locked = self.holds.get(&key).copied();
+                (key, locked)
+            })
+            .collect();
+        for hold in releases {
+            self.release(hold);
+        }
+
+        if trades.is_empty() {
+            return Ok(vec![]);
+        }
+        let sequence = self
+            .post(EntryKind::Settlement, &movements)
+            .inspect_err(|_| {
+                // put the holds back in the reverse order they were released
+                for (key, locked) in saved.into_iter().rev() {
+                    restore(&mut self.holds, key, locked);
+                }
+            })?;
+
+        let mut txs = vec![];
+        for trade in trades {
+            let instrument = Instrument {
+                base: trade.base.clone(),
+                quote: trade.quote.clone(),
+            };
+            self.last_prices.insert(instrument.clone(), trade.price);
+            self.repriced.insert(instrument);
+            self.traded.insert(trade.buyer.clone());
+            self.traded.insert(trade.seller.clone());
+            let reference = Some(trade.id.clone());
+            let kind = TxKind::TradeSettlement {
+                buyer: trade.buyer.clone(),
+                seller: trade.seller.clone(),
+                base: trade.base.clone(),
+                quote: trade.quote.clone(),
+                amount: trade.amount,
+                price: trade.price,
+            };
+            txs.push(self.issue(sequence, reference.clone(), kind));
+
+            let fees = [
+                (&trade.buyer, &trade.base, trade.buyer_fee),
+                (&trade.seller, &trade.quote, trade.seller_fee),
+            ];
+            for (account, asset, amount) in fees.into_iter().filter(|(_, _, fee)| *fee > 0) {
+                let kind = TxKind::Fee {
+                    account: account.clone(),
+                    asset: asset.clone(),
+                    amount,
+                };
+                txs.push(self.issue(sequence, reference.clone(), kind));
+            }
+        }
+        Ok(txs)
+    }
+
+    /// Records a transaction posted by the journal entry `sequence` under the next id
+    fn issue(&mut self, sequence: u64, reference: Option<String>, kind: TxKind) -> Tx {
+        self.issued += 1;
+        Tx {
+            id: self.issued,
+            sequence,
+            timestamp: timestamp(),
+            reference,
+            kind,
+        }
+    }
+
+    /// Posts the movements as one journal entry once every signer account can take its net change:
+    /// every account must exist, accounts that pay must keep their holds covered and none can overflow.
+    /// # Errors
+    /// The entry can't be posted and no balance changes
+    fn post(&mut self, kind: EntryKind, movements: &[Movement]) -> Result<u64, AccountError> {
+        for movement in movements {
+            for account in [&movement.from, &movement.to] {
+                if let LedgerAccount::User(signer) = account {
+                    self.account(signer)?;
+                }
+            }
+        }
+
+        // net change of each signer account, in the order they appear
+        let mut changes: Vec<(&str, &Asset, i128)> = vec![];
+        for movement in movements {
+            let amount = movement.amount as i128;
+            for (account, change) in [(&movement.from, -amount), (&movement.to, amount)] {
+                let LedgerAccount::User(signer) = account else {
+                    continue;
+                };
+                match changes
+                    .iter_mut()
+                    .find(|(s, a, _)| s == signer && *a == &movement.asset)
+                {
+                    Some((_, _, net)) => *net += change,
+                    None => changes.push((signer, &movement.asset, change)),
+                }
+            }
+        }
+
+        for (signer, asset, change) in changes {
+            let balance = self
+                .ledger
+                .balance(&LedgerAccount::User(signer.to_string()), asset);
+            let after = balance + change;
+            if change < 0 && after < self.locked(signer, asset) as i128 {
+                return Err(AccountError::UnderFunded(signer.to_string()));
+            }
+            if change > 0 && after > u64::MAX as i128 {
+                return Err(AccountError::OverFunded(signer.to_string(), change as u64));
+            }
+        }
+
+        Ok(self.ledger.post(kind, movements))
+    }
+}
+
+/// Puts back the value a key had, removing the key if it didn't exist
+fn restore(map: &mut HashMap<(String, Asset), u64>, key: (String, Asset), value: Option<u64>) {
+    match value {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+
+/// A match between a buyer and a seller, paid for with the base asset going one way and the quote asset the other
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Trade {
+    /// The market and the ordinals of the incoming and the resting order, as `SYMBOL:incoming:resting`
+    pub id: String,
+    pub buyer: String,
+    pub seller: String,
+    pub base: Asset,
+    pub quote: Asset,
+    pub amount: u64,
+    pub price: u64,
+    /// Charged to the buyer in the base asset they receive
+    pub buyer_fee: u64,
+    /// Charged to the seller in the quote asset they receive
+    pub seller_fee: u64,
+}
+
+impl Trade {
+    /// The trades of the matches of the [`Receipt`] issued for an [`Order`] in an [`Instrument`]
+    pub fn settling(instrument: &Instrument, order: &Order, receipt: &Receipt) -> Vec<Trade> {
+        receipt
+            .matches
+            .iter()
+            .map(|po| {
+                let (buyer, seller) = match order.side {
+                    Side::Buy => (&order.signer, &po.signer),
+                    Side::Sell => (&po.signer, &order.signer),
+                };
+                Trade {
+                    id: format!("{}:{}:{}", instrument.symbol(), receipt.ordinal, po.ordinal),
+                    buyer: buyer.clone(),
+                    seller: seller.clone(),
+                    base: instrument.base.clone(),
+                    quote: instrument.quote.clone(),
+                    amount: po.amount,
+                    price: po.price,
+                    buyer_fee: 0,
+                    seller_fee: 0,
+                }
+            })
+            .collect()
+    }
+
+    /// What the buyer pays the seller in the quote asset
+    /// # Errors
+    /// The value doesn't fit in a `u64`
+    pub fn value(&self) -> Result<u64, AccountError> {
+        types::value(self.amount, self.price)
+    }
+
+    /// The `amount` of the base asset moves from the seller to the buyer and its price
+    /// in the quote asset from the buyer to the seller, then each pays their fee to the platform
+    /// # Errors
+    /// The value of the trade doesn't fit in a `u64`
+    pub fn movements(&self) -> Result<Vec<Movement>, AccountError> {
+        let buyer = LedgerAccount::User(self.buyer.clone());
+        let seller = LedgerAccount::User(self.seller.clone());
+        let mut movements = vec![
+            Movement {
+                from: seller.clone(),
+                to: buyer.clone(),
+                asset: self.base.clone(),
+                amount: self.amount,
+            },
+            Movement {
+                from: buyer.clone(),
+                to: seller.clone(),
+                asset: self.quote.clone(),
+                amount: self.value()?,
+            },
+        ];
+        if self.buyer_fee > 0 {
+            movements.push(Movement {
+                from: buyer,
+                to: LedgerAccount::Revenue,
+                asset: self.base.clone(),
+                amount: self.buyer_fee,
+            });
+        }
+        if self.seller_fee > 0 {
+            movements.push(Movement {
+                from: seller,
+                to: LedgerAccount::Revenue,
+                asset: self.quote.clone(),
+                amount: self.seller_fee,
+            });
+        }
+        Ok(movements)
     }
 }
 
@@ -109,21 +1196,28 @@ mod tests {
 
     type TestResult = Result<(), AccountError>;
 
+    fn usd() -> Asset {
+        Asset::new("USD")
+    }
+
     // unit tests for Accounts.withdraw()
     // =========================================================================================================
     #[test]
     fn test_accounts_withdraw_successful() -> TestResult {
         let mut ledger = Accounts::new();
-        ledger.deposit("test_account", 50)?;
+        ledger.open("test_account")?;
+        ledger.deposit("test_account", &usd(), 50)?;
 
-        let actual = ledger.withdraw("test_account", 10);
+        let actual = ledger.withdraw("test_account", &usd(), 10)?;
         assert_eq!(
-            actual,
-            Ok(Tx::Withdraw {
+            actual.kind,
+            TxKind::Withdraw {
                 account: "test_account".to_string(),
+                asset: usd(),
                 amount: 10,
-            })
+            }
         );
+        assert_eq!((actual.id, actual.sequence, actual.reference), (2, 2, None));
 
         Ok(())
     }
@@ -132,7 +1226,7 @@ mod tests {
     fn test_accounts_withdraw_missing() -> TestResult {
         let mut ledger = Accounts::new();
 
-        let actual = ledger.withdraw("non_existant_account", 10);
+        let actual = ledger.withdraw("non_existant_account", &usd(), 10);
         assert_eq!(
             actual,
             Err(AccountError::NotFound("non_existant_account".to_string()))
@@ -144,9 +1238,10 @@ mod tests {
     #[test]
     fn test_accounts_withdraw_underfunded() -> TestResult {
         let mut ledger = Accounts::new();
-        ledger.deposit("test_account", 50)?;
+        ledger.open("test_account")?;
+        ledger.deposit("test_account", &usd(), 50)?;
 
-        let actual = ledger.withdraw("test_account", 60);
+        let actual = ledger.withdraw("test_account", &usd(), 60);
         assert_eq!(
             actual,
             Err(AccountError::UnderFunded("test_account".to_string()))
@@ -160,14 +1255,16 @@ mod tests {
     #[test]
     fn test_accounts_deposit_successful() -> TestResult {
         let mut ledger = Accounts::new();
+        ledger.open("test_account")?;
 
-        let actual = ledger.deposit("test_account", 50);
+        let actual = ledger.deposit("test_account", &usd(), 50)?;
         assert_eq!(
-            actual,
-            Ok(Tx::Deposit {
+            actual.kind,
+            TxKind::Deposit {
                 account: "test_account".to_string(),
+                asset: usd(),
                 amount: 50
-            })
+            }
         );
 
         Ok(())
@@ -176,9 +1273,10 @@ mod tests {
     #[test]
     fn test_accounts_deposit_overfunded() -> TestResult {
         let mut ledger = Accounts::new();
-        ledger.deposit("test_account", 10)?;
+        ledger.open("test_account")?;
+        ledger.deposit("test_account", &usd(), 10)?;
 
-        let actual = ledger.deposit("test_account", u64::MAX);
+        let actual = ledger.deposit("test_account", &usd(), u64::MAX);
         assert_eq!(
             actual,
             Err(AccountError::OverFunded(
@@ -195,39 +1293,650 @@ mod tests {
     #[test]
     fn test_accounts_send_successful() -> TestResult {
         let mut ledger = Accounts::new();
-        ledger.deposit("sender", 50)?;
-        ledger.deposit("recipient", 10)?;
+        ledger.open("sender")?;
+        ledger.open("recipient")?;
+        ledger.deposit("sender", &usd(), 50)?;
+        ledger.deposit("recipient", &usd(), 10)?;
 
-        let (actual_tx_1, actual_tx_2) = ledger.send("sender", "recipient", 30)?;
+        let actual = ledger.send("sender", "recipient", &usd(), 30)?;
 
         assert_eq!(
-            actual_tx_1,
-            Tx::Withdraw {
-                account: "sender".to_string(),
+            actual.kind,
+            TxKind::Transfer {
+                sender: "sender".to_string(),
+                recipient: "recipient".to_string(),
+                asset: usd(),
                 amount: 30
             }
         );
+        assert_eq!(ledger.balance_of("sender", &usd()), Ok(20));
+        assert_eq!(ledger.balance_of("recipient", &usd()), Ok(40));
 
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_send_missing_sender() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("recipient")?;
+        ledger.deposit("recipient", &usd(), 10)?;
+
+        let actual = ledger.send("non_existant_account", "recipient", &usd(), 30);
         assert_eq!(
-            actual_tx_2,
-            Tx::Deposit {
-                account: "recipient".to_string(),
-                amount: 30
-            }
+            actual,
+            Err(AccountError::NotFound("non_existant_account".to_string()))
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_accounts_send_missing_sender() -> TestResult {
+    fn test_accounts_send_overfunded_recipient() -> TestResult {
         let mut ledger = Accounts::new();
-        ledger.deposit("recipient", 10)?;
+        ledger.open("sender")?;
+        ledger.open("recipient")?;
+        ledger.deposit("sender", &usd(), 10)?;
+        ledger.deposit("recipient", &usd(), u64::MAX)?;
 
-        let actual = ledger.send("non_existant_account", "recipient", 30);
+        let actual = ledger.send("sender", "recipient", &usd(), 5);
         assert_eq!(
             actual,
-            Err(AccountError::NotFound("non_existant_account".to_string()))
+            Err(AccountError::OverFunded("recipient".to_string(), 5))
+        );
+        // The sender isn't debited when the deposit fails
+        assert_eq!(ledger.balance_of("sender", &usd()), Ok(10));
+        assert_eq!(ledger.balance_of("recipient", &usd()), Ok(u64::MAX));
+
+        Ok(())
+    }
+
+    // unit tests for Accounts.settle()
+    // =========================================================================================================
+    #[test]
+    fn test_accounts_settle_is_all_or_nothing() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("buyer")?;
+        ledger.open("seller")?;
+        ledger.deposit("buyer", &usd(), 10)?;
+        ledger.deposit("seller", &Asset::new("OCTO"), 1)?;
+        let hold = Hold {
+            signer: "seller".to_string(),
+            asset: Asset::new("OCTO"),
+            amount: 1,
+        };
+        ledger.reserve(&hold)?;
+
+        let trade = Trade {
+            id: "OCTO/USD:2:1".to_string(),
+            buyer: "buyer".to_string(),
+            seller: "seller".to_string(),
+            base: Asset::new("OCTO"),
+            quote: usd(),
+            amount: 1,
+            price: 20,
+            buyer_fee: 0,
+            seller_fee: 0,
+        };
+        assert_eq!(
+            ledger.settle(std::slice::from_ref(&hold), std::slice::from_ref(&trade)),
+            Err(AccountError::UnderFunded("buyer".to_string()))
+        );
+        // Neither the base leg nor the release were applied
+        assert_eq!(ledger.balance_of("seller", &Asset::new("OCTO")), Ok(1));
+        assert_eq!(ledger.balance_of("buyer", &Asset::new("OCTO")), Ok(0));
+        assert_eq!(ledger.locked("seller", &Asset::new("OCTO")), 1);
+
+        let trade = Trade { price: 10, ..trade };
+        let txs = ledger.settle(&[hold], &[trade])?;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].reference, Some("OCTO/USD:2:1".to_string()));
+        assert_eq!(ledger.balance_of("buyer", &Asset::new("OCTO")), Ok(1));
+        assert_eq!(ledger.balance_of("seller", &usd()), Ok(10));
+        assert_eq!(ledger.locked("seller", &Asset::new("OCTO")), 0);
+
+        Ok(())
+    }
+
+    // unit tests for Accounts.adjust()
+    // =========================================================================================================
+    #[test]
+    fn test_accounts_adjust_corrects_balance() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("test_account")?;
+        ledger.deposit("test_account", &usd(), 50)?;
+
+        let tx = ledger.adjust("test_account", &usd(), -20, "duplicate deposit")?;
+        assert_eq!(tx.reference, Some("duplicate deposit".to_string()));
+        assert_eq!(ledger.balance_of("test_account", &usd()), Ok(30));
+        assert_eq!(
+            ledger.adjust("test_account", &usd(), -31, "too much"),
+            Err(AccountError::UnderFunded("test_account".to_string()))
+        );
+        ledger.adjust("test_account", &usd(), 5, "goodwill")?;
+        assert_eq!(ledger.balance_of("test_account", &usd()), Ok(35));
+        assert_eq!(
+            ledger.ledger.balance(&LedgerAccount::Adjustments, &usd()),
+            15
+        );
+
+        Ok(())
+    }
+
+    // unit tests for Accounts::from_transactions()
+    // =========================================================================================================
+    #[test]
+    fn test_accounts_from_transactions_rebuilds_balances() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("buyer")?;
+        ledger.open("seller")?;
+        let mut txs = vec![
+            ledger.deposit("buyer", &usd(), 100)?,
+            ledger.deposit("seller", &Asset::new("OCTO"), 5)?,
+            ledger.send("buyer", "seller", &usd(), 10)?,
+            ledger.adjust("seller", &usd(), -3, "fat finger")?,
+        ];
+        let trades: Vec<Trade> = (1..=2)
+            .map(|ordinal| Trade {
+                id: format!("OCTO/USD:3:{ordinal}"),
+                buyer: "buyer".to_string(),
+                seller: "seller".to_string(),
+                base: Asset::new("OCTO"),
+                quote: usd(),
+                amount: 2,
+                price: 20,
+                buyer_fee: ordinal - 1,
+                seller_fee: 4,
+            })
+            .collect();
+        txs.extend(ledger.settle(&[], &trades)?);
+        txs.push(ledger.withdraw("seller", &usd(), 50)?);
+
+        // the first trade is free for the buyer, the other three fees are transactions
+        assert_eq!(txs.len(), 4 + 2 + 3 + 1);
+        let rebuilt = Accounts::from_transactions(&txs)?;
+        assert_eq!(rebuilt.holdings("buyer"), ledger.holdings("buyer"));
+        assert_eq!(rebuilt.holdings("seller"), ledger.holdings("seller"));
+        assert_eq!(rebuilt.ledger.journal(), ledger.ledger.journal());
+        assert_eq!(ledger.reconcile(&txs), Ok(vec![]));
+
+        // A log that doesn't add up can't be replayed
+        assert_eq!(
+            Accounts::from_transactions(&txs[2..]).map(|_| ()),
+            Err(AccountError::UnderFunded("buyer".to_string()))
+        );
+
+        Ok(())
+    }
+
+    // unit tests for the ledger behind Accounts
+    // =========================================================================================================
+    #[test]
+    fn test_accounts_movements_are_journal_entries() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("sender")?;
+        ledger.open("recipient")?;
+        ledger.deposit("sender", &usd(), 50)?;
+        ledger.send("sender", "recipient", &usd(), 30)?;
+        ledger.withdraw("recipient", &usd(), 10)?;
+        // failed movements aren't posted
+        assert!(ledger.withdraw("sender", &usd(), 21).is_err());
+
+        let kinds: Vec<EntryKind> = ledger.ledger.journal().iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EntryKind::Deposit,
+                EntryKind::Transfer,
+                EntryKind::Withdrawal
+            ]
+        );
+        let trial_balance = ledger.ledger.trial_balance();
+        assert!(trial_balance.is_balanced());
+        assert_eq!(trial_balance.totals(), BTreeMap::from([(usd(), (40, 40))]));
+        assert_eq!(ledger.ledger.balance(&LedgerAccount::External, &usd()), -40);
+        assert_eq!(ledger.balance_of("recipient", &usd()), Ok(20));
+
+        Ok(())
+    }
+
+    // unit tests for per asset balances
+    // =========================================================================================================
+    #[test]
+    fn test_accounts_assets_are_separate() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("test_account")?;
+        let octo = Asset::new("OCTO");
+        ledger.deposit("test_account", &usd(), 50)?;
+        ledger.deposit("test_account", &octo, 2)?;
+
+        assert_eq!(ledger.balance_of("test_account", &Asset::new("INK")), Ok(0));
+        assert_eq!(
+            ledger.withdraw("test_account", &octo, 3),
+            Err(AccountError::UnderFunded("test_account".to_string()))
+        );
+        let balance = |total| Balance {
+            total,
+            available: total,
+        };
+        assert_eq!(
+            ledger.holdings("test_account"),
+            Ok(BTreeMap::from([(octo, balance(2)), (usd(), balance(50))]))
+        );
+
+        Ok(())
+    }
+
+    // unit tests for Accounts.reserve() and Accounts.release()
+    // =========================================================================================================
+    #[test]
+    fn test_accounts_reserve_locks_funds() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("test_account")?;
+        ledger.deposit("test_account", &usd(), 50)?;
+        let hold = Hold {
+            signer: "test_account".to_string(),
+            asset: usd(),
+            amount: 30,
+        };
+        ledger.reserve(&hold)?;
+
+        assert_eq!(ledger.available("test_account", &usd()), Ok(20));
+        assert_eq!(
+            ledger.holdings("test_account"),
+            Ok(BTreeMap::from([(
+                usd(),
+                Balance {
+                    total: 50,
+                    available: 20
+                }
+            )]))
+        );
+        // Neither another hold nor a withdrawal can dip into the locked funds
+        assert_eq!(
+            ledger.reserve(&hold),
+            Err(AccountError::UnderFunded("test_account".to_string()))
+        );
+        assert_eq!(
+            ledger.withdraw("test_account", &usd(), 21),
+            Err(AccountError::UnderFunded("test_account".to_string()))
+        );
+        assert_eq!(ledger.balance_of("test_account", &usd()), Ok(50));
+
+        ledger.release(&hold);
+        assert_eq!(ledger.available("test_account", &usd()), Ok(50));
+        assert!(ledger.withdraw("test_account", &usd(), 50).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_reserve_missing() {
+        let mut ledger = Accounts::new();
+        let hold = Hold {
+            signer: "test_account".to_string(),
+            asset: usd(),
+            amount: 1,
+        };
+
+        assert_eq!(
+            ledger.reserve(&hold),
+            Err(AccountError::NotFound("test_account".to_string()))
+        );
+    }
+
+    // unit tests for the account lifecycle
+    // =========================================================================================================
+    #[test]
+    fn test_accounts_must_be_opened() -> TestResult {
+        let mut ledger = Accounts::new();
+        assert_eq!(
+            ledger.deposit("test_account", &usd(), 50),
+            Err(AccountError::NotFound("test_account".to_string()))
+        );
+
+        let account = ledger.open("test_account")?;
+        assert_eq!(account.status, AccountStatus::Active);
+        assert_eq!(account.opened_at, account.updated_at);
+        assert_eq!(ledger.holdings("test_account"), Ok(BTreeMap::new()));
+        assert_eq!(
+            ledger.open("test_account"),
+            Err(AccountError::AlreadyExists("test_account".to_string()))
+        );
+        assert!(matches!(
+            ledger.open("@revenue"),
+            Err(AccountError::Unsupported(_))
+        ));
+
+        // Nobody can be paid into an account that doesn't exist
+        ledger.deposit("test_account", &usd(), 50)?;
+        assert_eq!(
+            ledger.send("test_account", "recipient", &usd(), 10),
+            Err(AccountError::NotFound("recipient".to_string()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_frozen_can_only_be_credited() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("test_account")?;
+        ledger.open("recipient")?;
+        ledger.deposit("test_account", &usd(), 50)?;
+
+        let account = ledger.freeze("test_account")?;
+        assert_eq!(account.status, AccountStatus::Frozen);
+        let frozen = Err(AccountError::Frozen("test_account".to_string()));
+        assert_eq!(ledger.withdraw("test_account", &usd(), 10), frozen);
+        assert_eq!(ledger.send("test_account", "recipient", &usd(), 10), frozen);
+        let hold = Hold {
+            signer: "test_account".to_string(),
+            asset: usd(),
+            amount: 10,
+        };
+        assert_eq!(
+            ledger.reserve(&hold),
+            Err(AccountError::Frozen("test_account".to_string()))
+        );
+        ledger.deposit("test_account", &usd(), 5)?;
+        ledger.adjust("test_account", &usd(), -5, "chargeback")?;
+
+        assert_eq!(
+            ledger.unfreeze("test_account")?.status,
+            AccountStatus::Active
+        );
+        ledger.withdraw("test_account", &usd(), 10)?;
+        assert_eq!(ledger.balance_of("test_account", &usd()), Ok(40));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_close_requires_zero_balance() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("test_account")?;
+        ledger.deposit("test_account", &usd(), 50)?;
+
+        assert_eq!(
+            ledger.close("test_account"),
+            Err(AccountError::NotEmpty("test_account".to_string()))
+        );
+        ledger.withdraw("test_account", &usd(), 50)?;
+        assert_eq!(ledger.close("test_account")?.status, AccountStatus::Closed);
+
+        // Closed accounts stay closed and can't take funds
+        let closed = Err(AccountError::Closed("test_account".to_string()));
+        assert_eq!(ledger.deposit("test_account", &usd(), 1), closed);
+        assert_eq!(
+            ledger.unfreeze("test_account"),
+            Err(AccountError::Closed("test_account".to_string()))
+        );
+        assert_eq!(
+            ledger.open("test_account"),
+            Err(AccountError::AlreadyExists("test_account".to_string()))
+        );
+        assert_eq!(ledger.balance_of("test_account", &usd()), Ok(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_sub_accounts_act_within_the_family() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("desk")?;
+        ledger.open("outsider")?;
+        ledger.open_sub_account("desk", "desk_a")?;
+        ledger.open_sub_account("desk", "desk_b")?;
+        let nested = ledger.open_sub_account("desk_a", "desk_a1")?;
+        assert_eq!(nested.parent, Some("desk_a".to_string()));
+        let names: Vec<String> = ledger
+            .sub_accounts("desk")?
+            .into_iter()
+            .map(|account| account.signer)
+            .collect();
+        assert_eq!(names, vec!["desk_a", "desk_b"]);
+
+        // A parent acts on everything below it, never the other way around
+        assert_eq!(ledger.ensure_authorized("desk", "desk_a1"), Ok(()));
+        assert_eq!(
+            ledger.ensure_authorized("desk_a", "desk"),
+            Err(AccountError::Unauthorized("desk".to_string()))
+        );
+        assert_eq!(
+            ledger.ensure_authorized("desk_a", "desk_b"),
+            Err(AccountError::Unauthorized("desk_b".to_string()))
+        );
+
+        ledger.deposit("desk", &usd(), 100)?;
+        ledger.deposit("desk_a1", &usd(), 5)?;
+        ledger.transfer("desk", "desk", "desk_a", &usd(), 60)?;
+        ledger.transfer("desk", "desk_a", "desk_b", &usd(), 20)?;
+        ledger.transfer("desk_b", "desk_b", "desk_a", &usd(), 5)?;
+        assert_eq!(
+            ledger.transfer("desk_b", "desk_a", "desk_b", &usd(), 5),
+            Err(AccountError::Unauthorized("desk_a".to_string()))
+        );
+        assert_eq!(
+            ledger.transfer("desk", "desk_b", "outsider", &usd(), 5),
+            Err(AccountError::Unsupported(
+                "transfer from 'desk_b' to 'outsider'".to_string()
+            ))
+        );
+        assert_eq!(ledger.balance_of("desk_a", &usd()), Ok(45));
+        assert_eq!(ledger.balance_of("desk_b", &usd()), Ok(15));
+
+        ledger.reserve(&Hold {
+            signer: "desk_b".to_string(),
+            asset: usd(),
+            amount: 10,
+        })?;
+        let balance = Balance {
+            total: 105,
+            available: 95,
+        };
+        assert_eq!(ledger.rollup("desk")?, BTreeMap::from([(usd(), balance)]));
+        assert_eq!(ledger.rollup("desk_a")?[&usd()].total, 50);
+
+        // The parent stays open as long as a sub-account does
+        ledger.withdraw("desk", &usd(), 40)?;
+        assert_eq!(
+            ledger.close("desk"),
+            Err(AccountError::NotEmpty("desk".to_string()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_borrow_on_margin() -> TestResult {
+        let mut ledger = Accounts::new();
+        ledger.open("test_account")?;
+        let mut txs = vec![ledger.deposit("test_account", &usd(), 50)?];
+        let terms = MarginTerms {
+            asset: usd(),
+            credit_limit: 100,
+            maintenance_bps: 2_500,
+        };
+        ledger.set_margin("test_account", terms)?;
+
+        // Whatever isn't available is borrowed when the hold is set aside
+        let hold = Hold {
+            signer: "test_account".to_string(),
+            asset: usd(),
+            amount: 120,
+        };
+        let loan = ledger.reserve(&hold)?.expect("the shortfall is borrowed");
+        assert_eq!(
+            loan.kind,
+            TxKind::Borrow {
+                account: "test_account".to_string(),
+                asset: usd(),
+                amount: 70
+            }
+        );
+        txs.push(loan);
+        assert_eq!(ledger.owed("test_account", &usd()), 70);
+        assert_eq!(ledger.balance_of("test_account", &usd()), Ok(120));
+        assert_eq!(ledger.buying_power("test_account", &usd()), Ok(30));
+        assert_eq!(
+            ledger.reserve(&Hold {
+                amount: 31,
+                ..hold.clone()
+            }),
+            Err(AccountError::UnderFunded("test_account".to_string()))
+        );
+
+        // Only what is held over the debt can be moved out
+        ledger.release(&hold);
+        assert_eq!(
+            ledger.withdraw("test_account", &usd(), 51),
+            Err(AccountError::UnderFunded("test_account".to_string()))
+        );
+        txs.push(ledger.withdraw("test_account", &usd(), 50)?);
+        assert_eq!(
+            ledger.close("test_account"),
+            Err(AccountError::NotEmpty("test_account".to_string()))
+        );
+        let other = MarginTerms {
+            asset: Asset::new("OCTO"),
+            credit_limit: 100,
+            maintenance_bps: 2_500,
+        };
+        assert!(matches!(
+            ledger.set_margin("test_account", other),
+            Err(AccountError::Unsupported(_))
+        ));
+
+        // The rebuilt accounts still owe what was borrowed
+        let rebuilt = Accounts::from_transactions(&txs)?;
+        assert_eq!(rebuilt.owed("test_account", &usd()), 70);
+
+        let repaid = ledger.repay("test_account", &usd(), 100)?;
+        assert_eq!(
+            repaid.kind,
+            TxKind::Repay {
+                account: "test_account".to_string(),
+                asset: usd(),
+                amount: 70
+            }
+        );
+        assert_eq!(ledger.owed("test_account", &usd()), 0);
+        assert!(matches!(
+            ledger.repay("test_account", &usd(), 1),
+            Err(AccountError::Unsupported(_))
+        ));
+        assert_eq!(ledger.close("test_account")?.status, AccountStatus::Closed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accounts_take_margin_calls_checks_only_the_accounts_trades_touched() -> TestResult {
+        let mut ledger = Accounts::new();
+        for signer in ["borrower", "seller", "buyer"] {
+            ledger.open(signer)?;
+        }
+        ledger.deposit("seller", &Asset::new("OCTO"), 20)?;
+        ledger.deposit("seller", &Asset::new("INK"), 10)?;
+        ledger.deposit("buyer", &usd(), 200)?;
+        let terms = MarginTerms {
+            asset: usd(),
+            credit_limit: 100,
+            maintenance_bps: 2_500,
+        };
+        ledger.set_margin("borrower", terms)?;
+        let trade = |buyer: &str, base: &str, amount, price| Trade {
+            id: format!("{base}/USD:{buyer}:{price}"),
+            buyer: buyer.to_string(),
+            seller: "seller".to_string(),
+            base: Asset::new(base),
+            quote: usd(),
+            amount,
+            price,
+            buyer_fee: 0,
+            seller_fee: 0,
+        };
+
+        // Buying entirely on credit leaves no equity
+        let hold = Hold {
+            signer: "borrower".to_string(),
+            asset: usd(),
+            amount: 100,
+        };
+        ledger.reserve(&hold)?;
+        ledger.settle(&[hold], &[trade("borrower", "OCTO", 10, 10)])?;
+        let calls = ledger.take_margin_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].signer, "borrower");
+
+        // Still in a margin call, but nothing touched the account since
+        assert!(ledger.take_margin_calls().is_empty());
+        assert_eq!(ledger.margin_calls(), calls);
+        ledger.settle(&[], &[trade("buyer", "INK", 10, 10)])?;
+        assert!(ledger.take_margin_calls().is_empty());
+
+        // A trade between others moves the price of what it holds
+        ledger.settle(&[], &[trade("buyer", "OCTO", 1, 9)])?;
+        let calls = ledger.take_margin_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].exposure, 90);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trade_settling_moves_base_and_quote() -> TestResult {
+        let instrument = Instrument::new("OCTO", "USD");
+        let order = Order {
+            price: 11,
+            amount: 3,
+            side: Side::Sell,
+            signer: "seller".to_string(),
+        };
+        let receipt = Receipt {
+            ordinal: 2,
+            timestamp: 0,
+            matches: vec![Order {
+                price: 11,
+                amount: 3,
+                side: Side::Buy,
+                signer: "buyer".to_string(),
+            }
+            .into_partial_order(1, 0)],
+            fee: 0,
+        };
+
+        let trades = Trade::settling(&instrument, &order, &receipt);
+        assert_eq!(
+            trades,
+            vec![Trade {
+                id: "OCTO/USD:2:1".to_string(),
+                buyer: "buyer".to_string(),
+                seller: "seller".to_string(),
+                base: Asset::new("OCTO"),
+                quote: usd(),
+                amount: 3,
+                price: 11,
+                buyer_fee: 0,
+                seller_fee: 0,
+            }]
+        );
+        assert_eq!(
+            trades[0].movements()?,
+            [
+                Movement {
+                    from: LedgerAccount::User("seller".to_string()),
+                    to: LedgerAccount::User("buyer".to_string()),
+                    asset: Asset::new("OCTO"),
+                    amount: 3,
+                },
+                Movement {
+                    from: LedgerAccount::User("buyer".to_string()),
+                    to: LedgerAccount::User("seller".to_string()),
+                    asset: usd(),
+                    amount: 33,
+                },
+            ]
         );
 
         Ok(())
@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::types::Receipt;
+
+/// The width of a single candle bucket
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
+pub enum Interval {
+    #[serde(rename = "1s")]
+    OneSecond,
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Interval {
+    /// Every interval the [`CandleAggregator`] keeps bars for
+    pub const ALL: [Interval; 4] = [
+        Interval::OneSecond,
+        Interval::OneMinute,
+        Interval::OneHour,
+        Interval::OneDay,
+    ];
+
+    /// Length of the interval in milliseconds
+    pub fn millis(&self) -> u64 {
+        match self {
+            Interval::OneSecond => 1_000,
+            Interval::OneMinute => 60 * 1_000,
+            Interval::OneHour => 60 * 60 * 1_000,
+            Interval::OneDay => 24 * 60 * 60 * 1_000,
+        }
+    }
+
+    /// Rounds a millisecond `timestamp` down to the start of its bucket
+    pub fn open_time(&self, timestamp: u64) -> u64 {
+        timestamp - timestamp % self.millis()
+    }
+}
+
+/// An open/high/low/close/volume bar for a single bucket
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct Candle {
+    /// Start of the bucket in milliseconds since the unix epoch
+    pub open_time: u64,
+    /// Price of the first trade in the bucket
+    pub open: u64,
+    /// Highest traded price in the bucket
+    pub high: u64,
+    /// Lowest traded price in the bucket
+    pub low: u64,
+    /// Price of the last trade in the bucket
+    pub close: u64,
+    /// Number of units traded in the bucket
+    pub volume: u64,
+    /// Number of trades in the bucket
+    pub trades: u64,
+}
+
+impl Candle {
+    fn new(open_time: u64, price: u64, amount: u64) -> Self {
+        Candle {
+            open_time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: amount,
+            trades: 1,
+        }
+    }
+
+    fn update(&mut self, price: u64, amount: u64) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume = self.volume.saturating_add(amount);
+        self.trades += 1;
+    }
+}
+
+/// Aggregates executed trades into [`Candle`]s for every [`Interval`]
+#[derive(Default, Debug, Clone)]
+pub struct CandleAggregator {
+    bars: HashMap<Interval, BTreeMap<u64, Candle>>,
+}
+
+impl CandleAggregator {
+    /// Returns an aggregator without any bars
+    pub fn new() -> Self {
+        CandleAggregator {
+            bars: HashMap::new(),
+        }
+    }
+
+    /// Creates an aggregator and backfills it from the [`Receipt`]s in a matching engine's history
+    pub fn from_history(history: &[Receipt]) -> Self {
+        let mut aggregator = CandleAggregator::new();
+        aggregator.backfill(history);
+        aggregator
+    }
+
+    /// Discards all bars and rebuilds them from the provided `history`
+    pub fn backfill(&mut self, history: &[Receipt]) {
+        self.bars.clear();
+        history.iter().for_each(|r| self.record_receipt(r));
+    }
+
+    /// Adds every match in the [`Receipt`] as a trade at the receipt's timestamp
+    pub fn record_receipt(&mut self, receipt: &Receipt) {
+        for po in &receipt.matches {
+            self.record_trade(po.price, po.amount, receipt.timestamp);
+        }
+    }
+
+    /// Adds a single trade of `amount` units at `price` to the bar of each interval
+    pub fn record_trade(&mut self, price: u64, amount: u64, timestamp: u64) {
+        for interval in Interval::ALL {
+            let open_time = interval.open_time(timestamp);
+
+            self.bars
+                .entry(interval)
+                .or_default()
+                .entry(open_time)
+                .and_modify(|c| c.update(price, amount))
+                .or_insert_with(|| Candle::new(open_time, price, amount));
+        }
+    }
+
+    /// Fetches the bars of an interval, oldest first
+    pub fn candles(&self, interval: Interval) -> Vec<Candle> {
+        self.bars
+            .get(&interval)
+            .map(|bars| bars.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+    use crate::types::{PartialOrder, Side};
+
+    fn receipt(timestamp: u64, fills: &[(u64, u64)]) -> Receipt {
+        Receipt {
+            ordinal: 0,
+            timestamp,
+            matches: fills
+                .iter()
+                .map(|&(price, amount)| PartialOrder {
+                    price,
+                    amount,
+                    remaining: 0,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                    ordinal: 0,
+                    sequence: 0,
+                    condition: None,
+                })
+                .collect(),
+            fee: 0,
+        }
+    }
+
+    #[test]
+    fn test_CandleAggregator_record_trade_builds_ohlcv() {
+        let mut aggregator = CandleAggregator::new();
+
+        aggregator.record_trade(10, 1, 1_000);
+        aggregator.record_trade(12, 2, 1_200);
+        aggregator.record_trade(9, 3, 1_500);
+        aggregator.record_trade(11, 4, 1_999);
+
+        assert_eq!(
+            aggregator.candles(Interval::OneSecond),
+            vec![Candle {
+                open_time: 1_000,
+                open: 10,
+                high: 12,
+                low: 9,
+                close: 11,
+                volume: 10,
+                trades: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_CandleAggregator_record_trade_splits_buckets_per_interval() {
+        let mut aggregator = CandleAggregator::new();
+
+        aggregator.record_trade(10, 1, 500);
+        aggregator.record_trade(11, 1, 1_500);
+        aggregator.record_trade(12, 1, 61_000);
+
+        assert_eq!(aggregator.candles(Interval::OneSecond).len(), 3);
+        assert_eq!(aggregator.candles(Interval::OneMinute).len(), 2);
+        assert_eq!(aggregator.candles(Interval::OneHour).len(), 1);
+        assert_eq!(aggregator.candles(Interval::OneDay).len(), 1);
+
+        let minutes = aggregator.candles(Interval::OneMinute);
+        assert_eq!(minutes[0].open_time, 0);
+        assert_eq!(minutes[0].close, 11);
+        assert_eq!(minutes[1].open_time, 60_000);
+        assert_eq!(minutes[1].open, 12);
+    }
+
+    #[test]
+    fn test_CandleAggregator_from_history_backfills() {
+        let history = vec![
+            receipt(1_000, &[]),
+            receipt(2_000, &[(10, 2), (11, 1)]),
+            receipt(3_000, &[(8, 5)]),
+        ];
+
+        let aggregator = CandleAggregator::from_history(&history);
+
+        assert_eq!(aggregator.candles(Interval::OneSecond).len(), 2);
+        assert_eq!(
+            aggregator.candles(Interval::OneMinute),
+            vec![Candle {
+                open_time: 0,
+                open: 10,
+                high: 11,
+                low: 8,
+                close: 8,
+                volume: 8,
+                trades: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_CandleAggregator_candles_empty() {
+        let aggregator = CandleAggregator::new();
+
+        assert!(aggregator.candles(Interval::OneDay).is_empty());
+    }
+}
@@ -1,10 +1,17 @@
 use crate::{
-    errors::AccountError,
-    types::{Order, PartialOrder, Receipt, Side},
+    errors::{AccountError, InvariantViolation},
+    matcher::Matcher,
+    types::{
+        value, Condition, ConditionalOrder, Depth, Execution, Level, Order, PartialOrder,
+        PeggedOrder, Receipt, Side, StopOrder, TrailingStop, TriggeredOrder,
+    },
+};
+use std::{
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
 };
-use std::collections::{BTreeMap, BinaryHeap};
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct MatchingEngine {
     /// The last sequence number
     pub ordinal: u64,
@@ -16,6 +23,48 @@ pub struct MatchingEngine {
 
     /// Previous matches for record keeping
     pub history: Vec<Receipt>,
+
+    /// Stop orders waiting for their trigger price. Ordered by ordinal number.
+    pub stops: BTreeMap<u64, StopOrder>,
+    /// Trailing stop orders with their current trigger price. Ordered by ordinal number.
+    pub trailing_stops: BTreeMap<u64, (TrailingStop, u64)>,
+    /// Orders released by a stop that are yet to be processed
+    pub triggered: Vec<TriggeredOrder>,
+
+    /// Price of the most recent trade
+    pub last_price: Option<u64>,
+
+    /// Pegged orders resting in the book. Ordered by ordinal number.
+    pub pegs: BTreeMap<u64, PeggedOrder>,
+
+    /// The price levels changed since the last savepoint as they were before, while there is one
+    undo: Option<UndoLog>,
+}
+
+/// The price levels of both sides of the book as they were before they were first changed,
+/// nothing for a level that didn't exist yet
+#[derive(Default, Debug, Clone)]
+struct UndoLog {
+    bids: BTreeMap<u64, Option<BinaryHeap<PartialOrder>>>,
+    asks: BTreeMap<u64, Option<BinaryHeap<PartialOrder>>>,
+}
+
+impl UndoLog {
+    /// Puts every level that changed back the way it was
+    fn restore(
+        self,
+        bids: &mut BTreeMap<u64, BinaryHeap<PartialOrder>>,
+        asks: &mut BTreeMap<u64, BinaryHeap<PartialOrder>>,
+    ) {
+        for (book, levels) in [(bids, self.bids), (asks, self.asks)] {
+            for (price, level) in levels {
+                match level {
+                    Some(orders) => book.insert(price, orders),
+                    None => book.remove(&price),
+                };
+            }
+        }
+    }
 }
 
 impl MatchingEngine {
@@ -26,37 +75,262 @@ impl MatchingEngine {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             history: Vec::new(),
+            stops: BTreeMap::new(),
+            trailing_stops: BTreeMap::new(),
+            triggered: Vec::new(),
+            last_price: None,
+            pegs: BTreeMap::new(),
+            undo: None,
         }
     }
 
     /// Creates a new [`MatchingEngine`] with asks and bids from [`Vec<Order>`]
     pub fn new_with_orderbook(ask_orders: Vec<Order>, bid_orders: Vec<Order>) -> Self {
+        let mut ordinal = 0;
         let mut bids: BTreeMap<u64, BinaryHeap<PartialOrder>> = BTreeMap::new();
         let mut asks: BTreeMap<u64, BinaryHeap<PartialOrder>> = BTreeMap::new();
 
-        bid_orders.into_iter().for_each(|o| {
-            let price = o.price;
-            let po = o.into_partial_order(0, price);
+        // Orders are sequenced in the order they are provided, asks first
+        ask_orders.into_iter().for_each(|o| {
+            ordinal += 1;
+            let (price, amount) = (o.price, o.amount);
+            let po = o.into_partial_order(ordinal, amount);
 
-            bids.entry(price).or_insert(vec![].into()).push(po);
+            asks.entry(price).or_insert(vec![].into()).push(po);
         });
 
-        ask_orders.into_iter().for_each(|o| {
-            let price = o.price;
-            let po = o.into_partial_order(0, price);
+        bid_orders.into_iter().for_each(|o| {
+            ordinal += 1;
+            let (price, amount) = (o.price, o.amount);
+            let po = o.into_partial_order(ordinal, amount);
 
-            asks.entry(price).or_insert(vec![].into()).push(po);
+            bids.entry(price).or_insert(vec![].into()).push(po);
         });
 
         MatchingEngine {
-            ordinal: 0,
+            ordinal,
             bids,
             asks,
             history: Vec::new(),
+            stops: BTreeMap::new(),
+            trailing_stops: BTreeMap::new(),
+            triggered: Vec::new(),
+            last_price: None,
+            pegs: BTreeMap::new(),
+            undo: None,
+        }
+    }
+
+    /// The highest price in the bid book
+    pub fn best_bid(&self) -> Option<u64> {
+        self.bids.keys().next_back().copied()
+    }
+
+    /// The lowest price in the ask book
+    pub fn best_ask(&self) -> Option<u64> {
+        self.asks.keys().next().copied()
+    }
+
+    /// Whether the order with this ordinal is still resting in the book
+    pub fn is_resting(&self, ordinal: u64) -> bool {
+        self.bids
+            .values()
+            .chain(self.asks.values())
+            .any(|orders| orders.iter().any(|o| o.ordinal == ordinal))
+    }
+
+    /// The signer of a resting order, or of a stop or trailing stop order whether it is waiting or already triggered
+    pub fn signer_of(&self, ordinal: u64) -> Option<String> {
+        let held = self
+            .stops
+            .get(&ordinal)
+            .map(|stop| &stop.order)
+            .or_else(|| {
+                self.trailing_stops
+                    .get(&ordinal)
+                    .map(|(stop, _)| &stop.order)
+            })
+            .or_else(|| {
+                self.triggered
+                    .iter()
+                    .find(|t| t.ordinal == ordinal)
+                    .map(|t| &t.order)
+            });
+        match held {
+            Some(order) => Some(order.signer.clone()),
+            None => self
+                .bids
+                .values()
+                .chain(self.asks.values())
+                .flat_map(|orders| orders.iter())
+                .find(|o| o.ordinal == ordinal)
+                .map(|o| o.signer.clone()),
+        }
+    }
+
+    /// The orders at a price level of one side of the book, for changing them.
+    /// While there is a savepoint, the level is kept as it was before its first change.
+    fn level_mut(&mut self, side: &Side, price: u64) -> &mut BinaryHeap<PartialOrder> {
+        let (book, undo) = match side {
+            Side::Buy => (
+                &mut self.bids,
+                self.undo.as_mut().map(|undo| &mut undo.bids),
+            ),
+            Side::Sell => (
+                &mut self.asks,
+                self.undo.as_mut().map(|undo| &mut undo.asks),
+            ),
+        };
+        if let Some(undo) = undo {
+            undo.entry(price)
+                .or_insert_with(|| book.get(&price).cloned());
+        }
+        book.entry(price).or_default()
+    }
+
+    /// Removes a resting order from the book and returns it
+    pub fn cancel(&mut self, ordinal: u64) -> Option<PartialOrder> {
+        let mut cancelled = None;
+
+        let found = self
+            .bids
+            .values()
+            .chain(self.asks.values())
+            .flat_map(|orders| orders.iter())
+            .find(|o| o.ordinal == ordinal)
+            .map(|o| (o.side.clone(), o.price));
+        if let Some((side, price)) = found {
+            let orders = self.level_mut(&side, price);
+            cancelled = orders.iter().find(|o| o.ordinal == ordinal).cloned();
+            orders.retain(|o| o.ordinal != ordinal);
+        }
+
+        self.asks.retain(|_, orders| !orders.is_empty());
+        self.bids.retain(|_, orders| !orders.is_empty());
+        self.reprice_pegs();
+        cancelled
+    }
+
+    /// Holds a [`StopOrder`] until a trade reaches its trigger and returns its ordinal
+    pub fn submit_stop(&mut self, stop: StopOrder) -> u64 {
+        self.ordinal += 1;
+        self.stops.insert(self.ordinal, stop);
+        self.ordinal
+    }
+
+    /// Holds a [`TrailingStop`] and returns its ordinal. The trigger is anchored at the last trade price,
+    /// or at the order price before the first trade.
+    pub fn submit_trailing_stop(&mut self, stop: TrailingStop) -> u64 {
+        let price = self.last_price.unwrap_or(stop.order.price);
+        let offset = stop.trail.offset(price);
+        let trigger = match stop.order.side {
+            Side::Buy => price.saturating_add(offset),
+            Side::Sell => price.saturating_sub(offset),
+        };
+
+        self.ordinal += 1;
+        self.trailing_stops.insert(self.ordinal, (stop, trigger));
+        self.ordinal
+    }
+
+    /// The current trigger price of a trailing stop that hasn't fired yet
+    pub fn trailing_stop_trigger(&self, ordinal: u64) -> Option<u64> {
+        self.trailing_stops
+            .get(&ordinal)
+            .map(|(_, trigger)| *trigger)
+    }
+
+    /// Removes a stop or trailing stop order, whether it is waiting or already triggered, and returns its order
+    pub fn cancel_stop(&mut self, ordinal: u64) -> Option<Order> {
+        if let Some(stop) = self.stops.remove(&ordinal) {
+            return Some(stop.order);
+        }
+        if let Some((stop, _)) = self.trailing_stops.remove(&ordinal) {
+            return Some(stop.order);
+        }
+        let index = self.triggered.iter().position(|t| t.ordinal == ordinal)?;
+        Some(self.triggered.remove(index).order)
+    }
+
+    /// Hands over the orders released by previous trades, oldest first
+    pub fn take_triggered(&mut self) -> Vec<TriggeredOrder> {
+        std::mem::take(&mut self.triggered)
+    }
+
+    /// Total price of filling the order against the opposite side of the book at any price, skipping the signer's own orders
+    /// # Errors
+    /// The price doesn't fit in a `u64`
+    pub fn market_cost(&self, order: &Order) -> Result<u64, AccountError> {
+        let levels: Box<dyn Iterator<Item = &BinaryHeap<PartialOrder>>> = match order.side {
+            Side::Buy => Box::new(self.asks.values()),
+            Side::Sell => Box::new(self.bids.values().rev()),
+        };
+
+        let mut left = order.amount;
+        let mut cost: u64 = 0;
+        for po in levels.flat_map(|orders| orders.iter()) {
+            if left == 0 {
+                break;
+            }
+            if po.signer == order.signer {
+                continue;
+            }
+            let take = left.min(po.remaining);
+            cost = value(take, po.price)?
+                .checked_add(cost)
+                .ok_or(AccountError::ArithmeticOverflow)?;
+            left -= take;
+        }
+        Ok(cost)
+    }
+
+    /// Replays the trades in the [`Receipt`] against the held stops.
+    /// Every trade updates the last price, releases the stops it reaches and moves trailing triggers along.
+    fn trigger_stops(&mut self, receipt: &Receipt) {
+        for po in &receipt.matches {
+            let price = po.price;
+            self.last_price = Some(price);
+
+            let released: Vec<u64> = self
+                .stops
+                .iter()
+                .filter(|(_, stop)| stop.is_triggered_by(price))
+                .map(|(ordinal, _)| *ordinal)
+                .collect();
+            for ordinal in released {
+                if let Some(stop) = self.stops.remove(&ordinal) {
+                    self.triggered.push(TriggeredOrder {
+                        ordinal,
+                        order: stop.order,
+                        execution: Execution::Limit,
+                    });
+                }
+            }
+
+            let mut released = vec![];
+            for (ordinal, (stop, trigger)) in self.trailing_stops.iter_mut() {
+                let offset = stop.trail.offset(price);
+                match stop.order.side {
+                    Side::Buy if price >= *trigger => released.push(*ordinal),
+                    Side::Sell if price <= *trigger => released.push(*ordinal),
+                    // The trigger only ever follows the price in the favourable direction
+                    Side::Buy => *trigger = (*trigger).min(price.saturating_add(offset)),
+                    Side::Sell => *trigger = (*trigger).max(price.saturating_sub(offset)),
+                }
+            }
+            for ordinal in released {
+                if let Some((stop, _)) = self.trailing_stops.remove(&ordinal) {
+                    self.triggered.push(TriggeredOrder {
+                        ordinal,
+                        order: stop.order,
+                        execution: stop.execution,
+                    });
+                }
+            }
         }
     }
 
-    pub fn vectorised_orderbook(&mut self) -> Vec<PartialOrder> {
+    pub fn vectorised_orderbook(&self) -> Vec<PartialOrder> {
         let mut orderbook = Vec::new();
 
         // Cannot merge the two BTreeMaps as keys not unique. Must convert to vecs first
@@ -74,114 +348,379 @@ impl MatchingEngine {
         orderbook.concat()
     }
 
+    /// Verifies the books are consistent with each other and with the history of matches:
+    /// - every price level holds at least one order of its own price and side
+    /// - ordinals are issued once and in increasing order
+    /// - the units filled and remaining of every resting order add up to its amount
+    /// - no resting bid and ask without conditions could trade with each other
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        for (side, book) in [(Side::Buy, &self.bids), (Side::Sell, &self.asks)] {
+            for (price, orders) in book {
+                if orders.is_empty() || orders.iter().any(|o| o.price != *price || o.side != side) {
+                    return Err(InvariantViolation::InvalidLevel(*price));
+                }
+            }
+        }
+
+        let mut last = 0;
+        for receipt in &self.history {
+            if receipt.ordinal <= last {
+                return Err(InvariantViolation::OrdinalOutOfOrder(receipt.ordinal));
+            }
+            last = receipt.ordinal;
+        }
+        let resting = self.vectorised_orderbook();
+        let mut seen = HashSet::new();
+        let held = self.stops.keys().chain(self.trailing_stops.keys());
+        for ordinal in resting.iter().map(|o| &o.ordinal).chain(held) {
+            if *ordinal == 0 || *ordinal > self.ordinal || !seen.insert(*ordinal) {
+                return Err(InvariantViolation::OrdinalOutOfOrder(*ordinal));
+            }
+        }
+        if last > self.ordinal {
+            return Err(InvariantViolation::OrdinalOutOfOrder(last));
+        }
+
+        // Every match fills the same number of units of the maker and the taker
+        let mut filled: HashMap<u64, u64> = HashMap::new();
+        for receipt in &self.history {
+            for po in &receipt.matches {
+                *filled.entry(po.ordinal).or_default() += po.amount;
+                *filled.entry(receipt.ordinal).or_default() += po.amount;
+            }
+        }
+        for po in &resting {
+            let units = filled.get(&po.ordinal).copied().unwrap_or_default();
+            if po.remaining == 0 || units.checked_add(po.remaining) != Some(po.amount) {
+                return Err(InvariantViolation::QuantityMismatch {
+                    ordinal: po.ordinal,
+                    amount: po.amount,
+                    filled: units,
+                    remaining: po.remaining,
+                });
+            }
+        }
+
+        // Crossing orders may only rest if they're from the same signer or one of them has a condition.
+        // Conditional orders are only matched against incoming orders, so they can be left crossed once their condition is met.
+        let Some(best_ask) = self.best_ask() else {
+            return Ok(());
+        };
+        for bid in self.bids.range(best_ask..).flat_map(|(_, o)| o.iter()) {
+            for ask in self.asks.range(..=bid.price).flat_map(|(_, o)| o.iter()) {
+                let tradeable =
+                    bid.signer != ask.signer && bid.condition.is_none() && ask.condition.is_none();
+                if tradeable {
+                    return Err(InvariantViolation::CrossedBook {
+                        bid: bid.ordinal,
+                        ask: ask.ordinal,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Processes an [`Order`] and returns a [`Receipt`]
     /// This includes matching the order to whatever is in the current books and adding the remainder (if any) to the book for future matching.
     pub fn process(&mut self, order: Order) -> Result<Receipt, AccountError> {
+        self.process_order(order, true, None)
+    }
+
+    /// Processes a [`ConditionalOrder`] and returns a [`Receipt`]
+    /// Nothing is matched unless enough units can trade at once to satisfy the condition.
+    /// The remainder rests in the book and keeps the condition for later matches.
+    pub fn process_conditional(
+        &mut self,
+        order: ConditionalOrder,
+    ) -> Result<Receipt, AccountError> {
+        self.process_order(order.order, true, Some(order.condition))
+    }
+
+    /// Processes a [`PeggedOrder`] at its pegged price and returns a [`Receipt`]
+    /// Whatever rests in the book is repriced from then on whenever the top of book moves.
+    /// # Errors
+    /// The reference price doesn't exist
+    pub fn process_pegged(&mut self, pegged: PeggedOrder) -> Result<Receipt, AccountError> {
+        let price = self
+            .peg_price(&pegged)
+            .ok_or(AccountError::NoReferencePrice)?;
+        let order = Order {
+            price,
+            ..pegged.order.clone()
+        };
+
+        // Registered under the upcoming ordinal so the order is never taken as a reference for itself.
+        // Repricing forgets it again if nothing is left to rest in the book.
+        self.pegs.insert(self.ordinal + 1, pegged);
+        self.process(order)
+    }
+
+    /// The price a pegged order would get in the current book
+    pub fn peg_price(&self, pegged: &PeggedOrder) -> Option<u64> {
+        let (best_bid, best_ask) = self.unpegged_top_of_book();
+        pegged.price(best_bid, best_ask)
+    }
+
+    /// The best bid and ask prices ignoring pegged orders, so pegged orders never chase each other
+    fn unpegged_top_of_book(&self) -> (Option<u64>, Option<u64>) {
+        let unpegged = |orders: &BinaryHeap<PartialOrder>| {
+            orders.iter().any(|o| !self.pegs.contains_key(&o.ordinal))
+        };
+
+        (
+            self.bids
+                .iter()
+                .rev()
+                .find(|(_, orders)| unpegged(orders))
+                .map(|(price, _)| *price),
+            self.asks
+                .iter()
+                .find(|(_, orders)| unpegged(orders))
+                .map(|(price, _)| *price),
+        )
+    }
+
+    /// Moves pegged orders to their pegged price in a single pass.
+    /// Bids are repriced before asks and neither may cross the opposite side, so repricing never causes matches.
+    /// An order that moves goes to the back of its new price level, one that stays keeps its priority.
+    fn reprice_pegs(&mut self) {
+        // The price level of every resting order by ordinal. Forget pegged orders that were filled or cancelled
+        let resting: HashMap<u64, u64> = self
+            .bids
+            .iter()
+            .chain(self.asks.iter())
+            .flat_map(|(price, orders)| orders.iter().map(move |o| (o.ordinal, *price)))
+            .collect();
+        self.pegs.retain(|ordinal, _| resting.contains_key(ordinal));
+
+        let (best_bid, best_ask) = self.unpegged_top_of_book();
+        let pegs: Vec<(u64, PeggedOrder)> = self
+            .pegs
+            .iter()
+            .map(|(ordinal, pegged)| (*ordinal, pegged.clone()))
+            .collect();
+
+        for side in [Side::Buy, Side::Sell] {
+            for (ordinal, pegged) in pegs.iter().filter(|(_, p)| p.order.side == side) {
+                // Without a reference the order stays where it is
+                let Some(price) = pegged.price(best_bid, best_ask) else {
+                    continue;
+                };
+                let price = match side {
+                    Side::Buy => {
+                        price.min(self.best_ask().map_or(u64::MAX, |p| p.saturating_sub(1)))
+                    }
+                    Side::Sell => {
+                        price.max(self.best_bid().map_or(u64::MIN, |p| p.saturating_add(1)))
+                    }
+                };
+
+                let current = resting[ordinal];
+                if current == price {
+                    continue;
+                }
+
+                let level = self.level_mut(&side, current);
+                let Some(mut po) = level.iter().find(|o| o.ordinal == *ordinal).cloned() else {
+                    continue;
+                };
+                level.retain(|o| o.ordinal != *ordinal);
+
+                self.ordinal += 1;
+                po.price = price;
+                po.sequence = self.ordinal;
+                self.level_mut(&side, price).push(po);
+                let book = match side {
+                    Side::Buy => &mut self.bids,
+                    Side::Sell => &mut self.asks,
+                };
+                book.retain(|_, orders| !orders.is_empty());
+            }
+        }
+    }
+
+    /// Processes an [`Order`] at any price and returns a [`Receipt`]
+    /// The remainder that couldn't be matched straight away is discarded rather than added to the book.
+    pub fn process_market(&mut self, order: Order) -> Result<Receipt, AccountError> {
+        let price = match order.side {
+            Side::Buy => u64::MAX,
+            Side::Sell => u64::MIN,
+        };
+        self.process_order(Order { price, ..order }, false, None)
+    }
+
+    fn process_order(
+        &mut self,
+        order: Order,
+        rest: bool,
+        condition: Option<Condition>,
+    ) -> Result<Receipt, AccountError> {
         // Increment the ordinal number for this order
         self.ordinal += 1;
         let ordinal = self.ordinal;
 
         let original_amount = order.amount;
         let mut partial = order.into_partial_order(ordinal, original_amount);
+        partial.condition = condition;
+
+        // A conditional order only trades if enough of the book is eligible to trade with it at once
+        let tradeable =
+            condition.is_none_or(|c| self.fillable(&partial) >= c.required(original_amount));
 
         // Orders are matched to the opposite side
-        let receipt = match &partial.side {
+        let (receipt, matched_amount) = match &partial.side {
             Side::Buy => {
                 // Fetch all sell orders(asks) in the expected price range from the orderbook
-                let orderbook_entry = self.asks.range_mut(u64::MIN..=partial.price);
-
-                let receipt = MatchingEngine::match_order(&partial, orderbook_entry, ordinal)?;
-
-                // Sum up all the amount in the matches
-                let matched_amount: u64 = receipt.matches.iter().map(|m| m.amount).sum();
-
-                // If order wasn't fully matched
-                if matched_amount < original_amount {
-                    partial.amount = original_amount - matched_amount;
-                    let price = partial.price;
-                    // Find any bids of the same price or insert default as a min-heap
-                    let bids = self.bids.entry(price).or_insert(vec![].into());
-                    bids.push(partial)
-                }
+                let orderbook_entry = self
+                    .asks
+                    .range_mut(u64::MIN..=partial.price)
+                    .take_while(|_| tradeable);
+                let undo = self.undo.as_mut().map(|undo| &mut undo.asks);
 
-                receipt
+                MatchingEngine::match_order(&partial, orderbook_entry, undo, ordinal)?
             }
             Side::Sell => {
                 // Fetch all buy orders(bids) in the expected price range from the orderbook
-                let orderbook_entry = self.bids.range_mut(partial.price..=u64::MAX);
+                // The highest bids are matched first
+                let orderbook_entry = self
+                    .bids
+                    .range_mut(partial.price..=u64::MAX)
+                    .rev()
+                    .take_while(|_| tradeable);
+                let undo = self.undo.as_mut().map(|undo| &mut undo.bids);
 
                 // Pass the order to be proccessed and all the buy orders from the orderbook to the matching algorithm
-                let receipt = MatchingEngine::match_order(&partial, orderbook_entry, ordinal)?;
-                let matched_amount: u64 = receipt.matches.iter().map(|m| m.amount).sum();
-
-                // The order wasn't fully matched
-                if matched_amount < original_amount {
-                    partial.amount = original_amount - matched_amount;
-                    let price = partial.price;
-                    let asks = self.asks.entry(price).or_insert(vec![].into());
-                    asks.push(partial);
-                }
-                receipt
+                MatchingEngine::match_order(&partial, orderbook_entry, undo, ordinal)?
             }
         };
 
+        // If order wasn't fully matched, add the remainder to its side of the book
+        if rest && matched_amount < original_amount {
+            partial.remaining = original_amount - matched_amount;
+            let (side, price) = (partial.side.clone(), partial.price);
+            // Find any orders of the same price or insert default as a min-heap
+            self.level_mut(&side, price).push(partial);
+        }
+
         // Cleanup: Remove price entries without orders from the orderbook
         self.asks.retain(|_, orders| !orders.is_empty());
         self.bids.retain(|_, orders| !orders.is_empty());
 
+        // Follow the new top of book with the pegged orders
+        self.reprice_pegs();
+
+        // Release any stop orders the trades have reached
+        self.trigger_stops(&receipt);
+
         // Keep a log of matches
         self.history.push(receipt.clone());
         Ok(receipt)
     }
 
+    /// Number of units of an order that could be matched against the book right now.
+    /// Walks the book exactly like [`MatchingEngine::match_order`] without changing it.
+    fn fillable(&self, order: &PartialOrder) -> u64 {
+        let levels: Box<dyn Iterator<Item = &BinaryHeap<PartialOrder>>> = match order.side {
+            Side::Buy => Box::new(self.asks.range(u64::MIN..=order.price).map(|(_, o)| o)),
+            Side::Sell => Box::new(
+                self.bids
+                    .range(order.price..=u64::MAX)
+                    .rev()
+                    .map(|(_, o)| o),
+            ),
+        };
+
+        let mut remaining_amount = order.remaining;
+        for orders in levels {
+            // Reversing the sorted min-heap gives the order pop() would return them in
+            for entry in orders.clone().into_sorted_vec().iter().rev() {
+                if remaining_amount == 0 {
+                    break;
+                }
+                if let Some(take) = MatchingEngine::fill_amount(entry, order, remaining_amount) {
+                    remaining_amount -= take;
+                }
+            }
+        }
+        order.remaining - remaining_amount
+    }
+
+    /// The number of units an order with `remaining_amount` left can take from a resting `entry`.
+    /// Returns nothing for the signer's own orders and for orders whose condition rules out the fill.
+    fn fill_amount(
+        entry: &PartialOrder,
+        order: &PartialOrder,
+        remaining_amount: u64,
+    ) -> Option<u64> {
+        if entry.signer == order.signer {
+            return None;
+        }
+
+        let take = remaining_amount.min(entry.remaining);
+        let required = entry
+            .condition
+            .map_or(1, |c| c.required(entry.remaining).max(1));
+
+        (take >= required).then_some(take)
+    }
+
     /// Matches an order to the provided order book side.
+    /// Returns the [`Receipt`] along with the number of units that were matched.
     /// # Parameters
     /// - `order`: the order to match to the book
     /// - `orderbook_entry`: a pre-filtered iterator for order book_entry in the correct price range
+    /// - `undo`: where to keep the levels of the book side as they were before matching, if anywhere
     /// - `ordinal` the next ordinal number to use if a position is opened
     fn match_order<'a, T>(
         order: &PartialOrder,
         mut orderbook_entry: T,
+        mut undo: Option<&mut BTreeMap<u64, Option<BinaryHeap<PartialOrder>>>>,
         ordinal: u64,
-    ) -> Result<Receipt, AccountError>
+    ) -> Result<(Receipt, u64), AccountError>
     where
         T: Iterator<Item = (&'a u64, &'a mut BinaryHeap<PartialOrder>)>,
     {
-        let mut remaining_amount: u64 = order.amount;
+        let mut remaining_amount: u64 = order.remaining;
         let mut matches: Vec<PartialOrder> = vec![];
 
         // Each matching position's amount is subtracted
         'outer: while remaining_amount > 0 {
             // The iterator contains all orderbook_entry of a price point
             match orderbook_entry.next() {
-                Some((_price, orderbook_entry)) => {
-                    // store any self matches or partially filled orderbook entries to add back after loop
+                Some((price, orderbook_entry)) => {
+                    if let Some(undo) = undo.as_mut() {
+                        undo.entry(*price)
+                            .or_insert_with(|| Some(orderbook_entry.clone()));
+                    }
+
+                    // store any skipped or partially filled orderbook entries to add back after loop
                     let mut orderbook_returns = vec![];
 
                     // 1. remove the Order with the lowest sequence nr from the orderbook entry
                     // Orderbook entry is a Min-heap so pop() returns smallest value
-                    'pop: while let Some(mut entry) = orderbook_entry.pop() {
-                        // 2. skip over if it's their own order
-                        if entry.signer == order.signer {
-                            orderbook_returns.push(entry.clone());
+                    'pop: while remaining_amount > 0 {
+                        let Some(mut entry) = orderbook_entry.pop() else {
+                            break 'pop;
+                        };
+
+                        // 2. skip over their own orders and orders whose condition can't be met.
+                        // They go back with their sequence number so they keep their priority
+                        let Some(take) =
+                            MatchingEngine::fill_amount(&entry, order, remaining_amount)
+                        else {
+                            orderbook_returns.push(entry);
                             continue 'pop;
+                        };
+
+                        // 3. subtract the amount taken from the entry from the current order amount
+                        //  a. the units taken from the entry are added to the order matches
+                        //  b. if the entry isn't used up (partial match) it goes back into the orderbook entry
+                        matches.push(PartialOrder::take_from(&mut entry, take));
+                        if entry.remaining > 0 {
+                            orderbook_returns.push(entry);
                         }
-
-                        // 3. subtract the entry remaining amount from the current order amount
-                        //  a. if something left (partial match) add the order book order to the order matches and continue from 1
-                        //  b. if 0 is left (exact full match), do the same
-                        if (remaining_amount as i64 - entry.remaining as i64) >= 0 {
-                            entry.remaining = 0;
-                            remaining_amount -= entry.remaining;
-
-                            matches.push(entry);
-                        } else {
-                            // c. if negative is left (full match), split the Order into two, add original to matches and clone into the orderbook entry
-                            orderbook_returns
-                                .push(PartialOrder::take_from(&mut entry, remaining_amount));
-                            matches.push(entry);
-                            remaining_amount = 0;
-                        }
+                        remaining_amount -= take;
                     }
 
                     orderbook_returns
@@ -193,16 +732,177 @@ impl MatchingEngine {
             }
             // 4. repeat until the order has been filled to its fullest (remaining amount is 0)
         }
-        Ok(Receipt { ordinal, matches })
+
+        let receipt = Receipt {
+            ordinal,
+            timestamp: timestamp(),
+            matches,
+            fee: 0,
+        };
+        Ok((receipt, order.remaining - remaining_amount))
     }
 }
 
+impl Matcher for MatchingEngine {
+    fn process(&mut self, order: Order) -> Result<Receipt, AccountError> {
+        MatchingEngine::process(self, order)
+    }
+
+    fn cancel(&mut self, ordinal: u64) -> Option<PartialOrder> {
+        MatchingEngine::cancel(self, ordinal)
+    }
+
+    fn snapshot(&self) -> Vec<PartialOrder> {
+        self.vectorised_orderbook()
+    }
+
+    fn depth(&self) -> Depth {
+        let level = |(price, orders): (&u64, &BinaryHeap<PartialOrder>)| Level {
+            price: *price,
+            amount: orders
+                .iter()
+                .fold(0u64, |amount, po| amount.saturating_add(po.remaining)),
+            orders: orders.len(),
+        };
+
+        Depth {
+            bids: self.bids.iter().rev().map(level).collect(),
+            asks: self.asks.iter().map(level).collect(),
+        }
+    }
+
+    fn history(&self) -> &[Receipt] {
+        &self.history
+    }
+
+    fn best_bid(&self) -> Option<u64> {
+        MatchingEngine::best_bid(self)
+    }
+
+    fn best_ask(&self) -> Option<u64> {
+        MatchingEngine::best_ask(self)
+    }
+
+    fn is_resting(&self, ordinal: u64) -> bool {
+        MatchingEngine::is_resting(self, ordinal)
+    }
+
+    fn signer_of(&self, ordinal: u64) -> Option<String> {
+        MatchingEngine::signer_of(self, ordinal)
+    }
+
+    fn process_market(&mut self, order: Order) -> Result<Receipt, AccountError> {
+        MatchingEngine::process_market(self, order)
+    }
+
+    fn market_cost(&self, order: &Order) -> Result<u64, AccountError> {
+        MatchingEngine::market_cost(self, order)
+    }
+
+    fn submit_stop(&mut self, stop: StopOrder) -> Result<u64, AccountError> {
+        Ok(MatchingEngine::submit_stop(self, stop))
+    }
+
+    fn cancel_stop(&mut self, ordinal: u64) -> Option<Order> {
+        MatchingEngine::cancel_stop(self, ordinal)
+    }
+
+    fn take_triggered(&mut self) -> Vec<TriggeredOrder> {
+        MatchingEngine::take_triggered(self)
+    }
+
+    /// Everything but the book and the history. The price levels of the book are only kept once they change,
+    /// the history only ever grows and is cut back by ordinal instead.
+    fn savepoint(&mut self) -> Self {
+        self.undo = Some(UndoLog::default());
+        MatchingEngine {
+            ordinal: self.ordinal,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            history: Vec::new(),
+            stops: self.stops.clone(),
+            trailing_stops: self.trailing_stops.clone(),
+            triggered: self.triggered.clone(),
+            last_price: self.last_price,
+            pegs: self.pegs.clone(),
+            undo: None,
+        }
+    }
+
+    fn rollback(&mut self, savepoint: Self) {
+        let undo = self.undo.take().unwrap_or_default();
+        let mut history = std::mem::take(&mut self.history);
+        let mut bids = std::mem::take(&mut self.bids);
+        let mut asks = std::mem::take(&mut self.asks);
+        undo.restore(&mut bids, &mut asks);
+
+        *self = MatchingEngine {
+            bids,
+            asks,
+            ..savepoint
+        };
+        history.retain(|receipt| receipt.ordinal <= self.ordinal);
+        self.history = history;
+    }
+
+    fn commit(&mut self, _savepoint: Self) {
+        self.undo = None;
+    }
+
+    fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        MatchingEngine::check_invariants(self)
+    }
+}
+
+/// Milliseconds since the unix epoch
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     // reduce the warnings for naming tests
     #![allow(non_snake_case)]
 
     use super::*;
+    use crate::types::{Condition, ConditionalOrder, PegReference, Trail};
+    use proptest::prelude::*;
+
+    fn price_of(engine: &MatchingEngine, ordinal: u64) -> Option<u64> {
+        engine
+            .vectorised_orderbook()
+            .into_iter()
+            .find(|po| po.ordinal == ordinal)
+            .map(|po| po.price)
+    }
+
+    fn limit(engine: &mut MatchingEngine, side: Side, price: u64, signer: &str) -> Receipt {
+        engine
+            .process(Order {
+                price,
+                amount: 1,
+                side,
+                signer: signer.to_string(),
+            })
+            .unwrap()
+    }
+
+    fn pegged(side: Side, reference: PegReference, offset: i64, signer: &str) -> PeggedOrder {
+        PeggedOrder {
+            order: Order {
+                price: 0,
+                amount: 1,
+                side,
+                signer: signer.to_string(),
+            },
+            reference,
+            offset,
+            limit: None,
+        }
+    }
 
     #[test]
     fn test_MatchingEngine_process_partially_match_order() {
@@ -236,7 +936,9 @@ mod tests {
                 remaining: 0,
                 side: Side::Sell,
                 signer: "ALICE".to_string(),
-                ordinal: 1
+                ordinal: 1,
+                sequence: 1,
+                condition: None
             }]
         );
 
@@ -277,7 +979,9 @@ mod tests {
                 remaining: 0,
                 side: Side::Sell,
                 signer: "ALICE".to_string(),
-                ordinal: 1
+                ordinal: 1,
+                sequence: 1,
+                condition: None
             }]
         );
 
@@ -314,17 +1018,22 @@ mod tests {
             bob_receipt.matches,
             vec![PartialOrder {
                 price: 10,
-                amount: 3,
+                amount: 1,
                 remaining: 2,
                 side: Side::Sell,
                 signer: "ALICE".to_string(),
-                ordinal: 1
+                ordinal: 1,
+                sequence: 1,
+                condition: None
             }]
         );
 
-        // A sell order with an updated remaining is added to the book
+        // Only the units taken are matched, the sell order stays in the book with an updated remaining
         assert_eq!(matching_engine.asks.len(), 1);
         assert!(matching_engine.bids.is_empty());
+        let resting = matching_engine.asks[&10].peek().unwrap();
+        assert_eq!((resting.amount, resting.remaining), (3, 2));
+        assert_eq!(matching_engine.check_invariants(), Ok(()));
     }
 
     #[test]
@@ -371,7 +1080,9 @@ mod tests {
                     remaining: 0,
                     side: Side::Sell,
                     signer: "ALICE".to_string(),
-                    ordinal: 1
+                    ordinal: 1,
+                    sequence: 1,
+                    condition: None
                 },
                 PartialOrder {
                     price: 10,
@@ -379,7 +1090,9 @@ mod tests {
                     remaining: 0,
                     side: Side::Sell,
                     signer: "CHARLIE".to_string(),
-                    ordinal: 2
+                    ordinal: 2,
+                    sequence: 2,
+                    condition: None
                 }
             ]
         );
@@ -431,7 +1144,9 @@ mod tests {
                 remaining: 0,
                 side: Side::Sell,
                 signer: "CHARLIE".to_string(),
-                ordinal: 2
+                ordinal: 2,
+                sequence: 2,
+                condition: None
             }]
         );
         // A fully matched order doesn't remain in the book
@@ -504,4 +1219,774 @@ mod tests {
         assert_eq!(receipt.ordinal, matching_engine.ordinal);
         assert_eq!(matching_engine.ordinal, 3);
     }
+
+    #[test]
+    fn test_MatchingEngine_cancel_removes_resting_order() {
+        let mut matching_engine = MatchingEngine::new();
+
+        matching_engine
+            .process(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        matching_engine
+            .process(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        let cancelled = matching_engine.cancel(1).unwrap();
+        assert_eq!(cancelled.signer, "ALICE".to_string());
+        assert!(!matching_engine.is_resting(1));
+        assert!(matching_engine.is_resting(2));
+
+        // Cancelling the last order of a price level removes the level
+        assert!(matching_engine.cancel(2).is_some());
+        assert!(matching_engine.asks.is_empty());
+        assert_eq!(matching_engine.cancel(2), None);
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_triggers_stops() {
+        let mut matching_engine = MatchingEngine::new();
+
+        let sell_stop = matching_engine.submit_stop(StopOrder {
+            order: Order {
+                price: 8,
+                amount: 1,
+                side: Side::Sell,
+                signer: "CHARLIE".to_string(),
+            },
+            trigger: 9,
+        });
+        let buy_stop = matching_engine.submit_stop(StopOrder {
+            order: Order {
+                price: 12,
+                amount: 1,
+                side: Side::Buy,
+                signer: "CHARLIE".to_string(),
+            },
+            trigger: 11,
+        });
+        assert_eq!((sell_stop, buy_stop), (1, 2));
+
+        matching_engine
+            .process(Order {
+                price: 9,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        // Resting orders don't trigger stops, only trades do
+        assert!(matching_engine.take_triggered().is_empty());
+
+        matching_engine
+            .process(Order {
+                price: 9,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        let triggered = matching_engine.take_triggered();
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].ordinal, sell_stop);
+        assert_eq!(triggered[0].execution, Execution::Limit);
+        assert_eq!(matching_engine.stops.len(), 1);
+        assert!(matching_engine.cancel_stop(buy_stop).is_some());
+        assert!(matching_engine.stops.is_empty());
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_trails_sell_stop() {
+        let mut matching_engine = MatchingEngine::new();
+
+        let trade = |engine: &mut MatchingEngine, price: u64| {
+            engine
+                .process(Order {
+                    price,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                })
+                .unwrap();
+            engine
+                .process(Order {
+                    price,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                })
+                .unwrap();
+        };
+
+        trade(&mut matching_engine, 100);
+        assert_eq!(matching_engine.last_price, Some(100));
+
+        let stop = matching_engine.submit_trailing_stop(TrailingStop {
+            order: Order {
+                price: 0,
+                amount: 1,
+                side: Side::Sell,
+                signer: "CHARLIE".to_string(),
+            },
+            trail: Trail::Amount(5),
+            execution: Execution::Market,
+        });
+        assert_eq!(matching_engine.trailing_stop_trigger(stop), Some(95));
+
+        // The trigger follows rising prices
+        trade(&mut matching_engine, 110);
+        assert_eq!(matching_engine.trailing_stop_trigger(stop), Some(105));
+
+        // but stays put when they fall back
+        trade(&mut matching_engine, 107);
+        assert_eq!(matching_engine.trailing_stop_trigger(stop), Some(105));
+        assert!(matching_engine.take_triggered().is_empty());
+
+        trade(&mut matching_engine, 105);
+        assert_eq!(matching_engine.trailing_stop_trigger(stop), None);
+
+        let triggered = matching_engine.take_triggered();
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].ordinal, stop);
+        assert_eq!(triggered[0].execution, Execution::Market);
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_trails_buy_stop_by_percentage() {
+        let mut matching_engine = MatchingEngine::new();
+
+        let stop = matching_engine.submit_trailing_stop(TrailingStop {
+            order: Order {
+                price: 200,
+                amount: 1,
+                side: Side::Buy,
+                signer: "CHARLIE".to_string(),
+            },
+            trail: Trail::BasisPoints(1_000),
+            execution: Execution::Limit,
+        });
+        // Without any trades the order price is the anchor
+        assert_eq!(matching_engine.trailing_stop_trigger(stop), Some(220));
+
+        matching_engine
+            .process(Order {
+                price: 150,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        matching_engine
+            .process(Order {
+                price: 150,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        // The trigger follows falling prices
+        assert_eq!(matching_engine.trailing_stop_trigger(stop), Some(165));
+        assert!(matching_engine.cancel_stop(stop).is_some());
+        assert_eq!(matching_engine.trailing_stop_trigger(stop), None);
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_market_discards_remainder() {
+        let mut matching_engine = MatchingEngine::new();
+
+        matching_engine
+            .process(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        matching_engine
+            .process(Order {
+                price: 20,
+                amount: 1,
+                side: Side::Sell,
+                signer: "CHARLIE".to_string(),
+            })
+            .unwrap();
+
+        let market = Order {
+            price: 0,
+            amount: 3,
+            side: Side::Buy,
+            signer: "BOB".to_string(),
+        };
+        assert_eq!(matching_engine.market_cost(&market), Ok(30));
+
+        let receipt = matching_engine.process_market(market).unwrap();
+        assert_eq!(receipt.matches.len(), 2);
+        assert!(matching_engine.asks.is_empty());
+        assert!(matching_engine.bids.is_empty());
+    }
+
+    #[test]
+    fn test_MatchingEngine_market_cost_reports_overflow() {
+        let mut matching_engine = MatchingEngine::new();
+
+        for signer in ["ALICE", "CHARLIE"] {
+            matching_engine
+                .process(Order {
+                    price: u64::MAX,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: signer.to_string(),
+                })
+                .unwrap();
+        }
+
+        let market = |amount| Order {
+            price: 0,
+            amount,
+            side: Side::Buy,
+            signer: "BOB".to_string(),
+        };
+        assert_eq!(matching_engine.market_cost(&market(1)), Ok(u64::MAX));
+        assert_eq!(
+            matching_engine.market_cost(&market(2)),
+            Err(AccountError::ArithmeticOverflow)
+        );
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_pegged_follows_primary_and_loses_priority() {
+        let mut matching_engine = MatchingEngine::new();
+
+        limit(&mut matching_engine, Side::Buy, 10, "ALICE");
+        limit(&mut matching_engine, Side::Sell, 20, "ALICE");
+
+        let bob = matching_engine
+            .process_pegged(pegged(Side::Buy, PegReference::Primary, 0, "BOB"))
+            .unwrap()
+            .ordinal;
+        assert_eq!(price_of(&matching_engine, bob), Some(10));
+
+        // A better bid moves the peg up behind it
+        let charlie = limit(&mut matching_engine, Side::Buy, 11, "CHARLIE").ordinal;
+        assert_eq!(price_of(&matching_engine, bob), Some(11));
+
+        assert_eq!(
+            matching_engine.bids[&11].peek().unwrap().signer,
+            "CHARLIE".to_string()
+        );
+
+        // Without CHARLIE's bid the peg drops back to the best bid, behind ALICE
+        assert!(matching_engine.cancel(charlie).is_some());
+        assert_eq!(price_of(&matching_engine, bob), Some(10));
+        assert_eq!(
+            matching_engine.bids[&10].peek().unwrap().signer,
+            "ALICE".to_string()
+        );
+        assert!(matching_engine.pegs.contains_key(&bob));
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_pegged_mid_with_offset_and_limit() {
+        let mut matching_engine = MatchingEngine::new();
+
+        // No reference price without a book
+        assert_eq!(
+            matching_engine.process_pegged(pegged(Side::Buy, PegReference::Mid, 0, "BOB")),
+            Err(AccountError::NoReferencePrice)
+        );
+
+        limit(&mut matching_engine, Side::Buy, 10, "ALICE");
+        limit(&mut matching_engine, Side::Sell, 20, "ALICE");
+
+        let bob = matching_engine
+            .process_pegged(pegged(Side::Buy, PegReference::Mid, -1, "BOB"))
+            .unwrap()
+            .ordinal;
+        let charlie = matching_engine
+            .process_pegged(PeggedOrder {
+                limit: Some(13),
+                ..pegged(Side::Buy, PegReference::Mid, 0, "CHARLIE")
+            })
+            .unwrap()
+            .ordinal;
+        assert_eq!(price_of(&matching_engine, bob), Some(14));
+        assert_eq!(price_of(&matching_engine, charlie), Some(13));
+
+        // A higher bid pulls the mid up, the capped order stays at its limit
+        let dave = limit(&mut matching_engine, Side::Buy, 12, "DAVE").ordinal;
+        assert_eq!(price_of(&matching_engine, bob), Some(15));
+        assert_eq!(price_of(&matching_engine, charlie), Some(13));
+
+        assert!(matching_engine.cancel(dave).is_some());
+        assert_eq!(price_of(&matching_engine, bob), Some(14));
+
+        // Filled pegged orders are forgotten
+        limit(&mut matching_engine, Side::Sell, 14, "EVE");
+        assert!(!matching_engine.pegs.contains_key(&bob));
+        assert!(matching_engine.pegs.contains_key(&charlie));
+    }
+
+    #[test]
+    fn test_MatchingEngine_reprice_pegs_never_crosses() {
+        let mut matching_engine = MatchingEngine::new();
+
+        limit(&mut matching_engine, Side::Buy, 10, "ALICE");
+        limit(&mut matching_engine, Side::Sell, 20, "ALICE");
+
+        let bob = matching_engine
+            .process_pegged(pegged(Side::Buy, PegReference::Primary, 3, "BOB"))
+            .unwrap()
+            .ordinal;
+        let charlie = matching_engine
+            .process_pegged(pegged(Side::Sell, PegReference::Primary, -3, "CHARLIE"))
+            .unwrap()
+            .ordinal;
+        assert_eq!(price_of(&matching_engine, bob), Some(13));
+        assert_eq!(price_of(&matching_engine, charlie), Some(17));
+
+        // BOB would follow the new bid to 19 but stops short of CHARLIE's ask
+        let receipt = limit(&mut matching_engine, Side::Buy, 16, "DAVE");
+        assert!(receipt.matches.is_empty());
+        assert_eq!(price_of(&matching_engine, bob), Some(16));
+        assert_eq!(price_of(&matching_engine, charlie), Some(17));
+        assert_eq!(matching_engine.best_bid(), Some(16));
+        assert_eq!(matching_engine.best_ask(), Some(17));
+    }
+
+    fn conditional(
+        engine: &mut MatchingEngine,
+        side: Side,
+        amount: u64,
+        condition: Condition,
+        signer: &str,
+    ) -> Receipt {
+        engine
+            .process_conditional(ConditionalOrder {
+                order: Order {
+                    price: 10,
+                    amount,
+                    side,
+                    signer: signer.to_string(),
+                },
+                condition,
+            })
+            .unwrap()
+    }
+
+    fn sell(engine: &mut MatchingEngine, amount: u64, signer: &str) -> Receipt {
+        engine
+            .process(Order {
+                price: 10,
+                amount,
+                side: Side::Sell,
+                signer: signer.to_string(),
+            })
+            .unwrap()
+    }
+
+    fn buy(engine: &mut MatchingEngine, amount: u64, signer: &str) -> Receipt {
+        engine
+            .process(Order {
+                price: 10,
+                amount,
+                side: Side::Buy,
+                signer: signer.to_string(),
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_skips_resting_all_or_none_keeping_priority() {
+        let mut matching_engine = MatchingEngine::new();
+
+        sell(&mut matching_engine, 2, "ALICE");
+        conditional(
+            &mut matching_engine,
+            Side::Sell,
+            4,
+            Condition::AllOrNone,
+            "BOB",
+        );
+        sell(&mut matching_engine, 3, "CHARLIE");
+
+        // Only 3 units are left for BOB after ALICE, so his order is skipped for CHARLIE's
+        let receipt = buy(&mut matching_engine, 5, "DAVE");
+        let signers: Vec<&str> = receipt.matches.iter().map(|m| m.signer.as_str()).collect();
+        assert_eq!(signers, vec!["ALICE", "CHARLIE"]);
+        assert!(receipt.matches.iter().all(|m| m.remaining == 0));
+        assert!(matching_engine.bids.is_empty());
+
+        // BOB is still first in line
+        sell(&mut matching_engine, 1, "CHARLIE");
+        let receipt = buy(&mut matching_engine, 4, "EVE");
+        assert_eq!(receipt.matches.len(), 1);
+        assert_eq!(receipt.matches[0].signer, "BOB".to_string());
+        assert_eq!(receipt.matches[0].remaining, 0);
+        assert_eq!(matching_engine.asks[&10].len(), 1);
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_all_or_none_partially_fills_normal_orders() {
+        let mut matching_engine = MatchingEngine::new();
+
+        sell(&mut matching_engine, 2, "ALICE");
+        sell(&mut matching_engine, 2, "CHARLIE");
+
+        let receipt = conditional(
+            &mut matching_engine,
+            Side::Buy,
+            3,
+            Condition::AllOrNone,
+            "DAVE",
+        );
+        assert_eq!(receipt.matches.len(), 2);
+        assert_eq!(receipt.matches[1].signer, "CHARLIE".to_string());
+        assert_eq!(receipt.matches[1].remaining, 1);
+        assert!(matching_engine.bids.is_empty());
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_all_or_none_rests_when_not_fillable() {
+        let mut matching_engine = MatchingEngine::new();
+
+        sell(&mut matching_engine, 2, "ALICE");
+
+        let receipt = conditional(
+            &mut matching_engine,
+            Side::Buy,
+            3,
+            Condition::AllOrNone,
+            "DAVE",
+        );
+        assert!(receipt.matches.is_empty());
+        assert_eq!(matching_engine.asks[&10].len(), 1);
+
+        let resting = matching_engine.bids[&10].peek().unwrap();
+        assert_eq!(resting.remaining, 3);
+        assert_eq!(resting.condition, Some(Condition::AllOrNone));
+
+        // A sell that can fill it completely trades with it
+        let receipt = sell(&mut matching_engine, 3, "CHARLIE");
+        assert_eq!(receipt.matches.len(), 1);
+        assert_eq!(receipt.matches[0].signer, "DAVE".to_string());
+        assert!(matching_engine.bids.is_empty());
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_minimum_quantity() {
+        let mut matching_engine = MatchingEngine::new();
+
+        sell(&mut matching_engine, 2, "ALICE");
+
+        // Only 2 units available, less than the minimum of 3
+        let receipt = conditional(
+            &mut matching_engine,
+            Side::Buy,
+            5,
+            Condition::MinimumQuantity(3),
+            "DAVE",
+        );
+        assert!(receipt.matches.is_empty());
+        assert_eq!(matching_engine.bids[&10].len(), 1);
+
+        // Resting, a fill of 2 is still below the minimum
+        let receipt = sell(&mut matching_engine, 2, "CHARLIE");
+        assert!(receipt.matches.is_empty());
+
+        // 4 units can trade at once
+        let receipt = conditional(
+            &mut matching_engine,
+            Side::Buy,
+            5,
+            Condition::MinimumQuantity(3),
+            "EVE",
+        );
+        assert_eq!(receipt.matches.len(), 2);
+        assert!(matching_engine.asks.is_empty());
+
+        // What's left of EVE's order is below the minimum, so it can be filled by anything
+        assert_eq!(matching_engine.bids[&10].len(), 2);
+        let receipt = sell(&mut matching_engine, 1, "ALICE");
+        assert_eq!(receipt.matches.len(), 1);
+        assert_eq!(receipt.matches[0].signer, "EVE".to_string());
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_sell_matches_highest_bids_first() {
+        let mut matching_engine = MatchingEngine::new();
+
+        limit(&mut matching_engine, Side::Buy, 9, "ALICE");
+        limit(&mut matching_engine, Side::Buy, 11, "BOB");
+        limit(&mut matching_engine, Side::Buy, 10, "CHARLIE");
+
+        let receipt = matching_engine
+            .process(Order {
+                price: 9,
+                amount: 2,
+                side: Side::Sell,
+                signer: "DAVE".to_string(),
+            })
+            .unwrap();
+
+        let prices: Vec<u64> = receipt.matches.iter().map(|po| po.price).collect();
+        assert_eq!(prices, vec![11, 10]);
+        assert_eq!(matching_engine.best_bid(), Some(9));
+    }
+
+    #[test]
+    fn test_MatchingEngine_process_full_matches_reduce_remaining_across_levels() {
+        let mut matching_engine = MatchingEngine::new();
+
+        for (price, signer) in [(10, "ALICE"), (11, "BOB"), (12, "CHARLIE")] {
+            matching_engine
+                .process(Order {
+                    price,
+                    amount: 2,
+                    side: Side::Sell,
+                    signer: signer.to_string(),
+                })
+                .unwrap();
+        }
+
+        // ALICE's order is used up, the remaining 3 units come from BOB and CHARLIE
+        let receipt = matching_engine
+            .process(Order {
+                price: 12,
+                amount: 5,
+                side: Side::Buy,
+                signer: "DAVE".to_string(),
+            })
+            .unwrap();
+
+        let fills: Vec<(u64, u64)> = receipt
+            .matches
+            .iter()
+            .map(|m| (m.price, m.amount))
+            .collect();
+        assert_eq!(fills, vec![(10, 2), (11, 2), (12, 1)]);
+        assert!(matching_engine.bids.is_empty());
+        let resting = matching_engine.asks[&12].peek().unwrap();
+        assert_eq!((resting.amount, resting.remaining), (2, 1));
+        assert_eq!(matching_engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_MatchingEngine_new_with_orderbook_sequences_orders() {
+        let order = |price, side| Order {
+            price,
+            amount: 2,
+            side,
+            signer: "ALICE".to_string(),
+        };
+        let matching_engine = MatchingEngine::new_with_orderbook(
+            vec![order(12, Side::Sell)],
+            vec![order(10, Side::Buy)],
+        );
+
+        let ask = matching_engine.asks[&12].peek().unwrap();
+        assert_eq!((ask.ordinal, ask.remaining), (1, 2));
+        let bid = matching_engine.bids[&10].peek().unwrap();
+        assert_eq!((bid.ordinal, bid.remaining), (2, 2));
+        assert_eq!(matching_engine.ordinal, 2);
+        assert_eq!(matching_engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_MatchingEngine_rollback_undoes_orders_since_savepoint() {
+        let mut matching_engine = MatchingEngine::new();
+        sell(&mut matching_engine, 3, "ALICE");
+        buy(&mut matching_engine, 1, "BOB");
+        let savepoint = matching_engine.savepoint();
+        let orderbook = matching_engine.vectorised_orderbook();
+
+        buy(&mut matching_engine, 4, "CHARLIE");
+        assert_eq!(matching_engine.history.len(), 3);
+
+        matching_engine.rollback(savepoint);
+        assert_eq!(matching_engine.vectorised_orderbook(), orderbook);
+        assert_eq!(matching_engine.ordinal, 2);
+        // The history from before the savepoint is kept
+        assert_eq!(matching_engine.history.len(), 2);
+        assert_eq!(matching_engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_MatchingEngine_rollback_restores_the_levels_changed_since_savepoint() {
+        let mut matching_engine = MatchingEngine::new();
+        for price in [10, 11, 12] {
+            limit(&mut matching_engine, Side::Sell, price, "ALICE");
+        }
+        let bob = limit(&mut matching_engine, Side::Buy, 8, "BOB").ordinal;
+        let charlie = matching_engine
+            .process_pegged(pegged(Side::Buy, PegReference::Primary, 0, "CHARLIE"))
+            .unwrap()
+            .ordinal;
+        let orderbook = matching_engine.vectorised_orderbook();
+        let ordinal = matching_engine.ordinal;
+
+        let savepoint = matching_engine.savepoint();
+        // Sweep two levels, move the pegged bid up to a new level and cancel another
+        matching_engine
+            .process_market(Order {
+                price: 0,
+                amount: 2,
+                side: Side::Buy,
+                signer: "DAVE".to_string(),
+            })
+            .unwrap();
+        limit(&mut matching_engine, Side::Buy, 9, "DAVE");
+        assert_eq!(price_of(&matching_engine, charlie), Some(9));
+        matching_engine.cancel(bob);
+
+        matching_engine.rollback(savepoint);
+        assert_eq!(matching_engine.vectorised_orderbook(), orderbook);
+        assert_eq!(matching_engine.ordinal, ordinal);
+        assert_eq!(price_of(&matching_engine, charlie), Some(8));
+        assert!(matching_engine.pegs.contains_key(&charlie));
+        assert_eq!(matching_engine.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_MatchingEngine_check_invariants_finds_violations() {
+        let mut matching_engine = MatchingEngine::new();
+        limit(&mut matching_engine, Side::Sell, 10, "ALICE");
+        limit(&mut matching_engine, Side::Buy, 8, "BOB");
+        assert_eq!(matching_engine.check_invariants(), Ok(()));
+
+        // A bid moved through the ask without trading
+        let mut crossed = matching_engine.bids.remove(&8).unwrap().into_vec();
+        crossed[0].price = 11;
+        matching_engine.bids.insert(11, crossed.into());
+        assert_eq!(
+            matching_engine.check_invariants(),
+            Err(InvariantViolation::CrossedBook { bid: 2, ask: 1 })
+        );
+        matching_engine.cancel(2);
+
+        // Units that appeared out of nowhere
+        let mut asks = matching_engine.asks.remove(&10).unwrap().into_vec();
+        asks[0].remaining = 2;
+        matching_engine.asks.insert(10, asks.into());
+        assert_eq!(
+            matching_engine.check_invariants(),
+            Err(InvariantViolation::QuantityMismatch {
+                ordinal: 1,
+                amount: 1,
+                filled: 0,
+                remaining: 2
+            })
+        );
+
+        matching_engine.asks.insert(12, BinaryHeap::new());
+        assert_eq!(
+            matching_engine.check_invariants(),
+            Err(InvariantViolation::InvalidLevel(12))
+        );
+    }
+
+    /// Anything that can be done to an engine
+    #[derive(Clone, Debug)]
+    enum Operation {
+        Limit(Order),
+        Market(Order),
+        Conditional(ConditionalOrder),
+        Pegged(PeggedOrder),
+        Stop(StopOrder),
+        Cancel(u64),
+    }
+
+    fn any_order() -> impl Strategy<Value = Order> {
+        (
+            1..20u64,
+            1..10u64,
+            prop_oneof![Just(Side::Buy), Just(Side::Sell)],
+            prop::sample::select(vec!["ALICE", "BOB", "CHARLIE"]),
+        )
+            .prop_map(|(price, amount, side, signer)| Order {
+                price,
+                amount,
+                side,
+                signer: signer.to_string(),
+            })
+    }
+
+    fn any_operation() -> impl Strategy<Value = Operation> {
+        let condition = prop_oneof![
+            Just(Condition::AllOrNone),
+            (1..6u64).prop_map(Condition::MinimumQuantity)
+        ];
+        let reference = prop_oneof![
+            Just(PegReference::Primary),
+            Just(PegReference::Market),
+            Just(PegReference::Mid)
+        ];
+
+        prop_oneof![
+            6 => any_order().prop_map(Operation::Limit),
+            1 => any_order().prop_map(Operation::Market),
+            2 => (any_order(), condition).prop_map(|(order, condition)| {
+                Operation::Conditional(ConditionalOrder { order, condition })
+            }),
+            1 => (any_order(), reference, -2..3i64).prop_map(|(order, reference, offset)| {
+                Operation::Pegged(PeggedOrder {
+                    order,
+                    reference,
+                    offset,
+                    limit: None,
+                })
+            }),
+            1 => any_order().prop_map(|order| Operation::Stop(StopOrder {
+                trigger: order.price,
+                order
+            })),
+            2 => (1..50u64).prop_map(Operation::Cancel),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_MatchingEngine_check_invariants_hold_for_any_sequence(
+            operations in prop::collection::vec(any_operation(), 1..80)
+        ) {
+            let mut matching_engine = MatchingEngine::new();
+
+            for operation in operations {
+                let _ = match operation {
+                    Operation::Limit(order) => matching_engine.process(order).map(|_| ()),
+                    Operation::Market(order) => matching_engine.process_market(order).map(|_| ()),
+                    Operation::Conditional(order) => {
+                        matching_engine.process_conditional(order).map(|_| ())
+                    }
+                    Operation::Pegged(pegged) => matching_engine.process_pegged(pegged).map(|_| ()),
+                    Operation::Stop(stop) => {
+                        matching_engine.submit_stop(stop);
+                        Ok(())
+                    }
+                    Operation::Cancel(ordinal) => {
+                        if matching_engine.cancel(ordinal).is_none() {
+                            matching_engine.cancel_stop(ordinal);
+                        }
+                        Ok(())
+                    }
+                };
+                // Released stops are processed the way the platform does
+                for triggered in matching_engine.take_triggered() {
+                    let _ = matching_engine.process(triggered.order);
+                }
+
+                prop_assert_eq!(matching_engine.check_invariants(), Ok(()));
+            }
+        }
+    }
 }
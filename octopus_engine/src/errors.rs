@@ -2,22 +2,226 @@ use std::{error::Error, fmt};
 
 use serde::{Deserialize, Serialize};
 
-/// An application-specific error type
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+use crate::types::Asset;
+
+/// An application-specific error type. Every variant has a stable [`AccountError::code`] for clients to tell
+/// errors apart by and a message for people to read, see [`OctopusError`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AccountError {
-    // Add variants here for account not found, account underfunded and account overfunded
+    /// There is no account of the signer
     NotFound(String),
+    /// Crediting the amount would overflow the balance of the signer
     OverFunded(String, u64),
+    /// The signer can't pay for what they asked for
     UnderFunded(String),
+    OrderNotFound(u64),
+    /// The order can't be placed as it was given
+    InvalidOrder(String),
+    NoReferencePrice,
+    Unsupported(String),
+    MarketNotFound(String),
+    /// The named part of the service stopped responding
+    Unavailable(String),
+    AlreadyExists(String),
+    Frozen(String),
+    Closed(String),
+    /// The account still holds funds or has open sub-accounts
+    NotEmpty(String),
+    /// The idempotency key was used for a different request
+    IdempotencyConflict(String),
+    /// The first request under the idempotency key is still being processed
+    RequestInProgress(String),
+    /// The signer isn't allowed to act on the account
+    Unauthorized(String),
+    /// An amount or a value is too large to be represented
+    ArithmeticOverflow,
+    /// The request is malformed, a body that doesn't parse or a missing parameter
+    InvalidRequest(String),
+    /// There is nothing at the path requested
+    UnknownRoute,
+    /// The path requested doesn't take the method it was requested with
+    MethodNotAllowed,
+    /// The body of the request is larger than the server accepts
+    PayloadTooLarge,
+}
+
+impl AccountError {
+    /// A machine-readable name of the kind of error that doesn't change between releases
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountError::NotFound(_) => "account_not_found",
+            AccountError::OverFunded(..) => "over_funded",
+            AccountError::UnderFunded(_) => "under_funded",
+            AccountError::OrderNotFound(_) => "order_not_found",
+            AccountError::InvalidOrder(_) => "invalid_order",
+            AccountError::NoReferencePrice => "no_reference_price",
+            AccountError::Unsupported(_) => "unsupported",
+            AccountError::MarketNotFound(_) => "market_not_found",
+            AccountError::Unavailable(_) => "unavailable",
+            AccountError::AlreadyExists(_) => "account_exists",
+            AccountError::Frozen(_) => "account_frozen",
+            AccountError::Closed(_) => "account_closed",
+            AccountError::NotEmpty(_) => "account_not_empty",
+            AccountError::IdempotencyConflict(_) => "idempotency_conflict",
+            AccountError::RequestInProgress(_) => "request_in_progress",
+            AccountError::Unauthorized(_) => "unauthorized",
+            AccountError::ArithmeticOverflow => "arithmetic_overflow",
+            AccountError::InvalidRequest(_) => "invalid_request",
+            AccountError::UnknownRoute => "unknown_route",
+            AccountError::MethodNotAllowed => "method_not_allowed",
+            AccountError::PayloadTooLarge => "payload_too_large",
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccountError::NotFound(signer) => write!(f, "There is no account of '{}'", signer),
+            AccountError::OverFunded(signer, amount) => write!(
+                f,
+                "Crediting {} would overflow the balance of '{}'",
+                amount, signer
+            ),
+            AccountError::UnderFunded(signer) => {
+                write!(f, "'{}' doesn't have enough funds available", signer)
+            }
+            AccountError::OrderNotFound(ordinal) => {
+                write!(f, "There is no open order {}", ordinal)
+            }
+            AccountError::InvalidOrder(reason) => write!(f, "Invalid order: {}", reason),
+            AccountError::NoReferencePrice => {
+                write!(f, "The market has no price to reference yet")
+            }
+            AccountError::Unsupported(what) => write!(f, "Unsupported: {}", what),
+            AccountError::MarketNotFound(market) => {
+                write!(f, "There is no market '{}'", market)
+            }
+            AccountError::Unavailable(what) => {
+                write!(f, "'{}' is unavailable, try again later", what)
+            }
+            AccountError::AlreadyExists(signer) => {
+                write!(f, "There already is an account of '{}'", signer)
+            }
+            AccountError::Frozen(signer) => write!(f, "The account of '{}' is frozen", signer),
+            AccountError::Closed(signer) => write!(f, "The account of '{}' is closed", signer),
+            AccountError::NotEmpty(signer) => write!(
+                f,
+                "The account of '{}' still holds funds or has open sub-accounts",
+                signer
+            ),
+            AccountError::IdempotencyConflict(key) => write!(
+                f,
+                "The idempotency key '{}' was used for a different request",
+                key
+            ),
+            AccountError::RequestInProgress(key) => write!(
+                f,
+                "The request under the idempotency key '{}' is still being processed",
+                key
+            ),
+            AccountError::Unauthorized(signer) => {
+                write!(f, "'{}' isn't allowed to act on the account", signer)
+            }
+            AccountError::ArithmeticOverflow => {
+                write!(f, "An amount or a value is too large to be represented")
+            }
+            AccountError::InvalidRequest(reason) => write!(f, "Invalid request: {}", reason),
+            AccountError::UnknownRoute => write!(f, "There is nothing at this path"),
+            AccountError::MethodNotAllowed => {
+                write!(f, "This path doesn't take the method requested")
+            }
+            AccountError::PayloadTooLarge => write!(f, "The request body is too large"),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct OctopusError(pub AccountError);
+impl Error for AccountError {}
+
+/// A broken invariant of the engine or the platform
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InvariantViolation {
+    /// The units filled and remaining of a resting order don't add up to its amount
+    QuantityMismatch {
+        ordinal: u64,
+        amount: u64,
+        filled: u64,
+        remaining: u64,
+    },
+    /// A resting bid and ask that could trade with each other
+    CrossedBook { bid: u64, ask: u64 },
+    /// A price level without orders or with orders of another price or side
+    InvalidLevel(u64),
+    /// An ordinal that was issued twice or out of sequence
+    OrdinalOutOfOrder(u64),
+    /// The balances of an asset in all accounts don't add up to its deposits minus its withdrawals
+    CashMismatch {
+        asset: Asset,
+        deposits: u128,
+        withdrawals: u128,
+        balances: u128,
+    },
+    /// The debit balances of an asset in the ledger don't add up to its credit balances
+    UnbalancedLedger {
+        asset: Asset,
+        debits: u128,
+        credits: u128,
+    },
+    /// The funds locked in an account don't match the holds of its resting orders
+    HoldMismatch {
+        signer: String,
+        asset: Asset,
+        held: u64,
+        locked: u64,
+    },
+    /// An order that left the book still has funds set aside
+    StaleHold(u64),
+    /// More of an asset is locked in an account than its balance
+    Overcommitted {
+        signer: String,
+        asset: Asset,
+        balance: u64,
+        locked: u64,
+    },
+}
+
+/// An error the way it's reported to clients
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OctopusError {
+    /// See [`AccountError::code`]
+    pub code: String,
+    pub message: String,
+}
+
+impl From<&AccountError> for OctopusError {
+    fn from(error: &AccountError) -> Self {
+        OctopusError {
+            code: error.code().to_string(),
+            message: error.to_string(),
+        }
+    }
+}
 
 impl fmt::Display for OctopusError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "There is an error: {:?}", self.0)
+        write!(f, "{}", self.message)
     }
 }
 
 impl Error for OctopusError {}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_OctopusError_from_AccountError() {
+        let error = OctopusError::from(&AccountError::UnderFunded("ALICE".to_string()));
+        assert_eq!(error.code, "under_funded");
+        assert_eq!(error.message, "'ALICE' doesn't have enough funds available");
+        assert_eq!(error.to_string(), error.message);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Basis points in a whole, a rate of 1 bps is 1/100th of a percent
+pub const BPS: u64 = 10_000;
+
+/// The fee rates in basis points of the resting order that made the liquidity and the incoming order that took it
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct FeeRates {
+    pub maker_bps: u64,
+    pub taker_bps: u64,
+}
+
+impl FeeRates {
+    /// The rate of the maker or the taker of a trade
+    pub fn rate(&self, maker: bool) -> u64 {
+        if maker {
+            self.maker_bps
+        } else {
+            self.taker_bps
+        }
+    }
+}
+
+/// Rates for signers that have traded at least `volume`, in units of the quote asset
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct FeeTier {
+    pub volume: u64,
+    pub rates: FeeRates,
+}
+
+/// The fees charged on trades: the base rates, cheaper tiers as signers trade more volume
+/// and overrides for individual signers that apply regardless of their volume.
+/// The default schedule doesn't charge anything.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    pub rates: FeeRates,
+    /// Ordered by volume, lowest first
+    pub tiers: Vec<FeeTier>,
+    pub overrides: HashMap<String, FeeRates>,
+}
+
+impl FeeSchedule {
+    /// A schedule charging the same maker and taker rates to everyone
+    pub fn new(maker_bps: u64, taker_bps: u64) -> Self {
+        FeeSchedule {
+            rates: FeeRates {
+                maker_bps,
+                taker_bps,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Adds the rates of signers that have traded at least `volume`
+    pub fn with_tier(mut self, volume: u64, maker_bps: u64, taker_bps: u64) -> Self {
+        let rates = FeeRates {
+            maker_bps,
+            taker_bps,
+        };
+        self.tiers.push(FeeTier { volume, rates });
+        self.tiers.sort_by_key(|tier| tier.volume);
+        self
+    }
+
+    /// Sets the rates of a signer, whatever they traded
+    pub fn with_override(mut self, signer: &str, maker_bps: u64, taker_bps: u64) -> Self {
+        let rates = FeeRates {
+            maker_bps,
+            taker_bps,
+        };
+        self.overrides.insert(signer.to_string(), rates);
+        self
+    }
+
+    /// The rates of a signer that has traded `volume` so far: their override,
+    /// the highest tier the volume reaches or the base rates
+    pub fn rates(&self, signer: &str, volume: u64) -> FeeRates {
+        if let Some(rates) = self.overrides.get(signer) {
+            return *rates;
+        }
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| volume >= tier.volume)
+            .map_or(self.rates, |tier| tier.rates)
+    }
+}
+
+/// The fee at a rate of `bps` on `amount`, rounded down and never more than the amount
+pub fn fee(amount: u64, bps: u64) -> u64 {
+    (amount as u128 * bps.min(BPS) as u128 / BPS as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn rates(maker_bps: u64, taker_bps: u64) -> FeeRates {
+        FeeRates {
+            maker_bps,
+            taker_bps,
+        }
+    }
+
+    #[test]
+    fn test_FeeSchedule_rates_by_tier_and_override() {
+        let schedule = FeeSchedule::new(10, 20)
+            .with_tier(10_000, 5, 15)
+            .with_tier(1_000, 8, 18)
+            .with_override("MARKET_MAKER", 0, 5);
+
+        assert_eq!(schedule.rates("ALICE", 0), rates(10, 20));
+        assert_eq!(schedule.rates("ALICE", 999), rates(10, 20));
+        assert_eq!(schedule.rates("ALICE", 1_000), rates(8, 18));
+        assert_eq!(schedule.rates("ALICE", 50_000), rates(5, 15));
+        assert_eq!(schedule.rates("MARKET_MAKER", 0), rates(0, 5));
+        assert_eq!(
+            FeeSchedule::default().rates("ALICE", 50_000),
+            FeeRates::default()
+        );
+    }
+
+    #[test]
+    fn test_fee_rounds_down() {
+        assert_eq!(fee(1_000, 25), 2);
+        assert_eq!(fee(399, 25), 0);
+        assert_eq!(fee(u64::MAX, BPS), u64::MAX);
+        // More than the whole amount is never charged
+        assert_eq!(fee(100, 20_000), 100);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::types::{Order, StopOrder};
+
+/// The exits of a bracket order, placed for what its entry order has filled so far
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Bracket {
+    /// Limit order closing the position at a profit
+    pub take_profit: Order,
+    /// Stop order closing the position at a loss
+    pub stop_loss: StopOrder,
+    /// Units of the entry that are yet to fill
+    pub remaining: u64,
+    /// The one-cancels-other group of the exits placed for the fills so far
+    pub exits: Option<u64>,
+}
+
+/// Tracks which orders are linked to each other by their ordinal
+#[derive(Default, Debug, Clone)]
+pub struct OrderGroups {
+    /// The last group id
+    next_id: u64,
+    /// Members of each open one-cancels-other group
+    oco: HashMap<u64, Vec<u64>>,
+    /// The group each member ordinal belongs to
+    membership: HashMap<u64, u64>,
+    /// Brackets whose entry hasn't filled completely yet. Ordered by the entry ordinal.
+    brackets: BTreeMap<u64, Bracket>,
+}
+
+impl OrderGroups {
+    /// Returns an instance without any groups
+    pub fn new() -> Self {
+        OrderGroups {
+            next_id: 0,
+            oco: HashMap::new(),
+            membership: HashMap::new(),
+            brackets: BTreeMap::new(),
+        }
+    }
+
+    /// Opens an empty one-cancels-other group and returns its id
+    pub fn open_oco(&mut self) -> u64 {
+        self.next_id += 1;
+        self.oco.insert(self.next_id, vec![]);
+        self.next_id
+    }
+
+    /// Adds the order with this ordinal to an open group
+    pub fn join(&mut self, group: u64, ordinal: u64) {
+        if let Some(members) = self.oco.get_mut(&group) {
+            members.push(ordinal);
+            self.membership.insert(ordinal, group);
+        }
+    }
+
+    /// Whether the group hasn't been resolved by a fill or cancel yet
+    pub fn is_open(&self, group: u64) -> bool {
+        self.oco.contains_key(&group)
+    }
+
+    /// The group an ordinal is a member of
+    pub fn group_of(&self, ordinal: u64) -> Option<u64> {
+        self.membership.get(&ordinal).copied()
+    }
+
+    /// Closes the group of a member that filled or was cancelled and returns its siblings
+    pub fn resolve(&mut self, ordinal: u64) -> Vec<u64> {
+        let Some(group) = self.membership.remove(&ordinal) else {
+            return vec![];
+        };
+        let members = self.oco.remove(&group).unwrap_or_default();
+
+        members
+            .into_iter()
+            .filter(|m| *m != ordinal)
+            .inspect(|m| {
+                self.membership.remove(m);
+            })
+            .collect()
+    }
+
+    /// Closes a group as a whole and returns all of its members
+    pub fn close(&mut self, group: u64) -> Vec<u64> {
+        let members = self.oco.remove(&group).unwrap_or_default();
+        for member in &members {
+            self.membership.remove(member);
+        }
+        members
+    }
+
+    /// Holds the exits of a bracket until the entry with this ordinal has filled
+    pub fn add_bracket(&mut self, entry: u64, bracket: Bracket) {
+        self.brackets.insert(entry, bracket);
+    }
+
+    /// Ordinals of the bracket entries that are still waiting to fill
+    pub fn bracket_entries(&self) -> Vec<u64> {
+        self.brackets.keys().copied().collect()
+    }
+
+    /// The bracket of an entry that is still waiting to fill, for updating it
+    pub fn bracket_mut(&mut self, entry: u64) -> Option<&mut Bracket> {
+        self.brackets.get_mut(&entry)
+    }
+
+    /// Removes and returns the exits of a bracket entry
+    pub fn take_bracket(&mut self, entry: u64) -> Option<Bracket> {
+        self.brackets.remove(&entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_OrderGroups_resolve_returns_siblings() {
+        let mut groups = OrderGroups::new();
+
+        let group = groups.open_oco();
+        groups.join(group, 1);
+        groups.join(group, 2);
+        groups.join(group, 3);
+        assert_eq!(groups.group_of(2), Some(group));
+
+        assert_eq!(groups.resolve(2), vec![1, 3]);
+        assert!(!groups.is_open(group));
+        assert_eq!(groups.group_of(1), None);
+
+        // A resolved group has no siblings left to cancel
+        assert!(groups.resolve(1).is_empty());
+    }
+
+    #[test]
+    fn test_OrderGroups_resolve_ungrouped() {
+        let mut groups = OrderGroups::new();
+
+        assert!(groups.resolve(1).is_empty());
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::AccountError,
+    tx::Tx,
+    types::{DepositArgs, OrderArgs, Receipt, SendArgs, WithdrawArgs},
+};
+
+/// How long a key is remembered after its request was processed, 24 hours in milliseconds
+pub const IDEMPOTENCY_TTL: u64 = 24 * 60 * 60 * 1_000;
+
+/// A request that moves funds, which a client can retry safely by giving it an idempotency key
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum Request {
+    Deposit(DepositArgs),
+    Withdraw(WithdrawArgs),
+    Send(SendArgs),
+    Order(OrderArgs),
+}
+
+impl Request {
+    /// The key the client chose for the request, if any
+    pub fn idempotency_key(&self) -> Option<&str> {
+        match self {
+            Request::Deposit(args) => args.idempotency_key.as_deref(),
+            Request::Withdraw(args) => args.idempotency_key.as_deref(),
+            Request::Send(args) => args.idempotency_key.as_deref(),
+            Request::Order(args) => args.idempotency_key.as_deref(),
+        }
+    }
+
+    /// Who made the request, whose keys it's checked against
+    pub fn signer(&self) -> &str {
+        match self {
+            Request::Deposit(args) => args.actor(),
+            Request::Withdraw(args) => args.actor(),
+            Request::Send(args) => args.actor(),
+            Request::Order(args) => &args.signer,
+        }
+    }
+}
+
+/// What a [`Request`] resulted in: the transaction of a movement or the receipt of an order
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Outcome {
+    Tx(Tx),
+    Receipt(Receipt),
+}
+
+/// An idempotency key of a signer
+type Key = (String, String);
+
+#[derive(Debug, Clone)]
+struct Entry {
+    request: Request,
+    /// Nothing while the request is still being processed
+    outcome: Option<Outcome>,
+    /// When the outcome is forgotten, [`u64::MAX`] while the request is processed
+    expires_at: u64,
+}
+
+/// The requests each signer processed under their idempotency keys and the outcomes, until the keys expire.
+/// Only successful outcomes are kept: a request that failed changed nothing and can be processed again.
+/// A key stays claimed until its request is completed, and expired keys are dropped oldest first as new
+/// ones are claimed.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKeys {
+    entries: HashMap<Key, Entry>,
+    /// The keys with an outcome, in the order they expire
+    expiries: VecDeque<(u64, Key)>,
+    /// Milliseconds a key is remembered for
+    ttl: u64,
+}
+
+impl Default for IdempotencyKeys {
+    fn default() -> Self {
+        IdempotencyKeys::new(IDEMPOTENCY_TTL)
+    }
+}
+
+impl IdempotencyKeys {
+    /// Returns an instance that remembers keys for `ttl` milliseconds after their requests were processed
+    pub fn new(ttl: u64) -> Self {
+        IdempotencyKeys {
+            entries: HashMap::new(),
+            expiries: VecDeque::new(),
+            ttl,
+        }
+    }
+
+    /// Claims the key of the signer of the request at `now`. Returns the outcome of the first request under
+    /// the key if it was the same one, or nothing if the request hasn't been processed yet and should be now.
+    /// # Errors
+    /// The key was used for a different request, or the first request under it is still being processed
+    pub fn begin(
+        &mut self,
+        key: &str,
+        request: &Request,
+        now: u64,
+    ) -> Result<Option<Outcome>, AccountError> {
+        self.expire(now);
+
+        let key = (request.signer().to_string(), key.to_string());
+        match self.entries.get(&key) {
+            Some(entry) if &entry.request != request => {
+                Err(AccountError::IdempotencyConflict(key.1))
+            }
+            Some(entry) if entry.outcome.is_none() => Err(AccountError::RequestInProgress(key.1)),
+            Some(entry) => Ok(entry.outcome.clone()),
+            None => {
+                let entry = Entry {
+                    request: request.clone(),
+                    outcome: None,
+                    expires_at: u64::MAX,
+                };
+                self.entries.insert(key, entry);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Remembers the outcome of the request the signer claimed the key for until `ttl` after `now`,
+    /// or frees the key if the request failed or was abandoned
+    pub fn complete(&mut self, signer: &str, key: &str, outcome: Option<Outcome>, now: u64) {
+        let key = (signer.to_string(), key.to_string());
+        match outcome {
+            Some(outcome) => {
+                if let Some(entry) = self.entries.get_mut(&key) {
+                    let expires_at = now.saturating_add(self.ttl);
+                    entry.outcome = Some(outcome);
+                    entry.expires_at = expires_at;
+                    self.expiries.push_back((expires_at, key));
+                }
+            }
+            None => {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Forgets the outcomes that expired by `now`
+    fn expire(&mut self, now: u64) {
+        while let Some((expires_at, key)) = self.expiries.pop_front() {
+            if expires_at > now {
+                self.expiries.push_front((expires_at, key));
+                break;
+            }
+            // the key may have been claimed again since
+            if self
+                .entries
+                .get(&key)
+                .is_some_and(|entry| entry.expires_at == expires_at)
+            {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+    use crate::{tx::TxKind, types::Asset};
+
+    fn deposit(amount: u64) -> Request {
+        Request::Deposit(DepositArgs {
+            signer: "ALICE".to_string(),
+            amount,
+            asset: Asset::new("USD"),
+            idempotency_key: Some("retry-me".to_string()),
+            actor: None,
+        })
+    }
+
+    fn outcome() -> Outcome {
+        Outcome::Tx(Tx {
+            id: 1,
+            sequence: 1,
+            timestamp: 0,
+            reference: None,
+            kind: TxKind::Deposit {
+                account: "ALICE".to_string(),
+                asset: Asset::new("USD"),
+                amount: 10,
+            },
+        })
+    }
+
+    #[test]
+    fn test_IdempotencyKeys_replay_the_first_outcome() {
+        let mut keys = IdempotencyKeys::new(100);
+        assert_eq!(keys.begin("retry-me", &deposit(10), 0), Ok(None));
+        // Still being processed
+        assert_eq!(
+            keys.begin("retry-me", &deposit(10), 1),
+            Err(AccountError::RequestInProgress("retry-me".to_string()))
+        );
+
+        keys.complete("ALICE", "retry-me", Some(outcome()), 2);
+        assert_eq!(keys.begin("retry-me", &deposit(10), 3), Ok(Some(outcome())));
+        assert_eq!(
+            keys.begin("retry-me", &deposit(11), 4),
+            Err(AccountError::IdempotencyConflict("retry-me".to_string()))
+        );
+
+        // Expired keys can be used for anything again
+        assert_eq!(keys.begin("retry-me", &deposit(11), 102), Ok(None));
+    }
+
+    #[test]
+    fn test_IdempotencyKeys_claims_last_until_their_request_is_completed() {
+        let mut keys = IdempotencyKeys::new(100);
+        assert_eq!(keys.begin("retry-me", &deposit(10), 0), Ok(None));
+        assert_eq!(
+            keys.begin("retry-me", &deposit(10), 1_000),
+            Err(AccountError::RequestInProgress("retry-me".to_string()))
+        );
+
+        // Completed requests are remembered for the whole ttl from then on
+        keys.complete("ALICE", "retry-me", Some(outcome()), 1_000);
+        assert_eq!(
+            keys.begin("retry-me", &deposit(10), 1_099),
+            Ok(Some(outcome()))
+        );
+        assert_eq!(keys.begin("retry-me", &deposit(10), 1_100), Ok(None));
+    }
+
+    #[test]
+    fn test_IdempotencyKeys_forget_failed_requests() {
+        let mut keys = IdempotencyKeys::default();
+        assert_eq!(keys.begin("retry-me", &deposit(10), 0), Ok(None));
+        keys.complete("ALICE", "retry-me", None, 1);
+
+        assert_eq!(keys.begin("retry-me", &deposit(10), 2), Ok(None));
+    }
+
+    #[test]
+    fn test_IdempotencyKeys_are_kept_per_signer() {
+        let mut keys = IdempotencyKeys::new(100);
+        assert_eq!(keys.begin("retry-me", &deposit(10), 0), Ok(None));
+        keys.complete("ALICE", "retry-me", Some(outcome()), 0);
+
+        let bob = Request::Deposit(DepositArgs {
+            signer: "BOB".to_string(),
+            amount: 5,
+            asset: Asset::new("USD"),
+            idempotency_key: Some("retry-me".to_string()),
+            actor: None,
+        });
+        assert_eq!(keys.begin("retry-me", &bob, 1), Ok(None));
+        assert_eq!(keys.begin("retry-me", &deposit(10), 2), Ok(Some(outcome())));
+    }
+
+    #[test]
+    fn test_IdempotencyKeys_expire_oldest_first() {
+        let mut keys = IdempotencyKeys::new(100);
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            assert_eq!(keys.begin(key, &deposit(10), 0), Ok(None));
+            keys.complete("ALICE", key, Some(outcome()), i as u64 * 10);
+        }
+
+        keys.expire(110);
+        assert_eq!(keys.entries.len(), 1);
+        assert_eq!(keys.expiries.len(), 1);
+        assert_eq!(keys.begin("c", &deposit(10), 119), Ok(Some(outcome())));
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::Asset;
+
+/// The name of the system account that stands for cash outside of the platform
+pub const EXTERNAL: &str = "@external";
+
+/// The name of the system account that balance corrections are posted against
+pub const ADJUSTMENTS: &str = "@adjustments";
+
+/// The name of the system account that collects the fees charged by the platform
+pub const REVENUE: &str = "@revenue";
+
+/// The name of the system account that lends to accounts trading on margin
+pub const CREDIT: &str = "@credit";
+
+/// A named account of the ledger, either a user of the platform or one of its system accounts
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub enum LedgerAccount {
+    /// The funds the platform holds for a signer
+    User(String),
+    /// Cash outside of the platform, the other side of every deposit and withdrawal
+    External,
+    /// The other side of every correction made to a balance
+    Adjustments,
+    /// The platform's own funds, earned from fees
+    Revenue,
+    /// The other side of every loan to an account on margin and its repayment
+    Credit,
+}
+
+impl LedgerAccount {
+    /// Reads a ledger account from its name, system accounts are prefixed with `@`
+    pub fn parse(name: &str) -> Option<LedgerAccount> {
+        match name {
+            EXTERNAL => Some(LedgerAccount::External),
+            ADJUSTMENTS => Some(LedgerAccount::Adjustments),
+            REVENUE => Some(LedgerAccount::Revenue),
+            CREDIT => Some(LedgerAccount::Credit),
+            name if name.starts_with('@') || name.is_empty() => None,
+            name => Some(LedgerAccount::User(name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerAccount::User(signer) => write!(f, "{}", signer),
+            LedgerAccount::External => write!(f, "{}", EXTERNAL),
+            LedgerAccount::Adjustments => write!(f, "{}", ADJUSTMENTS),
+            LedgerAccount::Revenue => write!(f, "{}", REVENUE),
+            LedgerAccount::Credit => write!(f, "{}", CREDIT),
+        }
+    }
+}
+
+/// What kind of movement a journal entry records
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum EntryKind {
+    Deposit,
+    Withdrawal,
+    Transfer,
+    Settlement,
+    Adjustment,
+    Borrowing,
+    Repayment,
+}
+
+/// `amount` of an asset leaving one ledger account for another
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Movement {
+    pub from: LedgerAccount,
+    pub to: LedgerAccount,
+    pub asset: Asset,
+    pub amount: u64,
+}
+
+/// One line of a journal entry, debiting or crediting an account with an amount of an asset
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub asset: Asset,
+    pub debit: u64,
+    pub credit: u64,
+}
+
+/// A balanced set of postings: for every asset the debits add up to the credits
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub kind: EntryKind,
+    pub postings: Vec<Posting>,
+}
+
+impl JournalEntry {
+    /// Whether the debits of every asset equal its credits
+    pub fn is_balanced(&self) -> bool {
+        let mut sums: HashMap<&Asset, (u128, u128)> = HashMap::new();
+        for posting in &self.postings {
+            let sum = sums.entry(&posting.asset).or_default();
+            sum.0 += posting.debit as u128;
+            sum.1 += posting.credit as u128;
+        }
+        sums.values().all(|(debits, credits)| debits == credits)
+    }
+}
+
+/// The debits and credits posted to an account in one asset, netted into one of the two columns
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TrialBalanceLine {
+    pub account: LedgerAccount,
+    pub asset: Asset,
+    pub debit: u128,
+    pub credit: u128,
+}
+
+/// The balance of every account in the ledger
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TrialBalance {
+    pub lines: Vec<TrialBalanceLine>,
+}
+
+impl TrialBalance {
+    /// The total debit and credit balances of each asset
+    pub fn totals(&self) -> BTreeMap<Asset, (u128, u128)> {
+        let mut totals: BTreeMap<Asset, (u128, u128)> = BTreeMap::new();
+        for line in &self.lines {
+            let total = totals.entry(line.asset.clone()).or_default();
+            total.0 += line.debit;
+            total.1 += line.credit;
+        }
+        totals
+    }
+
+    /// Whether the debit balances of every asset equal its credit balances
+    pub fn is_balanced(&self) -> bool {
+        self.totals()
+            .values()
+            .all(|(debits, credits)| debits == credits)
+    }
+}
+
+/// A posting to one account with the balance of the account in that asset after it
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct LedgerLine {
+    pub entry: u64,
+    pub kind: EntryKind,
+    pub asset: Asset,
+    pub debit: u64,
+    pub credit: u64,
+    /// Credits minus debits so far, what the platform owes the account
+    pub balance: i128,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Totals {
+    debits: u128,
+    credits: u128,
+}
+
+impl Totals {
+    fn balance(&self) -> i128 {
+        self.credits as i128 - self.debits as i128
+    }
+}
+
+/// A double-entry ledger: a journal of balanced entries and the running totals of every account they posted to
+#[derive(Debug, Default, Clone)]
+pub struct Ledger {
+    journal: Vec<JournalEntry>,
+    totals: HashMap<(LedgerAccount, Asset), Totals>,
+}
+
+impl Ledger {
+    /// Returns an empty instance of the [`Ledger`] type
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Records the movements as one journal entry, debiting where each comes from and crediting where it goes.
+    /// The ledger doesn't judge whether an account can afford it, returns the id of the entry.
+    pub fn post(&mut self, kind: EntryKind, movements: &[Movement]) -> u64 {
+        let id = self.journal.len() as u64 + 1;
+        let postings: Vec<Posting> = movements
+            .iter()
+            .flat_map(|movement| {
+                [
+                    Posting {
+                        account: movement.from.clone(),
+                        asset: movement.asset.clone(),
+                        debit: movement.amount,
+                        credit: 0,
+                    },
+                    Posting {
+                        account: movement.to.clone(),
+                        asset: movement.asset.clone(),
+                        debit: 0,
+                        credit: movement.amount,
+                    },
+                ]
+            })
+            .collect();
+
+        for posting in &postings {
+            let totals = self
+                .totals
+                .entry((posting.account.clone(), posting.asset.clone()))
+                .or_default();
+            totals.debits += posting.debit as u128;
+            totals.credits += posting.credit as u128;
+        }
+        self.journal.push(JournalEntry { id, kind, postings });
+        id
+    }
+
+    /// Every entry posted so far in order
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
+    }
+
+    /// Credits minus debits of an asset in the account, 0 if nothing was ever posted to it
+    pub fn balance(&self, account: &LedgerAccount, asset: &Asset) -> i128 {
+        self.totals
+            .get(&(account.clone(), asset.clone()))
+            .map_or(0, Totals::balance)
+    }
+
+    /// The credits minus debits of every account and asset that has been posted to
+    pub fn balances(&self) -> impl Iterator<Item = (&LedgerAccount, &Asset, i128)> {
+        self.totals
+            .iter()
+            .map(|((account, asset), totals)| (account, asset, totals.balance()))
+    }
+
+    /// The net balance of every account and asset in the debit or credit column, ordered by account
+    pub fn trial_balance(&self) -> TrialBalance {
+        let sorted: BTreeMap<_, _> = self.totals.iter().collect();
+        let lines = sorted
+            .into_iter()
+            .map(|((account, asset), totals)| {
+                let balance = totals.balance();
+                TrialBalanceLine {
+                    account: account.clone(),
+                    asset: asset.clone(),
+                    debit: if balance < 0 {
+                        balance.unsigned_abs()
+                    } else {
+                        0
+                    },
+                    credit: if balance > 0 { balance as u128 } else { 0 },
+                }
+            })
+            .collect();
+        TrialBalance { lines }
+    }
+
+    /// Every posting to the account in order, with its balance in the asset after each of them
+    pub fn account_ledger(&self, account: &LedgerAccount) -> Vec<LedgerLine> {
+        let mut balances: HashMap<&Asset, i128> = HashMap::new();
+        self.journal
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .postings
+                    .iter()
+                    .filter(|posting| &posting.account == account)
+                    .map(move |posting| (entry, posting))
+            })
+            .map(|(entry, posting)| {
+                let balance = balances.entry(&posting.asset).or_default();
+                *balance += posting.credit as i128 - posting.debit as i128;
+                LedgerLine {
+                    entry: entry.id,
+                    kind: entry.kind,
+                    asset: posting.asset.clone(),
+                    debit: posting.debit,
+                    credit: posting.credit,
+                    balance: *balance,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn usd() -> Asset {
+        Asset::new("USD")
+    }
+
+    fn user(signer: &str) -> LedgerAccount {
+        LedgerAccount::User(signer.to_string())
+    }
+
+    fn movement(from: LedgerAccount, to: LedgerAccount, amount: u64) -> Movement {
+        Movement {
+            from,
+            to,
+            asset: usd(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_Ledger_post_records_balanced_entries() {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            EntryKind::Deposit,
+            &[movement(LedgerAccount::External, user("ALICE"), 100)],
+        );
+        let id = ledger.post(
+            EntryKind::Transfer,
+            &[movement(user("ALICE"), user("BOB"), 30)],
+        );
+
+        assert_eq!(id, 2);
+        assert!(ledger.journal().iter().all(JournalEntry::is_balanced));
+        assert_eq!(ledger.balance(&user("ALICE"), &usd()), 70);
+        assert_eq!(ledger.balance(&user("BOB"), &usd()), 30);
+        assert_eq!(ledger.balance(&LedgerAccount::External, &usd()), -100);
+        assert_eq!(ledger.balance(&user("CAROL"), &usd()), 0);
+    }
+
+    #[test]
+    fn test_Ledger_trial_balance() {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            EntryKind::Deposit,
+            &[movement(LedgerAccount::External, user("ALICE"), 100)],
+        );
+        ledger.post(
+            EntryKind::Withdrawal,
+            &[movement(user("ALICE"), LedgerAccount::External, 40)],
+        );
+
+        let trial_balance = ledger.trial_balance();
+        assert_eq!(
+            trial_balance.lines,
+            vec![
+                TrialBalanceLine {
+                    account: user("ALICE"),
+                    asset: usd(),
+                    debit: 0,
+                    credit: 60,
+                },
+                TrialBalanceLine {
+                    account: LedgerAccount::External,
+                    asset: usd(),
+                    debit: 60,
+                    credit: 0,
+                },
+            ]
+        );
+        assert!(trial_balance.is_balanced());
+    }
+
+    #[test]
+    fn test_Ledger_account_ledger_runs_balance() {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            EntryKind::Deposit,
+            &[movement(LedgerAccount::External, user("ALICE"), 100)],
+        );
+        ledger.post(
+            EntryKind::Deposit,
+            &[movement(LedgerAccount::External, user("BOB"), 5)],
+        );
+        ledger.post(
+            EntryKind::Transfer,
+            &[movement(user("ALICE"), user("BOB"), 30)],
+        );
+
+        assert_eq!(
+            ledger.account_ledger(&user("ALICE")),
+            vec![
+                LedgerLine {
+                    entry: 1,
+                    kind: EntryKind::Deposit,
+                    asset: usd(),
+                    debit: 0,
+                    credit: 100,
+                    balance: 100,
+                },
+                LedgerLine {
+                    entry: 3,
+                    kind: EntryKind::Transfer,
+                    asset: usd(),
+                    debit: 30,
+                    credit: 0,
+                    balance: 70,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_LedgerAccount_parse() {
+        assert_eq!(
+            LedgerAccount::parse("@external"),
+            Some(LedgerAccount::External)
+        );
+        assert_eq!(LedgerAccount::parse("ALICE"), Some(user("ALICE")));
+        assert_eq!(LedgerAccount::parse("@fees"), None);
+    }
+}
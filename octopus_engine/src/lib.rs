@@ -1,6 +1,18 @@
 pub mod accounting;
+pub mod candles;
 pub mod engine;
 pub mod errors;
+pub mod fees;
+pub mod groups;
+pub mod idempotency;
+pub mod ledger;
+pub mod liquidation;
+pub mod margin;
+pub mod market;
+pub mod matcher;
+pub mod positions;
+pub mod statement;
+pub mod ticker;
 pub mod trading_platform;
 pub mod tx;
 pub mod types;
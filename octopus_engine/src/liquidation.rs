@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fees::BPS,
+    margin::{MarginReport, MarginStatus},
+};
+
+/// A step taken to liquidate an account in a margin call, logged as a transaction of its own.
+/// The sales and repayments it makes are logged as the trades and repayments they are.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum LiquidationStep {
+    /// The account was found in a margin call
+    Started {
+        equity: i128,
+        maintenance_margin: u128,
+    },
+    /// Its resting orders were cancelled to release the funds set aside for them
+    OrdersCancelled { ordinals: Vec<u64> },
+    /// A reduce-only market order sold `filled` of the `amount` units it offered
+    Sold {
+        market: String,
+        ordinal: u64,
+        amount: u64,
+        filled: u64,
+    },
+    /// The account is healthy again, or there was nothing more to sell
+    Finished {
+        equity: i128,
+        maintenance_margin: u128,
+        status: MarginStatus,
+    },
+}
+
+impl LiquidationStep {
+    /// The step that starts the liquidation of the account in the report
+    pub fn started(report: &MarginReport) -> Self {
+        LiquidationStep::Started {
+            equity: report.equity,
+            maintenance_margin: report.maintenance_margin,
+        }
+    }
+
+    /// The step that ends the liquidation of the account in the report
+    pub fn finished(report: &MarginReport) -> Self {
+        LiquidationStep::Finished {
+            equity: report.equity,
+            maintenance_margin: report.maintenance_margin,
+            status: report.status,
+        }
+    }
+}
+
+/// Whether liquidating the account in the report can help: it's in a margin call and holds something to sell
+pub fn needs_liquidation(report: &MarginReport) -> bool {
+    report.status == MarginStatus::MarginCall && report.exposure > 0
+}
+
+/// How many of the `held` units valued at `price` have to be sold for the account in the report to keep
+/// its maintenance margin with the rest, if they sell at that price. Selling for less takes more units,
+/// so it's at least one unit while any are held and everything once the equity is gone.
+pub fn units_to_sell(report: &MarginReport, held: u64, price: u64) -> u64 {
+    if report.equity <= 0 || report.maintenance_bps == 0 || price == 0 {
+        return held;
+    }
+    // (exposure - units * price) * maintenance / BPS <= equity
+    let required = report
+        .exposure
+        .saturating_mul(report.maintenance_bps as u128);
+    let covered = (report.equity as u128).saturating_mul(BPS as u128);
+    let per_unit = price as u128 * report.maintenance_bps as u128;
+    let units = required.saturating_sub(covered).div_ceil(per_unit);
+    units.clamp(1, held as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+    use crate::{margin::MarginTerms, types::Asset};
+
+    fn margin(debt: u64, exposure: u128) -> MarginReport {
+        let terms = MarginTerms {
+            asset: Asset::new("USD"),
+            credit_limit: 100,
+            maintenance_bps: 2_500,
+        };
+        MarginReport::new("ALICE", &terms, debt, 0, exposure, 0)
+    }
+
+    #[test]
+    fn test_units_to_sell_restores_the_maintenance_margin() {
+        // 15 units at 8 against a debt of 100: equity 20, the 25% of 120 it has to keep is 30
+        let report = margin(100, 120);
+        assert!(needs_liquidation(&report));
+        let units = units_to_sell(&report, 15, 8);
+        assert_eq!(units, 5);
+        // the 10 units left ask for 20, which the equity covers
+        assert!((15 - units) as u128 * 8 * 2_500 / BPS as u128 <= 20);
+
+        // nothing is left of the equity
+        assert_eq!(units_to_sell(&margin(130, 120), 15, 8), 15);
+        assert!(!needs_liquidation(&margin(10, 0)));
+    }
+}
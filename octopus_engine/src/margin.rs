@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{fees::BPS, types::Asset};
+
+/// The credit an account can trade on and the equity it has to keep while it owes anything
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct MarginTerms {
+    /// The asset the account borrows, its equity is valued in it
+    pub asset: Asset,
+    /// The most of the asset the account can owe
+    pub credit_limit: u64,
+    /// The equity the account has to keep, in basis points of the value of what it holds in other assets
+    pub maintenance_bps: u64,
+}
+
+/// Whether an account keeps the equity its margin terms ask for
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum MarginStatus {
+    Healthy,
+    /// The equity fell below the maintenance margin, the account can't borrow until it recovers
+    MarginCall,
+}
+
+/// Where an account on margin stands, holdings in other assets are valued at the price they last traded at
+/// in the borrowed asset and count for nothing before they have traded
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct MarginReport {
+    pub signer: String,
+    pub asset: Asset,
+    pub credit_limit: u64,
+    /// What the account owes
+    pub debt: u64,
+    /// The share of the credit limit in use, in basis points
+    pub usage_bps: u64,
+    /// What the account can pay for orders with: the available balance and, unless it's in a margin call, the credit left
+    pub buying_power: u64,
+    /// The value of what the account holds in other assets
+    pub exposure: u128,
+    /// The value of everything the account holds minus its debt
+    pub equity: i128,
+    /// The equity the account has to keep in basis points of its exposure
+    pub maintenance_bps: u64,
+    /// The equity the account has to keep
+    pub maintenance_margin: u128,
+    pub status: MarginStatus,
+}
+
+impl MarginReport {
+    /// Works out the margin of an account from what it holds of the borrowed asset, the value of its other
+    /// holdings and the balance of the borrowed asset that isn't set aside for resting orders
+    pub fn new(
+        signer: &str,
+        terms: &MarginTerms,
+        debt: u64,
+        cash: u64,
+        exposure: u128,
+        available: u64,
+    ) -> Self {
+        let equity = (cash as i128).saturating_add_unsigned(exposure) - debt as i128;
+        let maintenance_margin =
+            exposure.saturating_mul(terms.maintenance_bps as u128) / BPS as u128;
+        let status = match equity < maintenance_margin.min(i128::MAX as u128) as i128 {
+            true => MarginStatus::MarginCall,
+            false => MarginStatus::Healthy,
+        };
+        let credit_left = match status {
+            MarginStatus::Healthy => terms.credit_limit.saturating_sub(debt),
+            MarginStatus::MarginCall => 0,
+        };
+        let usage_bps = match terms.credit_limit {
+            0 => 0,
+            limit => (debt as u128 * BPS as u128 / limit as u128) as u64,
+        };
+
+        MarginReport {
+            signer: signer.to_string(),
+            asset: terms.asset.clone(),
+            credit_limit: terms.credit_limit,
+            debt,
+            usage_bps,
+            buying_power: available.saturating_add(credit_left),
+            exposure,
+            equity,
+            maintenance_bps: terms.maintenance_bps,
+            maintenance_margin,
+            status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn terms() -> MarginTerms {
+        MarginTerms {
+            asset: Asset::new("USD"),
+            credit_limit: 100,
+            maintenance_bps: 2_500,
+        }
+    }
+
+    #[test]
+    fn test_MarginReport_healthy_until_equity_falls_below_maintenance() {
+        // 50 of their own and 100 borrowed bought 15 units at 10
+        let report = MarginReport::new("ALICE", &terms(), 100, 0, 150, 0);
+        assert_eq!(report.equity, 50);
+        assert_eq!(report.maintenance_margin, 37);
+        assert_eq!(report.usage_bps, 10_000);
+        assert_eq!(report.status, MarginStatus::Healthy);
+
+        // the units are worth 8 each now
+        let report = MarginReport::new("ALICE", &terms(), 100, 0, 120, 0);
+        assert_eq!(report.equity, 20);
+        assert_eq!(report.maintenance_margin, 30);
+        assert_eq!(report.status, MarginStatus::MarginCall);
+    }
+
+    #[test]
+    fn test_MarginReport_buying_power_includes_credit_left() {
+        let report = MarginReport::new("ALICE", &terms(), 40, 60, 0, 50);
+        assert_eq!(report.buying_power, 50 + 60);
+        assert_eq!(report.usage_bps, 4_000);
+
+        // no credit in a margin call
+        let report = MarginReport::new("ALICE", &terms(), 90, 10, 100, 10);
+        assert_eq!(report.status, MarginStatus::MarginCall);
+        assert_eq!(report.buying_power, 10);
+    }
+}
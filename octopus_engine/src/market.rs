@@ -0,0 +1,972 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    accounting::{Accounts, Hold, Trade},
+    candles::{Candle, CandleAggregator, Interval},
+    engine::{self, MatchingEngine},
+    errors::AccountError,
+    fees::{self, FeeSchedule},
+    groups::{Bracket, OrderGroups},
+    liquidation::{self, LiquidationStep},
+    margin::{MarginReport, MarginStatus},
+    matcher::Matcher,
+    positions::Positions,
+    ticker::{Ticker, TickerStats},
+    tx::{Tx, TxKind},
+    types::{
+        Asset, ConditionalOrder, Depth, Execution, GroupOrder, Instrument, Order, PartialOrder,
+        PeggedOrder, Receipt, Side, StopOrder, Trail, TrailingStop,
+    },
+};
+
+/// Where a [`Market`] reserves, releases and pays the funds of its orders.
+/// Every change to an account goes through it, the market itself only keeps track of its order book.
+pub trait Bookkeeper {
+    /// Checks the signer has an account that can trade, see [`Accounts::ensure_active`]
+    fn ensure_active(&mut self, signer: &str) -> Result<(), AccountError>;
+
+    /// What the signer can pay with in an asset, see [`Accounts::buying_power`]
+    fn buying_power(&mut self, signer: &str, asset: &Asset) -> Result<u64, AccountError>;
+
+    /// What the signer holds of an asset that isn't set aside for resting orders
+    fn available(&mut self, signer: &str, asset: &Asset) -> Result<u64, AccountError>;
+
+    /// Sets the hold aside, borrowing what the signer doesn't have available, see [`Accounts::reserve`].
+    /// Returns the amount borrowed.
+    fn reserve(&mut self, hold: &Hold) -> Result<u64, AccountError>;
+
+    /// Makes a hold available again
+    fn release(&mut self, hold: &Hold);
+
+    /// Pays back what the signer borrowed of an asset, see [`Accounts::repay`]
+    fn repay(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<(), AccountError>;
+
+    /// Sets the `hold` of an incoming order aside if it isn't yet, then releases the holds and pays for the trades
+    /// as a single unit of work and records the trades. Unless all of it can be done, nothing stays reserved
+    /// or borrowed for the `hold` and no balance changes, see [`Accounts::reserve`] and [`Accounts::settle`].
+    fn settle(
+        &mut self,
+        hold: Option<&Hold>,
+        releases: &[Hold],
+        trades: &[Trade],
+    ) -> Result<(), AccountError>;
+
+    /// Where the signer stands on margin
+    fn margin(&mut self, signer: &str) -> Result<MarginReport, AccountError>;
+
+    /// The accounts the trades settled since the last call put in a margin call, see [`Accounts::take_margin_calls`]
+    fn take_margin_calls(&mut self) -> Vec<MarginReport>;
+
+    /// Repays what the signer owes of an asset as far as its available balance goes, see [`Accounts::settle_debt`]
+    fn settle_debt(&mut self, signer: &str, asset: &Asset);
+
+    /// Records a step of a liquidation in the transaction log
+    fn log_liquidation(&mut self, signer: &str, step: LiquidationStep);
+
+    /// Records an order the market placed for its signer on its own that failed, see [`Accounts::log_rejection`]
+    fn log_rejection(&mut self, order: &Order, error: &AccountError);
+}
+
+/// The accounts, the transaction log and the positions of a platform, borrowed to settle a market with directly
+pub struct Books<'a> {
+    pub accounts: &'a mut Accounts,
+    pub transactions: &'a mut Vec<Tx>,
+    pub positions: &'a mut Positions,
+}
+
+impl Bookkeeper for Books<'_> {
+    fn ensure_active(&mut self, signer: &str) -> Result<(), AccountError> {
+        self.accounts.ensure_active(signer)
+    }
+
+    fn buying_power(&mut self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        self.accounts.buying_power(signer, asset)
+    }
+
+    fn available(&mut self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        self.accounts.available(signer, asset)
+    }
+
+    fn reserve(&mut self, hold: &Hold) -> Result<u64, AccountError> {
+        let loan = self.accounts.reserve(hold)?;
+        let borrowed = match &loan {
+            Some(Tx {
+                kind: TxKind::Borrow { amount, .. },
+                ..
+            }) => *amount,
+            _ => 0,
+        };
+        self.transactions.extend(loan);
+        Ok(borrowed)
+    }
+
+    fn release(&mut self, hold: &Hold) {
+        self.accounts.release(hold);
+    }
+
+    fn repay(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<(), AccountError> {
+        let repaid = self.accounts.repay(signer, asset, amount)?;
+        self.transactions.push(repaid);
+        Ok(())
+    }
+
+    fn settle(
+        &mut self,
+        hold: Option<&Hold>,
+        releases: &[Hold],
+        trades: &[Trade],
+    ) -> Result<(), AccountError> {
+        let positions = self.positions.after(trades)?;
+        let borrowed = match hold {
+            Some(hold) => self.reserve(hold)?,
+            None => 0,
+        };
+        let txs = self.accounts.settle(releases, trades).inspect_err(|_| {
+            if let Some(hold) = hold {
+                self.release(hold);
+                if borrowed > 0 {
+                    // the loan is still available, nothing else could have spent it
+                    let _ = self.repay(&hold.signer, &hold.asset, borrowed);
+                }
+            }
+        })?;
+        self.transactions.extend(txs);
+        self.positions.commit(positions);
+        Ok(())
+    }
+
+    fn margin(&mut self, signer: &str) -> Result<MarginReport, AccountError> {
+        self.accounts.margin(signer)
+    }
+
+    fn take_margin_calls(&mut self) -> Vec<MarginReport> {
+        self.accounts.take_margin_calls()
+    }
+
+    fn settle_debt(&mut self, signer: &str, asset: &Asset) {
+        if let Ok(Some(repaid)) = self.accounts.settle_debt(signer, asset) {
+            self.transactions.push(repaid);
+        }
+    }
+
+    fn log_liquidation(&mut self, signer: &str, step: LiquidationStep) {
+        let tx = self.accounts.log_liquidation(signer, step);
+        self.transactions.push(tx);
+    }
+
+    fn log_rejection(&mut self, order: &Order, error: &AccountError) {
+        let tx = self.accounts.log_rejection(order, error);
+        self.transactions.push(tx);
+    }
+}
+
+/// A single order book traded through a [`Matcher`] with everything that goes along with it: the holds of
+/// its resting orders, the fees of its trades, its order groups and its market data. The funds of the orders
+/// are reserved and paid through a [`Bookkeeper`].
+pub struct Market<M = MatchingEngine> {
+    pub(crate) engine: M,
+    /// The base and quote asset of the market
+    pub instrument: Instrument,
+    /// The funds set aside for each resting order, by ordinal
+    pub holds: HashMap<u64, Hold>,
+    /// The fees charged on every trade, nothing unless configured
+    pub fee_schedule: FeeSchedule,
+    /// How much of the quote asset each signer has traded, for the tiers of the fee schedule
+    pub volumes: HashMap<String, u64>,
+    pub candles: CandleAggregator,
+    pub ticker: TickerStats,
+    pub groups: OrderGroups,
+    /// Whether the accounts its trades leave in a margin call are liquidated in the market straight away.
+    /// Where several markets share the accounts, they are liquidated across all of them instead.
+    pub liquidates: bool,
+    /// The price of the last trade settled
+    last_price: Option<u64>,
+}
+
+/// What an order about to be processed has set aside for it
+enum Reservation {
+    /// Reserved before the order was processed, with what its signer had to borrow for it
+    Reserved(Hold, u64),
+    /// Reserved together with the settlement of the order, so the whole order takes a single call to the [`Bookkeeper`]
+    Pending(Hold),
+}
+
+impl Market {
+    /// Creates an empty market of the instrument matched by the [`MatchingEngine`]
+    pub fn new(instrument: Instrument) -> Self {
+        Market::with_engine(instrument, MatchingEngine::new())
+    }
+}
+
+impl<M: Matcher> Market<M> {
+    /// Creates an empty market of the instrument that trades through the provided engine
+    pub fn with_engine(instrument: Instrument, engine: M) -> Self {
+        Market {
+            engine,
+            instrument,
+            holds: HashMap::new(),
+            fee_schedule: FeeSchedule::default(),
+            volumes: HashMap::new(),
+            candles: CandleAggregator::new(),
+            ticker: TickerStats::new(),
+            groups: OrderGroups::new(),
+            liquidates: true,
+            last_price: None,
+        }
+    }
+
+    /// Charges the fees of the schedule on every trade from now on, the volumes traded so far count towards its tiers
+    pub fn set_fee_schedule(&mut self, mut schedule: FeeSchedule) {
+        schedule.tiers.sort_by_key(|tier| tier.volume);
+        self.fee_schedule = schedule;
+    }
+
+    /// Fetches the complete order book at this time
+    pub fn orderbook(&self) -> Vec<PartialOrder> {
+        self.engine.snapshot()
+    }
+
+    /// Fetches the resting amount of each price level, best prices first
+    pub fn depth(&self) -> Depth {
+        self.engine.depth()
+    }
+
+    /// Fetches the candles of an interval, oldest first
+    pub fn candles(&self, interval: Interval) -> Vec<Candle> {
+        self.candles.candles(interval)
+    }
+
+    /// Discards the candles and rebuilds them from the engine's history
+    pub fn backfill_candles(&mut self) {
+        self.candles.backfill(self.engine.history());
+    }
+
+    /// Computes the rolling 24h statistics of the market
+    pub fn ticker(&mut self) -> Ticker {
+        let best_bid = self.engine.best_bid();
+        let best_ask = self.engine.best_ask();
+
+        self.ticker.ticker(engine::timestamp(), best_bid, best_ask)
+    }
+
+    /// Process a given order and apply the outcome to the accounts involved. Note that there are very few safeguards in place.
+    /// Fills are propagated to linked order groups and any stop orders released by the trades are processed as well.
+    pub fn submit_order(
+        &mut self,
+        order: Order,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Receipt, AccountError> {
+        let receipt = self.execute(order, Execution::Limit, books)?;
+        self.propagate(&receipt, books);
+
+        Ok(receipt)
+    }
+
+    /// Cancels a resting or stop order along with the siblings in its group and returns the cancelled ordinals
+    pub fn cancel_order(
+        &mut self,
+        ordinal: u64,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Vec<u64>, AccountError> {
+        if self.engine.cancel(ordinal).is_none() && self.engine.cancel_stop(ordinal).is_none() {
+            return Err(AccountError::OrderNotFound(ordinal));
+        }
+        self.release(ordinal, books);
+        // A bracket entry that never filled takes its exits with it
+        self.groups.take_bracket(ordinal);
+
+        let mut cancelled = vec![ordinal];
+        cancelled.extend(self.cancel_siblings(ordinal, books));
+        Ok(cancelled)
+    }
+
+    /// Cancels an order of the signer along with the siblings in its group, see [`Market::cancel_order`]
+    /// # Errors
+    /// There is no resting or stop order of the signer with that ordinal
+    pub fn cancel_order_of(
+        &mut self,
+        signer: &str,
+        ordinal: u64,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Vec<u64>, AccountError> {
+        if self.engine.signer_of(ordinal).as_deref() != Some(signer) {
+            return Err(AccountError::OrderNotFound(ordinal));
+        }
+        self.cancel_order(ordinal, books)
+    }
+
+    /// Places a one-cancels-other group where a fill or cancel of one order cancels all the others.
+    /// The limit orders of the whole group are reserved before any order is placed. Stop orders are held first,
+    /// then limit orders are placed in sequence until one of them fills. Unless every order can be placed,
+    /// the ones that were are cancelled and nothing stays reserved. Returns the ordinals of the orders that were placed.
+    pub fn submit_oco(
+        &mut self,
+        orders: Vec<GroupOrder>,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Vec<u64>, AccountError> {
+        let mut stops = vec![];
+        let mut limits = vec![];
+        for order in orders {
+            match order {
+                GroupOrder::Stop(stop) => stops.push(stop),
+                GroupOrder::Limit(order) => limits.push(order),
+            }
+        }
+
+        // 1. Check the stop orders and set aside what every limit order can cost before placing any of them
+        for stop in &stops {
+            self.check_funds(&stop.order, stop.order.value()?, books)?;
+        }
+        let mut reservations = vec![];
+        for order in limits {
+            match order
+                .value()
+                .and_then(|value| self.reserve(&order, value, books))
+            {
+                Ok(reserved) => reservations.push((order, reserved)),
+                Err(e) => {
+                    for (_, reserved) in reservations {
+                        Self::unreserve(reserved, books);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        // 2. Place the orders, any that fails cancels the ones placed before it
+        let group = self.groups.open_oco();
+        let mut placed = vec![];
+        let mut failure = None;
+        for stop in stops {
+            match self.engine.submit_stop(stop) {
+                Ok(ordinal) => {
+                    self.groups.join(group, ordinal);
+                    placed.push(ordinal);
+                }
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        for (order, reserved) in reservations {
+            // A member filled straight away so the rest of the group is void
+            if failure.is_some() || !self.groups.is_open(group) {
+                Self::unreserve(reserved, books);
+                continue;
+            }
+
+            let o = order.clone();
+            match self.transact(&o, reserved, books, |engine| engine.process(order)) {
+                Ok(receipt) => {
+                    self.groups.join(group, receipt.ordinal);
+                    placed.push(receipt.ordinal);
+                    self.propagate(&receipt, books);
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+
+        match failure {
+            Some(e) => {
+                for ordinal in self.groups.close(group) {
+                    if self.engine.cancel(ordinal).is_none() {
+                        self.engine.cancel_stop(ordinal);
+                    }
+                    self.release(ordinal, books);
+                }
+                Err(e)
+            }
+            None => Ok(placed),
+        }
+    }
+
+    /// Places an entry order and, for what it has filled, a take-profit limit order and a stop-loss
+    /// on the opposite side as a one-cancels-other pair. Every later fill of the entry replaces the pair with one
+    /// for the units it covered that haven't traded plus the new fill, so cancelling the rest of the entry leaves
+    /// the exits of what filled in place. The stop-loss is processed as a limit order at its trigger price.
+    /// An exit that can't be placed by then is logged as a [`TxKind::Rejected`] transaction of the signer.
+    pub fn submit_bracket(
+        &mut self,
+        entry: Order,
+        take_profit: u64,
+        stop_loss: u64,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Receipt, AccountError> {
+        let exit = Order {
+            side: match entry.side {
+                Side::Buy => Side::Sell,
+                Side::Sell => Side::Buy,
+            },
+            ..entry.clone()
+        };
+        let bracket = Bracket {
+            take_profit: Order {
+                price: take_profit,
+                ..exit.clone()
+            },
+            stop_loss: StopOrder {
+                order: Order {
+                    price: stop_loss,
+                    ..exit
+                },
+                trigger: stop_loss,
+            },
+            remaining: entry.amount,
+            exits: None,
+        };
+
+        let receipt = self.execute(entry, Execution::Limit, books)?;
+        self.groups.add_bracket(receipt.ordinal, bracket);
+        self.propagate(&receipt, books);
+
+        Ok(receipt)
+    }
+
+    /// Places the exits of a bracket for a fill of `amount` units of its entry, if the ordinal is a bracket entry.
+    /// Exits of earlier fills that haven't traded yet are cancelled and placed again together with the new units.
+    /// Exits that can't be placed are logged as rejected. Returns the receipt of the take profit.
+    fn fill_bracket(
+        &mut self,
+        entry: u64,
+        amount: u64,
+        books: &mut impl Bookkeeper,
+    ) -> Option<Receipt> {
+        let bracket = self.groups.bracket_mut(entry)?;
+        bracket.remaining = bracket.remaining.saturating_sub(amount);
+        let bracket = match bracket.remaining {
+            0 => self.groups.take_bracket(entry)?,
+            _ => bracket.clone(),
+        };
+
+        // 1. Take back the exits that are still open, both cover the same units until one trades
+        let mut size = amount;
+        if let Some(group) = bracket.exits.filter(|group| self.groups.is_open(*group)) {
+            let mut covered = 0;
+            for ordinal in self.groups.close(group) {
+                let units = match self.engine.cancel(ordinal) {
+                    Some(take_profit) => take_profit.remaining,
+                    None => self
+                        .engine
+                        .cancel_stop(ordinal)
+                        .map_or(0, |stop| stop.amount),
+                };
+                covered = covered.max(units);
+                self.release(ordinal, books);
+            }
+            size = size.saturating_add(covered);
+        }
+
+        // 2. Place a pair for everything they covered and the new fill
+        let group = self.groups.open_oco();
+        if let Some(bracket) = self.groups.bracket_mut(entry) {
+            bracket.exits = Some(group);
+        }
+        let stop_loss = StopOrder {
+            order: Order {
+                amount: size,
+                ..bracket.stop_loss.order
+            },
+            ..bracket.stop_loss
+        };
+        let order = stop_loss.order.clone();
+        match self.engine.submit_stop(stop_loss) {
+            Ok(stop) => self.groups.join(group, stop),
+            Err(e) => books.log_rejection(&order, &e),
+        }
+
+        let take_profit = Order {
+            amount: size,
+            ..bracket.take_profit
+        };
+        match self.execute(take_profit.clone(), Execution::Limit, books) {
+            Ok(receipt) => {
+                self.groups.join(group, receipt.ordinal);
+                Some(receipt)
+            }
+            Err(e) => {
+                books.log_rejection(&take_profit, &e);
+                None
+            }
+        }
+    }
+
+    /// Cancels every resting order of the signer along with their groups and returns the ordinals cancelled
+    pub fn cancel_signer(&mut self, signer: &str, books: &mut impl Bookkeeper) -> Vec<u64> {
+        let resting: Vec<u64> = self
+            .engine
+            .snapshot()
+            .into_iter()
+            .filter(|po| po.signer == signer)
+            .map(|po| po.ordinal)
+            .collect();
+        let mut cancelled = vec![];
+        for ordinal in resting {
+            // siblings cancelled with an earlier order are gone already
+            if let Ok(ordinals) = self.cancel_order(ordinal, books) {
+                cancelled.extend(ordinals);
+            }
+        }
+        cancelled
+    }
+
+    /// Sells the base asset of an account in a margin call, see [`Market::sell_down`], and propagates the sales
+    /// like any other order. For markets that leave liquidating accounts to whoever shares them across markets.
+    pub fn liquidate_signer(
+        &mut self,
+        signer: &str,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Vec<Receipt>, AccountError> {
+        let report = books.margin(signer)?;
+        let receipts = self.sell_down(&report, books);
+        for receipt in &receipts {
+            self.propagate(receipt, books);
+        }
+        Ok(receipts)
+    }
+
+    /// What the order can cost its signer: a buy pays the `total_cost` in the quote asset,
+    /// a sell gives up the amount it sells in the base asset
+    fn cost(&self, order: &Order, total_cost: u64) -> Hold {
+        let (asset, amount) = match order.side {
+            Side::Buy => (&self.instrument.quote, total_cost),
+            Side::Sell => (&self.instrument.base, order.amount),
+        };
+        Hold {
+            signer: order.signer.clone(),
+            asset: asset.clone(),
+            amount,
+        }
+    }
+
+    /// Checks the signer has an active account with the buying power to pay for the order, see [`Market::cost`]
+    fn check_funds(
+        &self,
+        order: &Order,
+        total_cost: u64,
+        books: &mut impl Bookkeeper,
+    ) -> Result<(), AccountError> {
+        let cost = self.cost(order, total_cost);
+        // 1. Check if signer has an account that can trade
+        books.ensure_active(&cost.signer)?;
+        let buying_power = books.buying_power(&cost.signer, &cost.asset)?;
+
+        // 2. Check if the signer can pay with what isn't set aside for other orders and the credit they have left
+        buying_power
+            .checked_sub(cost.amount)
+            .ok_or(AccountError::UnderFunded(order.signer.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Sets aside what the order can cost its signer until it's settled, see [`Market::cost`]
+    fn reserve(
+        &self,
+        order: &Order,
+        total_cost: u64,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Reservation, AccountError> {
+        let cost = self.cost(order, total_cost);
+        let borrowed = books.reserve(&cost)?;
+        Ok(Reservation::Reserved(cost, borrowed))
+    }
+
+    /// Processes a single order through the engine and settles its matches.
+    /// Market orders are checked against the cost of sweeping the book instead of the order price.
+    fn execute(
+        &mut self,
+        order: Order,
+        execution: Execution,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Receipt, AccountError> {
+        let total_cost = match execution {
+            Execution::Limit => order.value()?,
+            Execution::Market => self.engine.market_cost(&order)?,
+        };
+        let pending = Reservation::Pending(self.cost(&order, total_cost));
+
+        // 3. Process the order by the engine and settle it
+        let o = order.clone();
+        self.transact(&o, pending, books, |engine| match execution {
+            Execution::Limit => engine.process(order),
+            Execution::Market => engine.process_market(order),
+        })
+    }
+
+    /// Processes an order with its `reservation` as a single unit of work over the engine and the accounts.
+    /// Unless its hold can be set aside and every payment of the receipt settles, the engine is rolled back
+    /// to where it was before, the hold is released and what was borrowed for it is paid back, so no balance changes.
+    fn transact(
+        &mut self,
+        order: &Order,
+        reservation: Reservation,
+        books: &mut impl Bookkeeper,
+        process: impl FnOnce(&mut M) -> Result<Receipt, AccountError>,
+    ) -> Result<Receipt, AccountError> {
+        let savepoint = self.engine.savepoint();
+
+        let settled = process(&mut self.engine).and_then(|mut receipt| {
+            receipt.fee = self.settle(order, &reservation, &receipt, books)?;
+            Ok(receipt)
+        });
+        match settled {
+            Ok(_) => self.engine.commit(savepoint),
+            Err(_) => {
+                self.engine.rollback(savepoint);
+                Self::unreserve(reservation, books);
+            }
+        }
+        settled
+    }
+
+    /// Releases the hold of an order that didn't go through and pays back what was borrowed for it.
+    /// A pending hold was never set aside, or was undone along with the settlement that failed.
+    fn unreserve(reservation: Reservation, books: &mut impl Bookkeeper) {
+        if let Reservation::Reserved(hold, borrowed) = reservation {
+            books.release(&hold);
+            if borrowed > 0 {
+                // the loan is still available, nothing else could have spent it
+                let _ = books.repay(&hold.signer, &hold.asset, borrowed);
+            }
+        }
+    }
+
+    /// Pays for every match between the order signer and the counterparty, charges their fees and records the trades.
+    /// The makers' holds are released by what they paid and the order keeps just enough of its `hold` for the remainder
+    /// resting in the book, so the payments only ever come out of available funds. A pending hold is set aside
+    /// along with the payments. Nothing changes unless all of it can be done. Returns the fee the order's signer paid.
+    fn settle(
+        &mut self,
+        order: &Order,
+        reservation: &Reservation,
+        receipt: &Receipt,
+        books: &mut impl Bookkeeper,
+    ) -> Result<u64, AccountError> {
+        let (mut hold, pending) = match reservation {
+            Reservation::Reserved(hold, _) => (hold.clone(), None),
+            Reservation::Pending(hold) => (hold.clone(), Some(hold)),
+        };
+        // 1. Work out the holds the trades release, the market keeps its own until the payments are made
+        let mut releases = vec![];
+        let mut holds = vec![];
+        for po in &receipt.matches {
+            let paid = Hold::of(&self.instrument, &po.signer, &po.side, po.price, po.amount)?;
+            if let Some(mut maker) = self.holds.get(&po.ordinal).cloned() {
+                releases.push(maker.take(paid.amount));
+                if po.remaining == 0 {
+                    releases.push(maker);
+                    holds.push((po.ordinal, None));
+                } else {
+                    holds.push((po.ordinal, Some(maker)));
+                }
+            }
+        }
+
+        if self.engine.is_resting(receipt.ordinal) {
+            let filled: u64 = receipt.matches.iter().map(|po| po.amount).sum();
+            let remainder = Hold::of(
+                &self.instrument,
+                &order.signer,
+                &order.side,
+                order.price,
+                order.amount - filled,
+            )?;
+            holds.push((receipt.ordinal, Some(hold.take(remainder.amount))));
+        }
+        releases.push(hold);
+
+        // 2. Release and pay everything at once
+        let mut trades = Trade::settling(&self.instrument, order, receipt);
+        let fee = self.charge_fees(order, &mut trades)?;
+        books.settle(pending, &releases, &trades)?;
+
+        // 3. Commit the rest
+        for (ordinal, hold) in holds {
+            match hold {
+                Some(hold) => self.holds.insert(ordinal, hold),
+                None => self.holds.remove(&ordinal),
+            };
+        }
+        for trade in &trades {
+            // the trade was paid for, so its value fits
+            let value = trade.value().unwrap_or(u64::MAX);
+            for signer in [&trade.buyer, &trade.seller] {
+                let volume = self.volumes.entry(signer.clone()).or_default();
+                *volume = volume.saturating_add(value);
+            }
+            self.last_price = Some(trade.price);
+        }
+        self.candles.record_receipt(receipt);
+        self.ticker.record_receipt(receipt);
+        Ok(fee)
+    }
+
+    /// Sets the fees of the trades of an incoming order at the rates of each signer,
+    /// the order took the liquidity the resting orders made. Returns the fee of the order's signer.
+    /// # Errors
+    /// The value of a trade or the fees of the order don't fit in a `u64`
+    fn charge_fees(&self, order: &Order, trades: &mut [Trade]) -> Result<u64, AccountError> {
+        let rates = |signer: &str| {
+            let volume = self.volumes.get(signer).copied().unwrap_or(0);
+            self.fee_schedule.rates(signer, volume)
+        };
+        let buyer_made = order.side == Side::Sell;
+
+        let mut fee: u64 = 0;
+        for trade in trades {
+            trade.buyer_fee = fees::fee(trade.amount, rates(&trade.buyer).rate(buyer_made));
+            trade.seller_fee = fees::fee(trade.value()?, rates(&trade.seller).rate(!buyer_made));
+            let charged = match order.side {
+                Side::Buy => trade.buyer_fee,
+                Side::Sell => trade.seller_fee,
+            };
+            fee = fee
+                .checked_add(charged)
+                .ok_or(AccountError::ArithmeticOverflow)?;
+        }
+        Ok(fee)
+    }
+
+    /// Applies the fills of a receipt to linked orders and processes the stop orders released by its trades,
+    /// repeating for every receipt that results from it. Once that's done, a market that [`Market::liquidates`]
+    /// liquidates the accounts the trades left in a margin call and propagates the receipts of their sales in turn.
+    fn propagate(&mut self, receipt: &Receipt, books: &mut impl Bookkeeper) {
+        let mut pending = vec![receipt.clone()];
+        let mut traded = false;
+        let mut liquidated = HashSet::new();
+
+        while let Some(receipt) = pending.pop() {
+            traded |= !receipt.matches.is_empty();
+            // 1. Any fill in a one-cancels-other group cancels the siblings, both the taker and the makers
+            let mut filled: Vec<u64> = receipt.matches.iter().map(|po| po.ordinal).collect();
+            if !receipt.matches.is_empty() {
+                filled.push(receipt.ordinal);
+            }
+            for ordinal in filled {
+                self.cancel_siblings(ordinal, books);
+            }
+
+            // 2. Every fill of a bracket entry, the incoming order or a resting one, places exits for it
+            let mut fills: Vec<(u64, u64)> = receipt
+                .matches
+                .iter()
+                .map(|po| (po.ordinal, po.amount))
+                .collect();
+            fills.push((
+                receipt.ordinal,
+                receipt.matches.iter().map(|po| po.amount).sum(),
+            ));
+            for (entry, amount) in fills {
+                if amount > 0 {
+                    pending.extend(self.fill_bracket(entry, amount, books));
+                }
+            }
+
+            // 3. Released stop orders void their group and are processed like any other order.
+            // Orders that can no longer be funded are logged as rejected.
+            for triggered in self.engine.take_triggered() {
+                self.cancel_siblings(triggered.ordinal, books);
+
+                let order = triggered.order.clone();
+                match self.execute(triggered.order, triggered.execution, books) {
+                    Ok(r) => pending.push(r),
+                    Err(e) => books.log_rejection(&order, &e),
+                }
+            }
+
+            // 4. Trades move the price the margin of every account is valued at
+            if pending.is_empty() && traded && self.liquidates {
+                traded = false;
+                pending = self.liquidate(&mut liquidated, books);
+            }
+        }
+    }
+
+    /// Liquidates every account borrowing the quote asset that is in a margin call, except the ones
+    /// `liquidated` already, and returns the receipts of the sales. See [`Market::liquidate_account`].
+    fn liquidate(
+        &mut self,
+        liquidated: &mut HashSet<String>,
+        books: &mut impl Bookkeeper,
+    ) -> Vec<Receipt> {
+        let calls: Vec<MarginReport> = books
+            .take_margin_calls()
+            .into_iter()
+            .filter(|report| {
+                report.asset == self.instrument.quote
+                    && liquidation::needs_liquidation(report)
+                    && !liquidated.contains(&report.signer)
+            })
+            .collect();
+
+        let mut receipts = vec![];
+        for report in calls {
+            liquidated.insert(report.signer.clone());
+            receipts.extend(self.liquidate_account(&report, books));
+        }
+        receipts
+    }
+
+    /// Cancels the resting orders of an account in a margin call and sells it down, see [`Market::sell_down`].
+    /// Every step is logged as a liquidation transaction. Returns the receipts of the sales.
+    fn liquidate_account(
+        &mut self,
+        report: &MarginReport,
+        books: &mut impl Bookkeeper,
+    ) -> Vec<Receipt> {
+        let signer = report.signer.as_str();
+        books.log_liquidation(signer, LiquidationStep::started(report));
+        let ordinals = self.cancel_signer(signer, books);
+        if !ordinals.is_empty() {
+            books.log_liquidation(signer, LiquidationStep::OrdersCancelled { ordinals });
+        }
+
+        let receipts = self.sell_down(report, books);
+        if let Ok(report) = books.margin(signer) {
+            books.log_liquidation(signer, LiquidationStep::finished(&report));
+        }
+        receipts
+    }
+
+    /// Sells the base asset of an account in a margin call with reduce-only market orders until it's healthy again
+    /// or nothing more trades, repaying its debt with the proceeds. Logs every sale and returns their receipts.
+    fn sell_down(&mut self, report: &MarginReport, books: &mut impl Bookkeeper) -> Vec<Receipt> {
+        let signer = report.signer.as_str();
+        let mut receipts = vec![];
+        let mut report = report.clone();
+        while report.status == MarginStatus::MarginCall {
+            let held = books.available(signer, &self.instrument.base).unwrap_or(0);
+            let Some(price) = self.last_price.filter(|_| held > 0) else {
+                break;
+            };
+            // only ever sells what is held and nothing of it rests in the book
+            let order = Order {
+                signer: signer.to_string(),
+                side: Side::Sell,
+                amount: liquidation::units_to_sell(&report, held, price),
+                price: 0,
+            };
+            let amount = order.amount;
+            let Ok(receipt) = self.execute(order, Execution::Market, books) else {
+                break;
+            };
+            let filled: u64 = receipt.matches.iter().map(|po| po.amount).sum();
+            let step = LiquidationStep::Sold {
+                market: self.instrument.symbol(),
+                ordinal: receipt.ordinal,
+                amount,
+                filled,
+            };
+            books.log_liquidation(signer, step);
+            receipts.push(receipt);
+            if filled == 0 {
+                break;
+            }
+            books.settle_debt(signer, &report.asset);
+            match books.margin(signer) {
+                Ok(next) => report = next,
+                Err(_) => break,
+            }
+        }
+        receipts
+    }
+
+    /// Resolves the group of an ordinal and removes all of its siblings from the engine
+    fn cancel_siblings(&mut self, ordinal: u64, books: &mut impl Bookkeeper) -> Vec<u64> {
+        let siblings = self.groups.resolve(ordinal);
+
+        for sibling in &siblings {
+            if self.engine.cancel(*sibling).is_none() {
+                self.engine.cancel_stop(*sibling);
+            }
+            self.release(*sibling, books);
+        }
+        siblings
+    }
+
+    /// Releases whatever is left of the hold of an order that left the book
+    fn release(&mut self, ordinal: u64, books: &mut impl Bookkeeper) {
+        if let Some(hold) = self.holds.remove(&ordinal) {
+            books.release(&hold);
+        }
+    }
+}
+
+impl Market<MatchingEngine> {
+    /// Holds a [`TrailingStop`] until the price moves against it by its trail and returns its ordinal.
+    /// A trail in basis points can't be more than the whole price.
+    pub fn submit_trailing_stop(
+        &mut self,
+        stop: TrailingStop,
+        books: &mut impl Bookkeeper,
+    ) -> Result<u64, AccountError> {
+        if let Trail::BasisPoints(bps @ 10_001..) = stop.trail {
+            return Err(AccountError::InvalidOrder(format!(
+                "a trail of {bps} basis points is more than the price"
+            )));
+        }
+        let order = &stop.order;
+        match stop.execution {
+            Execution::Limit => self.check_funds(order, order.value()?, books)?,
+            // The cost of a market order is only known once it's triggered
+            Execution::Market => self.check_funds(order, 0, books)?,
+        }
+
+        Ok(self.engine.submit_trailing_stop(stop))
+    }
+
+    /// The current trigger price of a trailing stop that hasn't fired yet
+    pub fn trailing_stop_trigger(&self, ordinal: u64) -> Result<u64, AccountError> {
+        self.engine
+            .trailing_stop_trigger(ordinal)
+            .ok_or(AccountError::OrderNotFound(ordinal))
+    }
+
+    /// Process a [`ConditionalOrder`] and apply the outcome to the accounts involved, see [`Market::submit_order`]
+    pub fn submit_conditional(
+        &mut self,
+        order: ConditionalOrder,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Receipt, AccountError> {
+        let o = order.order.clone();
+        let pending = Reservation::Pending(self.cost(&o, o.value()?));
+
+        let receipt = self.transact(&o, pending, books, |engine| {
+            engine.process_conditional(order)
+        })?;
+        self.propagate(&receipt, books);
+
+        Ok(receipt)
+    }
+
+    /// Places a [`PeggedOrder`] at its pegged price. The engine keeps repricing what rests in the book.
+    /// Funds are reserved at the limit price if there is one, otherwise at the current pegged price.
+    /// A buy order needs a limit, so repricing can never make it cost more than was reserved.
+    pub fn submit_pegged(
+        &mut self,
+        pegged: PeggedOrder,
+        books: &mut impl Bookkeeper,
+    ) -> Result<Receipt, AccountError> {
+        if pegged.order.side == Side::Buy && pegged.limit.is_none() {
+            return Err(AccountError::InvalidOrder(
+                "a pegged buy order needs a limit price".to_string(),
+            ));
+        }
+        let price = self
+            .engine
+            .peg_price(&pegged)
+            .ok_or(AccountError::NoReferencePrice)?;
+        let order = Order {
+            price: pegged.limit.unwrap_or(price),
+            ..pegged.order.clone()
+        };
+        let pending = Reservation::Pending(self.cost(&order, order.value()?));
+
+        let receipt = self.transact(&order, pending, books, |engine| {
+            engine.process_pegged(pegged)
+        })?;
+        self.propagate(&receipt, books);
+
+        Ok(receipt)
+    }
+}
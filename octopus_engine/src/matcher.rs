@@ -0,0 +1,159 @@
+use crate::{
+    errors::{AccountError, InvariantViolation},
+    types::{value, Depth, Order, PartialOrder, Receipt, Side, StopOrder, TriggeredOrder},
+};
+
+/// The order book behind a [`TradingPlatform`](crate::trading_platform::TradingPlatform).
+/// An engine only has to match, cancel and list its resting orders, everything else has a default built on top of those.
+/// Stop orders aren't supported unless the engine overrides the stop methods.
+/// Engines are cloned to take savepoints unless they provide a cheaper way.
+pub trait Matcher: Clone {
+    /// Processes an [`Order`] and returns a [`Receipt`]
+    /// This includes matching the order to whatever is in the current books and adding the remainder (if any) to the book for future matching.
+    fn process(&mut self, order: Order) -> Result<Receipt, AccountError>;
+
+    /// Removes a resting order from the book and returns it
+    fn cancel(&mut self, ordinal: u64) -> Option<PartialOrder>;
+
+    /// Every order resting in the book at this time
+    fn snapshot(&self) -> Vec<PartialOrder>;
+
+    /// The resting amount of each price level, best prices first
+    fn depth(&self) -> Depth {
+        Depth::from_orders(&self.snapshot())
+    }
+
+    /// Previous matches for record keeping, oldest first
+    fn history(&self) -> &[Receipt] {
+        &[]
+    }
+
+    /// The highest price in the bid book
+    fn best_bid(&self) -> Option<u64> {
+        self.depth().bids.first().map(|level| level.price)
+    }
+
+    /// The lowest price in the ask book
+    fn best_ask(&self) -> Option<u64> {
+        self.depth().asks.first().map(|level| level.price)
+    }
+
+    /// Whether the order with this ordinal is still resting in the book
+    fn is_resting(&self, ordinal: u64) -> bool {
+        self.snapshot().iter().any(|po| po.ordinal == ordinal)
+    }
+
+    /// The signer of a resting or held order
+    fn signer_of(&self, ordinal: u64) -> Option<String> {
+        self.snapshot()
+            .into_iter()
+            .find(|po| po.ordinal == ordinal)
+            .map(|po| po.signer)
+    }
+
+    /// Processes an [`Order`] at any price and returns a [`Receipt`]
+    /// The remainder that couldn't be matched straight away is discarded rather than added to the book.
+    fn process_market(&mut self, order: Order) -> Result<Receipt, AccountError> {
+        let price = match order.side {
+            Side::Buy => u64::MAX,
+            Side::Sell => u64::MIN,
+        };
+        let receipt = self.process(Order { price, ..order })?;
+        self.cancel(receipt.ordinal);
+        Ok(receipt)
+    }
+
+    /// Total price of filling the order against the opposite side of the book at any price, skipping the signer's own orders
+    /// # Errors
+    /// The price doesn't fit in a `u64`
+    fn market_cost(&self, order: &Order) -> Result<u64, AccountError> {
+        let mut book: Vec<PartialOrder> = self
+            .snapshot()
+            .into_iter()
+            .filter(|po| po.side != order.side && po.signer != order.signer)
+            .collect();
+        match order.side {
+            Side::Buy => book.sort_by_key(|po| (po.price, po.sequence)),
+            Side::Sell => book.sort_by_key(|po| (u64::MAX - po.price, po.sequence)),
+        }
+
+        let mut left = order.amount;
+        let mut cost: u64 = 0;
+        for po in book {
+            let take = left.min(po.remaining);
+            cost = value(take, po.price)?
+                .checked_add(cost)
+                .ok_or(AccountError::ArithmeticOverflow)?;
+            left -= take;
+        }
+        Ok(cost)
+    }
+
+    /// Holds a [`StopOrder`] until a trade reaches its trigger and returns its ordinal
+    fn submit_stop(&mut self, _stop: StopOrder) -> Result<u64, AccountError> {
+        Err(AccountError::Unsupported("stop orders".to_string()))
+    }
+
+    /// Removes a held stop order, whether it is waiting or already triggered, and returns its order
+    fn cancel_stop(&mut self, _ordinal: u64) -> Option<Order> {
+        None
+    }
+
+    /// Hands over the orders released by previous trades, oldest first
+    fn take_triggered(&mut self) -> Vec<TriggeredOrder> {
+        vec![]
+    }
+
+    /// The state to go back to with [`Matcher::rollback`] if what follows can't be settled.
+    /// Only the last savepoint taken can be rolled back to or committed.
+    fn savepoint(&mut self) -> Self {
+        self.clone()
+    }
+
+    /// Undoes every order processed and cancelled since the savepoint was taken
+    fn rollback(&mut self, savepoint: Self) {
+        *self = savepoint;
+    }
+
+    /// Keeps everything done since the savepoint was taken
+    fn commit(&mut self, _savepoint: Self) {}
+
+    /// Verifies what can be told from the resting orders alone: ordinals are unique,
+    /// every order has units left that don't exceed its amount and no bid crosses an ask of another signer
+    fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        let snapshot = self.snapshot();
+        let mut ordinals: Vec<u64> = snapshot.iter().map(|po| po.ordinal).collect();
+        ordinals.sort_unstable();
+        if let Some(pair) = ordinals.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(InvariantViolation::OrdinalOutOfOrder(pair[0]));
+        }
+
+        for po in &snapshot {
+            if po.remaining == 0 || po.remaining > po.amount {
+                return Err(InvariantViolation::QuantityMismatch {
+                    ordinal: po.ordinal,
+                    amount: po.amount,
+                    filled: po.amount.saturating_sub(po.remaining),
+                    remaining: po.remaining,
+                });
+            }
+        }
+
+        let (bids, asks): (Vec<_>, Vec<_>) = snapshot.iter().partition(|po| po.side == Side::Buy);
+        for bid in &bids {
+            let crossed = asks.iter().find(|ask| {
+                ask.price <= bid.price
+                    && ask.signer != bid.signer
+                    && ask.condition.is_none()
+                    && bid.condition.is_none()
+            });
+            if let Some(ask) = crossed {
+                return Err(InvariantViolation::CrossedBook {
+                    bid: bid.ordinal,
+                    ask: ask.ordinal,
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    accounting::Trade,
+    errors::AccountError,
+    ticker::Ticker,
+    types::{Instrument, Side},
+};
+
+/// What a signer holds in an instrument as a result of their trades. Fees aren't part of it.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Position {
+    /// Units of the base asset bought minus units sold, negative when short
+    pub net: i128,
+    /// What the open units were traded for in the quote asset
+    pub cost: u128,
+    /// Quote asset gained or lost on the units that were closed
+    pub realized_pnl: i128,
+}
+
+impl Position {
+    /// Applies a trade of `amount` units at `price`. Trading against the position closes it first,
+    /// realizing the difference to the average entry price, and whatever is left opens a position the other way.
+    /// # Errors
+    /// The share of the cost of the units closed can't be worked out in a `u128`, the position is left as it was
+    pub fn trade(&mut self, side: &Side, amount: u64, price: u64) -> Result<(), AccountError> {
+        let (amount, price) = (amount as u128, price as u128);
+        let signed = match side {
+            Side::Buy => amount as i128,
+            Side::Sell => -(amount as i128),
+        };
+
+        if self.net == 0 || self.net.signum() == signed.signum() {
+            self.cost = self.cost.saturating_add(amount * price);
+        } else {
+            let open = self.net.unsigned_abs();
+            let closed = amount.min(open);
+            // the share of the cost of the units closed, the rest stays with the open units
+            let released = share(self.cost, closed, open)?;
+            let proceeds = closed * price;
+            let pnl = match self.net > 0 {
+                true => signed_difference(proceeds, released),
+                false => signed_difference(released, proceeds),
+            };
+            self.realized_pnl = self.realized_pnl.saturating_add(pnl);
+            self.cost = self.cost.saturating_sub(released);
+            if amount > open {
+                self.cost = (amount - open) * price;
+            }
+        }
+        self.net = self.net.saturating_add(signed);
+        Ok(())
+    }
+
+    /// The average price the open units were traded at, rounded down
+    pub fn average_price(&self) -> Option<u64> {
+        (self.net != 0)
+            .then(|| u64::try_from(self.cost / self.net.unsigned_abs()).unwrap_or(u64::MAX))
+    }
+
+    /// What closing the open units at the `mark` price would gain or lose
+    pub fn unrealized_pnl(&self, mark: u64) -> i128 {
+        let value = self.net.unsigned_abs().saturating_mul(mark as u128);
+        match self.net > 0 {
+            true => signed_difference(value, self.cost),
+            false => signed_difference(self.cost, value),
+        }
+    }
+}
+
+/// `part / whole` of the `total`, rounded down
+/// # Errors
+/// The share or a step on the way there doesn't fit in a `u128`
+fn share(total: u128, part: u128, whole: u128) -> Result<u128, AccountError> {
+    let whole_units = (total / whole).checked_mul(part);
+    let remainder = (total % whole).checked_mul(part).map(|r| r / whole);
+    whole_units
+        .zip(remainder)
+        .and_then(|(units, remainder)| units.checked_add(remainder))
+        .ok_or(AccountError::ArithmeticOverflow)
+}
+
+/// `a - b`, saturating at the bounds of an `i128`
+fn signed_difference(a: u128, b: u128) -> i128 {
+    match a >= b {
+        true => i128::try_from(a - b).unwrap_or(i128::MAX),
+        false => i128::try_from(b - a).map_or(i128::MIN, |d| -d),
+    }
+}
+
+/// The price open positions are valued at
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkPrice {
+    /// The price of the most recent trade
+    #[default]
+    Last,
+    /// Halfway between the best bid and the best ask
+    Mid,
+}
+
+impl MarkPrice {
+    /// The mark of a market, if it has traded or has both a bid and an ask
+    pub fn of(&self, ticker: &Ticker) -> Option<u64> {
+        match self {
+            MarkPrice::Last => ticker.last_price,
+            MarkPrice::Mid => match (ticker.best_bid, ticker.best_ask) {
+                (Some(bid), Some(ask)) => Some(bid / 2 + ask / 2 + (bid % 2 + ask % 2) / 2),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A position valued at the mark price of its market
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PositionReport {
+    pub instrument: Instrument,
+    pub net: i128,
+    pub average_price: Option<u64>,
+    pub realized_pnl: i128,
+    pub mark_price: Option<u64>,
+    /// Nothing without a mark price
+    pub unrealized_pnl: Option<i128>,
+}
+
+impl PositionReport {
+    pub fn new(instrument: &Instrument, position: &Position, mark_price: Option<u64>) -> Self {
+        PositionReport {
+            instrument: instrument.clone(),
+            net: position.net,
+            average_price: position.average_price(),
+            realized_pnl: position.realized_pnl,
+            mark_price,
+            unrealized_pnl: mark_price.map(|mark| position.unrealized_pnl(mark)),
+        }
+    }
+}
+
+/// The position of every signer in every instrument they traded
+#[derive(Debug, Default, Clone)]
+pub struct Positions {
+    positions: HashMap<(String, Instrument), Position>,
+}
+
+impl Positions {
+    /// Returns an instance without any positions
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Applies a settled trade to the positions of its buyer and seller
+    /// # Errors
+    /// A position can't take the trade, see [`Position::trade`]. No position changes.
+    pub fn record(&mut self, trade: &Trade) -> Result<(), AccountError> {
+        let traded = self.after(std::slice::from_ref(trade))?;
+        self.commit(traded);
+        Ok(())
+    }
+
+    /// The positions the trades leave their buyers and sellers in, to [`Positions::commit`] once they're settled
+    /// # Errors
+    /// A position can't take one of the trades, see [`Position::trade`]
+    pub fn after(
+        &self,
+        trades: &[Trade],
+    ) -> Result<HashMap<(String, Instrument), Position>, AccountError> {
+        let mut traded: HashMap<(String, Instrument), Position> = HashMap::new();
+        for trade in trades {
+            let instrument = Instrument {
+                base: trade.base.clone(),
+                quote: trade.quote.clone(),
+            };
+            for (signer, side) in [(&trade.buyer, Side::Buy), (&trade.seller, Side::Sell)] {
+                let key = (signer.clone(), instrument.clone());
+                let position = match traded.entry(key) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let position = self.positions.get(entry.key()).cloned();
+                        entry.insert(position.unwrap_or_default())
+                    }
+                };
+                position.trade(&side, trade.amount, trade.price)?;
+            }
+        }
+        Ok(traded)
+    }
+
+    /// Replaces positions with the ones worked out by [`Positions::after`]
+    pub fn commit(&mut self, traded: HashMap<(String, Instrument), Position>) {
+        self.positions.extend(traded);
+    }
+
+    /// Every instrument the signer traded with their position in it, ordered by symbol
+    pub fn of(&self, signer: &str) -> Vec<(Instrument, Position)> {
+        let mut positions: Vec<(Instrument, Position)> = self
+            .positions
+            .iter()
+            .filter(|((s, _), _)| s == signer)
+            .map(|((_, instrument), position)| (instrument.clone(), position.clone()))
+            .collect();
+        positions.sort_by_key(|(instrument, _)| instrument.symbol());
+        positions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+    use crate::types::Asset;
+
+    #[test]
+    fn test_Position_averages_entries_and_realizes_closes() {
+        let mut position = Position::default();
+        position.trade(&Side::Buy, 2, 10).unwrap();
+        position.trade(&Side::Buy, 2, 13).unwrap();
+        assert_eq!(position.net, 4);
+        assert_eq!(position.average_price(), Some(11));
+        assert_eq!(position.unrealized_pnl(15), 14);
+
+        position.trade(&Side::Sell, 1, 20).unwrap();
+        assert_eq!(position.realized_pnl, 9);
+        assert_eq!(position.net, 3);
+        assert_eq!(position.average_price(), Some(11));
+        assert_eq!(position.unrealized_pnl(10), -5);
+
+        // Selling more than is held closes the long and opens a short at the trade price
+        position.trade(&Side::Sell, 5, 8).unwrap();
+        assert_eq!(position.realized_pnl, 9 - 11);
+        assert_eq!(position.net, -2);
+        assert_eq!(position.cost, 16);
+        assert_eq!(position.unrealized_pnl(5), 6);
+
+        position.trade(&Side::Buy, 2, 9).unwrap();
+        assert_eq!(position.realized_pnl, -2 - 2);
+        assert_eq!(
+            position,
+            Position {
+                net: 0,
+                cost: 0,
+                realized_pnl: -4
+            }
+        );
+        assert_eq!(position.average_price(), None);
+    }
+
+    #[test]
+    fn test_Position_at_the_largest_amounts_and_prices() {
+        let mut position = Position::default();
+        position.trade(&Side::Buy, u64::MAX, u64::MAX).unwrap();
+        assert_eq!(position.average_price(), Some(u64::MAX));
+        assert_eq!(position.unrealized_pnl(u64::MAX), 0);
+        // the cost doesn't fit an i128, so the loss saturates
+        assert_eq!(position.unrealized_pnl(0), i128::MIN);
+
+        position.trade(&Side::Sell, u64::MAX - 1, 0).unwrap();
+        assert_eq!(position.net, 1);
+        assert_eq!(position.average_price(), Some(u64::MAX));
+        assert_eq!(position.realized_pnl, i128::MIN);
+
+        position.trade(&Side::Sell, 2, u64::MAX).unwrap();
+        assert_eq!(position.net, -1);
+        assert_eq!(position.cost, u64::MAX as u128);
+        assert_eq!(position.realized_pnl, i128::MIN);
+    }
+
+    #[test]
+    fn test_Position_share_of_the_cost_near_the_largest_u128() {
+        assert_eq!(
+            share(u128::MAX, u128::MAX - 1, u128::MAX),
+            Ok(u128::MAX - 1)
+        );
+        assert_eq!(share(u128::MAX, 1, 2), Ok(u128::MAX / 2));
+        assert_eq!(
+            share(u128::MAX - 1, 2, u128::MAX),
+            Err(AccountError::ArithmeticOverflow)
+        );
+
+        // A close that can't be priced leaves the position as it was
+        let mut position = Position {
+            net: i128::MAX,
+            cost: u128::MAX - 2,
+            realized_pnl: 0,
+        };
+        let before = position.clone();
+        assert_eq!(
+            position.trade(&Side::Sell, u64::MAX, 1),
+            Err(AccountError::ArithmeticOverflow)
+        );
+        assert_eq!(position, before);
+    }
+
+    #[test]
+    fn test_Positions_record_both_sides_of_a_trade() {
+        let mut positions = Positions::new();
+        let trade = Trade {
+            id: "OCTO/USD:2:1".to_string(),
+            buyer: "BOB".to_string(),
+            seller: "ALICE".to_string(),
+            base: Asset::new("OCTO"),
+            quote: Asset::new("USD"),
+            amount: 3,
+            price: 10,
+            buyer_fee: 0,
+            seller_fee: 0,
+        };
+        positions.record(&trade).unwrap();
+
+        let octo = Instrument::new("OCTO", "USD");
+        let bob = positions.of("BOB");
+        assert_eq!(
+            bob,
+            vec![(
+                octo.clone(),
+                Position {
+                    net: 3,
+                    cost: 30,
+                    realized_pnl: 0
+                }
+            )]
+        );
+        let report = PositionReport::new(&octo, &positions.of("ALICE")[0].1, Some(12));
+        assert_eq!(report.net, -3);
+        assert_eq!(report.average_price, Some(10));
+        assert_eq!(report.unrealized_pnl, Some(-6));
+        assert!(positions.of("CHARLIE").is_empty());
+    }
+
+    #[test]
+    fn test_MarkPrice_of_ticker() {
+        let ticker = Ticker {
+            last_price: Some(10),
+            high: Some(10),
+            low: Some(10),
+            volume: 1,
+            quote_volume: 10,
+            vwap: Some(10),
+            price_change: 0,
+            percent_change: 0.0,
+            best_bid: Some(11),
+            best_ask: Some(u64::MAX),
+        };
+        assert_eq!(MarkPrice::Last.of(&ticker), Some(10));
+        assert_eq!(MarkPrice::Mid.of(&ticker), Some(u64::MAX / 2 + 6));
+        let one_sided = Ticker {
+            best_ask: None,
+            ..ticker
+        };
+        assert_eq!(MarkPrice::Mid.of(&one_sided), None);
+    }
+}
@@ -0,0 +1,391 @@
+use std::{cmp::Ordering, collections::BTreeMap, fmt::Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    tx::{Tx, TxKind},
+    types::{Asset, Side},
+};
+
+/// Whether a [`StatementRange`] is bounded by transaction sequences or timestamps
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RangeKind {
+    /// The sequence of the journal entry that posted each transaction
+    #[default]
+    Sequence,
+    /// Milliseconds since the unix epoch
+    Time,
+}
+
+/// The transactions a statement covers, both bounds are inclusive and open when missing
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct StatementRange {
+    pub by: RangeKind,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl StatementRange {
+    /// Whether the transaction comes before the range, within it or after it
+    pub fn locate(&self, tx: &Tx) -> Ordering {
+        let key = match self.by {
+            RangeKind::Sequence => tx.sequence,
+            RangeKind::Time => tx.timestamp,
+        };
+        if self.from.is_some_and(|from| key < from) {
+            Ordering::Less
+        } else if self.to.is_some_and(|to| key > to) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+}
+
+/// How a statement is exported
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// How a transaction changed the balance of one asset in the account
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct StatementLine {
+    pub id: u64,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub operation: String,
+    pub reference: Option<String>,
+    pub asset: Asset,
+    pub change: i128,
+    /// The balance of the asset after the change
+    pub balance: i128,
+}
+
+/// A trade the signer took part in along with the fee they paid on it
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    /// The id of the trade
+    pub trade: String,
+    pub timestamp: u64,
+    pub side: Side,
+    pub base: Asset,
+    pub quote: Asset,
+    pub amount: u64,
+    pub price: u64,
+    /// Charged in the asset the signer received, 0 if nothing was charged
+    pub fee: u64,
+}
+
+/// What happened to an account over a range of the transaction log:
+/// the balances it started and ended with and every change in between
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Statement {
+    pub signer: String,
+    pub range: StatementRange,
+    pub opening: BTreeMap<Asset, i128>,
+    pub lines: Vec<StatementLine>,
+    pub fills: Vec<Fill>,
+    /// Total fees paid within the range by asset
+    pub fees: BTreeMap<Asset, u64>,
+    pub closing: BTreeMap<Asset, i128>,
+}
+
+impl Statement {
+    /// Builds the statement of the signer from the transaction log, oldest transaction first.
+    /// Trade settlements are the fills, they are matched with the fees that reference them.
+    pub fn generate(signer: &str, range: StatementRange, txs: &[Tx]) -> Statement {
+        let mut balances: BTreeMap<Asset, i128> = BTreeMap::new();
+        let mut opening = None;
+        let mut lines = vec![];
+        let mut fills: Vec<Fill> = vec![];
+        let mut fees: BTreeMap<Asset, u64> = BTreeMap::new();
+
+        for tx in txs {
+            let changes = tx.kind.changes(signer);
+            match range.locate(tx) {
+                Ordering::Less => {
+                    for (asset, change) in changes {
+                        *balances.entry(asset).or_default() += change;
+                    }
+                }
+                Ordering::Equal => {
+                    opening.get_or_insert_with(|| balances.clone());
+                    for (asset, change) in changes {
+                        let balance = balances.entry(asset.clone()).or_default();
+                        *balance += change;
+                        lines.push(StatementLine {
+                            id: tx.id,
+                            sequence: tx.sequence,
+                            timestamp: tx.timestamp,
+                            operation: tx.kind.operation().to_string(),
+                            reference: tx.reference.clone(),
+                            asset,
+                            change,
+                            balance: *balance,
+                        });
+                    }
+                    match &tx.kind {
+                        TxKind::TradeSettlement {
+                            buyer,
+                            seller,
+                            base,
+                            quote,
+                            amount,
+                            price,
+                        } if buyer == signer || seller == signer => fills.push(Fill {
+                            trade: tx.reference.clone().unwrap_or_default(),
+                            timestamp: tx.timestamp,
+                            side: if buyer == signer {
+                                Side::Buy
+                            } else {
+                                Side::Sell
+                            },
+                            base: base.clone(),
+                            quote: quote.clone(),
+                            amount: *amount,
+                            price: *price,
+                            fee: 0,
+                        }),
+                        TxKind::Fee {
+                            account,
+                            asset,
+                            amount,
+                        } if account == signer => {
+                            *fees.entry(asset.clone()).or_default() += amount;
+                            if let Some(fill) = fills
+                                .iter_mut()
+                                .rev()
+                                .find(|fill| Some(&fill.trade) == tx.reference.as_ref())
+                            {
+                                fill.fee += amount;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Ordering::Greater => {}
+            }
+        }
+
+        Statement {
+            signer: signer.to_string(),
+            range,
+            opening: opening.unwrap_or_else(|| balances.clone()),
+            lines,
+            fills,
+            fees,
+            closing: balances,
+        }
+    }
+
+    /// The statement as comma separated values: the opening balances, a row for every line
+    /// and the closing balances, told apart by their operation
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            "id,sequence,timestamp,operation,reference,asset,change,balance\n".to_string();
+        for (asset, balance) in &self.opening {
+            let _ = writeln!(
+                csv,
+                ",,,OPENING,,{},,{}",
+                csv_field(&asset.to_string()),
+                balance
+            );
+        }
+        for line in &self.lines {
+            let reference = line.reference.as_deref().map_or(String::new(), csv_field);
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{}",
+                line.id,
+                line.sequence,
+                line.timestamp,
+                line.operation,
+                reference,
+                csv_field(&line.asset.to_string()),
+                line.change,
+                line.balance
+            );
+        }
+        for (asset, balance) in &self.closing {
+            let _ = writeln!(
+                csv,
+                ",,,CLOSING,,{},,{}",
+                csv_field(&asset.to_string()),
+                balance
+            );
+        }
+        csv
+    }
+}
+
+/// Quotes a field that holds a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    fn usd() -> Asset {
+        Asset::new("USD")
+    }
+
+    fn octo() -> Asset {
+        Asset::new("OCTO")
+    }
+
+    fn tx(id: u64, sequence: u64, reference: Option<&str>, kind: TxKind) -> Tx {
+        Tx {
+            id,
+            sequence,
+            timestamp: 1_000 * sequence,
+            reference: reference.map(str::to_string),
+            kind,
+        }
+    }
+
+    fn log() -> Vec<Tx> {
+        vec![
+            tx(
+                1,
+                1,
+                None,
+                TxKind::Deposit {
+                    account: "BOB".to_string(),
+                    asset: usd(),
+                    amount: 100,
+                },
+            ),
+            tx(
+                2,
+                2,
+                Some("OCTO/USD:2:1"),
+                TxKind::TradeSettlement {
+                    buyer: "BOB".to_string(),
+                    seller: "ALICE".to_string(),
+                    base: octo(),
+                    quote: usd(),
+                    amount: 3,
+                    price: 10,
+                },
+            ),
+            tx(
+                3,
+                2,
+                Some("OCTO/USD:2:1"),
+                TxKind::Fee {
+                    account: "BOB".to_string(),
+                    asset: octo(),
+                    amount: 1,
+                },
+            ),
+            tx(
+                4,
+                3,
+                Some("typo, reversed"),
+                TxKind::Adjustment {
+                    account: "BOB".to_string(),
+                    asset: usd(),
+                    amount: -5,
+                },
+            ),
+            tx(
+                5,
+                4,
+                None,
+                TxKind::Withdraw {
+                    account: "BOB".to_string(),
+                    asset: usd(),
+                    amount: 10,
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_Statement_balances_and_fills_over_a_range() {
+        let range = StatementRange {
+            by: RangeKind::Sequence,
+            from: Some(2),
+            to: Some(3),
+        };
+        let statement = Statement::generate("BOB", range, &log());
+
+        assert_eq!(statement.opening, BTreeMap::from([(usd(), 100)]));
+        let changes: Vec<(&str, &Asset, i128, i128)> = statement
+            .lines
+            .iter()
+            .map(|line| {
+                (
+                    line.operation.as_str(),
+                    &line.asset,
+                    line.change,
+                    line.balance,
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("TRADE", &octo(), 3, 3),
+                ("TRADE", &usd(), -30, 70),
+                ("FEE", &octo(), -1, 2),
+                ("ADJUSTMENT", &usd(), -5, 65),
+            ]
+        );
+        assert_eq!(
+            statement.fills,
+            vec![Fill {
+                trade: "OCTO/USD:2:1".to_string(),
+                timestamp: 2_000,
+                side: Side::Buy,
+                base: octo(),
+                quote: usd(),
+                amount: 3,
+                price: 10,
+                fee: 1,
+            }]
+        );
+        assert_eq!(statement.fees, BTreeMap::from([(octo(), 1)]));
+        // The withdrawal comes after the range
+        assert_eq!(
+            statement.closing,
+            BTreeMap::from([(octo(), 2), (usd(), 65)])
+        );
+    }
+
+    #[test]
+    fn test_Statement_by_time_and_as_csv() {
+        let range = StatementRange {
+            by: RangeKind::Time,
+            from: Some(2_500),
+            to: None,
+        };
+        let statement = Statement::generate("BOB", range, &log());
+        assert!(statement.fills.is_empty());
+
+        assert_eq!(
+            statement.to_csv(),
+            "id,sequence,timestamp,operation,reference,asset,change,balance\n\
+             ,,,OPENING,,OCTO,,2\n\
+             ,,,OPENING,,USD,,70\n\
+             4,3,3000,ADJUSTMENT,\"typo, reversed\",USD,-5,65\n\
+             5,4,4000,WITHDRAW,,USD,-10,55\n\
+             ,,,CLOSING,,OCTO,,2\n\
+             ,,,CLOSING,,USD,,55\n"
+        );
+    }
+}
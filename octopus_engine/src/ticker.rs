@@ -0,0 +1,190 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::types::Receipt;
+
+/// The rolling window covered by [`TickerStats`], 24 hours in milliseconds
+pub const TICKER_WINDOW: u64 = 24 * 60 * 60 * 1_000;
+
+/// A snapshot of the rolling statistics of a market
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct Ticker {
+    /// Price of the most recent trade
+    pub last_price: Option<u64>,
+    /// Highest traded price within the window
+    pub high: Option<u64>,
+    /// Lowest traded price within the window
+    pub low: Option<u64>,
+    /// Number of units traded within the window, capped at `u64::MAX`
+    pub volume: u64,
+    /// Sum of price * amount traded within the window, capped at `u64::MAX`
+    pub quote_volume: u64,
+    /// Volume weighted average price within the window
+    pub vwap: Option<u64>,
+    /// Difference between the last price and the first price of the window
+    pub price_change: i128,
+    /// `price_change` relative to the first price of the window
+    pub percent_change: f64,
+    /// Highest price in the bid book
+    pub best_bid: Option<u64>,
+    /// Lowest price in the ask book
+    pub best_ask: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Trade {
+    price: u64,
+    amount: u64,
+    timestamp: u64,
+}
+
+/// Keeps the executed trades of the last [`TICKER_WINDOW`] to compute a [`Ticker`]
+#[derive(Default, Debug, Clone)]
+pub struct TickerStats {
+    trades: VecDeque<Trade>,
+}
+
+impl TickerStats {
+    /// Returns an instance without any trades
+    pub fn new() -> Self {
+        TickerStats {
+            trades: VecDeque::new(),
+        }
+    }
+
+    /// Adds every match in the [`Receipt`] as a trade at the receipt's timestamp
+    pub fn record_receipt(&mut self, receipt: &Receipt) {
+        for po in &receipt.matches {
+            self.record_trade(po.price, po.amount, receipt.timestamp);
+        }
+        self.evict(receipt.timestamp);
+    }
+
+    /// Adds a single trade of `amount` units at `price`
+    pub fn record_trade(&mut self, price: u64, amount: u64, timestamp: u64) {
+        self.trades.push_back(Trade {
+            price,
+            amount,
+            timestamp,
+        });
+    }
+
+    /// Computes the statistics of the window ending at `now` together with the provided top of book
+    pub fn ticker(&mut self, now: u64, best_bid: Option<u64>, best_ask: Option<u64>) -> Ticker {
+        self.evict(now);
+
+        let first_price = self.trades.front().map(|t| t.price);
+        let last_price = self.trades.back().map(|t| t.price);
+        // Summed in u128 so the average is exact however much traded
+        let volume: u128 = self.trades.iter().map(|t| t.amount as u128).sum();
+        let quote_volume: u128 = self
+            .trades
+            .iter()
+            .map(|t| (t.price as u128).saturating_mul(t.amount as u128))
+            .fold(0, u128::saturating_add);
+
+        let (price_change, percent_change) = match (first_price, last_price) {
+            (Some(first), Some(last)) => {
+                let change = last as i128 - first as i128;
+                (change, change as f64 / first as f64 * 100.0)
+            }
+            _ => (0, 0.0),
+        };
+
+        Ticker {
+            last_price,
+            high: self.trades.iter().map(|t| t.price).max(),
+            low: self.trades.iter().map(|t| t.price).min(),
+            volume: u64::try_from(volume).unwrap_or(u64::MAX),
+            quote_volume: u64::try_from(quote_volume).unwrap_or(u64::MAX),
+            // never more than the highest price
+            vwap: quote_volume
+                .checked_div(volume)
+                .map(|vwap| u64::try_from(vwap).unwrap_or(u64::MAX)),
+            price_change,
+            percent_change,
+            best_bid,
+            best_ask,
+        }
+    }
+
+    /// Drops trades that fell out of the window ending at `now`
+    fn evict(&mut self, now: u64) {
+        let start = now.saturating_sub(TICKER_WINDOW);
+        while self.trades.front().is_some_and(|t| t.timestamp < start) {
+            self.trades.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+
+    #[test]
+    fn test_TickerStats_ticker_computes_statistics() {
+        let mut stats = TickerStats::new();
+
+        stats.record_trade(10, 2, 1_000);
+        stats.record_trade(14, 1, 2_000);
+        stats.record_trade(12, 1, 3_000);
+
+        assert_eq!(
+            stats.ticker(4_000, Some(11), Some(13)),
+            Ticker {
+                last_price: Some(12),
+                high: Some(14),
+                low: Some(10),
+                volume: 4,
+                quote_volume: 46,
+                vwap: Some(11),
+                price_change: 2,
+                percent_change: 20.0,
+                best_bid: Some(11),
+                best_ask: Some(13),
+            }
+        );
+    }
+
+    #[test]
+    fn test_TickerStats_ticker_evicts_trades_outside_window() {
+        let mut stats = TickerStats::new();
+
+        stats.record_trade(20, 5, 1_000);
+        stats.record_trade(10, 1, TICKER_WINDOW + 2_000);
+
+        let ticker = stats.ticker(TICKER_WINDOW + 2_000, None, None);
+        assert_eq!(ticker.last_price, Some(10));
+        assert_eq!(ticker.high, Some(10));
+        assert_eq!(ticker.volume, 1);
+        assert_eq!(ticker.price_change, 0);
+    }
+
+    #[test]
+    fn test_TickerStats_ticker_vwap_of_volumes_beyond_u64() {
+        let mut stats = TickerStats::new();
+
+        stats.record_trade(u64::MAX, 2, 1_000);
+        stats.record_trade(u64::MAX - 2, 2, 2_000);
+
+        let ticker = stats.ticker(3_000, None, None);
+        assert_eq!(ticker.volume, 4);
+        assert_eq!(ticker.quote_volume, u64::MAX);
+        assert_eq!(ticker.vwap, Some(u64::MAX - 1));
+    }
+
+    #[test]
+    fn test_TickerStats_ticker_without_trades() {
+        let mut stats = TickerStats::new();
+
+        let ticker = stats.ticker(1_000, None, Some(10));
+        assert_eq!(ticker.last_price, None);
+        assert_eq!(ticker.vwap, None);
+        assert_eq!(ticker.volume, 0);
+        assert_eq!(ticker.percent_change, 0.0);
+        assert_eq!(ticker.best_ask, Some(10));
+    }
+}
@@ -1,15 +1,35 @@
+use std::collections::{BTreeMap, HashMap};
+
 use crate::{
-    accounting::Accounts,
-    engine::MatchingEngine,
-    errors::AccountError,
-    tx::Tx,
-    types::{Order, PartialOrder, Receipt, Side},
+    accounting::{Account, Accounts, Balance, Mismatch},
+    candles::{Candle, Interval},
+    engine::{self, MatchingEngine},
+    errors::{AccountError, InvariantViolation},
+    idempotency::{IdempotencyKeys, Outcome, Request},
+    ledger::LedgerAccount,
+    margin::{MarginReport, MarginTerms},
+    market::{Books, Market},
+    matcher::Matcher,
+    positions::{MarkPrice, PositionReport, Positions},
+    statement::{Statement, StatementRange},
+    ticker::Ticker,
+    tx::{Tx, TxKind},
+    types::{
+        Asset, ConditionalOrder, Depth, GroupOrder, Instrument, Order, PartialOrder, PeggedOrder,
+        Receipt, TrailingStop,
+    },
 };
 
-pub struct TradingPlatform {
-    engine: MatchingEngine,
+/// Accounts trading through a [`Matcher`], the [`MatchingEngine`] unless another engine is plugged in
+pub struct TradingPlatform<M = MatchingEngine> {
+    /// The market traded on the platform
+    pub market: Market<M>,
     pub accounts: Accounts,
     pub transactions: Vec<Tx>,
+    /// The position of each signer built up by the trades settled on the platform
+    pub positions: Positions,
+    /// Recent requests and their outcomes by the idempotency key they were made with
+    pub idempotency: IdempotencyKeys,
 }
 
 impl Default for TradingPlatform {
@@ -20,100 +40,424 @@ impl Default for TradingPlatform {
 
 impl TradingPlatform {
     pub fn new() -> Self {
+        TradingPlatform::with_engine(MatchingEngine::new())
+    }
+}
+
+impl<M: Matcher> TradingPlatform<M> {
+    /// Creates a platform without any accounts that trades through the provided engine
+    pub fn with_engine(engine: M) -> Self {
         TradingPlatform {
-            engine: MatchingEngine::new(),
+            market: Market::with_engine(Instrument::default(), engine),
             accounts: Accounts::new(),
             transactions: Vec::new(),
+            positions: Positions::new(),
+            idempotency: IdempotencyKeys::default(),
         }
     }
 
     /// Fetches the complete order book at this time
     pub fn orderbook(&mut self) -> Vec<PartialOrder> {
-        self.engine.vectorised_orderbook()
+        self.market.orderbook()
+    }
+
+    /// Fetches the resting amount of each price level, best prices first
+    pub fn depth(&self) -> Depth {
+        self.market.depth()
+    }
+
+    /// Fetches the candles of an interval, oldest first
+    pub fn candles(&self, interval: Interval) -> Vec<Candle> {
+        self.market.candles(interval)
+    }
+
+    /// Discards the candles and rebuilds them from the engine's history
+    pub fn backfill_candles(&mut self) {
+        self.market.backfill_candles();
+    }
+
+    /// Computes the rolling 24h statistics of the market
+    pub fn ticker(&mut self) -> Ticker {
+        self.market.ticker()
+    }
+
+    /// Open an account for the signer, see [`Accounts::open`]
+    pub fn open(&mut self, signer: &str) -> Result<Account, AccountError> {
+        self.accounts.open(signer)
+    }
+
+    /// Open a sub-account of a parent account, which can act on it
+    pub fn open_sub_account(
+        &mut self,
+        parent: &str,
+        signer: &str,
+    ) -> Result<Account, AccountError> {
+        self.accounts.open_sub_account(parent, signer)
+    }
+
+    /// Fetch the sub-accounts of a user account
+    pub fn sub_accounts(&self, signer: &str) -> Result<Vec<Account>, AccountError> {
+        self.accounts.sub_accounts(signer)
+    }
+
+    /// Fetch the balances of a user account added up with those of every account below it
+    pub fn rollup(&self, signer: &str) -> Result<BTreeMap<Asset, Balance>, AccountError> {
+        self.accounts.rollup(signer)
+    }
+
+    /// Fetch the record of a user account
+    pub fn account(&self, signer: &str) -> Result<Account, AccountError> {
+        self.accounts.account(signer).cloned()
+    }
+
+    /// Freeze an account and cancel its resting orders along with their groups, releasing their funds.
+    /// Stop orders still waiting for their trigger are dropped if they trigger before the account is unfrozen.
+    pub fn freeze(&mut self, signer: &str) -> Result<Account, AccountError> {
+        let account = self.accounts.freeze(signer)?;
+        let (market, mut books) = self.split();
+        market.cancel_signer(signer, &mut books);
+        Ok(account)
+    }
+
+    /// Let a frozen account trade again
+    pub fn unfreeze(&mut self, signer: &str) -> Result<Account, AccountError> {
+        self.accounts.unfreeze(signer)
+    }
+
+    /// Close an account that has withdrawn everything
+    pub fn close(&mut self, signer: &str) -> Result<Account, AccountError> {
+        self.accounts.close(signer)
+    }
+
+    /// Fetch the positions of a user account with their unrealized PnL at the `mark` price of the market
+    pub fn positions(
+        &mut self,
+        signer: &str,
+        mark: MarkPrice,
+    ) -> Result<Vec<PositionReport>, AccountError> {
+        self.accounts.account(signer)?;
+        let mark_price = mark.of(&self.ticker());
+        Ok(self
+            .positions
+            .of(signer)
+            .iter()
+            .map(|(instrument, position)| PositionReport::new(instrument, position, mark_price))
+            .collect())
     }
 
-    /// Fetch total price of user account
-    pub fn balance_of(&mut self, signer: &str) -> Result<&u64, AccountError> {
-        self.accounts.balance_of(signer)
+    /// Fetch the statement of a user account over a range of the transaction log, see [`Statement::generate`]
+    pub fn statement(
+        &self,
+        signer: &str,
+        range: StatementRange,
+    ) -> Result<Statement, AccountError> {
+        self.accounts.account(signer)?;
+        Ok(Statement::generate(signer, range, &self.transactions))
     }
 
-    /// Deposit funds
-    pub fn deposit(&mut self, signer: &str, amount: u64) -> Result<Tx, AccountError> {
-        let operation: Result<Tx, AccountError> = self.accounts.deposit(signer, amount);
-        operation.map(|tx| {
-            self.transactions.push(tx.clone());
+    /// Let a user account trade on margin with the terms, see [`Accounts::set_margin`]
+    pub fn set_margin(
+        &mut self,
+        signer: &str,
+        terms: MarginTerms,
+    ) -> Result<MarginReport, AccountError> {
+        self.accounts.set_margin(signer, terms)
+    }
+
+    /// Fetch where a user account stands on margin at the last traded price
+    pub fn margin(&self, signer: &str) -> Result<MarginReport, AccountError> {
+        self.accounts.margin(signer)
+    }
+
+    /// Fetch every account in a margin call
+    pub fn margin_calls(&self) -> Vec<MarginReport> {
+        self.accounts.margin_calls()
+    }
+
+    /// Pay back what a user account borrowed of an asset
+    pub fn repay(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<Tx, AccountError> {
+        let operation: Result<Tx, AccountError> = self.accounts.repay(signer, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
 
-            tx
-        })
+    /// Fetch the balance of an asset in a user account
+    pub fn balance_of(&self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        self.accounts.balance_of(signer, asset)
     }
 
-    /// Withdraw funds
-    pub fn withdraw(&mut self, signer: &str, amount: u64) -> Result<Tx, AccountError> {
-        let operation: Result<Tx, AccountError> = self.accounts.withdraw(signer, amount);
-        operation.map(|tx| {
-            self.transactions.push(tx.clone());
+    /// Deposit funds of an asset
+    pub fn deposit(
+        &mut self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        let operation: Result<Tx, AccountError> = self.accounts.deposit(signer, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
 
-            tx
-        })
+    /// Withdraw funds of an asset
+    pub fn withdraw(
+        &mut self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        let operation: Result<Tx, AccountError> = self.accounts.withdraw(signer, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
     }
 
-    /// Transfer funds between sender and recipient
+    /// Transfer funds of an asset between sender and recipient
     pub fn send(
         &mut self,
         sender: &str,
         recipient: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        let operation: Result<Tx, AccountError> =
+            self.accounts.send(sender, recipient, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
+
+    /// Transfer funds of an asset between related accounts on behalf of the actor, see [`Accounts::transfer`]
+    pub fn transfer(
+        &mut self,
+        actor: &str,
+        sender: &str,
+        recipient: &str,
+        asset: &Asset,
         amount: u64,
-    ) -> Result<(Tx, Tx), AccountError> {
-        let operation: Result<(Tx, Tx), AccountError> =
-            self.accounts.send(sender, recipient, amount);
-        operation.map(|tx: (Tx, Tx)| {
-            // why clone ahhhh
-            // need to work out vec of structs ownership!!!
-            // 😭
-            // 😭
-            // 😭
-            let cloned_tx = tx.clone();
-            self.transactions.push(cloned_tx.0);
-            self.transactions.push(cloned_tx.1);
-
-            tx
-        })
-    }
-
-    /// Process a given order and apply the outcome to the accounts involved. Note that there are very few safeguards in place.
+    ) -> Result<Tx, AccountError> {
+        let operation: Result<Tx, AccountError> = self
+            .accounts
+            .transfer(actor, sender, recipient, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
+
+    /// Correct the balance of an asset, giving the reason for it
+    pub fn adjust(
+        &mut self,
+        signer: &str,
+        asset: &Asset,
+        amount: i64,
+        reason: &str,
+    ) -> Result<Tx, AccountError> {
+        let operation: Result<Tx, AccountError> =
+            self.accounts.adjust(signer, asset, amount, reason);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
+
+    /// Process a deposit, withdrawal, transfer or order once per idempotency key. A retry of a request that
+    /// succeeded returns its original outcome instead of being processed again, requests without a key always are.
+    pub fn process(&mut self, request: Request) -> Result<Outcome, AccountError> {
+        let key = request.idempotency_key().map(str::to_string);
+        let signer = request.signer().to_string();
+        if let Some(key) = &key {
+            if let Some(outcome) = self.idempotency.begin(key, &request, engine::timestamp())? {
+                return Ok(outcome);
+            }
+        }
+
+        let result = match request {
+            Request::Deposit(args) => self
+                .accounts
+                .ensure_authorized(args.actor(), &args.signer)
+                .and_then(|_| self.deposit(&args.signer, &args.asset, args.amount))
+                .map(Outcome::Tx),
+            Request::Withdraw(args) => self
+                .accounts
+                .ensure_authorized(args.actor(), &args.signer)
+                .and_then(|_| self.withdraw(&args.signer, &args.asset, args.amount))
+                .map(Outcome::Tx),
+            Request::Send(args) => self
+                .accounts
+                .ensure_authorized(args.actor(), &args.signer)
+                .and_then(|_| self.send(&args.signer, &args.recipient, &args.asset, args.amount))
+                .map(Outcome::Tx),
+            Request::Order(args) if args.market != self.market.instrument.symbol() => {
+                Err(AccountError::MarketNotFound(args.market))
+            }
+            Request::Order(args) => self
+                .accounts
+                .ensure_authorized(&args.signer, args.account())
+                .and_then(|_| self.submit_order(args.order()))
+                .map(Outcome::Receipt),
+        };
+        if let Some(key) = &key {
+            let outcome = result.as_ref().ok().cloned();
+            self.idempotency
+                .complete(&signer, key, outcome, engine::timestamp());
+        }
+        result
+    }
+
+    /// Process a given order and apply the outcome to the accounts involved, see [`Market::submit_order`].
+    /// Accounts its trades leave in a margin call are liquidated before it returns.
     pub fn submit_order(&mut self, order: Order) -> Result<Receipt, AccountError> {
-        let signer = &order.signer;
-
-        // 1. Check if signer has an account
-        let balance = self.balance_of(signer)?;
-
-        // 2. Check if buy order signer has enough money in account
-        let total_cost = order.amount * order.price;
-        balance
-            .checked_sub(total_cost)
-            .ok_or(AccountError::UnderFunded(signer.to_string()))?;
-
-        // 3. Process the order by the engine
-        let receipt = self.engine.process(order.clone())?;
-
-        match order.side {
-            // 4.If the order is BUY, send the total price to each of the matches
-            Side::Buy => {
-                for po in &receipt.matches {
-                    let total_realized = po.amount * po.price;
-                    self.send(signer, &po.signer, total_realized)?;
+        let (market, mut books) = self.split();
+        market.submit_order(order, &mut books)
+    }
+
+    /// Cancels a resting or stop order along with the siblings in its group and returns the cancelled ordinals
+    pub fn cancel_order(&mut self, ordinal: u64) -> Result<Vec<u64>, AccountError> {
+        let (market, mut books) = self.split();
+        market.cancel_order(ordinal, &mut books)
+    }
+
+    /// Places a one-cancels-other group, see [`Market::submit_oco`]. Returns the ordinals of the orders that were placed.
+    pub fn submit_oco(&mut self, orders: Vec<GroupOrder>) -> Result<Vec<u64>, AccountError> {
+        let (market, mut books) = self.split();
+        market.submit_oco(orders, &mut books)
+    }
+
+    /// Places an entry order with its take-profit and stop-loss exits, see [`Market::submit_bracket`]
+    pub fn submit_bracket(
+        &mut self,
+        entry: Order,
+        take_profit: u64,
+        stop_loss: u64,
+    ) -> Result<Receipt, AccountError> {
+        let (market, mut books) = self.split();
+        market.submit_bracket(entry, take_profit, stop_loss, &mut books)
+    }
+
+    /// Replays the transaction log into fresh accounts and reports every signer balance
+    /// that differs from the live accounts, see [`Accounts::reconcile`]
+    pub fn reconcile(&self) -> Result<Vec<Mismatch>, AccountError> {
+        self.accounts.reconcile(&self.transactions)
+    }
+
+    /// Verifies the invariants of the engine, that the balances of every asset add up to
+    /// its deposits minus its withdrawals in the transaction log, that the ledger's trial balance
+    /// balances and that the locked funds of every account are the holds of its resting orders
+    /// and never exceed its balance
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        self.market.engine.check_invariants()?;
+
+        // (deposits, withdrawals, balances) of every asset, adjustments, fees, loans and repayments count as
+        // deposits or withdrawals while transfers and trades only move funds between accounts
+        let mut totals: BTreeMap<&Asset, (u128, u128, u128)> = BTreeMap::new();
+        for tx in &self.transactions {
+            match &tx.kind {
+                TxKind::Deposit { asset, amount, .. } | TxKind::Borrow { asset, amount, .. } => {
+                    totals.entry(asset).or_default().0 += *amount as u128
                 }
-            }
-            // 5.If the order is SELL, send the total price from each of the matches
-            Side::Sell => {
-                for po in &receipt.matches {
-                    let total_realized = po.amount * po.price;
-                    self.send(&po.signer, signer, total_realized)?;
+                TxKind::Withdraw { asset, amount, .. }
+                | TxKind::Fee { asset, amount, .. }
+                | TxKind::Repay { asset, amount, .. } => {
+                    totals.entry(asset).or_default().1 += *amount as u128
                 }
+                TxKind::Adjustment { asset, amount, .. } if *amount < 0 => {
+                    totals.entry(asset).or_default().1 += amount.unsigned_abs() as u128
+                }
+                TxKind::Adjustment { asset, amount, .. } => {
+                    totals.entry(asset).or_default().0 += *amount as u128
+                }
+                TxKind::Transfer { .. }
+                | TxKind::TradeSettlement { .. }
+                | TxKind::Liquidation { .. }
+                | TxKind::Rejected { .. } => {}
+            }
+        }
+        for (account, asset, balance) in self.accounts.ledger.balances() {
+            if let LedgerAccount::User(_) = account {
+                totals.entry(asset).or_default().2 += balance as u128;
             }
         }
-        // 4. Return the receipt
-        Ok(receipt)
+
+        for (asset, (deposits, withdrawals, balances)) in totals {
+            if deposits != withdrawals + balances {
+                return Err(InvariantViolation::CashMismatch {
+                    asset: asset.clone(),
+                    deposits,
+                    withdrawals,
+                    balances,
+                });
+            }
+        }
+
+        for (asset, (debits, credits)) in self.accounts.ledger.trial_balance().totals() {
+            if debits != credits {
+                return Err(InvariantViolation::UnbalancedLedger {
+                    asset,
+                    debits,
+                    credits,
+                });
+            }
+        }
+
+        let mut held: HashMap<(String, Asset), u64> = HashMap::new();
+        for (ordinal, hold) in &self.market.holds {
+            if !self.market.engine.is_resting(*ordinal) {
+                return Err(InvariantViolation::StaleHold(*ordinal));
+            }
+            *held
+                .entry((hold.signer.clone(), hold.asset.clone()))
+                .or_default() += hold.amount;
+        }
+        // Holds of nothing don't have to be kept around
+        held.retain(|_, amount| *amount > 0);
+        let keys: Vec<&(String, Asset)> = held.keys().chain(self.accounts.holds.keys()).collect();
+        for (signer, asset) in keys {
+            let key = (signer.clone(), asset.clone());
+            let held = held.get(&key).copied().unwrap_or(0);
+            let locked = self.accounts.locked(signer, asset);
+            if held != locked {
+                return Err(InvariantViolation::HoldMismatch {
+                    signer: signer.clone(),
+                    asset: asset.clone(),
+                    held,
+                    locked,
+                });
+            }
+
+            let balance = self.accounts.balance_of(signer, asset).unwrap_or(0);
+            if locked > balance {
+                return Err(InvariantViolation::Overcommitted {
+                    signer: signer.clone(),
+                    asset: asset.clone(),
+                    balance,
+                    locked,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The market together with the accounts it settles with
+    fn split(&mut self) -> (&mut Market<M>, Books<'_>) {
+        let books = Books {
+            accounts: &mut self.accounts,
+            transactions: &mut self.transactions,
+            positions: &mut self.positions,
+        };
+        (&mut self.market, books)
+    }
+}
+
+impl TradingPlatform<MatchingEngine> {
+    /// Holds a [`TrailingStop`] until the price moves against it by its trail and returns its ordinal
+    pub fn submit_trailing_stop(&mut self, stop: TrailingStop) -> Result<u64, AccountError> {
+        let (market, mut books) = self.split();
+        market.submit_trailing_stop(stop, &mut books)
+    }
+
+    /// The current trigger price of a trailing stop that hasn't fired yet
+    pub fn trailing_stop_trigger(&self, ordinal: u64) -> Result<u64, AccountError> {
+        self.market.trailing_stop_trigger(ordinal)
+    }
+
+    /// Process a [`ConditionalOrder`] and apply the outcome to the accounts involved, see [`Market::submit_conditional`]
+    pub fn submit_conditional(&mut self, order: ConditionalOrder) -> Result<Receipt, AccountError> {
+        let (market, mut books) = self.split();
+        market.submit_conditional(order, &mut books)
+    }
+
+    /// Places a [`PeggedOrder`] at its pegged price, see [`Market::submit_pegged`]
+    pub fn submit_pegged(&mut self, pegged: PeggedOrder) -> Result<Receipt, AccountError> {
+        let (market, mut books) = self.split();
+        market.submit_pegged(pegged, &mut books)
     }
 }
 
@@ -122,10 +466,27 @@ mod tests {
     // reduce the warnings for naming tests
     #![allow(non_snake_case)]
 
-    use crate::types::{Order, Side};
+    use crate::types::{Condition, Execution, Level, Order, PegReference, Side, StopOrder, Trail};
+    use crate::{
+        accounting::AccountStatus,
+        fees::FeeSchedule,
+        liquidation::LiquidationStep,
+        margin::MarginStatus,
+        statement::RangeKind,
+        types::{DepositArgs, OrderArgs},
+    };
+    use proptest::prelude::*;
 
     use super::*;
 
+    fn usd() -> Asset {
+        Instrument::default().quote
+    }
+
+    fn octo() -> Asset {
+        Instrument::default().base
+    }
+
     #[test]
     fn test_TradingPlatform_order_requires_deposit_to_order() {
         let mut trading_platform = TradingPlatform::new();
@@ -139,17 +500,33 @@ mod tests {
             }),
             Err(AccountError::NotFound("ALICE".to_string()))
         );
-        assert!(trading_platform.engine.asks.is_empty());
-        assert!(trading_platform.engine.bids.is_empty());
+        assert!(trading_platform.market.engine.asks.is_empty());
+        assert!(trading_platform.market.engine.bids.is_empty());
     }
 
     #[test]
     fn test_TradingPlatform_order_partially_match_order_updates_accounts() {
         let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
 
         // Set up accounts
-        assert!(trading_platform.accounts.deposit("ALICE", 100).is_ok());
-        assert!(trading_platform.accounts.deposit("BOB", 100).is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
 
         let alice_receipt = trading_platform
             .submit_order(Order {
@@ -179,76 +556,112 @@ mod tests {
                 remaining: 0,
                 side: Side::Sell,
                 signer: "ALICE".to_string(),
-                ordinal: 1
+                ordinal: 1,
+                sequence: 1,
+                condition: None
             }]
         );
-        assert!(trading_platform.engine.asks.is_empty());
-        assert_eq!(trading_platform.engine.bids.len(), 1);
+        assert!(trading_platform.market.engine.asks.is_empty());
+        assert_eq!(trading_platform.market.engine.bids.len(), 1);
 
         // Check the account balances
-        assert_eq!(trading_platform.accounts.balance_of("ALICE"), Ok(&110));
-        assert_eq!(trading_platform.accounts.balance_of("BOB"), Ok(&90));
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(110)
+        );
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(90));
     }
 
     #[test]
-    fn test_TradingPlatform_order_fully_match_order_updates_accounts() {
+    fn test_TradingPlatform_sell_requires_base_asset() {
         let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        assert!(trading_platform.deposit("ALICE", &usd(), 1000).is_ok());
+        assert!(trading_platform.deposit("ALICE", &octo(), 2).is_ok());
 
-        // Set up accounts
-        assert!(trading_platform.accounts.deposit("ALICE", 100).is_ok());
-        assert!(trading_platform.accounts.deposit("BOB", 100).is_ok());
-
-        let alice_receipt = trading_platform
+        // Cash doesn't cover selling units that ALICE doesn't own
+        assert_eq!(
+            trading_platform.submit_order(Order {
+                price: 10,
+                amount: 3,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            }),
+            Err(AccountError::UnderFunded("ALICE".to_string()))
+        );
+        assert!(trading_platform
             .submit_order(Order {
                 price: 10,
                 amount: 2,
                 side: Side::Sell,
                 signer: "ALICE".to_string(),
             })
-            .unwrap();
-        assert_eq!(alice_receipt.matches, vec![]);
-        assert_eq!(alice_receipt.ordinal, 1);
+            .is_ok());
+    }
 
-        let bob_receipt = trading_platform
+    #[test]
+    fn test_TradingPlatform_resting_orders_lock_funds() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &usd(), 100).is_ok());
+        assert!(trading_platform.deposit("BOB", &octo(), 10).is_ok());
+
+        let bid = trading_platform
             .submit_order(Order {
                 price: 10,
-                amount: 2,
+                amount: 5,
                 side: Side::Buy,
-                signer: "BOB".to_string(),
+                signer: "ALICE".to_string(),
             })
             .unwrap();
+        assert_eq!(trading_platform.accounts.locked("ALICE", &usd()), 50);
 
+        // The locked funds can't pay for another order or be withdrawn
         assert_eq!(
-            bob_receipt.matches,
-            vec![PartialOrder {
+            trading_platform.submit_order(Order {
                 price: 10,
-                amount: 2,
-                remaining: 0,
-                side: Side::Sell,
+                amount: 6,
+                side: Side::Buy,
                 signer: "ALICE".to_string(),
-                ordinal: 1
-            }]
+            }),
+            Err(AccountError::UnderFunded("ALICE".to_string()))
+        );
+        assert_eq!(
+            trading_platform.withdraw("ALICE", &usd(), 60),
+            Err(AccountError::UnderFunded("ALICE".to_string()))
         );
 
-        // A fully matched order doesn't remain in the book
-        assert!(trading_platform.engine.asks.is_empty());
-        assert!(trading_platform.engine.bids.is_empty());
+        // A fill pays out of the hold and releases it by the same amount
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Sell,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+        assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(80));
+        assert_eq!(trading_platform.accounts.locked("ALICE", &usd()), 30);
+        assert_eq!(trading_platform.accounts.locked("BOB", &octo()), 0);
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
 
-        // Check the account balances
-        assert_eq!(trading_platform.accounts.balance_of("ALICE"), Ok(&120));
-        assert_eq!(trading_platform.accounts.balance_of("BOB"), Ok(&80));
+        assert!(trading_platform.cancel_order(bid.ordinal).is_ok());
+        assert_eq!(trading_platform.accounts.available("ALICE", &usd()), Ok(80));
+        assert!(trading_platform.market.holds.is_empty());
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
     }
 
     #[test]
-    fn test_TradingPlatform_order_fully_match_order_multi_match_updates_accounts() {
+    fn test_TradingPlatform_order_that_cannot_settle_changes_nothing() {
         let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 1).is_ok());
+        assert!(trading_platform.deposit("ALICE", &usd(), u64::MAX).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
 
-        // Set up accounts
-        assert!(trading_platform.accounts.deposit("ALICE", 100).is_ok());
-        assert!(trading_platform.accounts.deposit("BOB", 100).is_ok());
-        assert!(trading_platform.accounts.deposit("CHARLIE", 100).is_ok());
-
-        let alice_receipt = trading_platform
+        let ask = trading_platform
             .submit_order(Order {
                 price: 10,
                 amount: 1,
@@ -256,125 +669,631 @@ mod tests {
                 signer: "ALICE".to_string(),
             })
             .unwrap();
-        assert_eq!(alice_receipt.matches, vec![]);
-        assert_eq!(alice_receipt.ordinal, 1);
+        let orderbook = trading_platform.orderbook();
+        let transactions = trading_platform.transactions.len();
 
-        let charlie_receipt = trading_platform
+        // Paying ALICE would overflow her balance
+        assert_eq!(
+            trading_platform.submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            }),
+            Err(AccountError::OverFunded("ALICE".to_string(), 10))
+        );
+        assert_eq!(trading_platform.orderbook(), orderbook);
+        assert_eq!(trading_platform.transactions.len(), transactions);
+        assert_eq!(trading_platform.balance_of("ALICE", &octo()), Ok(1));
+        assert_eq!(trading_platform.accounts.locked("ALICE", &octo()), 1);
+        assert_eq!(trading_platform.accounts.available("BOB", &usd()), Ok(100));
+        assert_eq!(trading_platform.balance_of("BOB", &octo()), Ok(0));
+        assert!(trading_platform.market.holds.contains_key(&ask.ordinal));
+        assert_eq!(trading_platform.ticker().last_price, None);
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_TradingPlatform_margin_order_that_cannot_settle_repays_its_loan() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 1).is_ok());
+        assert!(trading_platform.deposit("ALICE", &usd(), u64::MAX).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 4).is_ok());
+        let terms = MarginTerms {
+            asset: usd(),
+            credit_limit: 100,
+            maintenance_bps: 2_500,
+        };
+        trading_platform.set_margin("BOB", terms).unwrap();
+        trading_platform
             .submit_order(Order {
                 price: 10,
                 amount: 1,
                 side: Side::Sell,
-                signer: "CHARLIE".to_string(),
+                signer: "ALICE".to_string(),
             })
             .unwrap();
-        assert_eq!(charlie_receipt.matches, vec![]);
-        assert_eq!(charlie_receipt.ordinal, 2);
 
-        let bob_receipt = trading_platform
-            .submit_order(Order {
+        // BOB borrows 6 to pay for the order, but paying ALICE would overflow
+        assert_eq!(
+            trading_platform.submit_order(Order {
                 price: 10,
-                amount: 2,
+                amount: 1,
                 side: Side::Buy,
                 signer: "BOB".to_string(),
-            })
-            .unwrap();
+            }),
+            Err(AccountError::OverFunded("ALICE".to_string(), 10))
+        );
+        let margin = trading_platform.margin("BOB").unwrap();
+        assert_eq!(margin.debt, 0);
+        assert_eq!(margin.buying_power, 104);
+        assert_eq!(trading_platform.balance_of("BOB", &usd()), Ok(4));
+        assert_eq!(trading_platform.accounts.available("BOB", &usd()), Ok(4));
+        let kinds: Vec<&str> = trading_platform
+            .transactions
+            .iter()
+            .map(|tx| tx.kind.operation())
+            .filter(|operation| ["BORROW", "REPAY"].contains(operation))
+            .collect();
+        assert_eq!(kinds, vec!["BORROW", "REPAY"]);
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_TradingPlatform_order_whose_value_overflows_is_rejected() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &usd(), u64::MAX).is_ok());
+        assert!(trading_platform.deposit("BOB", &octo(), u64::MAX).is_ok());
 
         assert_eq!(
-            bob_receipt.matches,
-            vec![
-                PartialOrder {
-                    price: 10,
-                    amount: 1,
-                    remaining: 0,
-                    side: Side::Sell,
-                    signer: "ALICE".to_string(),
-                    ordinal: 1
-                },
-                PartialOrder {
-                    price: 10,
-                    amount: 1,
-                    remaining: 0,
-                    side: Side::Sell,
-                    signer: "CHARLIE".to_string(),
-                    ordinal: 2
-                }
-            ]
+            trading_platform.submit_order(Order {
+                price: u64::MAX,
+                amount: 2,
+                side: Side::Buy,
+                signer: "ALICE".to_string(),
+            }),
+            Err(AccountError::ArithmeticOverflow)
         );
-        // A fully matched order doesn't remain in the book
-        assert!(trading_platform.engine.asks.is_empty());
-        assert!(trading_platform.engine.bids.is_empty());
-
-        // Check account balances
-        assert_eq!(trading_platform.accounts.balance_of("ALICE"), Ok(&110));
-        assert_eq!(trading_platform.accounts.balance_of("BOB"), Ok(&80));
-        assert_eq!(trading_platform.accounts.balance_of("CHARLIE"), Ok(&110));
+        assert_eq!(
+            trading_platform.submit_order(Order {
+                price: 2,
+                amount: u64::MAX,
+                side: Side::Sell,
+                signer: "BOB".to_string(),
+            }),
+            Err(AccountError::ArithmeticOverflow)
+        );
+        assert!(trading_platform.market.engine.asks.is_empty());
+        assert!(trading_platform.market.engine.bids.is_empty());
+        assert_eq!(
+            trading_platform.accounts.available("ALICE", &usd()),
+            Ok(u64::MAX)
+        );
+        assert_eq!(
+            trading_platform.accounts.available("BOB", &octo()),
+            Ok(u64::MAX)
+        );
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
     }
 
     #[test]
-    fn test_TradingPlatform_order_fully_match_order_no_self_match_updates_accounts() {
+    fn test_TradingPlatform_order_at_the_largest_value_settles() {
         let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), u64::MAX).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), u64::MAX).is_ok());
 
-        // Set up accounts
-        assert!(trading_platform.accounts.deposit("ALICE", 100).is_ok());
-        assert!(trading_platform.accounts.deposit("CHARLIE", 100).is_ok());
-
-        let alice_receipt = trading_platform
+        trading_platform
             .submit_order(Order {
-                price: 10,
-                amount: 1,
+                price: 1,
+                amount: u64::MAX,
                 side: Side::Sell,
                 signer: "ALICE".to_string(),
             })
             .unwrap();
-        assert_eq!(alice_receipt.matches, vec![]);
-        assert_eq!(alice_receipt.ordinal, 1);
+        let receipt = trading_platform
+            .submit_order(Order {
+                price: 1,
+                amount: u64::MAX,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
 
-        let charlie_receipt = trading_platform
+        assert_eq!(receipt.matches.len(), 1);
+        assert_eq!(trading_platform.balance_of("ALICE", &octo()), Ok(0));
+        assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(u64::MAX));
+        assert_eq!(trading_platform.balance_of("BOB", &octo()), Ok(u64::MAX));
+        assert_eq!(trading_platform.balance_of("BOB", &usd()), Ok(0));
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_TradingPlatform_order_settles_base_and_quote() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 5).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
+
+        trading_platform
             .submit_order(Order {
                 price: 10,
-                amount: 1,
+                amount: 5,
                 side: Side::Sell,
-                signer: "CHARLIE".to_string(),
+                signer: "ALICE".to_string(),
             })
             .unwrap();
-        assert_eq!(charlie_receipt.matches, vec![]);
-        assert_eq!(charlie_receipt.ordinal, 2);
-
-        let bob_receipt = trading_platform
+        trading_platform
             .submit_order(Order {
                 price: 10,
-                amount: 2,
+                amount: 3,
                 side: Side::Buy,
-                signer: "ALICE".to_string(),
+                signer: "BOB".to_string(),
             })
             .unwrap();
 
+        // The base moves from the seller to the buyer and the quote the other way
+        assert_eq!(trading_platform.balance_of("ALICE", &octo()), Ok(2));
+        assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(30));
+        assert_eq!(trading_platform.balance_of("BOB", &octo()), Ok(3));
+        assert_eq!(trading_platform.balance_of("BOB", &usd()), Ok(70));
+        // The trade is a single transaction referencing both orders
+        let tx = trading_platform.transactions.last().unwrap();
         assert_eq!(
-            bob_receipt.matches,
-            vec![PartialOrder {
+            tx.kind,
+            TxKind::TradeSettlement {
+                buyer: "BOB".to_string(),
+                seller: "ALICE".to_string(),
+                base: octo(),
+                quote: usd(),
+                amount: 3,
                 price: 10,
-                amount: 1,
+            }
+        );
+        assert_eq!(tx.reference, Some("OCTO/USD:2:1".to_string()));
+        assert_eq!(trading_platform.transactions.len(), 3);
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_TradingPlatform_order_pays_maker_and_taker_fees() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.market.fee_schedule = FeeSchedule::new(100, 200);
+        assert!(trading_platform.deposit("ALICE", &octo(), 100).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 10_000).is_ok());
+
+        let maker = trading_platform
+            .submit_order(Order {
+                price: 100,
+                amount: 50,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        assert_eq!(maker.fee, 0);
+        let taker = trading_platform
+            .submit_order(Order {
+                price: 100,
+                amount: 50,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        // The buyer took liquidity and pays 2% of the units, the seller made it and pays 1% of the price
+        assert_eq!(taker.fee, 1);
+        assert_eq!(trading_platform.balance_of("BOB", &octo()), Ok(49));
+        assert_eq!(trading_platform.balance_of("BOB", &usd()), Ok(5_000));
+        assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(4_950));
+        let ledger = &trading_platform.accounts.ledger;
+        assert_eq!(ledger.balance(&LedgerAccount::Revenue, &octo()), 1);
+        assert_eq!(ledger.balance(&LedgerAccount::Revenue, &usd()), 50);
+
+        let kinds: Vec<&TxKind> = trading_platform.transactions[2..]
+            .iter()
+            .map(|tx| &tx.kind)
+            .collect();
+        assert!(matches!(kinds[0], TxKind::TradeSettlement { .. }));
+        assert_eq!(
+            kinds[1..],
+            [
+                &TxKind::Fee {
+                    account: "BOB".to_string(),
+                    asset: octo(),
+                    amount: 1,
+                },
+                &TxKind::Fee {
+                    account: "ALICE".to_string(),
+                    asset: usd(),
+                    amount: 50,
+                },
+            ]
+        );
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_TradingPlatform_fee_tiers_follow_volume() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.open("CHARLIE").unwrap();
+        trading_platform.market.fee_schedule = FeeSchedule::new(0, 100)
+            .with_tier(1_000, 0, 50)
+            .with_override("CHARLIE", 0, 0);
+        assert!(trading_platform.deposit("ALICE", &usd(), 10_000).is_ok());
+        assert!(trading_platform.deposit("BOB", &octo(), 20).is_ok());
+        assert!(trading_platform.deposit("CHARLIE", &octo(), 10).is_ok());
+        trading_platform
+            .submit_order(Order {
+                price: 100,
+                amount: 30,
+                side: Side::Buy,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+
+        let sell = |signer: &str| Order {
+            price: 100,
+            amount: 10,
+            side: Side::Sell,
+            signer: signer.to_string(),
+        };
+        // 1% of 1000 before BOB has any volume, 0.5% once they traded 1000
+        assert_eq!(trading_platform.submit_order(sell("BOB")).unwrap().fee, 10);
+        assert_eq!(trading_platform.submit_order(sell("BOB")).unwrap().fee, 5);
+        assert_eq!(
+            trading_platform.submit_order(sell("CHARLIE")).unwrap().fee,
+            0
+        );
+
+        assert_eq!(trading_platform.market.volumes.get("BOB"), Some(&2_000));
+        assert_eq!(trading_platform.market.volumes.get("ALICE"), Some(&3_000));
+        assert_eq!(trading_platform.balance_of("BOB", &usd()), Ok(1_985));
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_TradingPlatform_reconcile_reports_unlogged_changes() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.open("CHARLIE").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 5).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 5,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 3,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+
+        // Changing the accounts behind the platform's back leaves no transaction
+        trading_platform.accounts.deposit("BOB", &usd(), 7).unwrap();
+        trading_platform
+            .accounts
+            .deposit("CHARLIE", &usd(), 1)
+            .unwrap();
+        assert_eq!(
+            trading_platform.reconcile(),
+            Ok(vec![
+                Mismatch {
+                    signer: "BOB".to_string(),
+                    asset: usd(),
+                    expected: 70,
+                    actual: 77,
+                },
+                Mismatch {
+                    signer: "CHARLIE".to_string(),
+                    asset: usd(),
+                    expected: 0,
+                    actual: 1,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_order_fully_match_order_updates_accounts() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+
+        let alice_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        assert_eq!(alice_receipt.matches, vec![]);
+        assert_eq!(alice_receipt.ordinal, 1);
+
+        let bob_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        assert_eq!(
+            bob_receipt.matches,
+            vec![PartialOrder {
+                price: 10,
+                amount: 2,
+                remaining: 0,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+                ordinal: 1,
+                sequence: 1,
+                condition: None
+            }]
+        );
+
+        // A fully matched order doesn't remain in the book
+        assert!(trading_platform.market.engine.asks.is_empty());
+        assert!(trading_platform.market.engine.bids.is_empty());
+
+        // Check the account balances
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(120)
+        );
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(80));
+    }
+
+    #[test]
+    fn test_TradingPlatform_order_fully_match_order_multi_match_updates_accounts() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.open("CHARLIE").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &octo(), 100)
+            .is_ok());
+
+        let alice_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        assert_eq!(alice_receipt.matches, vec![]);
+        assert_eq!(alice_receipt.ordinal, 1);
+
+        let charlie_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "CHARLIE".to_string(),
+            })
+            .unwrap();
+        assert_eq!(charlie_receipt.matches, vec![]);
+        assert_eq!(charlie_receipt.ordinal, 2);
+
+        let bob_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        assert_eq!(
+            bob_receipt.matches,
+            vec![
+                PartialOrder {
+                    price: 10,
+                    amount: 1,
+                    remaining: 0,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                    ordinal: 1,
+                    sequence: 1,
+                    condition: None
+                },
+                PartialOrder {
+                    price: 10,
+                    amount: 1,
+                    remaining: 0,
+                    side: Side::Sell,
+                    signer: "CHARLIE".to_string(),
+                    ordinal: 2,
+                    sequence: 2,
+                    condition: None
+                }
+            ]
+        );
+        // A fully matched order doesn't remain in the book
+        assert!(trading_platform.market.engine.asks.is_empty());
+        assert!(trading_platform.market.engine.bids.is_empty());
+
+        // Check account balances
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(110)
+        );
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(80));
+        assert_eq!(
+            trading_platform.accounts.balance_of("CHARLIE", &usd()),
+            Ok(110)
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_order_fully_match_order_no_self_match_updates_accounts() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("CHARLIE").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &octo(), 100)
+            .is_ok());
+
+        let alice_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        assert_eq!(alice_receipt.matches, vec![]);
+        assert_eq!(alice_receipt.ordinal, 1);
+
+        let charlie_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "CHARLIE".to_string(),
+            })
+            .unwrap();
+        assert_eq!(charlie_receipt.matches, vec![]);
+        assert_eq!(charlie_receipt.ordinal, 2);
+
+        let bob_receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Buy,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+
+        assert_eq!(
+            bob_receipt.matches,
+            vec![PartialOrder {
+                price: 10,
+                amount: 1,
                 remaining: 0,
                 side: Side::Sell,
                 signer: "CHARLIE".to_string(),
-                ordinal: 2
+                ordinal: 2,
+                sequence: 2,
+                condition: None
             }]
         );
         // A fully matched order doesn't remain in the book
-        assert_eq!(trading_platform.engine.asks.len(), 1);
-        assert_eq!(trading_platform.engine.bids.len(), 1);
+        assert_eq!(trading_platform.market.engine.asks.len(), 1);
+        assert_eq!(trading_platform.market.engine.bids.len(), 1);
         // Check account balances
-        assert_eq!(trading_platform.accounts.balance_of("ALICE"), Ok(&90));
-        assert_eq!(trading_platform.accounts.balance_of("CHARLIE"), Ok(&110));
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(90)
+        );
+        assert_eq!(
+            trading_platform.accounts.balance_of("CHARLIE", &usd()),
+            Ok(110)
+        );
     }
 
     #[test]
     fn test_TradingPlatform_order_no_match_updates_accounts() {
         let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
 
         // Set up accounts
-        assert!(trading_platform.accounts.deposit("ALICE", 100).is_ok());
-        assert!(trading_platform.accounts.deposit("BOB", 100).is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
 
         let alice_receipt = trading_platform
             .submit_order(Order {
@@ -400,7 +1319,1892 @@ mod tests {
         assert_eq!(trading_platform.orderbook().len(), 2);
 
         // Check the account balances
-        assert_eq!(trading_platform.accounts.balance_of("ALICE"), Ok(&100));
-        assert_eq!(trading_platform.accounts.balance_of("BOB"), Ok(&100));
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(100)
+        );
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(100));
+    }
+
+    #[test]
+    fn test_TradingPlatform_order_records_candles() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        assert!(trading_platform.candles(Interval::OneMinute).is_empty());
+
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        let candles = trading_platform.candles(Interval::OneMinute);
+        assert_eq!(candles.len(), 1);
+        assert_eq!(candles[0].close, 10);
+        assert_eq!(candles[0].volume, 2);
+        assert_eq!(candles[0].trades, 1);
+
+        // Rebuilding from the engine history gives the same bars
+        trading_platform.backfill_candles();
+        assert_eq!(trading_platform.candles(Interval::OneMinute), candles);
+    }
+
+    #[test]
+    fn test_TradingPlatform_ticker_tracks_trades_and_book() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        trading_platform
+            .submit_order(Order {
+                price: 12,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+        trading_platform
+            .submit_order(Order {
+                price: 8,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        let ticker = trading_platform.ticker();
+        assert_eq!(ticker.last_price, Some(10));
+        assert_eq!(ticker.volume, 1);
+        assert_eq!(ticker.quote_volume, 10);
+        assert_eq!(ticker.best_bid, Some(8));
+        assert_eq!(ticker.best_ask, Some(12));
+    }
+
+    #[test]
+    fn test_TradingPlatform_oco_fill_cancels_siblings() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+
+        let placed = trading_platform
+            .submit_oco(vec![
+                GroupOrder::Limit(Order {
+                    price: 12,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                }),
+                GroupOrder::Stop(StopOrder {
+                    order: Order {
+                        price: 9,
+                        amount: 1,
+                        side: Side::Sell,
+                        signer: "ALICE".to_string(),
+                    },
+                    trigger: 9,
+                }),
+            ])
+            .unwrap();
+        // Stops are held before limits are placed
+        assert_eq!(placed, vec![1, 2]);
+        assert_eq!(trading_platform.market.engine.stops.len(), 1);
+
+        trading_platform
+            .submit_order(Order {
+                price: 12,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        // The limit order filled so the stop was cancelled
+        assert!(trading_platform.market.engine.stops.is_empty());
+        assert!(trading_platform.market.engine.asks.is_empty());
+        assert_eq!(trading_platform.market.groups.group_of(1), None);
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(112)
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_oco_stop_trigger_cancels_siblings() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.open("CHARLIE").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &octo(), 100)
+            .is_ok());
+
+        let placed = trading_platform
+            .submit_oco(vec![
+                GroupOrder::Limit(Order {
+                    price: 12,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                }),
+                GroupOrder::Stop(StopOrder {
+                    order: Order {
+                        price: 9,
+                        amount: 1,
+                        side: Side::Sell,
+                        signer: "ALICE".to_string(),
+                    },
+                    trigger: 9,
+                }),
+            ])
+            .unwrap();
+        let (stop, limit) = (placed[0], placed[1]);
+
+        trading_platform
+            .submit_order(Order {
+                price: 9,
+                amount: 1,
+                side: Side::Sell,
+                signer: "CHARLIE".to_string(),
+            })
+            .unwrap();
+        trading_platform
+            .submit_order(Order {
+                price: 9,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        // The trade at 9 released the stop and cancelled the limit order
+        assert!(trading_platform.market.engine.stops.is_empty());
+        assert!(!trading_platform.market.engine.is_resting(limit));
+        assert_eq!(
+            trading_platform.cancel_order(stop),
+            Err(AccountError::OrderNotFound(stop))
+        );
+
+        // The released stop now rests in the book
+        let orderbook = trading_platform.orderbook();
+        assert_eq!(orderbook.len(), 1);
+        assert_eq!(orderbook[0].signer, "ALICE".to_string());
+        assert_eq!(orderbook[0].price, 9);
+    }
+
+    #[test]
+    fn test_TradingPlatform_cancel_order_cancels_siblings() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+
+        let placed = trading_platform
+            .submit_oco(vec![
+                GroupOrder::Limit(Order {
+                    price: 12,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                }),
+                GroupOrder::Limit(Order {
+                    price: 8,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "ALICE".to_string(),
+                }),
+            ])
+            .unwrap();
+        assert_eq!(trading_platform.orderbook().len(), 2);
+
+        assert_eq!(trading_platform.cancel_order(placed[1]), Ok(vec![2, 1]));
+        assert!(trading_platform.orderbook().is_empty());
+        assert_eq!(
+            trading_platform.cancel_order(placed[0]),
+            Err(AccountError::OrderNotFound(placed[0]))
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_oco_reserves_the_whole_group() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        assert!(trading_platform.deposit("ALICE", &usd(), 10).is_ok());
+
+        // Each order can be paid for on its own, but not both of them
+        assert_eq!(
+            trading_platform.submit_oco(vec![
+                GroupOrder::Limit(Order {
+                    price: 8,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "ALICE".to_string(),
+                }),
+                GroupOrder::Limit(Order {
+                    price: 5,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "ALICE".to_string(),
+                }),
+            ]),
+            Err(AccountError::UnderFunded("ALICE".to_string()))
+        );
+        assert!(trading_platform.orderbook().is_empty());
+        assert_eq!(trading_platform.accounts.available("ALICE", &usd()), Ok(10));
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_TradingPlatform_oco_that_fails_part_way_cancels_the_orders_placed() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 1).is_ok());
+        assert!(trading_platform.deposit("ALICE", &usd(), u64::MAX).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+
+        // The stop and the first limit order are placed, paying ALICE for the second would overflow
+        assert_eq!(
+            trading_platform.submit_oco(vec![
+                GroupOrder::Stop(StopOrder {
+                    order: Order {
+                        price: 20,
+                        amount: 1,
+                        side: Side::Buy,
+                        signer: "BOB".to_string(),
+                    },
+                    trigger: 20,
+                }),
+                GroupOrder::Limit(Order {
+                    price: 5,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                }),
+                GroupOrder::Limit(Order {
+                    price: 10,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                }),
+            ]),
+            Err(AccountError::OverFunded("ALICE".to_string(), 10))
+        );
+        assert!(trading_platform.market.engine.stops.is_empty());
+        let orderbook = trading_platform.orderbook();
+        assert_eq!(orderbook.len(), 1);
+        assert_eq!(orderbook[0].signer, "ALICE".to_string());
+        assert_eq!(trading_platform.accounts.available("BOB", &usd()), Ok(100));
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_TradingPlatform_bracket_places_exits_once_filled() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.open("CHARLIE").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("CHARLIE", &octo(), 100)
+            .is_ok());
+
+        let entry = trading_platform
+            .submit_bracket(
+                Order {
+                    price: 10,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                },
+                12,
+                8,
+            )
+            .unwrap();
+        // Nothing is placed while the entry rests
+        assert!(trading_platform.market.engine.is_resting(entry.ordinal));
+        assert!(trading_platform.market.engine.stops.is_empty());
+
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+
+        // The entry filled, so the take profit rests and the stop loss is held
+        let orderbook = trading_platform.orderbook();
+        assert_eq!(orderbook.len(), 1);
+        assert_eq!(orderbook[0].price, 12);
+        assert_eq!(orderbook[0].side, Side::Sell);
+        assert_eq!(trading_platform.market.engine.stops.len(), 1);
+
+        trading_platform
+            .submit_order(Order {
+                price: 12,
+                amount: 1,
+                side: Side::Buy,
+                signer: "CHARLIE".to_string(),
+            })
+            .unwrap();
+
+        // Taking profit cancelled the stop loss
+        assert!(trading_platform.orderbook().is_empty());
+        assert!(trading_platform.market.engine.stops.is_empty());
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(102));
+    }
+
+    #[test]
+    fn test_TradingPlatform_bracket_partial_fills_resize_exits_that_outlive_a_cancel() {
+        let mut trading_platform = TradingPlatform::new();
+        for signer in ["ALICE", "BOB", "CHARLIE"] {
+            trading_platform.open(signer).unwrap();
+            assert!(trading_platform.deposit(signer, &usd(), 100).is_ok());
+            assert!(trading_platform.deposit(signer, &octo(), 100).is_ok());
+        }
+
+        let entry = trading_platform
+            .submit_bracket(
+                Order {
+                    price: 10,
+                    amount: 3,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                },
+                12,
+                8,
+            )
+            .unwrap();
+        let sell = Order {
+            price: 10,
+            amount: 1,
+            side: Side::Sell,
+            signer: "ALICE".to_string(),
+        };
+
+        // Each fill grows the exits to what has filled so far
+        for filled in 1..=2 {
+            trading_platform.submit_order(sell.clone()).unwrap();
+            let take_profit: Vec<PartialOrder> = trading_platform
+                .orderbook()
+                .into_iter()
+                .filter(|po| po.side == Side::Sell)
+                .collect();
+            assert_eq!(take_profit.len(), 1);
+            assert_eq!(take_profit[0].price, 12);
+            assert_eq!(take_profit[0].remaining, filled);
+            let stops: Vec<&StopOrder> = trading_platform.market.engine.stops.values().collect();
+            assert_eq!(stops.len(), 1);
+            assert_eq!(stops[0].order.amount, filled);
+            assert_eq!(trading_platform.check_invariants(), Ok(()));
+        }
+
+        // The rest of the entry goes, the exits of what filled stay
+        assert_eq!(
+            trading_platform.cancel_order(entry.ordinal),
+            Ok(vec![entry.ordinal])
+        );
+        assert!(trading_platform.market.groups.bracket_entries().is_empty());
+        let orderbook = trading_platform.orderbook();
+        assert_eq!(orderbook.len(), 1);
+        assert_eq!(orderbook[0].remaining, 2);
+        assert_eq!(trading_platform.market.engine.stops.len(), 1);
+
+        trading_platform
+            .submit_order(Order {
+                price: 12,
+                amount: 2,
+                side: Side::Buy,
+                signer: "CHARLIE".to_string(),
+            })
+            .unwrap();
+        assert!(trading_platform.orderbook().is_empty());
+        assert!(trading_platform.market.engine.stops.is_empty());
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(104));
+        assert_eq!(
+            trading_platform.accounts.balance_of("BOB", &octo()),
+            Ok(100)
+        );
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_TradingPlatform_cancel_bracket_entry_discards_exits() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("BOB").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+
+        let entry = trading_platform
+            .submit_bracket(
+                Order {
+                    price: 10,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                },
+                12,
+                8,
+            )
+            .unwrap();
+
+        assert_eq!(
+            trading_platform.cancel_order(entry.ordinal),
+            Ok(vec![entry.ordinal])
+        );
+        assert!(trading_platform.market.groups.bracket_entries().is_empty());
+        assert!(trading_platform.orderbook().is_empty());
+        assert!(trading_platform.market.engine.stops.is_empty());
+    }
+
+    #[test]
+    fn test_TradingPlatform_bracket_exit_that_cannot_be_placed_is_logged() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.open("CHARLIE").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 1).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 10).is_ok());
+        assert!(trading_platform.deposit("CHARLIE", &usd(), 9).is_ok());
+        assert!(trading_platform
+            .deposit("CHARLIE", &octo(), u64::MAX)
+            .is_ok());
+        for (price, side, signer) in [(9, Side::Buy, "CHARLIE"), (10, Side::Sell, "ALICE")] {
+            trading_platform
+                .submit_order(Order {
+                    price,
+                    amount: 1,
+                    side,
+                    signer: signer.to_string(),
+                })
+                .unwrap();
+        }
+
+        // The entry fills straight away, the take profit would pay CHARLIE more OCTO than fits
+        trading_platform
+            .submit_bracket(
+                Order {
+                    price: 10,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                },
+                9,
+                5,
+            )
+            .unwrap();
+        assert_eq!(trading_platform.market.engine.stops.len(), 1);
+        assert_eq!(trading_platform.accounts.available("BOB", &octo()), Ok(1));
+        let rejected = trading_platform.transactions.last().unwrap();
+        assert_eq!(
+            rejected.kind,
+            TxKind::Rejected {
+                account: "BOB".to_string(),
+                order: Order {
+                    price: 9,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "BOB".to_string(),
+                },
+                reason: AccountError::OverFunded("CHARLIE".to_string(), 1),
+            }
+        );
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_TradingPlatform_trailing_stop_fires_market_order() {
+        let mut trading_platform = TradingPlatform::new();
+
+        // Set up accounts
+        for signer in ["ALICE", "BOB", "CHARLIE", "DAVE"] {
+            trading_platform.open(signer).unwrap();
+            assert!(trading_platform
+                .accounts
+                .deposit(signer, &usd(), 1000)
+                .is_ok());
+            assert!(trading_platform
+                .accounts
+                .deposit(signer, &octo(), 1000)
+                .is_ok());
+        }
+
+        let trade = |platform: &mut TradingPlatform, price: u64| {
+            platform
+                .submit_order(Order {
+                    price,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                })
+                .unwrap();
+            platform
+                .submit_order(Order {
+                    price,
+                    amount: 1,
+                    side: Side::Buy,
+                    signer: "BOB".to_string(),
+                })
+                .unwrap();
+        };
+
+        trade(&mut trading_platform, 100);
+        let stop = trading_platform
+            .submit_trailing_stop(TrailingStop {
+                order: Order {
+                    price: 0,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "CHARLIE".to_string(),
+                },
+                trail: Trail::Amount(5),
+                execution: Execution::Market,
+            })
+            .unwrap();
+        assert_eq!(trading_platform.trailing_stop_trigger(stop), Ok(95));
+
+        trading_platform
+            .submit_order(Order {
+                price: 104,
+                amount: 1,
+                side: Side::Buy,
+                signer: "DAVE".to_string(),
+            })
+            .unwrap();
+        trade(&mut trading_platform, 110);
+        assert_eq!(trading_platform.trailing_stop_trigger(stop), Ok(105));
+
+        trade(&mut trading_platform, 105);
+
+        // The stop fired and sold into the best bid
+        assert_eq!(
+            trading_platform.trailing_stop_trigger(stop),
+            Err(AccountError::OrderNotFound(stop))
+        );
+        assert!(trading_platform.orderbook().is_empty());
+        assert_eq!(
+            trading_platform.accounts.balance_of("CHARLIE", &usd()),
+            Ok(1104)
+        );
+        assert_eq!(
+            trading_platform.accounts.balance_of("DAVE", &usd()),
+            Ok(896)
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_triggered_stop_that_cannot_be_funded_is_logged() {
+        let mut trading_platform = TradingPlatform::new();
+        for signer in ["ALICE", "BOB", "CHARLIE"] {
+            trading_platform.open(signer).unwrap();
+            assert!(trading_platform.deposit(signer, &usd(), 1000).is_ok());
+            assert!(trading_platform.deposit(signer, &octo(), 1000).is_ok());
+        }
+        let trade = |platform: &mut TradingPlatform, price: u64| {
+            for (side, signer) in [(Side::Sell, "ALICE"), (Side::Buy, "BOB")] {
+                platform
+                    .submit_order(Order {
+                        price,
+                        amount: 1,
+                        side,
+                        signer: signer.to_string(),
+                    })
+                    .unwrap();
+            }
+        };
+
+        trade(&mut trading_platform, 100);
+        let order = Order {
+            price: 0,
+            amount: 1,
+            side: Side::Sell,
+            signer: "CHARLIE".to_string(),
+        };
+        trading_platform
+            .submit_trailing_stop(TrailingStop {
+                order: order.clone(),
+                trail: Trail::Amount(5),
+                execution: Execution::Market,
+            })
+            .unwrap();
+        // Stops don't set anything aside, so CHARLIE has nothing left to sell once it fires
+        assert!(trading_platform.withdraw("CHARLIE", &octo(), 1000).is_ok());
+
+        trade(&mut trading_platform, 95);
+        assert_eq!(
+            trading_platform.transactions.last().unwrap().kind,
+            TxKind::Rejected {
+                account: "CHARLIE".to_string(),
+                order,
+                reason: AccountError::UnderFunded("CHARLIE".to_string()),
+            }
+        );
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_TradingPlatform_trailing_stop_trails_at_most_the_whole_price() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 1).is_ok());
+
+        let stop = |bps| TrailingStop {
+            order: Order {
+                price: u64::MAX,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            },
+            trail: Trail::BasisPoints(bps),
+            execution: Execution::Market,
+        };
+        assert!(matches!(
+            trading_platform.submit_trailing_stop(stop(10_001)),
+            Err(AccountError::InvalidOrder(_))
+        ));
+        let ordinal = trading_platform.submit_trailing_stop(stop(10_000)).unwrap();
+        assert_eq!(trading_platform.trailing_stop_trigger(ordinal), Ok(0));
+        assert_eq!(Trail::BasisPoints(20_000).offset(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_TradingPlatform_pegged_order_settles_when_matched() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+
+        let pegged = PeggedOrder {
+            order: Order {
+                price: 0,
+                amount: 1,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            },
+            reference: PegReference::Primary,
+            offset: 1,
+            limit: None,
+        };
+        // Without a limit a pegged bid could move up to cost more than was reserved for it
+        assert!(matches!(
+            trading_platform.submit_pegged(pegged.clone()),
+            Err(AccountError::InvalidOrder(_))
+        ));
+        let pegged = PeggedOrder {
+            limit: Some(20),
+            ..pegged
+        };
+        assert_eq!(
+            trading_platform.submit_pegged(pegged.clone()),
+            Err(AccountError::NoReferencePrice)
+        );
+
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 1,
+                side: Side::Buy,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        let receipt = trading_platform.submit_pegged(pegged).unwrap();
+        assert!(receipt.matches.is_empty());
+        assert_eq!(trading_platform.accounts.available("BOB", &usd()), Ok(80));
+
+        // A better bid moves the pegged bid up to 16, still within what was reserved at the limit
+        trading_platform
+            .submit_order(Order {
+                price: 15,
+                amount: 1,
+                side: Side::Buy,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        assert!(trading_platform
+            .orderbook()
+            .iter()
+            .any(|po| po.signer == "BOB" && po.price == 16));
+        assert_eq!(trading_platform.accounts.available("BOB", &usd()), Ok(80));
+        trading_platform
+            .submit_order(Order {
+                price: 16,
+                amount: 1,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+
+        // The pegged bid at 16 was the best bid and got filled
+        assert_eq!(trading_platform.orderbook().len(), 2);
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(116)
+        );
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(84));
+        assert_eq!(trading_platform.accounts.available("BOB", &usd()), Ok(84));
+    }
+
+    #[test]
+    fn test_TradingPlatform_conditional_order_settles_only_complete_fills() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+
+        // Set up accounts
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("ALICE", &octo(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &usd(), 100)
+            .is_ok());
+        assert!(trading_platform
+            .accounts
+            .deposit("BOB", &octo(), 100)
+            .is_ok());
+
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+
+        let all_or_none = ConditionalOrder {
+            order: Order {
+                price: 10,
+                amount: 3,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            },
+            condition: Condition::AllOrNone,
+        };
+        let receipt = trading_platform.submit_conditional(all_or_none).unwrap();
+        assert!(receipt.matches.is_empty());
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(100));
+
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 3,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+
+        // The second sell was big enough to fill BOB's order completely
+        assert_eq!(
+            trading_platform.accounts.balance_of("ALICE", &usd()),
+            Ok(130)
+        );
+        assert_eq!(trading_platform.accounts.balance_of("BOB", &usd()), Ok(70));
+        assert_eq!(trading_platform.orderbook().len(), 1);
+    }
+
+    #[test]
+    fn test_TradingPlatform_positions_track_pnl() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 10).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
+        let order = |price, amount, side, signer: &str| Order {
+            price,
+            amount,
+            side,
+            signer: signer.to_string(),
+        };
+        trading_platform
+            .submit_order(order(10, 4, Side::Sell, "ALICE"))
+            .unwrap();
+        trading_platform
+            .submit_order(order(10, 4, Side::Buy, "BOB"))
+            .unwrap();
+        // BOB sells half back higher and leaves a bid below
+        trading_platform
+            .submit_order(order(14, 2, Side::Sell, "BOB"))
+            .unwrap();
+        trading_platform
+            .submit_order(order(14, 2, Side::Buy, "ALICE"))
+            .unwrap();
+        trading_platform
+            .submit_order(order(12, 1, Side::Buy, "BOB"))
+            .unwrap();
+        trading_platform
+            .submit_order(order(16, 1, Side::Sell, "ALICE"))
+            .unwrap();
+
+        let positions = trading_platform.positions("BOB", MarkPrice::Last).unwrap();
+        assert_eq!(
+            positions,
+            vec![PositionReport {
+                instrument: Instrument::default(),
+                net: 2,
+                average_price: Some(10),
+                realized_pnl: 8,
+                mark_price: Some(14),
+                unrealized_pnl: Some(8),
+            }]
+        );
+        let alice = trading_platform.positions("ALICE", MarkPrice::Mid).unwrap();
+        assert_eq!((alice[0].net, alice[0].realized_pnl), (-2, -8));
+        assert_eq!(alice[0].mark_price, Some(14));
+        assert_eq!(alice[0].unrealized_pnl, Some(-8));
+        assert_eq!(
+            trading_platform.positions("CHARLIE", MarkPrice::Last),
+            Err(AccountError::NotFound("CHARLIE".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_statement_covers_trades_and_fees() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.market.fee_schedule = FeeSchedule::new(0, 2_000);
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 10).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 5,
+                side: Side::Sell,
+                signer: "ALICE".to_string(),
+            })
+            .unwrap();
+        let receipt = trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 5,
+                side: Side::Buy,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+        assert!(trading_platform.withdraw("BOB", &usd(), 50).is_ok());
+
+        // From the trade on, leaving out the deposits
+        let range = StatementRange {
+            by: RangeKind::Sequence,
+            from: Some(3),
+            to: None,
+        };
+        let statement = trading_platform.statement("BOB", range).unwrap();
+        assert_eq!(statement.opening, BTreeMap::from([(usd(), 100)]));
+        assert_eq!(statement.fills.len(), 1);
+        assert_eq!(statement.fills[0].fee, receipt.fee);
+        assert_eq!(statement.fees, BTreeMap::from([(octo(), receipt.fee)]));
+        assert_eq!(statement.lines.len(), 4);
+        for (asset, balance) in &statement.closing {
+            assert_eq!(
+                trading_platform.balance_of("BOB", asset),
+                Ok(*balance as u64)
+            );
+        }
+        assert_eq!(
+            trading_platform.statement("CHARLIE", range),
+            Err(AccountError::NotFound("CHARLIE".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_retried_requests_are_processed_once() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        let deposit = |amount, key: &str| {
+            Request::Deposit(DepositArgs {
+                signer: "ALICE".to_string(),
+                amount,
+                asset: usd(),
+                idempotency_key: Some(key.to_string()),
+                actor: None,
+            })
+        };
+
+        let first = trading_platform.process(deposit(100, "deposit-1")).unwrap();
+        let retry = trading_platform.process(deposit(100, "deposit-1")).unwrap();
+        assert_eq!(first, retry);
+        assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(100));
+        assert_eq!(trading_platform.transactions.len(), 1);
+        assert_eq!(
+            trading_platform.process(deposit(50, "deposit-1")),
+            Err(AccountError::IdempotencyConflict("deposit-1".to_string()))
+        );
+
+        let order = OrderArgs {
+            signer: "ALICE".to_string(),
+            side: Side::Buy,
+            amount: 1,
+            price: 10,
+            market: "OCTO/USD".to_string(),
+            account: None,
+            idempotency_key: Some("order-1".to_string()),
+        };
+        let receipt = trading_platform
+            .process(Request::Order(order.clone()))
+            .unwrap();
+        assert_eq!(
+            trading_platform.process(Request::Order(order.clone())),
+            Ok(receipt)
+        );
+        assert_eq!(trading_platform.orderbook().len(), 1);
+        // Failed requests don't hold on to their key
+        let elsewhere = OrderArgs {
+            market: "INK/USD".to_string(),
+            account: None,
+            idempotency_key: Some("order-2".to_string()),
+            ..order
+        };
+        assert_eq!(
+            trading_platform.process(Request::Order(elsewhere.clone())),
+            Err(AccountError::MarketNotFound("INK/USD".to_string()))
+        );
+        let order = OrderArgs {
+            market: "OCTO/USD".to_string(),
+            ..elsewhere
+        };
+        assert!(trading_platform.process(Request::Order(order)).is_ok());
+        assert_eq!(trading_platform.orderbook().len(), 2);
+    }
+
+    #[test]
+    fn test_TradingPlatform_parents_trade_for_their_sub_accounts() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("DESK").unwrap();
+        trading_platform.open("BOB").unwrap();
+        trading_platform.open_sub_account("DESK", "DESK_A").unwrap();
+        trading_platform.open_sub_account("DESK", "DESK_B").unwrap();
+        assert!(trading_platform.deposit("DESK", &usd(), 100).is_ok());
+        assert!(trading_platform.deposit("BOB", &octo(), 2).is_ok());
+        trading_platform
+            .transfer("DESK", "DESK", "DESK_A", &usd(), 30)
+            .unwrap();
+        trading_platform
+            .submit_order(Order {
+                price: 10,
+                amount: 2,
+                side: Side::Sell,
+                signer: "BOB".to_string(),
+            })
+            .unwrap();
+
+        let order = |signer: &str, account: &str| {
+            Request::Order(OrderArgs {
+                signer: signer.to_string(),
+                side: Side::Buy,
+                amount: 1,
+                price: 10,
+                market: "OCTO/USD".to_string(),
+                account: Some(account.to_string()),
+                idempotency_key: None,
+            })
+        };
+        // The trade is attributed to the sub-account and paid out of it
+        assert!(trading_platform.process(order("DESK", "DESK_A")).is_ok());
+        assert_eq!(trading_platform.balance_of("DESK_A", &octo()), Ok(1));
+        assert_eq!(trading_platform.balance_of("DESK_A", &usd()), Ok(20));
+        assert_eq!(trading_platform.balance_of("DESK", &usd()), Ok(70));
+
+        assert_eq!(
+            trading_platform.process(order("DESK_A", "DESK")),
+            Err(AccountError::Unauthorized("DESK".to_string()))
+        );
+        assert_eq!(
+            trading_platform.process(order("DESK_A", "DESK_B")),
+            Err(AccountError::Unauthorized("DESK_B".to_string()))
+        );
+        assert_eq!(
+            trading_platform.transfer("DESK_A", "DESK", "DESK_A", &usd(), 10),
+            Err(AccountError::Unauthorized("DESK".to_string()))
+        );
+
+        let rollup = trading_platform.rollup("DESK").unwrap();
+        assert_eq!(rollup[&usd()].total, 90);
+        assert_eq!(rollup[&octo()].total, 1);
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_TradingPlatform_freeze_cancels_resting_orders() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &octo(), 10).is_ok());
+        assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
+        let sell = |amount| Order {
+            price: 10,
+            amount,
+            side: Side::Sell,
+            signer: "ALICE".to_string(),
+        };
+        trading_platform.submit_order(sell(4)).unwrap();
+        trading_platform.submit_order(sell(3)).unwrap();
+        assert_eq!(trading_platform.accounts.locked("ALICE", &octo()), 7);
+
+        let account = trading_platform.freeze("ALICE").unwrap();
+        assert_eq!(account.status, AccountStatus::Frozen);
+        assert!(trading_platform.orderbook().is_empty());
+        assert!(trading_platform.market.holds.is_empty());
+        assert_eq!(trading_platform.accounts.locked("ALICE", &octo()), 0);
+
+        // Frozen accounts neither trade nor withdraw, but can still be paid
+        let frozen = Err(AccountError::Frozen("ALICE".to_string()));
+        assert_eq!(trading_platform.submit_order(sell(1)), frozen);
+        assert_eq!(
+            trading_platform.withdraw("ALICE", &octo(), 1),
+            Err(AccountError::Frozen("ALICE".to_string()))
+        );
+        assert!(trading_platform.send("BOB", "ALICE", &usd(), 10).is_ok());
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+
+        trading_platform.unfreeze("ALICE").unwrap();
+        trading_platform.submit_order(sell(1)).unwrap();
+        assert_eq!(trading_platform.orderbook().len(), 1);
+    }
+
+    #[test]
+    fn test_TradingPlatform_close_requires_empty_account() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        assert!(trading_platform.deposit("ALICE", &usd(), 10).is_ok());
+
+        assert_eq!(
+            trading_platform.close("ALICE"),
+            Err(AccountError::NotEmpty("ALICE".to_string()))
+        );
+        assert!(trading_platform.withdraw("ALICE", &usd(), 10).is_ok());
+        let account = trading_platform.close("ALICE").unwrap();
+        assert_eq!(account.status, AccountStatus::Closed);
+        assert_eq!(trading_platform.account("ALICE"), Ok(account));
+        assert_eq!(
+            trading_platform.deposit("ALICE", &usd(), 10),
+            Err(AccountError::Closed("ALICE".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_TradingPlatform_buys_on_margin_until_a_margin_call() {
+        let mut trading_platform = TradingPlatform::new();
+        for signer in ["ALICE", "BOB", "CHARLIE"] {
+            trading_platform.open(signer).unwrap();
+        }
+        assert!(trading_platform.deposit("ALICE", &usd(), 50).is_ok());
+        assert!(trading_platform.deposit("BOB", &octo(), 15).is_ok());
+        assert!(trading_platform.deposit("CHARLIE", &octo(), 1).is_ok());
+        let order = |price, amount, side, signer: &str| Order {
+            price,
+            amount,
+            side,
+            signer: signer.to_string(),
+        };
+        trading_platform
+            .submit_order(order(10, 15, Side::Sell, "BOB"))
+            .unwrap();
+
+        assert_eq!(
+            trading_platform.submit_order(order(10, 15, Side::Buy, "ALICE")),
+            Err(AccountError::UnderFunded("ALICE".to_string()))
+        );
+        let terms = MarginTerms {
+            asset: usd(),
+            credit_limit: 200,
+            maintenance_bps: 2_500,
+        };
+        trading_platform.set_margin("ALICE", terms).unwrap();
+        trading_platform
+            .submit_order(order(10, 15, Side::Buy, "ALICE"))
+            .unwrap();
+
+        let margin = trading_platform.margin("ALICE").unwrap();
+        assert_eq!(margin.debt, 100);
+        assert_eq!(margin.usage_bps, 5_000);
+        assert_eq!(margin.buying_power, 100);
+        assert_eq!(margin.exposure, 150);
+        assert_eq!(margin.equity, 50);
+        assert_eq!(margin.status, MarginStatus::Healthy);
+        assert!(trading_platform.margin_calls().is_empty());
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+
+        // A trade at 8 values ALICE's units at 120, below the 25% of it they have to keep
+        trading_platform
+            .submit_order(order(8, 1, Side::Sell, "CHARLIE"))
+            .unwrap();
+        trading_platform
+            .submit_order(order(8, 1, Side::Buy, "BOB"))
+            .unwrap();
+        let margin = trading_platform.margin("ALICE").unwrap();
+        assert_eq!(margin.equity, 20);
+        assert_eq!(margin.maintenance_margin, 30);
+        assert_eq!(margin.status, MarginStatus::MarginCall);
+        assert_eq!(margin.buying_power, 0);
+        assert_eq!(trading_platform.margin_calls(), vec![margin]);
+        assert_eq!(
+            trading_platform.submit_order(order(8, 1, Side::Buy, "ALICE")),
+            Err(AccountError::UnderFunded("ALICE".to_string()))
+        );
+
+        // Paying down the debt restores the account
+        assert!(trading_platform.deposit("ALICE", &usd(), 60).is_ok());
+        assert!(trading_platform.repay("ALICE", &usd(), 60).is_ok());
+        let margin = trading_platform.margin("ALICE").unwrap();
+        assert_eq!(margin.debt, 40);
+        assert_eq!(margin.equity, 80);
+        assert_eq!(margin.status, MarginStatus::Healthy);
+        assert!(trading_platform.margin_calls().is_empty());
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_TradingPlatform_liquidates_accounts_in_a_margin_call() {
+        let mut trading_platform = TradingPlatform::new();
+        for signer in ["ALICE", "BOB", "CHARLIE", "DAVE"] {
+            trading_platform.open(signer).unwrap();
+        }
+        assert!(trading_platform.deposit("ALICE", &usd(), 50).is_ok());
+        assert!(trading_platform.deposit("BOB", &octo(), 15).is_ok());
+        assert!(trading_platform.deposit("CHARLIE", &octo(), 1).is_ok());
+        assert!(trading_platform.deposit("DAVE", &usd(), 200).is_ok());
+        let order = |price, amount, side, signer: &str| Order {
+            price,
+            amount,
+            side,
+            signer: signer.to_string(),
+        };
+        let terms = MarginTerms {
+            asset: usd(),
+            credit_limit: 200,
+            maintenance_bps: 2_500,
+        };
+        trading_platform.set_margin("ALICE", terms).unwrap();
+        trading_platform
+            .submit_order(order(10, 15, Side::Sell, "BOB"))
+            .unwrap();
+        trading_platform
+            .submit_order(order(10, 15, Side::Buy, "ALICE"))
+            .unwrap();
+        let resting = trading_platform
+            .submit_order(order(20, 5, Side::Sell, "ALICE"))
+            .unwrap();
+        trading_platform
+            .submit_order(order(8, 20, Side::Buy, "DAVE"))
+            .unwrap();
+
+        // The trade at 8 puts ALICE in a margin call: equity 20 against the 30 they have to keep
+        trading_platform
+            .submit_order(order(8, 1, Side::Sell, "CHARLIE"))
+            .unwrap();
+
+        // 5 units sold to DAVE at 8 repay 40 and leave equity 20 against the 20 the rest asks for
+        let margin = trading_platform.margin("ALICE").unwrap();
+        assert_eq!(margin.status, MarginStatus::Healthy);
+        assert_eq!(margin.debt, 60);
+        assert_eq!(margin.exposure, 80);
+        assert_eq!(margin.equity, 20);
+        assert_eq!(margin.maintenance_margin, 20);
+        assert!(trading_platform.margin_calls().is_empty());
+        assert_eq!(
+            trading_platform.accounts.available("ALICE", &octo()),
+            Ok(10)
+        );
+        assert!(!trading_platform
+            .orderbook()
+            .iter()
+            .any(|po| po.ordinal == resting.ordinal));
+
+        let steps: Vec<LiquidationStep> = trading_platform
+            .transactions
+            .iter()
+            .filter_map(|tx| match &tx.kind {
+                TxKind::Liquidation { account, step } if account == "ALICE" => Some(step.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(steps.len(), 4);
+        assert_eq!(
+            steps[0],
+            LiquidationStep::Started {
+                equity: 20,
+                maintenance_margin: 30
+            }
+        );
+        assert_eq!(
+            steps[1],
+            LiquidationStep::OrdersCancelled {
+                ordinals: vec![resting.ordinal]
+            }
+        );
+        assert!(matches!(
+            steps[2],
+            LiquidationStep::Sold {
+                amount: 5,
+                filled: 5,
+                ..
+            }
+        ));
+        assert_eq!(
+            steps[3],
+            LiquidationStep::Finished {
+                equity: 20,
+                maintenance_margin: 20,
+                status: MarginStatus::Healthy
+            }
+        );
+        assert!(trading_platform.transactions.iter().any(|tx| matches!(
+            &tx.kind,
+            TxKind::Repay { account, amount: 40, .. } if account == "ALICE"
+        )));
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+        assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_TradingPlatform_check_invariants_finds_unrecorded_cash() {
+        let mut trading_platform = TradingPlatform::new();
+        trading_platform.open("ALICE").unwrap();
+        trading_platform.open("BOB").unwrap();
+        assert!(trading_platform.deposit("ALICE", &usd(), 100).is_ok());
+        assert!(trading_platform.withdraw("ALICE", &usd(), 30).is_ok());
+        assert_eq!(trading_platform.check_invariants(), Ok(()));
+
+        // Bypassing the platform leaves the deposit out of the transaction log
+        assert!(trading_platform.accounts.deposit("BOB", &usd(), 5).is_ok());
+        assert_eq!(
+            trading_platform.check_invariants(),
+            Err(InvariantViolation::CashMismatch {
+                asset: usd(),
+                deposits: 100,
+                withdrawals: 30,
+                balances: 75
+            })
+        );
+    }
+
+    /// Anything a user can do on the platform
+    #[derive(Clone, Debug)]
+    enum Operation {
+        Deposit(&'static str, Asset, u64),
+        Withdraw(&'static str, Asset, u64),
+        Send(&'static str, &'static str, Asset, u64),
+        Adjust(&'static str, Asset, i64),
+        Order(Order),
+        Conditional(ConditionalOrder),
+        Pegged(PeggedOrder),
+        TrailingStop(TrailingStop),
+        Oco(Order, StopOrder),
+        Bracket(Order),
+        Cancel(u64),
+        Freeze(&'static str),
+        Unfreeze(&'static str),
+    }
+
+    fn any_signer() -> impl Strategy<Value = &'static str> {
+        prop::sample::select(vec!["ALICE", "BOB", "CHARLIE"])
+    }
+
+    fn any_asset() -> impl Strategy<Value = Asset> {
+        prop::sample::select(vec![usd(), octo()])
+    }
+
+    fn any_order() -> impl Strategy<Value = Order> {
+        (
+            1..20u64,
+            1..10u64,
+            prop_oneof![Just(Side::Buy), Just(Side::Sell)],
+            any_signer(),
+        )
+            .prop_map(|(price, amount, side, signer)| Order {
+                price,
+                amount,
+                side,
+                signer: signer.to_string(),
+            })
+    }
+
+    fn any_operation() -> impl Strategy<Value = Operation> {
+        prop_oneof![
+            3 => (any_signer(), any_asset(), 0..200u64)
+                .prop_map(|(s, asset, a)| Operation::Deposit(s, asset, a)),
+            1 => (any_signer(), any_asset(), 0..100u64)
+                .prop_map(|(s, asset, a)| Operation::Withdraw(s, asset, a)),
+            1 => (any_signer(), any_signer(), any_asset(), 0..100u64)
+                .prop_map(|(s, r, asset, a)| Operation::Send(s, r, asset, a)),
+            1 => (any_signer(), any_asset(), -50..50i64)
+                .prop_map(|(s, asset, a)| Operation::Adjust(s, asset, a)),
+            6 => any_order().prop_map(Operation::Order),
+            1 => (any_order(), 1..5u64).prop_map(|(order, minimum)| {
+                Operation::Conditional(ConditionalOrder {
+                    order,
+                    condition: Condition::MinimumQuantity(minimum),
+                })
+            }),
+            1 => any_order().prop_map(|order| Operation::Pegged(PeggedOrder {
+                order,
+                reference: PegReference::Primary,
+                offset: 0,
+                limit: None,
+            })),
+            1 => (any_order(), 1..4u64).prop_map(|(order, trail)| {
+                Operation::TrailingStop(TrailingStop {
+                    order,
+                    trail: Trail::Amount(trail),
+                    execution: Execution::Market,
+                })
+            }),
+            1 => (any_order(), 1..20u64).prop_map(|(order, trigger)| {
+                let stop = StopOrder {
+                    order: order.clone(),
+                    trigger,
+                };
+                Operation::Oco(order, stop)
+            }),
+            1 => any_order().prop_map(Operation::Bracket),
+            2 => (1..60u64).prop_map(Operation::Cancel),
+            1 => any_signer().prop_map(Operation::Freeze),
+            1 => any_signer().prop_map(Operation::Unfreeze),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_TradingPlatform_check_invariants_hold_for_any_sequence(
+            operations in prop::collection::vec(any_operation(), 1..80)
+        ) {
+            let mut trading_platform = TradingPlatform::new();
+            for signer in ["ALICE", "BOB", "CHARLIE"] {
+                trading_platform.open(signer).unwrap();
+            }
+            // High enough to charge something on the small trades generated
+            trading_platform.market.fee_schedule = FeeSchedule::new(500, 1_000)
+                .with_tier(100, 250, 500)
+                .with_override("CHARLIE", 0, 0);
+
+            for operation in operations {
+                // Rejected operations must leave the platform as consistent as accepted ones
+                let _ = match operation {
+                    Operation::Deposit(signer, asset, amount) => {
+                        trading_platform.deposit(signer, &asset, amount).map(|_| ())
+                    }
+                    Operation::Withdraw(signer, asset, amount) => {
+                        trading_platform.withdraw(signer, &asset, amount).map(|_| ())
+                    }
+                    Operation::Send(sender, recipient, asset, amount) => {
+                        trading_platform.send(sender, recipient, &asset, amount).map(|_| ())
+                    }
+                    Operation::Adjust(signer, asset, amount) => trading_platform
+                        .adjust(signer, &asset, amount, "correction")
+                        .map(|_| ()),
+                    Operation::Order(order) => trading_platform.submit_order(order).map(|_| ()),
+                    Operation::Conditional(order) => {
+                        trading_platform.submit_conditional(order).map(|_| ())
+                    }
+                    Operation::Pegged(pegged) => trading_platform.submit_pegged(pegged).map(|_| ()),
+                    Operation::TrailingStop(stop) => {
+                        trading_platform.submit_trailing_stop(stop).map(|_| ())
+                    }
+                    Operation::Oco(order, stop) => trading_platform
+                        .submit_oco(vec![GroupOrder::Limit(order), GroupOrder::Stop(stop)])
+                        .map(|_| ()),
+                    Operation::Bracket(entry) => {
+                        let (take_profit, stop_loss) = match entry.side {
+                            Side::Buy => (entry.price + 2, entry.price.saturating_sub(2)),
+                            Side::Sell => (entry.price.saturating_sub(2), entry.price + 2),
+                        };
+                        trading_platform
+                            .submit_bracket(entry, take_profit, stop_loss)
+                            .map(|_| ())
+                    }
+                    Operation::Cancel(ordinal) => {
+                        trading_platform.cancel_order(ordinal).map(|_| ())
+                    }
+                    Operation::Freeze(signer) => trading_platform.freeze(signer).map(|_| ()),
+                    Operation::Unfreeze(signer) => trading_platform.unfreeze(signer).map(|_| ()),
+                };
+
+                prop_assert_eq!(trading_platform.check_invariants(), Ok(()));
+            }
+            // The transaction log adds up to the same balances
+            prop_assert_eq!(trading_platform.reconcile(), Ok(vec![]));
+        }
+    }
+
+    /// The simplest possible [`Matcher`], a single list of orders matched by price and then time.
+    /// It only implements the required methods so the suite covers the defaults of the trait as well.
+    #[derive(Default, Clone)]
+    struct ListBook {
+        ordinal: u64,
+        orders: Vec<PartialOrder>,
+    }
+
+    impl Matcher for ListBook {
+        fn process(&mut self, order: Order) -> Result<Receipt, AccountError> {
+            self.ordinal += 1;
+            let amount = order.amount;
+            let mut taker = order.into_partial_order(self.ordinal, amount);
+
+            let mut matches = vec![];
+            while taker.remaining > 0 {
+                let best = self
+                    .orders
+                    .iter_mut()
+                    .filter(|po| po.remaining > 0 && po.side != taker.side)
+                    .filter(|po| po.signer != taker.signer)
+                    .filter(|po| match taker.side {
+                        Side::Buy => po.price <= taker.price,
+                        Side::Sell => po.price >= taker.price,
+                    })
+                    .min_by_key(|po| match taker.side {
+                        Side::Buy => (po.price, po.sequence),
+                        Side::Sell => (u64::MAX - po.price, po.sequence),
+                    });
+                let Some(maker) = best else {
+                    break;
+                };
+
+                let take = maker.remaining.min(taker.remaining);
+                taker.remaining -= take;
+                matches.push(PartialOrder::take_from(maker, take));
+            }
+            self.orders.retain(|po| po.remaining > 0);
+
+            if taker.remaining > 0 {
+                self.orders.push(taker);
+            }
+            Ok(Receipt {
+                ordinal: self.ordinal,
+                timestamp: engine::timestamp(),
+                matches,
+                fee: 0,
+            })
+        }
+
+        fn cancel(&mut self, ordinal: u64) -> Option<PartialOrder> {
+            let index = self.orders.iter().position(|po| po.ordinal == ordinal)?;
+            Some(self.orders.remove(index))
+        }
+
+        fn snapshot(&self) -> Vec<PartialOrder> {
+            self.orders.clone()
+        }
+    }
+
+    /// Platform behaviour that doesn't depend on the engine, run once for every [`Matcher`]
+    macro_rules! matcher_suite {
+        ($suite:ident, $engine:ty) => {
+            mod $suite {
+                use super::*;
+
+                fn platform() -> TradingPlatform<$engine> {
+                    let mut trading_platform = TradingPlatform::with_engine(<$engine>::default());
+                    trading_platform.open("ALICE").unwrap();
+                    trading_platform.open("BOB").unwrap();
+                    assert!(trading_platform.deposit("ALICE", &usd(), 100).is_ok());
+                    assert!(trading_platform.deposit("ALICE", &octo(), 100).is_ok());
+                    assert!(trading_platform.deposit("BOB", &usd(), 100).is_ok());
+                    assert!(trading_platform.deposit("BOB", &octo(), 100).is_ok());
+                    trading_platform
+                }
+
+                fn order(price: u64, amount: u64, side: Side, signer: &str) -> Order {
+                    Order {
+                        price,
+                        amount,
+                        side,
+                        signer: signer.to_string(),
+                    }
+                }
+
+                #[test]
+                fn test_TradingPlatform_order_requires_deposit_to_order() {
+                    let mut trading_platform = platform();
+
+                    assert_eq!(
+                        trading_platform.submit_order(order(10, 1, Side::Buy, "CHARLIE")),
+                        Err(AccountError::NotFound("CHARLIE".to_string()))
+                    );
+                    assert_eq!(
+                        trading_platform.submit_order(order(10, 11, Side::Buy, "BOB")),
+                        Err(AccountError::UnderFunded("BOB".to_string()))
+                    );
+                    assert!(trading_platform.orderbook().is_empty());
+                }
+
+                #[test]
+                fn test_TradingPlatform_partial_match_rests_remainder() {
+                    let mut trading_platform = platform();
+
+                    trading_platform
+                        .submit_order(order(10, 1, Side::Sell, "ALICE"))
+                        .unwrap();
+                    let receipt = trading_platform
+                        .submit_order(order(10, 3, Side::Buy, "BOB"))
+                        .unwrap();
+
+                    assert_eq!(receipt.matches.len(), 1);
+                    assert_eq!(
+                        trading_platform.depth(),
+                        Depth {
+                            bids: vec![Level {
+                                price: 10,
+                                amount: 2,
+                                orders: 1
+                            }],
+                            asks: vec![],
+                        }
+                    );
+                    assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(110));
+                    assert_eq!(trading_platform.balance_of("BOB", &usd()), Ok(90));
+                }
+
+                #[test]
+                fn test_TradingPlatform_depth_caps_levels_at_u64_max() {
+                    let mut trading_platform = platform();
+                    for signer in ["CHARLIE", "DAVE"] {
+                        trading_platform.open(signer).unwrap();
+                        assert!(trading_platform.deposit(signer, &octo(), u64::MAX).is_ok());
+                        trading_platform
+                            .submit_order(order(1, u64::MAX, Side::Sell, signer))
+                            .unwrap();
+                    }
+
+                    assert_eq!(
+                        trading_platform.depth(),
+                        Depth {
+                            bids: vec![],
+                            asks: vec![Level {
+                                price: 1,
+                                amount: u64::MAX,
+                                orders: 2
+                            }],
+                        }
+                    );
+                }
+
+                #[test]
+                fn test_TradingPlatform_order_sweeps_best_prices_first() {
+                    let mut trading_platform = platform();
+
+                    trading_platform
+                        .submit_order(order(12, 1, Side::Sell, "ALICE"))
+                        .unwrap();
+                    trading_platform
+                        .submit_order(order(11, 1, Side::Sell, "ALICE"))
+                        .unwrap();
+                    trading_platform
+                        .submit_order(order(13, 1, Side::Sell, "ALICE"))
+                        .unwrap();
+
+                    let receipt = trading_platform
+                        .submit_order(order(12, 2, Side::Buy, "BOB"))
+                        .unwrap();
+
+                    let prices: Vec<u64> = receipt.matches.iter().map(|po| po.price).collect();
+                    assert_eq!(prices, vec![11, 12]);
+                    assert_eq!(trading_platform.depth().asks[0].price, 13);
+                    assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(123));
+                    assert_eq!(trading_platform.balance_of("BOB", &usd()), Ok(77));
+                    assert_eq!(trading_platform.check_invariants(), Ok(()));
+                }
+
+                #[test]
+                fn test_TradingPlatform_order_no_self_match() {
+                    let mut trading_platform = platform();
+
+                    trading_platform
+                        .submit_order(order(10, 1, Side::Sell, "ALICE"))
+                        .unwrap();
+                    let receipt = trading_platform
+                        .submit_order(order(10, 1, Side::Buy, "ALICE"))
+                        .unwrap();
+
+                    assert!(receipt.matches.is_empty());
+                    assert_eq!(trading_platform.orderbook().len(), 2);
+                    assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(100));
+                }
+
+                #[test]
+                fn test_TradingPlatform_depth_aggregates_levels() {
+                    let mut trading_platform = platform();
+
+                    trading_platform
+                        .submit_order(order(9, 1, Side::Buy, "BOB"))
+                        .unwrap();
+                    trading_platform
+                        .submit_order(order(10, 2, Side::Buy, "BOB"))
+                        .unwrap();
+                    trading_platform
+                        .submit_order(order(10, 3, Side::Buy, "BOB"))
+                        .unwrap();
+                    trading_platform
+                        .submit_order(order(12, 1, Side::Sell, "ALICE"))
+                        .unwrap();
+
+                    let depth = trading_platform.depth();
+                    assert_eq!(
+                        depth.bids,
+                        vec![
+                            Level {
+                                price: 10,
+                                amount: 5,
+                                orders: 2
+                            },
+                            Level {
+                                price: 9,
+                                amount: 1,
+                                orders: 1
+                            },
+                        ]
+                    );
+                    assert_eq!(depth.asks.len(), 1);
+
+                    let ticker = trading_platform.ticker();
+                    assert_eq!(ticker.best_bid, Some(10));
+                    assert_eq!(ticker.best_ask, Some(12));
+                }
+
+                #[test]
+                fn test_TradingPlatform_cancel_order_removes_order() {
+                    let mut trading_platform = platform();
+
+                    let receipt = trading_platform
+                        .submit_order(order(10, 1, Side::Sell, "ALICE"))
+                        .unwrap();
+
+                    assert_eq!(
+                        trading_platform.cancel_order(receipt.ordinal),
+                        Ok(vec![receipt.ordinal])
+                    );
+                    assert!(trading_platform.orderbook().is_empty());
+                    assert_eq!(
+                        trading_platform.cancel_order(receipt.ordinal),
+                        Err(AccountError::OrderNotFound(receipt.ordinal))
+                    );
+                }
+
+                #[test]
+                fn test_TradingPlatform_oco_fill_cancels_limit_siblings() {
+                    let mut trading_platform = platform();
+
+                    let placed = trading_platform
+                        .submit_oco(vec![
+                            GroupOrder::Limit(order(12, 1, Side::Sell, "ALICE")),
+                            GroupOrder::Limit(order(14, 1, Side::Sell, "ALICE")),
+                        ])
+                        .unwrap();
+                    assert_eq!(placed.len(), 2);
+
+                    trading_platform
+                        .submit_order(order(12, 1, Side::Buy, "BOB"))
+                        .unwrap();
+
+                    assert!(trading_platform.orderbook().is_empty());
+                    assert_eq!(trading_platform.balance_of("ALICE", &usd()), Ok(112));
+                }
+            }
+        };
+    }
+
+    matcher_suite!(matching_engine, MatchingEngine);
+    matcher_suite!(list_book, ListBook);
+
+    #[test]
+    fn test_TradingPlatform_stop_orders_require_engine_support() {
+        let mut trading_platform = TradingPlatform::with_engine(ListBook::default());
+        trading_platform.open("ALICE").unwrap();
+        assert!(trading_platform.deposit("ALICE", &usd(), 100).is_ok());
+        assert!(trading_platform.deposit("ALICE", &octo(), 100).is_ok());
+
+        assert_eq!(
+            trading_platform.submit_oco(vec![GroupOrder::Stop(StopOrder {
+                order: Order {
+                    price: 9,
+                    amount: 1,
+                    side: Side::Sell,
+                    signer: "ALICE".to_string(),
+                },
+                trigger: 9,
+            })]),
+            Err(AccountError::Unsupported("stop orders".to_string()))
+        );
     }
 }
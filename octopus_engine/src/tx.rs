@@ -1,9 +1,194 @@
 use serde::{Deserialize, Serialize};
 
-/// A transaction type. Transactions should be able to rebuild a ledger's state
+use crate::{
+    errors::AccountError,
+    liquidation::LiquidationStep,
+    types::{Asset, Order},
+};
+
+/// A transaction record. Transactions should be able to rebuild a ledger's state
 /// when they are applied in the same sequence to an empty state.
 #[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
-pub enum Tx {
-    Deposit { account: String, amount: u64 },
-    Withdraw { account: String, amount: u64 },
+pub struct Tx {
+    /// Unique id of the transaction, issued in the order transactions are made
+    pub id: u64,
+    /// The journal entry that posted the transaction, transactions sharing it were applied together
+    pub sequence: u64,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// What caused the transaction, such as the trade it settles or the reason for an adjustment
+    pub reference: Option<String>,
+    pub kind: TxKind,
+}
+
+/// What a transaction did to the balances of the accounts involved
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub enum TxKind {
+    Deposit {
+        account: String,
+        asset: Asset,
+        amount: u64,
+    },
+    Withdraw {
+        account: String,
+        asset: Asset,
+        amount: u64,
+    },
+    Transfer {
+        sender: String,
+        recipient: String,
+        asset: Asset,
+        amount: u64,
+    },
+    /// `amount` units of the base asset going to the buyer for their `price` each in the quote asset
+    TradeSettlement {
+        buyer: String,
+        seller: String,
+        base: Asset,
+        quote: Asset,
+        amount: u64,
+        price: u64,
+    },
+    Fee {
+        account: String,
+        asset: Asset,
+        amount: u64,
+    },
+    /// A correction of the balance, positive amounts credit the account and negative ones debit it
+    Adjustment {
+        account: String,
+        asset: Asset,
+        amount: i64,
+    },
+    /// Funds lent by the platform to an account on margin
+    Borrow {
+        account: String,
+        asset: Asset,
+        amount: u64,
+    },
+    /// Funds paid back to the platform by an account on margin
+    Repay {
+        account: String,
+        asset: Asset,
+        amount: u64,
+    },
+    /// A step of the liquidation of an account in a margin call, which doesn't change any balance itself
+    Liquidation {
+        account: String,
+        step: LiquidationStep,
+    },
+    /// An order the market placed for an account on its own, such as a bracket exit or a triggered stop,
+    /// that failed with the error `reason`. It doesn't change any balance itself.
+    Rejected {
+        account: String,
+        order: Order,
+        reason: AccountError,
+    },
+}
+
+impl TxKind {
+    /// The signer accounts the transaction changed
+    pub fn accounts(&self) -> Vec<&str> {
+        match self {
+            TxKind::Deposit { account, .. }
+            | TxKind::Withdraw { account, .. }
+            | TxKind::Fee { account, .. }
+            | TxKind::Adjustment { account, .. }
+            | TxKind::Borrow { account, .. }
+            | TxKind::Repay { account, .. }
+            | TxKind::Liquidation { account, .. }
+            | TxKind::Rejected { account, .. } => vec![account],
+            TxKind::Transfer {
+                sender, recipient, ..
+            } => vec![sender, recipient],
+            TxKind::TradeSettlement { buyer, seller, .. } => vec![buyer, seller],
+        }
+    }
+
+    /// A short name of what the transaction did
+    pub fn operation(&self) -> &'static str {
+        match self {
+            TxKind::Deposit { .. } => "DEPOSIT",
+            TxKind::Withdraw { .. } => "WITHDRAW",
+            TxKind::Transfer { .. } => "TRANSFER",
+            TxKind::TradeSettlement { .. } => "TRADE",
+            TxKind::Fee { .. } => "FEE",
+            TxKind::Adjustment { .. } => "ADJUSTMENT",
+            TxKind::Borrow { .. } => "BORROW",
+            TxKind::Repay { .. } => "REPAY",
+            TxKind::Liquidation { .. } => "LIQUIDATION",
+            TxKind::Rejected { .. } => "REJECTED",
+        }
+    }
+
+    /// How the transaction changed the balances of the signer account, by asset
+    pub fn changes(&self, signer: &str) -> Vec<(Asset, i128)> {
+        let mut changes = vec![];
+        match self {
+            TxKind::Deposit {
+                account,
+                asset,
+                amount,
+            }
+            | TxKind::Borrow {
+                account,
+                asset,
+                amount,
+            } if account == signer => changes.push((asset.clone(), *amount as i128)),
+            TxKind::Withdraw {
+                account,
+                asset,
+                amount,
+            }
+            | TxKind::Fee {
+                account,
+                asset,
+                amount,
+            }
+            | TxKind::Repay {
+                account,
+                asset,
+                amount,
+            } if account == signer => changes.push((asset.clone(), -(*amount as i128))),
+            TxKind::Adjustment {
+                account,
+                asset,
+                amount,
+            } if account == signer => changes.push((asset.clone(), *amount as i128)),
+            TxKind::Transfer {
+                sender,
+                recipient,
+                asset,
+                amount,
+            } => {
+                if sender == signer {
+                    changes.push((asset.clone(), -(*amount as i128)));
+                }
+                if recipient == signer {
+                    changes.push((asset.clone(), *amount as i128));
+                }
+            }
+            TxKind::TradeSettlement {
+                buyer,
+                seller,
+                base,
+                quote,
+                amount,
+                price,
+            } => {
+                let value = (*amount as i128).saturating_mul(*price as i128);
+                let amount = *amount as i128;
+                if buyer == signer {
+                    changes.push((base.clone(), amount));
+                    changes.push((quote.clone(), -value));
+                }
+                if seller == signer {
+                    changes.push((base.clone(), -amount));
+                    changes.push((quote.clone(), value));
+                }
+            }
+            _ => {}
+        }
+        changes
+    }
 }
@@ -1,5 +1,82 @@
 use serde::{Deserialize, Serialize};
-use std::cmp::{Ordering, Reverse};
+use std::{
+    cmp::{Ordering, Reverse},
+    fmt,
+};
+
+use crate::{
+    candles::Interval,
+    errors::AccountError,
+    fees::FeeSchedule,
+    positions::MarkPrice,
+    statement::{RangeKind, StatementFormat},
+};
+
+/// The market of requests that don't name one
+pub const DEFAULT_MARKET: &str = "OCTO/USD";
+
+fn default_market() -> String {
+    DEFAULT_MARKET.to_string()
+}
+
+fn default_asset() -> Asset {
+    Instrument::default().quote
+}
+
+/// Something held in an account, identified by its symbol
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Deserialize, Serialize)]
+pub struct Asset(pub String);
+
+impl Asset {
+    pub fn new(symbol: &str) -> Self {
+        Asset(symbol.to_string())
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A market where units of the `base` asset are bought and sold for a price in the `quote` asset
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Deserialize, Serialize)]
+pub struct Instrument {
+    /// The asset being traded, order amounts are in this asset
+    pub base: Asset,
+    /// The asset paid for it, order prices are in this asset
+    pub quote: Asset,
+}
+
+impl Instrument {
+    pub fn new(base: &str, quote: &str) -> Self {
+        Instrument {
+            base: Asset::new(base),
+            quote: Asset::new(quote),
+        }
+    }
+
+    /// Parses a symbol of the form `BASE/QUOTE`
+    pub fn parse(symbol: &str) -> Option<Self> {
+        let (base, quote) = symbol.split_once('/')?;
+        let (base, quote) = (base.trim(), quote.trim());
+        if base.is_empty() || quote.is_empty() || base == quote {
+            return None;
+        }
+        Some(Instrument::new(base, quote))
+    }
+
+    /// The `BASE/QUOTE` symbol of the instrument
+    pub fn symbol(&self) -> String {
+        format!("{}/{}", self.base, self.quote)
+    }
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Instrument::parse(DEFAULT_MARKET).expect("the default market is a valid symbol")
+    }
+}
 
 /// Simplified side of a position as well as order.
 #[derive(Clone, PartialOrd, PartialEq, Eq, Debug, Ord, Deserialize, Serialize)]
@@ -11,7 +88,7 @@ pub enum Side {
 }
 
 /// An order for a specified symbol to buy or sell an amount at a given price.
-#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct Order {
     /// Max/min price (depending on the side)
     pub price: u64,
@@ -23,7 +100,21 @@ pub struct Order {
     pub signer: String,
 }
 
+/// What `amount` units at `price` are worth in the quote asset
+/// # Errors
+/// The value doesn't fit in a `u64`
+pub fn value(amount: u64, price: u64) -> Result<u64, AccountError> {
+    amount
+        .checked_mul(price)
+        .ok_or(AccountError::ArithmeticOverflow)
+}
+
 impl Order {
+    /// What the order is worth at its price, see [`value`]
+    pub fn value(&self) -> Result<u64, AccountError> {
+        value(self.amount, self.price)
+    }
+
     /// Convert an [`Order`] into a [`PartialOrder`] with the added parameters
     pub fn into_partial_order(self, ordinal: u64, remaining: u64) -> PartialOrder {
         let Order {
@@ -39,6 +130,169 @@ impl Order {
             side,
             signer,
             ordinal,
+            sequence: ordinal,
+            condition: None,
+        }
+    }
+}
+
+/// An [`Order`] that is held back until a trade happens at or through the `trigger` price.
+/// Sell stops trigger on trades at or below the trigger, buy stops on trades at or above it.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct StopOrder {
+    /// The order to process once triggered
+    pub order: Order,
+    /// Trade price that releases the order
+    pub trigger: u64,
+}
+
+impl StopOrder {
+    /// Whether a trade at `price` releases this stop
+    pub fn is_triggered_by(&self, price: u64) -> bool {
+        match self.order.side {
+            Side::Buy => price >= self.trigger,
+            Side::Sell => price <= self.trigger,
+        }
+    }
+}
+
+/// How an order released by a stop is processed
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub enum Execution {
+    /// Matched against the book at any price, the unfilled remainder is discarded
+    Market,
+    /// Processed as a limit order at the order price
+    Limit,
+}
+
+/// The distance a trailing stop keeps from the last trade price
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub enum Trail {
+    /// A fixed price difference
+    Amount(u64),
+    /// A share of the last price in basis points (1/100th of a percent), at most 10_000
+    BasisPoints(u64),
+}
+
+impl Trail {
+    /// The price difference to keep at the given price. Differences that don't fit in a `u64` are capped at `u64::MAX`.
+    pub fn offset(&self, price: u64) -> u64 {
+        match self {
+            Trail::Amount(amount) => *amount,
+            Trail::BasisPoints(bps) => {
+                u64::try_from(price as u128 * *bps as u128 / 10_000).unwrap_or(u64::MAX)
+            }
+        }
+    }
+}
+
+/// A stop order whose trigger follows the last trade price at a distance, only ever moving in the favourable direction.
+/// Sell stops trail below the price and rise with it, buy stops trail above the price and fall with it.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct TrailingStop {
+    /// The order to process once triggered. The price is only used by [`Execution::Limit`]
+    pub order: Order,
+    /// Distance between the trigger and the last trade price
+    pub trail: Trail,
+    /// How the order is processed once triggered
+    pub execution: Execution,
+}
+
+/// An order released by a stop, ready to be processed
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct TriggeredOrder {
+    /// Ordinal of the stop that released the order
+    pub ordinal: u64,
+    /// The order to process
+    pub order: Order,
+    /// How the order is processed
+    pub execution: Execution,
+}
+
+/// The top of book price a pegged order follows
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub enum PegReference {
+    /// The best price on the order's own side
+    Primary,
+    /// The best price on the opposite side
+    Market,
+    /// Halfway between the best bid and the best ask
+    Mid,
+}
+
+/// An order whose price is kept at a reference price of the book plus an offset
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct PeggedOrder {
+    /// The order to rest in the book. The price is replaced by the pegged price
+    pub order: Order,
+    /// The price the order follows
+    pub reference: PegReference,
+    /// Added to the reference price, may be negative
+    pub offset: i64,
+    /// The highest price of a buy order or the lowest price of a sell order
+    pub limit: Option<u64>,
+}
+
+impl PeggedOrder {
+    /// The pegged price for a top of book, if the reference exists
+    pub fn price(&self, best_bid: Option<u64>, best_ask: Option<u64>) -> Option<u64> {
+        let reference = match (self.reference, &self.order.side) {
+            (PegReference::Primary, Side::Buy) | (PegReference::Market, Side::Sell) => best_bid?,
+            (PegReference::Primary, Side::Sell) | (PegReference::Market, Side::Buy) => best_ask?,
+            (PegReference::Mid, _) => (best_bid? as u128 + best_ask? as u128).div_euclid(2) as u64,
+        };
+        let price = reference.saturating_add_signed(self.offset);
+
+        Some(match (self.limit, &self.order.side) {
+            (Some(limit), Side::Buy) => price.min(limit),
+            (Some(limit), Side::Sell) => price.max(limit),
+            (None, _) => price,
+        })
+    }
+}
+
+/// A restriction on how an order may be filled
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub enum Condition {
+    /// The order may only ever be filled completely, never in parts
+    AllOrNone,
+    /// Every fill must be at least this many units, or whatever is left of the order if that's less
+    MinimumQuantity(u64),
+}
+
+impl Condition {
+    /// How many units must trade at once for an order with `remaining` units
+    pub fn required(&self, remaining: u64) -> u64 {
+        match self {
+            Condition::AllOrNone => remaining,
+            Condition::MinimumQuantity(minimum) => remaining.min(*minimum),
+        }
+    }
+}
+
+/// An [`Order`] with a [`Condition`] that applies when it's matched and while it rests in the book
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct ConditionalOrder {
+    /// The order to process
+    pub order: Order,
+    /// The restriction on its fills
+    pub condition: Condition,
+}
+
+/// A member of a linked order group
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub enum GroupOrder {
+    /// A limit order placed in the book straight away
+    Limit(Order),
+    /// A stop order held back until it is triggered
+    Stop(StopOrder),
+}
+
+impl GroupOrder {
+    /// The order placed in the book, straight away or once triggered
+    pub fn order(&self) -> &Order {
+        match self {
+            GroupOrder::Limit(order) | GroupOrder::Stop(StopOrder { order, .. }) => order,
         }
     }
 }
@@ -56,20 +310,24 @@ pub struct PartialOrder {
     pub side: Side,
     /// Signer of the order
     pub signer: String,
-    /// Sequence number for order prioritisation
+    /// Sequence number identifying the order
     pub ordinal: u64,
+    /// Sequence number for order prioritisation. Starts at the ordinal and is renewed when the order is repriced
+    pub sequence: u64,
+    /// Restriction on how the order may be filled
+    pub condition: Option<Condition>,
 }
 
 impl PartialOrd for PartialOrder {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // this reverses the comparison to create a min heap
-        Reverse(self.ordinal).partial_cmp(&Reverse(other.ordinal))
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for PartialOrder {
     fn cmp(&self, other: &Self) -> Ordering {
-        Reverse(self.ordinal).cmp(&Reverse(other.ordinal))
+        // this reverses the comparison to create a min heap
+        Reverse(self.sequence).cmp(&Reverse(other.sequence))
     }
 }
 
@@ -79,12 +337,21 @@ pub struct Receipt {
     /// Sequence number
     pub ordinal: u64,
 
+    /// Milliseconds since the unix epoch when the order was processed
+    pub timestamp: u64,
+
     /// Matches that happened immediately
     pub matches: Vec<PartialOrder>,
+
+    /// The fee the signer paid for the matches, in the asset the order received:
+    /// the base asset of a buy and the quote asset of a sell
+    #[serde(default)]
+    pub fee: u64,
 }
 
 impl PartialOrder {
-    /// Splits one [`PartialOrder`] into two by taking a defined `take` amount
+    /// Splits one [`PartialOrder`] into two by taking a defined `take` amount.
+    /// The returned part has an amount of `take` and the remaining units left in `pos`.
     pub fn take_from(pos: &mut PartialOrder, take: u64) -> PartialOrder {
         pos.remaining -= take;
         let mut new = pos.clone();
@@ -93,36 +360,304 @@ impl PartialOrder {
     }
 }
 
+/// The orders resting at a single price
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct Level {
+    /// Price of the level
+    pub price: u64,
+    /// Sum of the remaining units of the orders at this price, capped at `u64::MAX`
+    pub amount: u64,
+    /// Number of orders at this price
+    pub orders: usize,
+}
+
+/// Both sides of an order book aggregated by price
+#[derive(Clone, Default, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct Depth {
+    /// Bid levels, highest price first
+    pub bids: Vec<Level>,
+    /// Ask levels, lowest price first
+    pub asks: Vec<Level>,
+}
+
+impl Depth {
+    /// Aggregates resting [`PartialOrder`]s into price levels
+    pub fn from_orders(orders: &[PartialOrder]) -> Self {
+        let mut depth = Depth::default();
+
+        for po in orders {
+            let levels = match po.side {
+                Side::Buy => &mut depth.bids,
+                Side::Sell => &mut depth.asks,
+            };
+            match levels.iter_mut().find(|l| l.price == po.price) {
+                Some(level) => {
+                    level.amount = level.amount.saturating_add(po.remaining);
+                    level.orders += 1;
+                }
+                None => levels.push(Level {
+                    price: po.price,
+                    amount: po.remaining,
+                    orders: 1,
+                }),
+            }
+        }
+
+        depth.bids.sort_by_key(|l| Reverse(l.price));
+        depth.asks.sort_by_key(|l| l.price);
+        depth
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct AccountArgs {
     pub signer: String,
 }
 
+/// The positions of a signer, valued at the last trade price unless the mid price is asked for
 #[derive(Deserialize, Serialize)]
+pub struct PositionArgs {
+    pub signer: String,
+    #[serde(default)]
+    pub mark: MarkPrice,
+}
+
+/// The statement of a signer over a range of sequences or timestamps, as JSON unless CSV is asked for
+#[derive(Deserialize, Serialize)]
+pub struct StatementArgs {
+    pub signer: String,
+    #[serde(default)]
+    pub by: RangeKind,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    #[serde(default)]
+    pub format: StatementFormat,
+}
+
+/// Names a ledger account: a signer or a system account such as `@external`
+#[derive(Deserialize, Serialize)]
+pub struct LedgerArgs {
+    pub account: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
 pub struct DepositArgs {
     pub signer: String,
     pub amount: u64,
+    #[serde(default = "default_asset")]
+    pub asset: Asset,
+    /// Chosen by the client so a retried request is only processed once
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// The account acting for the signer, theirs or one above it, the signer themselves when missing
+    #[serde(default)]
+    pub actor: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+impl DepositArgs {
+    /// The account making the request
+    pub fn actor(&self) -> &str {
+        self.actor.as_deref().unwrap_or(&self.signer)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
 pub struct WithdrawArgs {
     pub signer: String,
     pub amount: u64,
+    #[serde(default = "default_asset")]
+    pub asset: Asset,
+    /// Chosen by the client so a retried request is only processed once
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// The account acting for the signer, theirs or one above it, the signer themselves when missing
+    #[serde(default)]
+    pub actor: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+impl WithdrawArgs {
+    /// The account making the request
+    pub fn actor(&self) -> &str {
+        self.actor.as_deref().unwrap_or(&self.signer)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
 pub struct SendArgs {
     pub signer: String,
     pub recipient: String,
     pub amount: u64,
+    #[serde(default = "default_asset")]
+    pub asset: Asset,
+    /// Chosen by the client so a retried request is only processed once
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// The account acting for the signer, theirs or one above it, the signer themselves when missing
+    #[serde(default)]
+    pub actor: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+impl SendArgs {
+    /// The account making the request
+    pub fn actor(&self) -> &str {
+        self.actor.as_deref().unwrap_or(&self.signer)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
 pub struct OrderArgs {
     pub signer: String,
     pub side: Side,
     pub amount: u64,
     pub price: u64,
+    #[serde(default = "default_market")]
+    pub market: String,
+    /// The sub-account of the signer the order is placed for and attributed to, their own account when missing
+    #[serde(default)]
+    pub account: Option<String>,
+    /// Chosen by the client so a retried request is only processed once
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+impl OrderArgs {
+    /// The account the order is attributed to
+    pub fn account(&self) -> &str {
+        self.account.as_deref().unwrap_or(&self.signer)
+    }
+
+    /// The order, signed by the account it's attributed to
+    pub fn order(&self) -> Order {
+        Order {
+            signer: self.account().to_string(),
+            side: self.side.clone(),
+            amount: self.amount,
+            price: self.price,
+        }
+    }
+}
+
+/// A one-cancels-other group placed by the signer, for their own account or ones below it
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct OcoArgs {
+    pub signer: String,
+    pub orders: Vec<GroupOrder>,
+    #[serde(default = "default_market")]
+    pub market: String,
+}
+
+/// A resting or stop order cancelled by the signer along with the siblings in its group
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct CancelArgs {
+    pub signer: String,
+    pub ordinal: u64,
+    #[serde(default = "default_market")]
+    pub market: String,
+    /// The sub-account of the signer the order was placed for, their own account when missing
+    #[serde(default)]
+    pub account: Option<String>,
+}
+
+impl CancelArgs {
+    /// The account the order is attributed to
+    pub fn account(&self) -> &str {
+        self.account.as_deref().unwrap_or(&self.signer)
+    }
+}
+
+/// An entry order with the prices of its take-profit and stop-loss exits, see [`OcoArgs`]
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct BracketArgs {
+    pub signer: String,
+    pub entry: Order,
+    pub take_profit: u64,
+    pub stop_loss: u64,
+    #[serde(default = "default_market")]
+    pub market: String,
+}
+
+/// A trailing stop placed by the signer, see [`OcoArgs`]
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct TrailingStopArgs {
+    pub signer: String,
+    pub stop: TrailingStop,
+    #[serde(default = "default_market")]
+    pub market: String,
+}
+
+/// An order with a condition on its fills placed by the signer, see [`OcoArgs`]
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct ConditionalArgs {
+    pub signer: String,
+    pub order: ConditionalOrder,
+    #[serde(default = "default_market")]
+    pub market: String,
+}
+
+/// A pegged order placed by the signer, see [`OcoArgs`]
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct PeggedArgs {
+    pub signer: String,
+    pub pegged: PeggedOrder,
+    #[serde(default = "default_market")]
+    pub market: String,
+}
+
+/// A parent opening a sub-account
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct SubAccountArgs {
+    pub signer: String,
+    pub account: String,
+}
+
+/// Moves funds between related accounts on behalf of the signer
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct TransferArgs {
+    pub signer: String,
+    pub from: String,
+    pub to: String,
+    pub amount: u64,
+    #[serde(default = "default_asset")]
+    pub asset: Asset,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct RepayArgs {
+    pub signer: String,
+    pub amount: u64,
+    #[serde(default = "default_asset")]
+    pub asset: Asset,
+}
+
+/// The terms a signer can trade on margin with, see [`MarginTerms`](crate::margin::MarginTerms)
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct MarginArgs {
+    pub signer: String,
+    #[serde(default = "default_asset")]
+    pub asset: Asset,
+    pub credit_limit: u64,
+    pub maintenance_bps: u64,
+}
+
+/// The fees charged on the trades of a market from now on
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct FeeArgs {
+    pub schedule: FeeSchedule,
+    #[serde(default = "default_market")]
+    pub market: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MarketArgs {
+    #[serde(default = "default_market")]
+    pub market: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CandleArgs {
+    pub interval: Interval,
+    #[serde(default = "default_market")]
+    pub market: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -1,40 +1,104 @@
+use crate::runtime::Runtime;
 use octopus_engine::{
     engine::MatchingEngine,
-    errors::AccountError,
-    trading_platform::TradingPlatform,
+    errors::{AccountError, OctopusError},
+    idempotency::Request,
+    ledger::LedgerAccount,
+    margin::MarginTerms,
+    statement::{StatementFormat, StatementRange},
     types::{
-        AccountArgs, DepositArgs, MatchArgs, MatchResponse, Order, OrderArgs, SendArgs,
-        WithdrawArgs,
+        AccountArgs, BracketArgs, CancelArgs, CandleArgs, ConditionalArgs, DepositArgs, FeeArgs,
+        GroupOrder, LedgerArgs, MarginArgs, MarketArgs, MatchArgs, MatchResponse, OcoArgs,
+        OrderArgs, PeggedArgs, PositionArgs, RepayArgs, SendArgs, StatementArgs, SubAccountArgs,
+        TrailingStopArgs, TransferArgs, WithdrawArgs,
     },
 };
-use serde::Serialize;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use warp::{reject::Reject, Rejection, Reply};
+use warp::{
+    body::BodyDeserializeError,
+    http::StatusCode,
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingCookie,
+        MissingHeader, PayloadTooLarge, Reject, UnsupportedMediaType,
+    },
+    Rejection, Reply,
+};
 
-#[derive(Debug, Serialize)]
-pub struct OctopusError(AccountError);
+/// A request the runtime turned down, reported to the client by [`handle_rejection`]
+#[derive(Debug)]
+pub struct Rejected(AccountError);
 
-impl Reject for OctopusError {}
+impl Reject for Rejected {}
 
-#[derive(Debug, Serialize)]
-struct ServerError(String);
+/// The HTTP status an error is reported with
+fn status_of(error: &AccountError) -> StatusCode {
+    match error {
+        AccountError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        AccountError::Unauthorized(_) => StatusCode::FORBIDDEN,
+        AccountError::NotFound(_)
+        | AccountError::OrderNotFound(_)
+        | AccountError::MarketNotFound(_)
+        | AccountError::UnknownRoute => StatusCode::NOT_FOUND,
+        AccountError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+        AccountError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        AccountError::AlreadyExists(_)
+        | AccountError::Frozen(_)
+        | AccountError::Closed(_)
+        | AccountError::NotEmpty(_)
+        | AccountError::IdempotencyConflict(_) => StatusCode::CONFLICT,
+        AccountError::OverFunded(..)
+        | AccountError::UnderFunded(_)
+        | AccountError::InvalidOrder(_)
+        | AccountError::NoReferencePrice
+        | AccountError::Unsupported(_)
+        | AccountError::ArithmeticOverflow => StatusCode::UNPROCESSABLE_ENTITY,
+        AccountError::RequestInProgress(_) => StatusCode::TOO_MANY_REQUESTS,
+        AccountError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
 
-// Custom rejection handler that maps rejections into responses.
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
-    if let Some(e) = err.find::<OctopusError>() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(e),
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+/// What went wrong with a request warp couldn't route to a handler, if anything
+fn error_of(err: &Rejection) -> Option<AccountError> {
+    if let Some(e) = err.find::<BodyDeserializeError>() {
+        Some(AccountError::InvalidRequest(e.to_string()))
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        Some(AccountError::InvalidRequest(e.to_string()))
+    } else if let Some(e) = err.find::<UnsupportedMediaType>() {
+        Some(AccountError::InvalidRequest(e.to_string()))
+    } else if let Some(e) = err.find::<LengthRequired>() {
+        Some(AccountError::InvalidRequest(e.to_string()))
+    } else if let Some(e) = err.find::<MissingHeader>() {
+        Some(AccountError::InvalidRequest(e.to_string()))
+    } else if let Some(e) = err.find::<InvalidHeader>() {
+        Some(AccountError::InvalidRequest(e.to_string()))
+    } else if let Some(e) = err.find::<MissingCookie>() {
+        Some(AccountError::InvalidRequest(e.to_string()))
+    } else if err.find::<PayloadTooLarge>().is_some() {
+        Some(AccountError::PayloadTooLarge)
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        Some(AccountError::MethodNotAllowed)
+    } else if err.is_not_found() {
+        Some(AccountError::UnknownRoute)
     } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&ServerError("Server error".to_string())),
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+        None
     }
 }
 
+// Custom rejection handler that maps rejections into responses.
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::Infallible> {
+    let error = match err.find::<Rejected>() {
+        Some(Rejected(e)) => Some((OctopusError::from(e), status_of(e))),
+        None => error_of(&err).map(|e| (OctopusError::from(&e), status_of(&e))),
+    };
+    let (error, status) = error.unwrap_or_else(|| {
+        let error = OctopusError {
+            code: "server_error".to_string(),
+            message: "Server error".to_string(),
+        };
+        (error, StatusCode::INTERNAL_SERVER_ERROR)
+    });
+    Ok(warp::reply::with_status(warp::reply::json(&error), status))
+}
+
 // GET /
 pub async fn status() -> Result<impl Reply, Rejection> {
     Ok(warp::reply::with_status(
@@ -43,88 +107,351 @@ pub async fn status() -> Result<impl Reply, Rejection> {
     ))
 }
 
-// GET /orderbook
-pub async fn orderbook(platform: Arc<Mutex<TradingPlatform>>) -> Result<impl Reply, Rejection> {
-    let mut p = platform.lock().await;
-
-    Ok(warp::reply::json(&p.orderbook()))
+// GET /orderbook?market=
+pub async fn orderbook(args: MarketArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.orderbook(&args.market).await {
+        Ok(orderbook) => Ok(warp::reply::json(&orderbook)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
 }
 
 // GET /transactions
-pub async fn transactions(platform: Arc<Mutex<TradingPlatform>>) -> Result<impl Reply, Rejection> {
-    let p = platform.lock().await;
+pub async fn transactions(runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.transactions().await {
+        Ok(transactions) => Ok(warp::reply::json(&transactions)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /candles?interval=&market=
+pub async fn candles(args: CandleArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.candles(&args.market, args.interval).await {
+        Ok(candles) => Ok(warp::reply::json(&candles)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
 
-    Ok(warp::reply::json(&p.transactions))
+// GET /ticker?market=
+pub async fn ticker(args: MarketArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.ticker(&args.market).await {
+        Ok(ticker) => Ok(warp::reply::json(&ticker)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
 }
 
 // GET /account?signer=
-pub async fn account(
-    args: AccountArgs,
-    platform: Arc<Mutex<TradingPlatform>>,
+pub async fn account(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.holdings(&args.signer).await {
+        Ok(holdings) => Ok(warp::reply::json(&holdings)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /account/rollup?signer=
+pub async fn rollup(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.rollup(&args.signer).await {
+        Ok(holdings) => Ok(warp::reply::json(&holdings)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /account/sub_accounts?signer=
+pub async fn sub_accounts(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.sub_accounts(&args.signer).await {
+        Ok(accounts) => Ok(warp::reply::json(&accounts)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /account/sub_accounts/open
+pub async fn open_sub_account(
+    args: SubAccountArgs,
+    runtime: Runtime,
 ) -> Result<impl Reply, Rejection> {
-    let mut p = platform.lock().await;
+    match runtime.open_sub_account(&args.signer, &args.account).await {
+        Ok(account) => Ok(warp::reply::json(&account)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
 
-    match p.balance_of(&args.signer) {
-        Ok(balance) => Ok(warp::reply::json(&balance)),
-        Err(e) => Err(warp::reject::custom(OctopusError(e))),
+// GET /positions?signer=&mark=
+pub async fn positions(args: PositionArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.positions(&args.signer, args.mark).await {
+        Ok(positions) => Ok(warp::reply::json(&positions)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
     }
 }
 
-// POST /account/deposit
-pub async fn deposit(
-    args: DepositArgs,
-    platform: Arc<Mutex<TradingPlatform>>,
+// GET /account/statement?signer=&by=&from=&to=&format=
+pub async fn statement(args: StatementArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    let range = StatementRange {
+        by: args.by,
+        from: args.from,
+        to: args.to,
+    };
+    match runtime.statement(&args.signer, range).await {
+        Ok(statement) => match args.format {
+            StatementFormat::Json => Ok(warp::reply::json(&statement).into_response()),
+            StatementFormat::Csv => {
+                Ok(
+                    warp::reply::with_header(statement.to_csv(), "content-type", "text/csv")
+                        .into_response(),
+                )
+            }
+        },
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /ledger/trial_balance
+pub async fn trial_balance(runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.trial_balance().await {
+        Ok(trial_balance) => Ok(warp::reply::json(&trial_balance)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /admin/reconcile
+pub async fn reconcile(runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.reconcile().await {
+        Ok(mismatches) => Ok(warp::reply::json(&mismatches)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /admin/account?signer=
+pub async fn account_record(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.account(&args.signer).await {
+        Ok(account) => Ok(warp::reply::json(&account)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /admin/account/open
+pub async fn open_account(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.open(&args.signer).await {
+        Ok(account) => Ok(warp::reply::json(&account)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /admin/account/freeze
+pub async fn freeze_account(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.freeze(&args.signer).await {
+        Ok(account) => Ok(warp::reply::json(&account)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /admin/account/unfreeze
+pub async fn unfreeze_account(
+    args: AccountArgs,
+    runtime: Runtime,
 ) -> Result<impl Reply, Rejection> {
-    let mut p = platform.lock().await;
+    match runtime.unfreeze(&args.signer).await {
+        Ok(account) => Ok(warp::reply::json(&account)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /admin/account/close
+pub async fn close_account(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.close(&args.signer).await {
+        Ok(account) => Ok(warp::reply::json(&account)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /margin?signer=
+pub async fn margin(args: AccountArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.margin(&args.signer).await {
+        Ok(report) => Ok(warp::reply::json(&report)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
 
-    match p.deposit(&args.signer, args.amount) {
+// POST /admin/margin
+pub async fn set_margin(args: MarginArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    let terms = MarginTerms {
+        asset: args.asset,
+        credit_limit: args.credit_limit,
+        maintenance_bps: args.maintenance_bps,
+    };
+
+    match runtime.set_margin(&args.signer, terms).await {
+        Ok(report) => Ok(warp::reply::json(&report)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /admin/fees
+pub async fn set_fees(args: FeeArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime
+        .set_fee_schedule(&args.market, args.schedule.clone())
+        .await
+    {
+        Ok(()) => Ok(warp::reply::json(&args)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /admin/margin_calls
+pub async fn margin_calls(runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.margin_calls().await {
+        Ok(reports) => Ok(warp::reply::json(&reports)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// GET /ledger?account=
+pub async fn ledger(args: LedgerArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    let account = LedgerAccount::parse(&args.account).ok_or(warp::reject::custom(Rejected(
+        AccountError::InvalidRequest(format!("'{}' isn't a ledger account", args.account)),
+    )))?;
+    match runtime.ledger(&account).await {
+        Ok(lines) => Ok(warp::reply::json(&lines)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /account/deposit
+pub async fn deposit(args: DepositArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.process(Request::Deposit(args)).await {
         Ok(tx) => Ok(warp::reply::json(&tx)),
-        Err(e) => Err(warp::reject::custom(OctopusError(e))),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
     }
 }
 
 // POST /account/withdraw
-pub async fn withdraw(
-    args: WithdrawArgs,
-    platform: Arc<Mutex<TradingPlatform>>,
-) -> Result<impl Reply, Rejection> {
-    let mut p = platform.lock().await;
-
-    match p.withdraw(&args.signer, args.amount) {
+pub async fn withdraw(args: WithdrawArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.process(Request::Withdraw(args)).await {
         Ok(tx) => Ok(warp::reply::json(&tx)),
-        Err(e) => Err(warp::reject::custom(OctopusError(e))),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
     }
 }
 
 // POST /account/send
-pub async fn send(
-    args: SendArgs,
-    platform: Arc<Mutex<TradingPlatform>>,
-) -> Result<impl Reply, Rejection> {
-    let mut p = platform.lock().await;
+pub async fn send(args: SendArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.process(Request::Send(args)).await {
+        Ok(tx) => Ok(warp::reply::json(&tx)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /account/transfer
+pub async fn transfer(args: TransferArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime
+        .transfer(&args.signer, &args.from, &args.to, &args.asset, args.amount)
+        .await
+    {
+        Ok(tx) => Ok(warp::reply::json(&tx)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
 
-    match p.send(&args.signer, &args.recipient, args.amount) {
+// POST /account/repay
+pub async fn repay(args: RepayArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.repay(&args.signer, &args.asset, args.amount).await {
         Ok(tx) => Ok(warp::reply::json(&tx)),
-        Err(e) => Err(warp::reject::custom(OctopusError(e))),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
     }
 }
 
 // POST /submit_order
-pub async fn submit_order(
-    args: OrderArgs,
-    platform: Arc<Mutex<TradingPlatform>>,
+pub async fn submit_order(args: OrderArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    match runtime.process(Request::Order(args)).await {
+        Ok(receipt) => Ok(warp::reply::json(&receipt)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /submit_oco
+pub async fn submit_oco(args: OcoArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    let placed = async {
+        for order in args.orders.iter().map(GroupOrder::order) {
+            runtime.authorize(&args.signer, &order.signer).await?;
+        }
+        runtime.submit_oco(&args.market, args.orders).await
+    };
+    match placed.await {
+        Ok(ordinals) => Ok(warp::reply::json(&ordinals)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /submit_bracket
+pub async fn submit_bracket(args: BracketArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    let receipt = async {
+        runtime.authorize(&args.signer, &args.entry.signer).await?;
+        runtime
+            .submit_bracket(&args.market, args.entry, args.take_profit, args.stop_loss)
+            .await
+    };
+    match receipt.await {
+        Ok(receipt) => Ok(warp::reply::json(&receipt)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /cancel_order
+pub async fn cancel_order(args: CancelArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    let cancelled = async {
+        runtime.authorize(&args.signer, args.account()).await?;
+        runtime
+            .cancel_order(&args.market, args.account(), args.ordinal)
+            .await
+    };
+    match cancelled.await {
+        Ok(ordinals) => Ok(warp::reply::json(&ordinals)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /submit_trailing_stop
+pub async fn submit_trailing_stop(
+    args: TrailingStopArgs,
+    runtime: Runtime,
+) -> Result<impl Reply, Rejection> {
+    let ordinal = async {
+        runtime
+            .authorize(&args.signer, &args.stop.order.signer)
+            .await?;
+        runtime.submit_trailing_stop(&args.market, args.stop).await
+    };
+    match ordinal.await {
+        Ok(ordinal) => Ok(warp::reply::json(&ordinal)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+// POST /submit_conditional
+pub async fn submit_conditional(
+    args: ConditionalArgs,
+    runtime: Runtime,
 ) -> Result<impl Reply, Rejection> {
-    let mut p = platform.lock().await;
-    let order = Order {
-        signer: args.signer,
-        price: args.price,
-        amount: args.amount,
-        side: args.side,
+    let receipt = async {
+        runtime
+            .authorize(&args.signer, &args.order.order.signer)
+            .await?;
+        runtime.submit_conditional(&args.market, args.order).await
     };
+    match receipt.await {
+        Ok(receipt) => Ok(warp::reply::json(&receipt)),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
 
-    match p.submit_order(order) {
+// POST /submit_pegged
+pub async fn submit_pegged(args: PeggedArgs, runtime: Runtime) -> Result<impl Reply, Rejection> {
+    let receipt = async {
+        runtime
+            .authorize(&args.signer, &args.pegged.order.signer)
+            .await?;
+        runtime.submit_pegged(&args.market, args.pegged).await
+    };
+    match receipt.await {
         Ok(receipt) => Ok(warp::reply::json(&receipt)),
-        Err(e) => Err(warp::reject::custom(OctopusError(e))),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
     }
 }
 
@@ -141,6 +468,136 @@ pub async fn match_order(args: MatchArgs) -> Result<impl Reply, Rejection> {
 
             Ok(warp::reply::json(&body))
         }
-        Err(e) => Err(warp::reject::custom(OctopusError(e))),
+        Err(e) => Err(warp::reject::custom(Rejected(e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+    use warp::Filter;
+
+    async fn reply(rejection: fn() -> Rejection) -> (StatusCode, String) {
+        let filter = warp::any()
+            .and_then(move || async move { Err::<String, _>(rejection()) })
+            .recover(handle_rejection);
+        let response = warp::test::request().reply(&filter).await;
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        (response.status(), body)
+    }
+
+    #[tokio::test]
+    async fn test_handle_rejection_reports_code_message_and_status() {
+        assert_eq!(
+            reply(|| warp::reject::custom(Rejected(AccountError::UnderFunded(
+                "ALICE".to_string()
+            ))))
+            .await,
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                r#"{"code":"under_funded","message":"'ALICE' doesn't have enough funds available"}"#
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            reply(
+                || warp::reject::custom(Rejected(AccountError::MarketNotFound(
+                    "ABC/USD".to_string()
+                )))
+            )
+            .await,
+            (
+                StatusCode::NOT_FOUND,
+                r#"{"code":"market_not_found","message":"There is no market 'ABC/USD'"}"#
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            reply(
+                || warp::reject::custom(Rejected(AccountError::RequestInProgress(
+                    "order-1".to_string()
+                )))
+            )
+            .await
+            .0,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            reply(warp::reject::not_found).await,
+            (
+                StatusCode::NOT_FOUND,
+                r#"{"code":"unknown_route","message":"There is nothing at this path"}"#.to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_rejection_reports_malformed_requests() {
+        let body = warp::post()
+            .and(warp::body::json())
+            .map(|args: AccountArgs| args.signer)
+            .recover(handle_rejection);
+        let response = warp::test::request()
+            .method("POST")
+            .body("{\"signer\":")
+            .reply(&body)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response
+            .body()
+            .starts_with(br#"{"code":"invalid_request","#));
+
+        let query = warp::get()
+            .and(warp::query::<LedgerArgs>())
+            .map(|args: LedgerArgs| args.account)
+            .recover(handle_rejection);
+        let response = warp::test::request().path("/ledger").reply(&query).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response
+            .body()
+            .starts_with(br#"{"code":"invalid_request","#));
+    }
+
+    #[tokio::test]
+    async fn test_handle_rejection_tells_unknown_paths_from_wrong_methods() {
+        let routes = warp::path!("submit_order")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(16))
+            .and(warp::body::json())
+            .map(|args: AccountArgs| args.signer)
+            .or(warp::path!("orderbook").and(warp::get()).map(|| "[]"))
+            .recover(handle_rejection);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/submit_order")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.body(),
+            r#"{"code":"method_not_allowed","message":"This path doesn't take the method requested"}"#
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/nowhere")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/submit_order")
+            .body(r#"{"signer":"A_VERY_LONG_NAME"}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response
+            .body()
+            .starts_with(br#"{"code":"payload_too_large","#));
     }
 }
@@ -1,14 +1,18 @@
 mod handlers;
+mod runtime;
 
 #[macro_use]
 extern crate log;
 extern crate pretty_env_logger;
 
 use handlers::*;
-use octopus_engine::trading_platform::TradingPlatform;
+use octopus_engine::{
+    fees::FeeSchedule,
+    types::{Instrument, DEFAULT_MARKET},
+};
+use runtime::Runtime;
 
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::env;
 use warp::Filter;
 
 #[tokio::main]
@@ -16,73 +20,322 @@ async fn main() {
     pretty_env_logger::init();
     info!("starting up");
 
-    let trading_platform = Arc::new(Mutex::new(TradingPlatform::new()));
-    let trading_platform_state = warp::any().map(move || trading_platform.clone());
+    // Comma separated BASE/QUOTE symbols, each market is matched on its own thread
+    let markets: Vec<Instrument> = env::var("OCTOPUS_MARKETS")
+        .unwrap_or(DEFAULT_MARKET.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(|m| Instrument::parse(m).unwrap_or_else(|| panic!("invalid market {m}")))
+        .collect();
+    info!("starting markets {:?}", markets);
+
+    let runtime = Runtime::start(&markets);
+
+    // MAKER_BPS,TAKER_BPS charged in every market until an admin sets another schedule
+    if let Ok(rates) = env::var("OCTOPUS_FEE_BPS") {
+        let bps: Vec<u64> = rates
+            .split(',')
+            .map(|r| {
+                r.trim()
+                    .parse()
+                    .unwrap_or_else(|_| panic!("invalid fee rate {r}"))
+            })
+            .collect();
+        let [maker_bps, taker_bps] = bps[..] else {
+            panic!("invalid fee rates {rates}, expected MAKER_BPS,TAKER_BPS");
+        };
+        for market in &markets {
+            let schedule = FeeSchedule::new(maker_bps, taker_bps);
+            runtime
+                .set_fee_schedule(&market.symbol(), schedule)
+                .await
+                .expect("failed to set the fee schedule");
+        }
+        info!("charging fees of {maker_bps} bps to makers and {taker_bps} bps to takers");
+    }
+    let runtime_state = warp::any().map(move || runtime.clone());
+
+    // Every route matches its path before its method, so a wrong method is only reported for a path that exists
 
     // GET /
-    let status = warp::get().and(warp::path!()).and_then(status);
+    let status = warp::path!().and(warp::get()).and_then(status);
 
-    // GET /orderbook
-    let orderbook = warp::get()
-        .and(warp::path!("orderbook"))
-        .and(trading_platform_state.clone())
+    // GET /orderbook?market=
+    let orderbook = warp::path!("orderbook")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
         .and_then(orderbook);
 
     // GET /transactions
-    let transactions = warp::get()
-        .and(warp::path!("transactions"))
-        .and(trading_platform_state.clone())
+    let transactions = warp::path!("transactions")
+        .and(warp::get())
+        .and(runtime_state.clone())
         .and_then(transactions);
 
+    // GET /candles?interval=&market=
+    let candles = warp::path!("candles")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(candles);
+
+    // GET /ticker?market=
+    let ticker = warp::path!("ticker")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(ticker);
+
     // GET /account?signer=
-    let account = warp::get()
-        .and(warp::path!("account"))
+    let account = warp::path!("account")
+        .and(warp::get())
         .and(warp::query::query())
-        .and(trading_platform_state.clone())
+        .and(runtime_state.clone())
         .and_then(account);
 
+    // GET /account/rollup?signer=
+    let rollup = warp::path!("account" / "rollup")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(rollup);
+
+    // GET /account/sub_accounts?signer=
+    let sub_accounts = warp::path!("account" / "sub_accounts")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(sub_accounts);
+
+    // POST /account/sub_accounts/open
+    let open_sub_account = warp::path!("account" / "sub_accounts" / "open")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(open_sub_account);
+
+    // GET /account/statement?signer=&by=&from=&to=&format=
+    let statement = warp::path!("account" / "statement")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(statement);
+
+    // GET /positions?signer=&mark=
+    let positions = warp::path!("positions")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(positions);
+
+    // GET /margin?signer=
+    let margin = warp::path!("margin")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(margin);
+
+    // GET /ledger/trial_balance
+    let trial_balance = warp::path!("ledger" / "trial_balance")
+        .and(warp::get())
+        .and(runtime_state.clone())
+        .and_then(trial_balance);
+
+    // GET /admin/reconcile
+    let reconcile = warp::path!("admin" / "reconcile")
+        .and(warp::get())
+        .and(runtime_state.clone())
+        .and_then(reconcile);
+
+    // GET /admin/account?signer=
+    let account_record = warp::path!("admin" / "account")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(account_record);
+
+    // POST /admin/account/open
+    let open_account = warp::path!("admin" / "account" / "open")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(open_account);
+
+    // POST /admin/account/freeze
+    let freeze_account = warp::path!("admin" / "account" / "freeze")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(freeze_account);
+
+    // POST /admin/account/unfreeze
+    let unfreeze_account = warp::path!("admin" / "account" / "unfreeze")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(unfreeze_account);
+
+    // POST /admin/account/close
+    let close_account = warp::path!("admin" / "account" / "close")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(close_account);
+
+    // POST /admin/margin
+    let set_margin = warp::path!("admin" / "margin")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(set_margin);
+
+    // POST /admin/fees
+    let set_fees = warp::path!("admin" / "fees")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(set_fees);
+
+    // GET /admin/margin_calls
+    let margin_calls = warp::path!("admin" / "margin_calls")
+        .and(warp::get())
+        .and(runtime_state.clone())
+        .and_then(margin_calls);
+
+    // GET /ledger?account=
+    let ledger = warp::path!("ledger")
+        .and(warp::get())
+        .and(warp::query::query())
+        .and(runtime_state.clone())
+        .and_then(ledger);
+
     // POST /account/deposit
-    let deposit = warp::post()
-        .and(warp::path!("account" / "deposit"))
+    let deposit = warp::path!("account" / "deposit")
+        .and(warp::post())
         .and(warp::body::json())
-        .and(trading_platform_state.clone())
+        .and(runtime_state.clone())
         .and_then(deposit);
 
     // POST /account/withdraw
-    let withdraw = warp::post()
-        .and(warp::path!("account" / "withdraw"))
+    let withdraw = warp::path!("account" / "withdraw")
+        .and(warp::post())
         .and(warp::body::json())
-        .and(trading_platform_state.clone())
+        .and(runtime_state.clone())
         .and_then(withdraw);
 
     // POST /account/send
-    let send = warp::post()
-        .and(warp::path!("account" / "send"))
+    let send = warp::path!("account" / "send")
+        .and(warp::post())
         .and(warp::body::json())
-        .and(trading_platform_state.clone())
+        .and(runtime_state.clone())
         .and_then(send);
 
+    // POST /account/transfer
+    let transfer = warp::path!("account" / "transfer")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(transfer);
+
+    // POST /account/repay
+    let repay = warp::path!("account" / "repay")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(repay);
+
     // POST /submit_order
-    let submit_order = warp::post()
-        .and(warp::path!("submit_order"))
+    let submit_order = warp::path!("submit_order")
+        .and(warp::post())
         .and(warp::body::json())
-        .and(trading_platform_state.clone())
+        .and(runtime_state.clone())
         .and_then(submit_order);
 
+    // POST /submit_oco
+    let submit_oco = warp::path!("submit_oco")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(submit_oco);
+
+    // POST /submit_bracket
+    let submit_bracket = warp::path!("submit_bracket")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(submit_bracket);
+
+    // POST /cancel_order
+    let cancel_order = warp::path!("cancel_order")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(cancel_order);
+
+    // POST /submit_trailing_stop
+    let submit_trailing_stop = warp::path!("submit_trailing_stop")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(submit_trailing_stop);
+
+    // POST /submit_conditional
+    let submit_conditional = warp::path!("submit_conditional")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(submit_conditional);
+
+    // POST /submit_pegged
+    let submit_pegged = warp::path!("submit_pegged")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(runtime_state.clone())
+        .and_then(submit_pegged);
+
     // POST /match_order
-    let match_order = warp::post()
-        .and(warp::path!("match_order"))
+    let match_order = warp::path!("match_order")
+        .and(warp::post())
         .and(warp::body::json())
         .and_then(match_order);
 
     let routes = status
         .or(orderbook)
         .or(transactions)
+        .or(candles)
+        .or(ticker)
         .or(account)
+        .or(rollup)
+        .or(sub_accounts)
+        .or(open_sub_account)
+        .or(statement)
+        .or(positions)
+        .or(margin)
+        .or(trial_balance)
+        .or(ledger)
+        .or(reconcile)
+        .or(account_record)
+        .or(open_account)
+        .or(freeze_account)
+        .or(unfreeze_account)
+        .or(close_account)
+        .or(set_margin)
+        .or(margin_calls)
+        .or(set_fees)
         .or(deposit)
         .or(withdraw)
         .or(send)
+        .or(transfer)
+        .or(repay)
         .or(submit_order)
+        .or(submit_oco)
+        .or(submit_bracket)
+        .or(cancel_order)
+        .or(submit_trailing_stop)
+        .or(submit_conditional)
+        .or(submit_pegged)
         .or(match_order)
         .recover(handle_rejection);
 
@@ -0,0 +1,2058 @@
+use octopus_engine::{
+    accounting::{Account, Accounts, Balance, Hold, Mismatch, Trade},
+    candles::{Candle, Interval},
+    engine,
+    errors::AccountError,
+    fees::FeeSchedule,
+    idempotency::{IdempotencyKeys, Outcome, Request},
+    ledger::{LedgerAccount, LedgerLine, TrialBalance},
+    liquidation::{self, LiquidationStep},
+    margin::{MarginReport, MarginStatus, MarginTerms},
+    market::{Bookkeeper, Books, Market},
+    positions::{MarkPrice, Position, PositionReport, Positions},
+    statement::{Statement, StatementRange},
+    ticker::Ticker,
+    tx::Tx,
+    types::{
+        Asset, ConditionalOrder, GroupOrder, Instrument, Order, PartialOrder, PeggedOrder, Receipt,
+        TrailingStop,
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    thread,
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of requests a market or the accounting actor queues before senders have to wait
+pub const CHANNEL_CAPACITY: usize = 1024;
+
+type Reply<T> = oneshot::Sender<T>;
+
+/// Requests served by the matching thread of a market
+enum MarketRequest {
+    SubmitOrder(Order, Reply<Result<Receipt, AccountError>>),
+    SubmitOco(Vec<GroupOrder>, Reply<Result<Vec<u64>, AccountError>>),
+    SubmitBracket(Order, u64, u64, Reply<Result<Receipt, AccountError>>),
+    SubmitTrailingStop(TrailingStop, Reply<Result<u64, AccountError>>),
+    SubmitConditional(ConditionalOrder, Reply<Result<Receipt, AccountError>>),
+    SubmitPegged(PeggedOrder, Reply<Result<Receipt, AccountError>>),
+    Cancel(String, u64, Reply<Result<Vec<u64>, AccountError>>),
+    SetFeeSchedule(FeeSchedule, Reply<()>),
+    Orderbook(Reply<Vec<PartialOrder>>),
+    Candles(Interval, Reply<Vec<Candle>>),
+    Ticker(Reply<Ticker>),
+    CancelSigner(String, Reply<Vec<u64>>),
+    Liquidate(String, Reply<Result<Vec<Receipt>, AccountError>>),
+}
+
+/// Requests served by the accounting actor
+enum AccountingRequest {
+    Open(String, Reply<Result<Account, AccountError>>),
+    OpenSubAccount(String, String, Reply<Result<Account, AccountError>>),
+    Account(String, Reply<Result<Account, AccountError>>),
+    SubAccounts(String, Reply<Result<Vec<Account>, AccountError>>),
+    Authorize(String, String, Reply<Result<(), AccountError>>),
+    Freeze(String, Reply<Result<Account, AccountError>>),
+    Unfreeze(String, Reply<Result<Account, AccountError>>),
+    Close(String, Reply<Result<Account, AccountError>>),
+    Positions(
+        String,
+        Reply<Result<Vec<(Instrument, Position)>, AccountError>>,
+    ),
+    Statement(
+        String,
+        StatementRange,
+        Reply<Result<Statement, AccountError>>,
+    ),
+    SetMargin(
+        String,
+        MarginTerms,
+        Reply<Result<MarginReport, AccountError>>,
+    ),
+    Margin(String, Reply<Result<MarginReport, AccountError>>),
+    MarginCalls(Reply<Vec<MarginReport>>),
+    TakeMarginCalls(Reply<Vec<MarginReport>>),
+    Repay(String, Asset, u64, Reply<Result<Tx, AccountError>>),
+    SettleDebt(String, Asset, Reply<Result<Option<Tx>, AccountError>>),
+    LogLiquidation(String, LiquidationStep, Reply<Result<Tx, AccountError>>),
+    LogRejection(Order, AccountError, Reply<Tx>),
+    Holdings(
+        String,
+        Reply<Result<BTreeMap<Asset, Balance>, AccountError>>,
+    ),
+    Rollup(
+        String,
+        Reply<Result<BTreeMap<Asset, Balance>, AccountError>>,
+    ),
+    Deposit(String, Asset, u64, Reply<Result<Tx, AccountError>>),
+    Withdraw(String, Asset, u64, Reply<Result<Tx, AccountError>>),
+    Send(String, String, Asset, u64, Reply<Result<Tx, AccountError>>),
+    Transfer(
+        String,
+        String,
+        String,
+        Asset,
+        u64,
+        Reply<Result<Tx, AccountError>>,
+    ),
+    Transactions(Reply<Vec<Tx>>),
+    TrialBalance(Reply<TrialBalance>),
+    Reconcile(Reply<Result<Vec<Mismatch>, AccountError>>),
+    Ledger(LedgerAccount, Reply<Vec<LedgerLine>>),
+    EnsureActive(String, Reply<Result<(), AccountError>>),
+    BuyingPower(String, Asset, Reply<Result<u64, AccountError>>),
+    Available(String, Asset, Reply<Result<u64, AccountError>>),
+    Reserve(Hold, Reply<Result<u64, AccountError>>),
+    Release(Hold, Reply<()>),
+    Settle(
+        Option<Hold>,
+        Vec<Hold>,
+        Vec<Trade>,
+        Reply<Result<(), AccountError>>,
+    ),
+    BeginIdempotent(
+        String,
+        Request,
+        Reply<Result<Option<Outcome>, AccountError>>,
+    ),
+    CompleteIdempotent(String, String, Option<Outcome>, Reply<()>),
+}
+
+/// Runs every market on its own matching thread and settles their trades through a single accounting actor.
+/// Each thread is the only writer of its state and serves one request at a time from a bounded channel,
+/// so markets never wait for each other and only meet at the accounting actor.
+#[derive(Clone)]
+pub struct Runtime {
+    markets: Arc<HashMap<String, mpsc::Sender<MarketRequest>>>,
+    accounting: mpsc::Sender<AccountingRequest>,
+}
+
+impl Runtime {
+    /// Spawns the accounting actor and a matching thread for each of the `markets`, named by their symbol.
+    /// The threads stop once every clone of the [`Runtime`] has been dropped.
+    pub fn start(markets: &[Instrument]) -> Self {
+        let (accounting, inbox) = mpsc::channel(CHANNEL_CAPACITY);
+        thread::Builder::new()
+            .name("accounting".to_string())
+            .spawn(move || Accounting::new().run(inbox))
+            .expect("failed to spawn the accounting actor");
+
+        let markets = markets
+            .iter()
+            .map(|instrument| {
+                let name = instrument.symbol();
+                let (sender, inbox) = mpsc::channel(CHANNEL_CAPACITY);
+                let market = Matching::new(instrument.clone(), accounting.clone());
+                thread::Builder::new()
+                    .name(format!("market {name}"))
+                    .spawn(move || market.run(inbox))
+                    .expect("failed to spawn a market thread");
+
+                (name, sender)
+            })
+            .collect();
+
+        Runtime {
+            markets: Arc::new(markets),
+            accounting,
+        }
+    }
+
+    /// Process a given order in its market and settle the outcome with the accounts involved.
+    /// Its trades move the price holdings are valued at, so the accounts they leave in a margin call are liquidated.
+    pub async fn submit_order(&self, market: &str, order: Order) -> Result<Receipt, AccountError> {
+        let receipt = self
+            .market(market, |reply| MarketRequest::SubmitOrder(order, reply))
+            .await??;
+        self.traded(&receipt).await;
+        Ok(receipt)
+    }
+
+    /// Places a one-cancels-other group in a market and returns the ordinals of the orders that were placed,
+    /// see [`Market::submit_oco`]
+    pub async fn submit_oco(
+        &self,
+        market: &str,
+        orders: Vec<GroupOrder>,
+    ) -> Result<Vec<u64>, AccountError> {
+        let placed = self
+            .market(market, |reply| MarketRequest::SubmitOco(orders, reply))
+            .await??;
+        // any of the orders may have traded
+        if let Err(e) = self.liquidate().await {
+            error!("liquidating after a one-cancels-other group in {market}: {e}");
+        }
+        Ok(placed)
+    }
+
+    /// Places an entry order in a market with its take-profit and stop-loss exits, see [`Market::submit_bracket`]
+    pub async fn submit_bracket(
+        &self,
+        market: &str,
+        entry: Order,
+        take_profit: u64,
+        stop_loss: u64,
+    ) -> Result<Receipt, AccountError> {
+        let receipt = self
+            .market(market, |reply| {
+                MarketRequest::SubmitBracket(entry, take_profit, stop_loss, reply)
+            })
+            .await??;
+        self.traded(&receipt).await;
+        Ok(receipt)
+    }
+
+    /// Cancels a resting or stop order of the signer in a market along with the siblings in its group
+    /// and returns the cancelled ordinals, see [`Market::cancel_order_of`]
+    pub async fn cancel_order(
+        &self,
+        market: &str,
+        signer: &str,
+        ordinal: u64,
+    ) -> Result<Vec<u64>, AccountError> {
+        self.market(market, |reply| {
+            MarketRequest::Cancel(signer.to_string(), ordinal, reply)
+        })
+        .await?
+    }
+
+    /// Holds a [`TrailingStop`] in a market until the price moves against it by its trail and returns its ordinal
+    pub async fn submit_trailing_stop(
+        &self,
+        market: &str,
+        stop: TrailingStop,
+    ) -> Result<u64, AccountError> {
+        self.market(market, |reply| {
+            MarketRequest::SubmitTrailingStop(stop, reply)
+        })
+        .await?
+    }
+
+    /// Process a [`ConditionalOrder`] in its market, see [`Runtime::submit_order`]
+    pub async fn submit_conditional(
+        &self,
+        market: &str,
+        order: ConditionalOrder,
+    ) -> Result<Receipt, AccountError> {
+        let receipt = self
+            .market(market, |reply| {
+                MarketRequest::SubmitConditional(order, reply)
+            })
+            .await??;
+        self.traded(&receipt).await;
+        Ok(receipt)
+    }
+
+    /// Places a [`PeggedOrder`] in a market at its pegged price, see [`Market::submit_pegged`]
+    pub async fn submit_pegged(
+        &self,
+        market: &str,
+        pegged: PeggedOrder,
+    ) -> Result<Receipt, AccountError> {
+        let receipt = self
+            .market(market, |reply| MarketRequest::SubmitPegged(pegged, reply))
+            .await??;
+        self.traded(&receipt).await;
+        Ok(receipt)
+    }
+
+    /// Liquidates the accounts left in a margin call by the trades of an order
+    async fn traded(&self, receipt: &Receipt) {
+        if !receipt.matches.is_empty() {
+            // the order went through either way, an account left in a margin call is caught by the next trade
+            if let Err(e) = self.liquidate().await {
+                error!("liquidating after order {}: {e}", receipt.ordinal);
+            }
+        }
+    }
+
+    /// Liquidates every account the trades since the last check put in a margin call that holds something to sell,
+    /// including the ones pushed into it by the sales, once each. Their resting orders are cancelled in every market first, then their holdings are
+    /// sold in the markets quoted in the asset they borrowed. Every step is logged as a liquidation transaction.
+    async fn liquidate(&self) -> Result<(), AccountError> {
+        let mut liquidated = HashSet::new();
+        loop {
+            let calls: Vec<MarginReport> = self
+                .accounting(AccountingRequest::TakeMarginCalls)
+                .await?
+                .into_iter()
+                .filter(|report| {
+                    liquidation::needs_liquidation(report) && !liquidated.contains(&report.signer)
+                })
+                .collect();
+            if calls.is_empty() {
+                return Ok(());
+            }
+
+            for report in calls {
+                let signer = report.signer.as_str();
+                liquidated.insert(signer.to_string());
+                self.log_liquidation(signer, LiquidationStep::started(&report))
+                    .await?;
+                let mut ordinals = vec![];
+                for market in self.markets.keys() {
+                    let cancelled = self
+                        .market(market, |reply| {
+                            MarketRequest::CancelSigner(signer.to_string(), reply)
+                        })
+                        .await?;
+                    ordinals.extend(cancelled);
+                }
+                if !ordinals.is_empty() {
+                    let step = LiquidationStep::OrdersCancelled { ordinals };
+                    self.log_liquidation(signer, step).await?;
+                }
+
+                for market in self.markets.keys() {
+                    let quoted = Instrument::parse(market).is_some_and(|i| i.quote == report.asset);
+                    if quoted && self.margin(signer).await?.status == MarginStatus::MarginCall {
+                        self.market(market, |reply| {
+                            MarketRequest::Liquidate(signer.to_string(), reply)
+                        })
+                        .await??;
+                    }
+                }
+                let report = self.margin(signer).await?;
+                self.log_liquidation(signer, LiquidationStep::finished(&report))
+                    .await?;
+            }
+        }
+    }
+
+    /// Records a step of a liquidation in the transaction log
+    async fn log_liquidation(
+        &self,
+        signer: &str,
+        step: LiquidationStep,
+    ) -> Result<Tx, AccountError> {
+        self.accounting(|reply| AccountingRequest::LogLiquidation(signer.to_string(), step, reply))
+            .await?
+    }
+
+    /// Charges the fees of the schedule on the trades of a market from now on
+    pub async fn set_fee_schedule(
+        &self,
+        market: &str,
+        schedule: FeeSchedule,
+    ) -> Result<(), AccountError> {
+        self.market(market, |reply| {
+            MarketRequest::SetFeeSchedule(schedule, reply)
+        })
+        .await
+    }
+
+    /// Fetches the complete order book of a market at this time
+    pub async fn orderbook(&self, market: &str) -> Result<Vec<PartialOrder>, AccountError> {
+        self.market(market, MarketRequest::Orderbook).await
+    }
+
+    /// Fetches the candles of an interval in a market, oldest first
+    pub async fn candles(
+        &self,
+        market: &str,
+        interval: Interval,
+    ) -> Result<Vec<Candle>, AccountError> {
+        self.market(market, |reply| MarketRequest::Candles(interval, reply))
+            .await
+    }
+
+    /// Computes the rolling 24h statistics of a market
+    pub async fn ticker(&self, market: &str) -> Result<Ticker, AccountError> {
+        self.market(market, MarketRequest::Ticker).await
+    }
+
+    /// Open an account for the signer and return its record
+    pub async fn open(&self, signer: &str) -> Result<Account, AccountError> {
+        self.accounting(|reply| AccountingRequest::Open(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Open a sub-account of a parent account, which can act on it
+    pub async fn open_sub_account(
+        &self,
+        parent: &str,
+        signer: &str,
+    ) -> Result<Account, AccountError> {
+        self.accounting(|reply| {
+            AccountingRequest::OpenSubAccount(parent.to_string(), signer.to_string(), reply)
+        })
+        .await?
+    }
+
+    /// Fetch the record of a user account
+    pub async fn account(&self, signer: &str) -> Result<Account, AccountError> {
+        self.accounting(|reply| AccountingRequest::Account(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Checks that the actor can act on a user account: it's their own or one below it
+    pub async fn authorize(&self, actor: &str, signer: &str) -> Result<(), AccountError> {
+        self.accounting(|reply| {
+            AccountingRequest::Authorize(actor.to_string(), signer.to_string(), reply)
+        })
+        .await?
+    }
+
+    /// Fetch the sub-accounts of a user account
+    pub async fn sub_accounts(&self, signer: &str) -> Result<Vec<Account>, AccountError> {
+        self.accounting(|reply| AccountingRequest::SubAccounts(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Freeze an account, then cancel its resting orders in every market.
+    /// Once frozen no new order can reserve funds, so the markets only have to clear what is resting already.
+    pub async fn freeze(&self, signer: &str) -> Result<Account, AccountError> {
+        let account = self
+            .accounting(|reply| AccountingRequest::Freeze(signer.to_string(), reply))
+            .await??;
+        for market in self.markets.keys() {
+            self.market(market, |reply| {
+                MarketRequest::CancelSigner(signer.to_string(), reply)
+            })
+            .await?;
+        }
+        Ok(account)
+    }
+
+    /// Let a frozen account trade again
+    pub async fn unfreeze(&self, signer: &str) -> Result<Account, AccountError> {
+        self.accounting(|reply| AccountingRequest::Unfreeze(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Close an account that has withdrawn everything
+    pub async fn close(&self, signer: &str) -> Result<Account, AccountError> {
+        self.accounting(|reply| AccountingRequest::Close(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Fetch the positions of a user account with their unrealized PnL at the `mark` price of each market
+    pub async fn positions(
+        &self,
+        signer: &str,
+        mark: MarkPrice,
+    ) -> Result<Vec<PositionReport>, AccountError> {
+        let positions = self
+            .accounting(|reply| AccountingRequest::Positions(signer.to_string(), reply))
+            .await??;
+
+        let mut reports = vec![];
+        for (instrument, position) in positions {
+            let ticker = self.ticker(&instrument.symbol()).await?;
+            reports.push(PositionReport::new(
+                &instrument,
+                &position,
+                mark.of(&ticker),
+            ));
+        }
+        Ok(reports)
+    }
+
+    /// Fetch the statement of a user account over a range of the transaction log
+    pub async fn statement(
+        &self,
+        signer: &str,
+        range: StatementRange,
+    ) -> Result<Statement, AccountError> {
+        self.accounting(|reply| AccountingRequest::Statement(signer.to_string(), range, reply))
+            .await?
+    }
+
+    /// Let a user account trade on margin with the terms
+    pub async fn set_margin(
+        &self,
+        signer: &str,
+        terms: MarginTerms,
+    ) -> Result<MarginReport, AccountError> {
+        self.accounting(|reply| AccountingRequest::SetMargin(signer.to_string(), terms, reply))
+            .await?
+    }
+
+    /// Fetch where a user account stands on margin at the prices its holdings last traded at in any market
+    pub async fn margin(&self, signer: &str) -> Result<MarginReport, AccountError> {
+        self.accounting(|reply| AccountingRequest::Margin(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Fetch every account in a margin call
+    pub async fn margin_calls(&self) -> Result<Vec<MarginReport>, AccountError> {
+        self.accounting(AccountingRequest::MarginCalls).await
+    }
+
+    /// Pay back what a user account borrowed of an asset
+    pub async fn repay(
+        &self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.accounting(|reply| {
+            AccountingRequest::Repay(signer.to_string(), asset.clone(), amount, reply)
+        })
+        .await?
+    }
+
+    /// Fetch the total and available balance of every asset held by a user account
+    pub async fn holdings(&self, signer: &str) -> Result<BTreeMap<Asset, Balance>, AccountError> {
+        self.accounting(|reply| AccountingRequest::Holdings(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Fetch the balances of a user account added up with those of every account below it
+    pub async fn rollup(&self, signer: &str) -> Result<BTreeMap<Asset, Balance>, AccountError> {
+        self.accounting(|reply| AccountingRequest::Rollup(signer.to_string(), reply))
+            .await?
+    }
+
+    /// Transfer funds of an asset between related accounts on behalf of the actor
+    pub async fn transfer(
+        &self,
+        actor: &str,
+        sender: &str,
+        recipient: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.accounting(|reply| {
+            AccountingRequest::Transfer(
+                actor.to_string(),
+                sender.to_string(),
+                recipient.to_string(),
+                asset.clone(),
+                amount,
+                reply,
+            )
+        })
+        .await?
+    }
+
+    /// Deposit funds of an asset
+    pub async fn deposit(
+        &self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.accounting(|reply| {
+            AccountingRequest::Deposit(signer.to_string(), asset.clone(), amount, reply)
+        })
+        .await?
+    }
+
+    /// Withdraw funds of an asset
+    pub async fn withdraw(
+        &self,
+        signer: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.accounting(|reply| {
+            AccountingRequest::Withdraw(signer.to_string(), asset.clone(), amount, reply)
+        })
+        .await?
+    }
+
+    /// Transfer funds of an asset between sender and recipient
+    pub async fn send(
+        &self,
+        sender: &str,
+        recipient: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        self.accounting(|reply| {
+            AccountingRequest::Send(
+                sender.to_string(),
+                recipient.to_string(),
+                asset.clone(),
+                amount,
+                reply,
+            )
+        })
+        .await?
+    }
+
+    /// Process a deposit, withdrawal, transfer or order once per idempotency key. A retry of a request that
+    /// succeeded returns its original outcome instead of being processed again, requests without a key always are.
+    /// The request runs on a task of its own, so it's completed even if the caller stops waiting, and its key is
+    /// released if the task is abandoned part way.
+    pub async fn process(&self, request: Request) -> Result<Outcome, AccountError> {
+        let signer = request.signer().to_string();
+        let key = request.idempotency_key().map(str::to_string);
+        let runtime = self.clone();
+        let processed = tokio::spawn(async move { runtime.process_once(request).await }).await;
+        if let (Err(_), Some(key)) = (&processed, key) {
+            self.accounting(|reply| {
+                AccountingRequest::CompleteIdempotent(signer, key, None, reply)
+            })
+            .await?;
+        }
+        processed.map_err(|_| AccountError::Unavailable("runtime".to_string()))?
+    }
+
+    /// Processes a request under its idempotency key, see [`Runtime::process`]
+    async fn process_once(&self, request: Request) -> Result<Outcome, AccountError> {
+        let key = request.idempotency_key().map(str::to_string);
+        let signer = request.signer().to_string();
+        if let Some(key) = &key {
+            let claimed = request.clone();
+            if let Some(outcome) = self
+                .accounting(|reply| AccountingRequest::BeginIdempotent(key.clone(), claimed, reply))
+                .await??
+            {
+                return Ok(outcome);
+            }
+        }
+
+        let result = match request {
+            Request::Deposit(args) => match self.authorize(args.actor(), &args.signer).await {
+                Ok(()) => self
+                    .deposit(&args.signer, &args.asset, args.amount)
+                    .await
+                    .map(Outcome::Tx),
+                Err(e) => Err(e),
+            },
+            Request::Withdraw(args) => match self.authorize(args.actor(), &args.signer).await {
+                Ok(()) => self
+                    .withdraw(&args.signer, &args.asset, args.amount)
+                    .await
+                    .map(Outcome::Tx),
+                Err(e) => Err(e),
+            },
+            Request::Send(args) => match self.authorize(args.actor(), &args.signer).await {
+                Ok(()) => self
+                    .send(&args.signer, &args.recipient, &args.asset, args.amount)
+                    .await
+                    .map(Outcome::Tx),
+                Err(e) => Err(e),
+            },
+            Request::Order(args) => match self.authorize(&args.signer, args.account()).await {
+                Ok(()) => self
+                    .submit_order(&args.market, args.order())
+                    .await
+                    .map(Outcome::Receipt),
+                Err(e) => Err(e),
+            },
+        };
+        if let Some(key) = key {
+            let outcome = result.as_ref().ok().cloned();
+            self.accounting(|reply| {
+                AccountingRequest::CompleteIdempotent(signer, key, outcome, reply)
+            })
+            .await?;
+        }
+        result
+    }
+
+    /// Fetches every transaction so far, oldest first
+    pub async fn transactions(&self) -> Result<Vec<Tx>, AccountError> {
+        self.accounting(AccountingRequest::Transactions).await
+    }
+
+    /// Computes the balance of every ledger account, including the system accounts
+    pub async fn trial_balance(&self) -> Result<TrialBalance, AccountError> {
+        self.accounting(AccountingRequest::TrialBalance).await
+    }
+
+    /// Replays the transaction log into fresh accounts and reports every signer balance that differs
+    pub async fn reconcile(&self) -> Result<Vec<Mismatch>, AccountError> {
+        self.accounting(AccountingRequest::Reconcile).await?
+    }
+
+    /// Fetches every posting to a ledger account with its running balance, oldest first
+    pub async fn ledger(&self, account: &LedgerAccount) -> Result<Vec<LedgerLine>, AccountError> {
+        self.accounting(|reply| AccountingRequest::Ledger(account.clone(), reply))
+            .await
+    }
+
+    /// Queues a request with the thread of a market and waits for its reply
+    async fn market<T>(
+        &self,
+        market: &str,
+        request: impl FnOnce(Reply<T>) -> MarketRequest,
+    ) -> Result<T, AccountError> {
+        let sender = self
+            .markets
+            .get(market)
+            .ok_or(AccountError::MarketNotFound(market.to_string()))?;
+        let (reply, response) = oneshot::channel();
+
+        sender
+            .send(request(reply))
+            .await
+            .map_err(|_| AccountError::Unavailable(market.to_string()))?;
+        response
+            .await
+            .map_err(|_| AccountError::Unavailable(market.to_string()))
+    }
+
+    /// Queues a request with the accounting actor and waits for its reply
+    async fn accounting<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> AccountingRequest,
+    ) -> Result<T, AccountError> {
+        let (reply, response) = oneshot::channel();
+
+        self.accounting
+            .send(request(reply))
+            .await
+            .map_err(|_| unavailable_accounting())?;
+        response.await.map_err(|_| unavailable_accounting())
+    }
+}
+
+fn unavailable_accounting() -> AccountError {
+    AccountError::Unavailable("accounting".to_string())
+}
+
+/// The only owner of the accounts, the transaction log, the positions built up by the trades settled
+/// and the idempotency keys of recent requests
+struct Accounting {
+    accounts: Accounts,
+    transactions: Vec<Tx>,
+    positions: Positions,
+    idempotency: IdempotencyKeys,
+}
+
+impl Accounting {
+    fn new() -> Self {
+        Accounting {
+            accounts: Accounts::new(),
+            transactions: Vec::new(),
+            positions: Positions::new(),
+            idempotency: IdempotencyKeys::default(),
+        }
+    }
+
+    /// Serves requests until every sender is gone. Replies to callers that stopped waiting are dropped.
+    fn run(mut self, mut inbox: mpsc::Receiver<AccountingRequest>) {
+        while let Some(request) = inbox.blocking_recv() {
+            match request {
+                AccountingRequest::Open(signer, reply) => {
+                    let _ = reply.send(self.accounts.open(&signer));
+                }
+                AccountingRequest::OpenSubAccount(parent, signer, reply) => {
+                    let _ = reply.send(self.accounts.open_sub_account(&parent, &signer));
+                }
+                AccountingRequest::Account(signer, reply) => {
+                    let _ = reply.send(self.accounts.account(&signer).cloned());
+                }
+                AccountingRequest::SubAccounts(signer, reply) => {
+                    let _ = reply.send(self.accounts.sub_accounts(&signer));
+                }
+                AccountingRequest::Authorize(actor, signer, reply) => {
+                    let _ = reply.send(self.accounts.ensure_authorized(&actor, &signer));
+                }
+                AccountingRequest::Freeze(signer, reply) => {
+                    let _ = reply.send(self.accounts.freeze(&signer));
+                }
+                AccountingRequest::Unfreeze(signer, reply) => {
+                    let _ = reply.send(self.accounts.unfreeze(&signer));
+                }
+                AccountingRequest::Close(signer, reply) => {
+                    let _ = reply.send(self.accounts.close(&signer));
+                }
+                AccountingRequest::Positions(signer, reply) => {
+                    let positions = self
+                        .accounts
+                        .account(&signer)
+                        .map(|_| self.positions.of(&signer));
+                    let _ = reply.send(positions);
+                }
+                AccountingRequest::Statement(signer, range, reply) => {
+                    let statement = self
+                        .accounts
+                        .account(&signer)
+                        .map(|_| Statement::generate(&signer, range, &self.transactions));
+                    let _ = reply.send(statement);
+                }
+                AccountingRequest::SetMargin(signer, terms, reply) => {
+                    let _ = reply.send(self.accounts.set_margin(&signer, terms));
+                }
+                AccountingRequest::Margin(signer, reply) => {
+                    let _ = reply.send(self.accounts.margin(&signer));
+                }
+                AccountingRequest::MarginCalls(reply) => {
+                    let _ = reply.send(self.accounts.margin_calls());
+                }
+                AccountingRequest::TakeMarginCalls(reply) => {
+                    let _ = reply.send(self.accounts.take_margin_calls());
+                }
+                AccountingRequest::Repay(signer, asset, amount, reply) => {
+                    let repaid = self.accounts.repay(&signer, &asset, amount);
+                    let _ = reply.send(repaid.inspect(|tx| self.transactions.push(tx.clone())));
+                }
+                AccountingRequest::SettleDebt(signer, asset, reply) => {
+                    let repaid = self.accounts.settle_debt(&signer, &asset);
+                    let _ = reply.send(repaid.inspect(|tx| self.transactions.extend(tx.clone())));
+                }
+                AccountingRequest::LogLiquidation(signer, step, reply) => {
+                    let _ = reply.send(self.log_liquidation(&signer, step));
+                }
+                AccountingRequest::LogRejection(order, error, reply) => {
+                    let tx = self.accounts.log_rejection(&order, &error);
+                    self.transactions.push(tx.clone());
+                    let _ = reply.send(tx);
+                }
+                AccountingRequest::Holdings(signer, reply) => {
+                    let _ = reply.send(self.accounts.holdings(&signer));
+                }
+                AccountingRequest::Rollup(signer, reply) => {
+                    let _ = reply.send(self.accounts.rollup(&signer));
+                }
+                AccountingRequest::Deposit(signer, asset, amount, reply) => {
+                    let _ = reply.send(self.deposit(&signer, &asset, amount));
+                }
+                AccountingRequest::Withdraw(signer, asset, amount, reply) => {
+                    let _ = reply.send(self.withdraw(&signer, &asset, amount));
+                }
+                AccountingRequest::Send(sender, recipient, asset, amount, reply) => {
+                    let _ = reply.send(self.send(&sender, &recipient, &asset, amount));
+                }
+                AccountingRequest::Transfer(actor, sender, recipient, asset, amount, reply) => {
+                    let transfer = self
+                        .accounts
+                        .transfer(&actor, &sender, &recipient, &asset, amount);
+                    let _ = reply.send(transfer.inspect(|tx| self.transactions.push(tx.clone())));
+                }
+                AccountingRequest::Transactions(reply) => {
+                    let _ = reply.send(self.transactions.clone());
+                }
+                AccountingRequest::TrialBalance(reply) => {
+                    let _ = reply.send(self.accounts.ledger.trial_balance());
+                }
+                AccountingRequest::Reconcile(reply) => {
+                    let _ = reply.send(self.accounts.reconcile(&self.transactions));
+                }
+                AccountingRequest::Ledger(account, reply) => {
+                    let _ = reply.send(self.accounts.ledger.account_ledger(&account));
+                }
+                AccountingRequest::EnsureActive(signer, reply) => {
+                    let _ = reply.send(self.accounts.ensure_active(&signer));
+                }
+                AccountingRequest::BuyingPower(signer, asset, reply) => {
+                    let _ = reply.send(self.accounts.buying_power(&signer, &asset));
+                }
+                AccountingRequest::Available(signer, asset, reply) => {
+                    let _ = reply.send(self.accounts.available(&signer, &asset));
+                }
+                AccountingRequest::Reserve(hold, reply) => {
+                    let _ = reply.send(self.books().reserve(&hold));
+                }
+                AccountingRequest::Release(hold, reply) => {
+                    self.books().release(&hold);
+                    let _ = reply.send(());
+                }
+                AccountingRequest::Settle(hold, releases, trades, reply) => {
+                    let settled = self.books().settle(hold.as_ref(), &releases, &trades);
+                    let _ = reply.send(settled);
+                }
+                AccountingRequest::BeginIdempotent(key, request, reply) => {
+                    let begun = self.idempotency.begin(&key, &request, engine::timestamp());
+                    let _ = reply.send(begun);
+                }
+                AccountingRequest::CompleteIdempotent(signer, key, outcome, reply) => {
+                    let now = engine::timestamp();
+                    self.idempotency.complete(&signer, &key, outcome, now);
+                    let _ = reply.send(());
+                }
+            }
+        }
+    }
+
+    fn deposit(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<Tx, AccountError> {
+        let operation = self.accounts.deposit(signer, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
+
+    fn withdraw(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<Tx, AccountError> {
+        let operation = self.accounts.withdraw(signer, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
+
+    fn send(
+        &mut self,
+        sender: &str,
+        recipient: &str,
+        asset: &Asset,
+        amount: u64,
+    ) -> Result<Tx, AccountError> {
+        let operation = self.accounts.send(sender, recipient, asset, amount);
+        operation.inspect(|tx| self.transactions.push(tx.clone()))
+    }
+
+    fn log_liquidation(&mut self, signer: &str, step: LiquidationStep) -> Result<Tx, AccountError> {
+        self.accounts.account(signer)?;
+        let tx = self.accounts.log_liquidation(signer, step);
+        self.transactions.push(tx.clone());
+        Ok(tx)
+    }
+
+    /// The accounts as the markets reserve and settle with them
+    fn books(&mut self) -> Books<'_> {
+        Books {
+            accounts: &mut self.accounts,
+            transactions: &mut self.transactions,
+            positions: &mut self.positions,
+        }
+    }
+}
+
+/// The matching thread of a single market, the only owner of its order book and market data.
+/// It funds and pays for its orders with the accounting actor.
+struct Matching {
+    market: Market,
+    books: RemoteBooks,
+}
+
+impl Matching {
+    fn new(instrument: Instrument, accounting: mpsc::Sender<AccountingRequest>) -> Self {
+        let mut market = Market::new(instrument);
+        // accounts are liquidated across every market by the runtime
+        market.liquidates = false;
+        Matching {
+            market,
+            books: RemoteBooks { accounting },
+        }
+    }
+
+    /// Serves requests until every sender is gone. Replies to callers that stopped waiting are dropped.
+    fn run(mut self, mut inbox: mpsc::Receiver<MarketRequest>) {
+        let Matching { market, books } = &mut self;
+        while let Some(request) = inbox.blocking_recv() {
+            match request {
+                MarketRequest::SubmitOrder(order, reply) => {
+                    let _ = reply.send(market.submit_order(order, books));
+                }
+                MarketRequest::SubmitOco(orders, reply) => {
+                    let _ = reply.send(market.submit_oco(orders, books));
+                }
+                MarketRequest::SubmitBracket(entry, take_profit, stop_loss, reply) => {
+                    let receipt = market.submit_bracket(entry, take_profit, stop_loss, books);
+                    let _ = reply.send(receipt);
+                }
+                MarketRequest::SubmitTrailingStop(stop, reply) => {
+                    let _ = reply.send(market.submit_trailing_stop(stop, books));
+                }
+                MarketRequest::SubmitConditional(order, reply) => {
+                    let _ = reply.send(market.submit_conditional(order, books));
+                }
+                MarketRequest::SubmitPegged(pegged, reply) => {
+                    let _ = reply.send(market.submit_pegged(pegged, books));
+                }
+                MarketRequest::Cancel(signer, ordinal, reply) => {
+                    let _ = reply.send(market.cancel_order_of(&signer, ordinal, books));
+                }
+                MarketRequest::SetFeeSchedule(schedule, reply) => {
+                    market.set_fee_schedule(schedule);
+                    let _ = reply.send(());
+                }
+                MarketRequest::Orderbook(reply) => {
+                    let _ = reply.send(market.orderbook());
+                }
+                MarketRequest::Candles(interval, reply) => {
+                    let _ = reply.send(market.candles(interval));
+                }
+                MarketRequest::Ticker(reply) => {
+                    let _ = reply.send(market.ticker());
+                }
+                MarketRequest::CancelSigner(signer, reply) => {
+                    let _ = reply.send(market.cancel_signer(&signer, books));
+                }
+                MarketRequest::Liquidate(signer, reply) => {
+                    let _ = reply.send(market.liquidate_signer(&signer, books));
+                }
+            }
+        }
+    }
+}
+
+/// The accounting actor as a market thread sees it. Every call blocks the thread until the actor has answered,
+/// calls that can't fail give up quietly once the actor is gone. An order is matched first and then reserved
+/// and settled with a single call, so a market waits on the actor once per order.
+struct RemoteBooks {
+    accounting: mpsc::Sender<AccountingRequest>,
+}
+
+impl RemoteBooks {
+    /// Sends a request to the accounting actor and blocks the thread until it has been answered
+    fn ask<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> AccountingRequest,
+    ) -> Result<T, AccountError> {
+        let (reply, response) = oneshot::channel();
+
+        self.accounting
+            .blocking_send(request(reply))
+            .map_err(|_| unavailable_accounting())?;
+        response
+            .blocking_recv()
+            .map_err(|_| unavailable_accounting())
+    }
+}
+
+impl Bookkeeper for RemoteBooks {
+    fn ensure_active(&mut self, signer: &str) -> Result<(), AccountError> {
+        self.ask(|reply| AccountingRequest::EnsureActive(signer.to_string(), reply))?
+    }
+
+    fn buying_power(&mut self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        self.ask(|reply| AccountingRequest::BuyingPower(signer.to_string(), asset.clone(), reply))?
+    }
+
+    fn available(&mut self, signer: &str, asset: &Asset) -> Result<u64, AccountError> {
+        self.ask(|reply| AccountingRequest::Available(signer.to_string(), asset.clone(), reply))?
+    }
+
+    fn reserve(&mut self, hold: &Hold) -> Result<u64, AccountError> {
+        self.ask(|reply| AccountingRequest::Reserve(hold.clone(), reply))?
+    }
+
+    fn release(&mut self, hold: &Hold) {
+        if let Err(e) = self.ask(|reply| AccountingRequest::Release(hold.clone(), reply)) {
+            error!(
+                "releasing a hold of {} {} for {}: {e}",
+                hold.amount, hold.asset, hold.signer
+            );
+        }
+    }
+
+    fn repay(&mut self, signer: &str, asset: &Asset, amount: u64) -> Result<(), AccountError> {
+        let repaid = self.ask(|reply| {
+            AccountingRequest::Repay(signer.to_string(), asset.clone(), amount, reply)
+        })?;
+        repaid.map(|_| ())
+    }
+
+    fn settle(
+        &mut self,
+        hold: Option<&Hold>,
+        releases: &[Hold],
+        trades: &[Trade],
+    ) -> Result<(), AccountError> {
+        self.ask(|reply| {
+            AccountingRequest::Settle(hold.cloned(), releases.to_vec(), trades.to_vec(), reply)
+        })?
+    }
+
+    fn margin(&mut self, signer: &str) -> Result<MarginReport, AccountError> {
+        self.ask(|reply| AccountingRequest::Margin(signer.to_string(), reply))?
+    }
+
+    fn take_margin_calls(&mut self) -> Vec<MarginReport> {
+        self.ask(AccountingRequest::TakeMarginCalls)
+            .unwrap_or_else(|e| {
+                error!("collecting margin calls: {e}");
+                vec![]
+            })
+    }
+
+    fn settle_debt(&mut self, signer: &str, asset: &Asset) {
+        let settled = self
+            .ask(|reply| AccountingRequest::SettleDebt(signer.to_string(), asset.clone(), reply))
+            .and_then(|settled| settled);
+        if let Err(e) = settled {
+            error!("settling the {asset} debt of {signer}: {e}");
+        }
+    }
+
+    fn log_liquidation(&mut self, signer: &str, step: LiquidationStep) {
+        let logged = self
+            .ask(|reply| AccountingRequest::LogLiquidation(signer.to_string(), step, reply))
+            .and_then(|logged| logged);
+        if let Err(e) = logged {
+            error!("logging a liquidation step of {signer}: {e}");
+        }
+    }
+
+    fn log_rejection(&mut self, order: &Order, error: &AccountError) {
+        let logged =
+            self.ask(|reply| AccountingRequest::LogRejection(order.clone(), error.clone(), reply));
+        if let Err(e) = logged {
+            error!("logging the rejection of an order of {}: {e}", order.signer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // reduce the warnings for naming tests
+    #![allow(non_snake_case)]
+
+    use super::*;
+    use octopus_engine::{
+        accounting::AccountStatus,
+        ledger::EntryKind,
+        tx::TxKind,
+        types::{
+            DepositArgs, Execution, OrderArgs, PegReference, SendArgs, Side, Trail, WithdrawArgs,
+        },
+    };
+    use std::future;
+
+    fn order(price: u64, amount: u64, side: Side, signer: &str) -> Order {
+        Order {
+            price,
+            amount,
+            side,
+            signer: signer.to_string(),
+        }
+    }
+
+    fn runtime(markets: &[&str]) -> Runtime {
+        let markets: Vec<Instrument> = markets
+            .iter()
+            .map(|m| Instrument::parse(m).unwrap())
+            .collect();
+        Runtime::start(&markets)
+    }
+
+    fn asset(symbol: &str) -> Asset {
+        Asset::new(symbol)
+    }
+
+    async fn balance(runtime: &Runtime, signer: &str, asset: &Asset) -> u64 {
+        let holdings = runtime.holdings(signer).await.unwrap();
+        holdings.get(asset).map_or(0, |balance| balance.total)
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_markets_match_separately_and_settle_together() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("ALICE", &asset("OCTO"), 1).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 100).await.unwrap();
+
+        runtime
+            .submit_order("OCTO/USD", order(10, 1, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        // The ask is in another market
+        let receipt = runtime
+            .submit_order("INK/USD", order(10, 1, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+        assert!(receipt.matches.is_empty());
+
+        let receipt = runtime
+            .submit_order("OCTO/USD", order(10, 1, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+        assert_eq!(receipt.matches.len(), 1);
+
+        assert!(runtime.orderbook("OCTO/USD").await.unwrap().is_empty());
+        assert_eq!(runtime.orderbook("INK/USD").await.unwrap().len(), 1);
+        assert_eq!(balance(&runtime, "ALICE", &asset("USD")).await, 10);
+        assert_eq!(balance(&runtime, "BOB", &asset("USD")).await, 90);
+        let balance = |total, available| Balance { total, available };
+        assert_eq!(
+            runtime.holdings("ALICE").await,
+            Ok(BTreeMap::from([
+                (asset("OCTO"), balance(0, 0)),
+                (asset("USD"), balance(10, 10))
+            ]))
+        );
+        // The bid resting in INK/USD keeps 10 USD locked
+        assert_eq!(
+            runtime.holdings("BOB").await,
+            Ok(BTreeMap::from([
+                (asset("OCTO"), balance(1, 1)),
+                (asset("USD"), balance(90, 80))
+            ]))
+        );
+        assert_eq!(
+            runtime.ticker("OCTO/USD").await.unwrap().last_price,
+            Some(10)
+        );
+        // Two deposits and the settlement of the trade, which references both orders
+        let transactions = runtime.transactions().await.unwrap();
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(transactions[2].reference, Some("OCTO/USD:2:1".to_string()));
+        let ids: Vec<u64> = transactions.iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        let statement = runtime
+            .statement("BOB", StatementRange::default())
+            .await
+            .unwrap();
+        assert_eq!(statement.fills.len(), 1);
+        assert_eq!(statement.closing[&asset("USD")], 90);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_markets_progress_while_another_waits_on_accounting() {
+        let runtime = runtime(&["OCTO/USD"]);
+        // INK/USD settles with an accounting actor that doesn't answer until it's told to
+        let (stalled, mut requests) = mpsc::channel(CHANNEL_CAPACITY);
+        let ink = Matching::new(Instrument::parse("INK/USD").unwrap(), stalled);
+        let (sender, inbox) = mpsc::channel(CHANNEL_CAPACITY);
+        thread::spawn(move || ink.run(inbox));
+        let mut markets = (*runtime.markets).clone();
+        markets.insert("INK/USD".to_string(), sender);
+        let runtime = Runtime {
+            markets: Arc::new(markets),
+            ..runtime
+        };
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("ALICE", &asset("OCTO"), 10).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 100).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let runtime = runtime.clone();
+            async move {
+                runtime
+                    .submit_order("INK/USD", order(10, 1, Side::Buy, "BOB"))
+                    .await
+            }
+        });
+        // The order is reserved and settled with a single request once it's matched
+        let request = requests.recv().await.unwrap();
+        assert!(matches!(
+            request,
+            AccountingRequest::Settle(Some(_), _, _, _)
+        ));
+
+        for _ in 0..10 {
+            runtime
+                .submit_order("OCTO/USD", order(5, 1, Side::Sell, "ALICE"))
+                .await
+                .unwrap();
+            let receipt = runtime
+                .submit_order("OCTO/USD", order(5, 1, Side::Buy, "BOB"))
+                .await
+                .unwrap();
+            assert_eq!(receipt.matches.len(), 1);
+        }
+        assert!(!waiting.is_finished());
+        assert!(requests.try_recv().is_err());
+
+        // Without an answer the order fails and leaves nothing behind
+        drop(request);
+        assert_eq!(
+            waiting.await.unwrap(),
+            Err(AccountError::Unavailable("accounting".to_string()))
+        );
+        assert!(runtime.orderbook("INK/USD").await.unwrap().is_empty());
+        assert_eq!(balance(&runtime, "BOB", &asset("OCTO")).await, 10);
+        assert_eq!(balance(&runtime, "BOB", &asset("USD")).await, 50);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_trades_are_posted_to_the_ledger() {
+        let runtime = runtime(&["OCTO/USD"]);
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("ALICE", &asset("OCTO"), 1).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 100).await.unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(10, 1, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(10, 1, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+
+        let trial_balance = runtime.trial_balance().await.unwrap();
+        assert!(trial_balance.is_balanced());
+        assert_eq!(
+            trial_balance.totals(),
+            BTreeMap::from([(asset("OCTO"), (1, 1)), (asset("USD"), (100, 100))])
+        );
+
+        let alice = LedgerAccount::User("ALICE".to_string());
+        let lines = runtime.ledger(&alice).await.unwrap();
+        let kinds: Vec<EntryKind> = lines.iter().map(|line| line.kind).collect();
+        // the sale moves both legs in one entry
+        assert_eq!(
+            kinds,
+            vec![
+                EntryKind::Deposit,
+                EntryKind::Settlement,
+                EntryKind::Settlement
+            ]
+        );
+        assert_eq!(lines[1].entry, lines[2].entry);
+        assert_eq!(
+            runtime
+                .ledger(&LedgerAccount::External)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_places_every_kind_of_order() {
+        let runtime = runtime(&["OCTO/USD"]);
+        for signer in ["ALICE", "BOB", "CHARLIE"] {
+            runtime.open(signer).await.unwrap();
+        }
+        runtime.deposit("ALICE", &asset("OCTO"), 10).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 100).await.unwrap();
+        runtime
+            .deposit("CHARLIE", &asset("USD"), 100)
+            .await
+            .unwrap();
+
+        let entry = runtime
+            .submit_bracket("OCTO/USD", order(10, 2, Side::Buy, "BOB"), 12, 8)
+            .await
+            .unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(10, 2, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        // The entry filled, so its take-profit rests in the book
+        let orderbook = runtime.orderbook("OCTO/USD").await.unwrap();
+        assert_eq!(orderbook.len(), 1);
+        assert_eq!(
+            (orderbook[0].signer.as_str(), orderbook[0].price),
+            ("BOB", 12)
+        );
+        assert_eq!(orderbook[0].ordinal, entry.ordinal + 3);
+
+        let pegged = PeggedOrder {
+            order: order(0, 2, Side::Buy, "CHARLIE"),
+            reference: PegReference::Market,
+            offset: -1,
+            limit: Some(11),
+        };
+        let receipt = runtime.submit_pegged("OCTO/USD", pegged).await.unwrap();
+        assert!(receipt.matches.is_empty());
+        let stop = TrailingStop {
+            order: order(0, 1, Side::Sell, "ALICE"),
+            trail: Trail::Amount(2),
+            execution: Execution::Market,
+        };
+        runtime
+            .submit_trailing_stop("OCTO/USD", stop)
+            .await
+            .unwrap();
+
+        let placed = runtime
+            .submit_oco(
+                "OCTO/USD",
+                vec![GroupOrder::Limit(order(11, 1, Side::Sell, "ALICE"))],
+            )
+            .await
+            .unwrap();
+        assert_eq!(placed.len(), 1);
+        assert_eq!(balance(&runtime, "CHARLIE", &asset("OCTO")).await, 1);
+        assert_eq!(balance(&runtime, "BOB", &asset("USD")).await, 80);
+        assert_eq!(balance(&runtime, "ALICE", &asset("USD")).await, 31);
+        assert_eq!(runtime.reconcile().await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_cancel_order_cancels_its_siblings() {
+        let runtime = runtime(&["OCTO/USD"]);
+        for signer in ["ALICE", "BOB"] {
+            runtime.open(signer).await.unwrap();
+        }
+        runtime.deposit("ALICE", &asset("OCTO"), 10).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 100).await.unwrap();
+
+        let entry = runtime
+            .submit_bracket("OCTO/USD", order(10, 2, Side::Buy, "BOB"), 12, 8)
+            .await
+            .unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(10, 2, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        let (stop_loss, take_profit) = (entry.ordinal + 2, entry.ordinal + 3);
+        assert_eq!(
+            runtime.orderbook("OCTO/USD").await.unwrap()[0].ordinal,
+            take_profit
+        );
+
+        // Only the signer of the order can cancel it
+        assert_eq!(
+            runtime.cancel_order("OCTO/USD", "ALICE", take_profit).await,
+            Err(AccountError::OrderNotFound(take_profit))
+        );
+        assert_eq!(
+            runtime.cancel_order("OCTO/USD", "BOB", take_profit).await,
+            Ok(vec![take_profit, stop_loss])
+        );
+        assert!(runtime.orderbook("OCTO/USD").await.unwrap().is_empty());
+        assert_eq!(
+            runtime.cancel_order("OCTO/USD", "BOB", stop_loss).await,
+            Err(AccountError::OrderNotFound(stop_loss))
+        );
+        // Nothing of BOB's stays set aside
+        let holdings = runtime.holdings("BOB").await.unwrap();
+        assert_eq!(holdings[&asset("OCTO")].available, 2);
+        assert_eq!(runtime.reconcile().await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_charges_fees_by_the_volume_traded_in_a_market() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("ALICE", &asset("OCTO"), 200).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 3_000).await.unwrap();
+        let schedule = FeeSchedule::new(100, 200).with_tier(1_000, 0, 0);
+        runtime
+            .set_fee_schedule("OCTO/USD", schedule)
+            .await
+            .unwrap();
+        assert_eq!(
+            runtime
+                .set_fee_schedule("ABC/USD", FeeSchedule::default())
+                .await,
+            Err(AccountError::MarketNotFound("ABC/USD".to_string()))
+        );
+
+        runtime
+            .submit_order("OCTO/USD", order(10, 100, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        let receipt = runtime
+            .submit_order("OCTO/USD", order(10, 100, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+        // The taker pays 2% of the OCTO bought, the maker 1% of the USD received
+        assert_eq!(receipt.fee, 2);
+        assert_eq!(balance(&runtime, "BOB", &asset("OCTO")).await, 98);
+        assert_eq!(balance(&runtime, "ALICE", &asset("USD")).await, 990);
+
+        // Both traded 1,000 USD, which reaches the free tier
+        runtime
+            .submit_order("OCTO/USD", order(10, 100, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        let receipt = runtime
+            .submit_order("OCTO/USD", order(10, 100, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+        assert_eq!(receipt.fee, 0);
+        assert_eq!(balance(&runtime, "BOB", &asset("OCTO")).await, 198);
+        assert_eq!(balance(&runtime, "ALICE", &asset("USD")).await, 1_990);
+        let fees = runtime
+            .transactions()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|tx| matches!(tx.kind, TxKind::Fee { .. }))
+            .count();
+        assert_eq!(fees, 2);
+        assert_eq!(runtime.reconcile().await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_positions_are_marked_per_market() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("ALICE", &asset("OCTO"), 5).await.unwrap();
+        runtime.deposit("ALICE", &asset("INK"), 5).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 100).await.unwrap();
+        for (market, price) in [("OCTO/USD", 10), ("INK/USD", 4)] {
+            runtime
+                .submit_order(market, order(price, 3, Side::Sell, "ALICE"))
+                .await
+                .unwrap();
+            runtime
+                .submit_order(market, order(price, 3, Side::Buy, "BOB"))
+                .await
+                .unwrap();
+        }
+        runtime
+            .submit_order("OCTO/USD", order(12, 1, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(12, 1, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+
+        let positions = runtime.positions("BOB", MarkPrice::Last).await.unwrap();
+        let summary: Vec<(String, i128, Option<i128>)> = positions
+            .iter()
+            .map(|p| (p.instrument.symbol(), p.net, p.unrealized_pnl))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("INK/USD".to_string(), 3, Some(0)),
+                ("OCTO/USD".to_string(), 4, Some(6)),
+            ]
+        );
+        // Nothing rests in either book to take a mid price from
+        let positions = runtime.positions("ALICE", MarkPrice::Mid).await.unwrap();
+        assert!(positions.iter().all(|p| p.unrealized_pnl.is_none()));
+        assert_eq!(
+            runtime.positions("CHARLIE", MarkPrice::Last).await,
+            Err(AccountError::NotFound("CHARLIE".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_rejects_unknown_market_and_missing_funds() {
+        let runtime = runtime(&["OCTO/USD"]);
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 5).await.unwrap();
+        runtime.deposit("BOB", &asset("INK"), 5).await.unwrap();
+
+        assert_eq!(
+            runtime.orderbook("ABC/USD").await,
+            Err(AccountError::MarketNotFound("ABC/USD".to_string()))
+        );
+        assert_eq!(
+            runtime
+                .submit_order("OCTO/USD", order(10, 1, Side::Buy, "BOB"))
+                .await,
+            Err(AccountError::UnderFunded("BOB".to_string()))
+        );
+        // BOB holds INK but no OCTO to sell
+        assert_eq!(
+            runtime
+                .submit_order("OCTO/USD", order(10, 1, Side::Sell, "BOB"))
+                .await,
+            Err(AccountError::UnderFunded("BOB".to_string()))
+        );
+        assert_eq!(
+            runtime
+                .submit_order("OCTO/USD", order(10, 1, Side::Buy, "CHARLIE"))
+                .await,
+            Err(AccountError::NotFound("CHARLIE".to_string()))
+        );
+        assert!(runtime.orderbook("OCTO/USD").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_rejects_orders_whose_value_overflows() {
+        let runtime = runtime(&["OCTO/USD"]);
+        runtime.open("BOB").await.unwrap();
+        runtime
+            .deposit("BOB", &asset("USD"), u64::MAX)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            runtime
+                .submit_order("OCTO/USD", order(u64::MAX, 2, Side::Buy, "BOB"))
+                .await,
+            Err(AccountError::ArithmeticOverflow)
+        );
+        assert!(runtime.orderbook("OCTO/USD").await.unwrap().is_empty());
+
+        runtime
+            .submit_order("OCTO/USD", order(u64::MAX, 1, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+        assert_eq!(runtime.orderbook("OCTO/USD").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_retried_requests_are_processed_once() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        runtime.open("BOB").await.unwrap();
+        let deposit = Request::Deposit(DepositArgs {
+            signer: "BOB".to_string(),
+            amount: 10,
+            asset: asset("USD"),
+            idempotency_key: Some("deposit-1".to_string()),
+            actor: None,
+        });
+        let first = runtime.process(deposit.clone()).await.unwrap();
+        assert_eq!(runtime.process(deposit).await, Ok(first));
+        assert_eq!(
+            runtime.holdings("BOB").await.unwrap()[&asset("USD")].total,
+            10
+        );
+
+        let order = OrderArgs {
+            signer: "BOB".to_string(),
+            side: Side::Buy,
+            amount: 1,
+            price: 10,
+            market: "INK/USD".to_string(),
+            account: None,
+            idempotency_key: Some("order-1".to_string()),
+        };
+        let receipt = runtime
+            .process(Request::Order(order.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            runtime.process(Request::Order(order.clone())).await,
+            Ok(receipt)
+        );
+        assert_eq!(runtime.orderbook("INK/USD").await.unwrap().len(), 1);
+        // The key now belongs to the INK/USD order
+        let elsewhere = OrderArgs {
+            market: "OCTO/USD".to_string(),
+            ..order
+        };
+        assert_eq!(
+            runtime.process(Request::Order(elsewhere)).await,
+            Err(AccountError::IdempotencyConflict("order-1".to_string()))
+        );
+        assert!(runtime.orderbook("OCTO/USD").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_requests_the_caller_stopped_waiting_for_are_completed() {
+        let runtime = runtime(&["OCTO/USD"]);
+        runtime.open("BOB").await.unwrap();
+        let deposit = Request::Deposit(DepositArgs {
+            signer: "BOB".to_string(),
+            amount: 10,
+            asset: asset("USD"),
+            idempotency_key: Some("deposit-1".to_string()),
+            actor: None,
+        });
+
+        // Gives up as soon as the request is under way
+        tokio::select! {
+            biased;
+            _ = runtime.process(deposit.clone()) => unreachable!("processed without yielding"),
+            _ = future::ready(()) => {}
+        }
+        let retried = loop {
+            match runtime.process(deposit.clone()).await {
+                Err(AccountError::RequestInProgress(_)) => tokio::task::yield_now().await,
+                outcome => break outcome,
+            }
+        };
+        assert!(matches!(retried, Ok(Outcome::Tx(_))));
+        assert_eq!(balance(&runtime, "BOB", &asset("USD")).await, 10);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_margin_values_holdings_of_every_market() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        for signer in ["ALICE", "BOB"] {
+            runtime.open(signer).await.unwrap();
+        }
+        runtime.deposit("ALICE", &asset("OCTO"), 5).await.unwrap();
+        runtime.deposit("ALICE", &asset("INK"), 5).await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 30).await.unwrap();
+        let terms = MarginTerms {
+            asset: asset("USD"),
+            credit_limit: 50,
+            maintenance_bps: 4_000,
+        };
+        runtime.set_margin("BOB", terms).await.unwrap();
+
+        for (market, price) in [("OCTO/USD", 10), ("INK/USD", 4)] {
+            runtime
+                .submit_order(market, order(price, 5, Side::Sell, "ALICE"))
+                .await
+                .unwrap();
+            runtime
+                .submit_order(market, order(price, 5, Side::Buy, "BOB"))
+                .await
+                .unwrap();
+        }
+
+        let margin = runtime.margin("BOB").await.unwrap();
+        assert_eq!(margin.debt, 40);
+        assert_eq!(margin.exposure, 50 + 20);
+        assert_eq!(margin.equity, 30);
+        assert_eq!(margin.status, MarginStatus::Healthy);
+        assert!(runtime.margin_calls().await.unwrap().is_empty());
+
+        // Selling OCTO for less pushes BOB into a margin call
+        runtime.deposit("ALICE", &asset("USD"), 5).await.unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(5, 1, Side::Buy, "ALICE"))
+            .await
+            .unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(5, 1, Side::Sell, "BOB"))
+            .await
+            .unwrap();
+        let calls = runtime.margin_calls().await.unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].equity, 5 + 20 + 20 - 40);
+
+        runtime.repay("BOB", &asset("USD"), 5).await.unwrap();
+        assert_eq!(runtime.margin("BOB").await.unwrap().debt, 35);
+        let kinds: Vec<&str> = runtime
+            .transactions()
+            .await
+            .unwrap()
+            .iter()
+            .map(|tx| tx.kind.operation())
+            .filter(|operation| ["BORROW", "REPAY"].contains(operation))
+            .collect();
+        assert_eq!(kinds, vec!["BORROW", "BORROW", "REPAY"]);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_liquidates_accounts_in_a_margin_call() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        for signer in ["ALICE", "BOB", "CHARLIE", "DAVE"] {
+            runtime.open(signer).await.unwrap();
+        }
+        runtime.deposit("ALICE", &asset("USD"), 50).await.unwrap();
+        runtime.deposit("ALICE", &asset("INK"), 1).await.unwrap();
+        runtime.deposit("BOB", &asset("OCTO"), 15).await.unwrap();
+        runtime.deposit("CHARLIE", &asset("OCTO"), 1).await.unwrap();
+        runtime.deposit("DAVE", &asset("USD"), 200).await.unwrap();
+        let terms = MarginTerms {
+            asset: asset("USD"),
+            credit_limit: 200,
+            maintenance_bps: 2_500,
+        };
+        runtime.set_margin("ALICE", terms).await.unwrap();
+
+        runtime
+            .submit_order("OCTO/USD", order(10, 15, Side::Sell, "BOB"))
+            .await
+            .unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(10, 15, Side::Buy, "ALICE"))
+            .await
+            .unwrap();
+        for (market, price, amount) in [("OCTO/USD", 20, 5), ("INK/USD", 100, 1)] {
+            runtime
+                .submit_order(market, order(price, amount, Side::Sell, "ALICE"))
+                .await
+                .unwrap();
+        }
+        runtime
+            .submit_order("OCTO/USD", order(8, 20, Side::Buy, "DAVE"))
+            .await
+            .unwrap();
+
+        // The trade at 8 puts ALICE in a margin call, 5 units sold to DAVE repay 40 of the debt
+        runtime
+            .submit_order("OCTO/USD", order(8, 1, Side::Sell, "CHARLIE"))
+            .await
+            .unwrap();
+        let margin = runtime.margin("ALICE").await.unwrap();
+        assert_eq!(margin.status, MarginStatus::Healthy);
+        assert_eq!(margin.debt, 60);
+        assert_eq!(margin.equity, 20);
+        assert_eq!(margin.maintenance_margin, 20);
+        assert!(runtime.margin_calls().await.unwrap().is_empty());
+        assert_eq!(balance(&runtime, "ALICE", &asset("OCTO")).await, 10);
+        assert_eq!(balance(&runtime, "DAVE", &asset("OCTO")).await, 6);
+        for market in ["OCTO/USD", "INK/USD"] {
+            let orderbook = runtime.orderbook(market).await.unwrap();
+            assert!(!orderbook.iter().any(|po| po.signer == "ALICE"));
+        }
+
+        let steps: Vec<LiquidationStep> = runtime
+            .transactions()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|tx| match tx.kind {
+                TxKind::Liquidation { step, .. } => Some(step),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(steps.len(), 4);
+        assert!(matches!(
+            &steps[1],
+            LiquidationStep::OrdersCancelled { ordinals } if ordinals.len() == 2
+        ));
+        assert_eq!(
+            steps[2],
+            LiquidationStep::Sold {
+                market: "OCTO/USD".to_string(),
+                ordinal: 6,
+                amount: 5,
+                filled: 5
+            }
+        );
+        assert_eq!(steps[3], LiquidationStep::finished(&margin),);
+        assert_eq!(runtime.reconcile().await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_parents_act_for_their_sub_accounts() {
+        let runtime = runtime(&["OCTO/USD"]);
+        runtime.open("DESK").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        for sub_account in ["DESK_B", "DESK_A"] {
+            runtime.open_sub_account("DESK", sub_account).await.unwrap();
+        }
+        let names: Vec<String> = runtime
+            .sub_accounts("DESK")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|account| account.signer)
+            .collect();
+        assert_eq!(names, vec!["DESK_A", "DESK_B"]);
+        runtime.deposit("DESK", &asset("USD"), 100).await.unwrap();
+        runtime.deposit("BOB", &asset("OCTO"), 2).await.unwrap();
+        runtime
+            .transfer("DESK", "DESK", "DESK_B", &asset("USD"), 50)
+            .await
+            .unwrap();
+        runtime
+            .transfer("DESK", "DESK_B", "DESK_A", &asset("USD"), 20)
+            .await
+            .unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(10, 2, Side::Sell, "BOB"))
+            .await
+            .unwrap();
+
+        let order = |signer: &str, account: &str| {
+            Request::Order(OrderArgs {
+                signer: signer.to_string(),
+                side: Side::Buy,
+                amount: 1,
+                price: 10,
+                market: "OCTO/USD".to_string(),
+                account: Some(account.to_string()),
+                idempotency_key: None,
+            })
+        };
+        runtime.process(order("DESK", "DESK_A")).await.unwrap();
+        assert_eq!(balance(&runtime, "DESK_A", &asset("OCTO")).await, 1);
+        assert_eq!(
+            runtime.process(order("DESK_A", "DESK")).await,
+            Err(AccountError::Unauthorized("DESK".to_string()))
+        );
+        assert_eq!(
+            runtime
+                .transfer("DESK_A", "DESK_B", "DESK_A", &asset("USD"), 5)
+                .await,
+            Err(AccountError::Unauthorized("DESK_B".to_string()))
+        );
+
+        // Moving funds in or out of an account takes the same authority
+        let withdraw = Request::Withdraw(WithdrawArgs {
+            signer: "DESK_B".to_string(),
+            amount: 5,
+            asset: asset("USD"),
+            idempotency_key: None,
+            actor: Some("DESK".to_string()),
+        });
+        runtime.process(withdraw).await.unwrap();
+        let send = Request::Send(SendArgs {
+            signer: "DESK".to_string(),
+            recipient: "DESK_A".to_string(),
+            amount: 5,
+            asset: asset("USD"),
+            idempotency_key: None,
+            actor: Some("DESK_A".to_string()),
+        });
+        assert_eq!(
+            runtime.process(send).await,
+            Err(AccountError::Unauthorized("DESK".to_string()))
+        );
+        let deposit = Request::Deposit(DepositArgs {
+            signer: "DESK".to_string(),
+            amount: 5,
+            asset: asset("USD"),
+            idempotency_key: None,
+            actor: Some("BOB".to_string()),
+        });
+        assert_eq!(
+            runtime.process(deposit).await,
+            Err(AccountError::Unauthorized("DESK".to_string()))
+        );
+
+        let rollup = runtime.rollup("DESK").await.unwrap();
+        assert_eq!(rollup[&asset("USD")].total, 85);
+        assert_eq!(rollup[&asset("OCTO")].total, 1);
+        assert_eq!(runtime.reconcile().await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_markets_cannot_spend_the_same_funds() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("BOB", &asset("USD"), 10).await.unwrap();
+
+        runtime
+            .submit_order("OCTO/USD", order(10, 1, Side::Buy, "BOB"))
+            .await
+            .unwrap();
+        // The resting bid locks all of BOB's cash
+        assert_eq!(
+            runtime
+                .submit_order("INK/USD", order(10, 1, Side::Buy, "BOB"))
+                .await,
+            Err(AccountError::UnderFunded("BOB".to_string()))
+        );
+        assert_eq!(
+            runtime.withdraw("BOB", &asset("USD"), 1).await,
+            Err(AccountError::UnderFunded("BOB".to_string()))
+        );
+        assert!(runtime.orderbook("INK/USD").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_order_that_cannot_settle_is_rolled_back() {
+        let runtime = runtime(&["OCTO/USD"]);
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("ALICE", &asset("OCTO"), 1).await.unwrap();
+        runtime
+            .deposit("ALICE", &asset("USD"), u64::MAX)
+            .await
+            .unwrap();
+        runtime.deposit("BOB", &asset("USD"), 100).await.unwrap();
+
+        runtime
+            .submit_order("OCTO/USD", order(10, 1, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+        let orderbook = runtime.orderbook("OCTO/USD").await.unwrap();
+
+        // Paying ALICE would overflow her balance
+        assert_eq!(
+            runtime
+                .submit_order("OCTO/USD", order(10, 1, Side::Buy, "BOB"))
+                .await,
+            Err(AccountError::OverFunded("ALICE".to_string(), 10))
+        );
+        assert_eq!(runtime.orderbook("OCTO/USD").await.unwrap(), orderbook);
+        assert_eq!(
+            runtime.holdings("BOB").await.unwrap()[&asset("USD")],
+            Balance {
+                total: 100,
+                available: 100
+            }
+        );
+        assert_eq!(runtime.transactions().await.unwrap().len(), 3);
+        assert_eq!(runtime.ticker("OCTO/USD").await.unwrap().last_price, None);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_margin_order_that_cannot_settle_repays_its_loan() {
+        let runtime = runtime(&["OCTO/USD"]);
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        runtime.deposit("ALICE", &asset("OCTO"), 1).await.unwrap();
+        runtime
+            .deposit("ALICE", &asset("USD"), u64::MAX)
+            .await
+            .unwrap();
+        runtime.deposit("BOB", &asset("USD"), 4).await.unwrap();
+        let terms = MarginTerms {
+            asset: asset("USD"),
+            credit_limit: 100,
+            maintenance_bps: 2_500,
+        };
+        runtime.set_margin("BOB", terms).await.unwrap();
+        runtime
+            .submit_order("OCTO/USD", order(10, 1, Side::Sell, "ALICE"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            runtime
+                .submit_order("OCTO/USD", order(10, 1, Side::Buy, "BOB"))
+                .await,
+            Err(AccountError::OverFunded("ALICE".to_string(), 10))
+        );
+        assert_eq!(runtime.margin("BOB").await.unwrap().debt, 0);
+        assert_eq!(
+            runtime.holdings("BOB").await.unwrap()[&asset("USD")],
+            Balance {
+                total: 4,
+                available: 4
+            }
+        );
+        assert_eq!(runtime.reconcile().await.unwrap(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_Runtime_freeze_cancels_orders_in_every_market() {
+        let runtime = runtime(&["OCTO/USD", "INK/USD"]);
+        assert_eq!(
+            runtime.deposit("ALICE", &asset("USD"), 100).await,
+            Err(AccountError::NotFound("ALICE".to_string()))
+        );
+        let account = runtime.open("ALICE").await.unwrap();
+        assert_eq!(runtime.account("ALICE").await, Ok(account));
+        runtime.deposit("ALICE", &asset("USD"), 100).await.unwrap();
+        for market in ["OCTO/USD", "INK/USD"] {
+            runtime
+                .submit_order(market, order(10, 2, Side::Buy, "ALICE"))
+                .await
+                .unwrap();
+        }
+
+        let account = runtime.freeze("ALICE").await.unwrap();
+        assert_eq!(account.status, AccountStatus::Frozen);
+        assert!(runtime.orderbook("OCTO/USD").await.unwrap().is_empty());
+        assert!(runtime.orderbook("INK/USD").await.unwrap().is_empty());
+        assert_eq!(
+            runtime.holdings("ALICE").await.unwrap()[&asset("USD")],
+            Balance {
+                total: 100,
+                available: 100
+            }
+        );
+        assert_eq!(
+            runtime
+                .submit_order("OCTO/USD", order(10, 1, Side::Buy, "ALICE"))
+                .await,
+            Err(AccountError::Frozen("ALICE".to_string()))
+        );
+        assert_eq!(
+            runtime.close("ALICE").await,
+            Err(AccountError::NotEmpty("ALICE".to_string()))
+        );
+
+        runtime.unfreeze("ALICE").await.unwrap();
+        runtime.withdraw("ALICE", &asset("USD"), 100).await.unwrap();
+        let account = runtime.close("ALICE").await.unwrap();
+        assert_eq!(account.status, AccountStatus::Closed);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_Runtime_concurrent_markets_conserve_cash() {
+        let markets = ["A/USD", "B/USD", "C/USD", "D/USD"];
+        let runtime = runtime(&markets);
+        runtime.open("ALICE").await.unwrap();
+        runtime.open("BOB").await.unwrap();
+        for market in markets {
+            let base = Instrument::parse(market).unwrap().base;
+            runtime.deposit("ALICE", &base, 100).await.unwrap();
+        }
+        runtime.deposit("BOB", &asset("USD"), 10_000).await.unwrap();
+
+        let tasks: Vec<_> = markets
+            .into_iter()
+            .map(|market| {
+                let runtime = runtime.clone();
+                tokio::spawn(async move {
+                    for _ in 0..50 {
+                        runtime
+                            .submit_order(market, order(5, 2, Side::Sell, "ALICE"))
+                            .await
+                            .unwrap();
+                        runtime
+                            .submit_order(market, order(5, 2, Side::Buy, "BOB"))
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(runtime.reconcile().await, Ok(vec![]));
+        // 4 markets * 50 trades * 2 units * 5
+        assert_eq!(balance(&runtime, "ALICE", &asset("USD")).await, 2_000);
+        assert_eq!(balance(&runtime, "BOB", &asset("USD")).await, 8_000);
+        for market in markets {
+            let base = Instrument::parse(market).unwrap().base;
+            assert_eq!(balance(&runtime, "ALICE", &base).await, 0);
+            assert_eq!(balance(&runtime, "BOB", &base).await, 100);
+            assert!(runtime.orderbook(market).await.unwrap().is_empty());
+        }
+    }
+}